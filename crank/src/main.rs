@@ -14,15 +14,21 @@ use serum_common::client::rpc::{
     create_and_init_mint, create_token_account, mint_to_new_account, send_txn,
 };
 use serum_common::client::Cluster;
+use serum_dex::fees::FeeSchedule;
 use serum_dex::instruction::{MarketInstruction, NewOrderInstructionV1};
 use serum_dex::matching::{OrderType, Side};
 use serum_dex::state::gen_vault_signer_key;
 use serum_dex::state::Event;
 use serum_dex::state::EventQueueHeader;
+use serum_dex::state::EventView;
 use serum_dex::state::MarketState;
+use serum_dex::state::OpenOrders;
+use serum_dex::state::OpenOrdersExtension;
 use serum_dex::state::QueueHeader;
 use serum_dex::state::Request;
 use serum_dex::state::RequestQueueHeader;
+use serum_dex::state::RequestView;
+use serum_dex::state::LEGACY_MARKET_STATE_LEN;
 use solana_client::rpc_client::RpcClient;
 use solana_client::rpc_config::RpcSendTransactionConfig;
 use solana_sdk::commitment_config::CommitmentConfig;
@@ -174,6 +180,12 @@ enum Command {
         #[clap(long, short)]
         signer: Option<String>,
     },
+    SettleFundsToWallets {
+        payer: String,
+        dex_program_id: Pubkey,
+        market: Pubkey,
+        orders: Vec<Pubkey>,
+    },
     ListMarket {
         payer: String,
         dex_program_id: Pubkey,
@@ -336,6 +348,18 @@ fn main() -> Result<()> {
                 pc_wallet,
             )?;
         }
+        Command::SettleFundsToWallets {
+            ref payer,
+            ref dex_program_id,
+            ref market,
+            ref orders,
+        } => {
+            let payer = read_keypair_file(payer)?;
+            let market_keys = get_keys_for_market(&client, dex_program_id, &market)?;
+            for orders in orders {
+                settle_funds_to_wallets(&client, dex_program_id, &payer, &market_keys, orders)?;
+            }
+        }
         Command::ListMarket {
             ref payer,
             ref dex_program_id,
@@ -378,6 +402,9 @@ struct MarketPubkeys {
     coin_vault: Box<Pubkey>,
     pc_vault: Box<Pubkey>,
     vault_signer_key: Box<Pubkey>,
+    stop_orders: Option<Box<Pubkey>>,
+    pegged_orders: Option<Box<Pubkey>>,
+    market_stats: Option<Box<Pubkey>>,
 }
 
 #[cfg(target_endian = "little")]
@@ -416,7 +443,13 @@ fn get_keys_for_market<'a>(
     market: &'a Pubkey,
 ) -> Result<MarketPubkeys> {
     let account_data: Vec<u8> = client.get_account_data(&market)?;
-    let words: Cow<[u64]> = remove_dex_account_padding(&account_data)?;
+    let mut words: Cow<[u64]> = remove_dex_account_padding(&account_data)?;
+    // markets from before MarketV2 lack the later fields, which read as zero
+    if words.len() * size_of::<u64>() == LEGACY_MARKET_STATE_LEN {
+        words
+            .to_mut()
+            .resize(size_of::<MarketState>() / size_of::<u64>(), 0);
+    }
     let market_state: MarketState =
         transmute_one_pedantic::<MarketState>(transmute_to_bytes(&words))
             .map_err(|e| e.without_src())?;
@@ -438,6 +471,27 @@ fn get_keys_for_market<'a>(
         ))),
         pc_vault: Box::new(Pubkey::new(transmute_one_to_bytes(&market_state.pc_vault))),
         vault_signer_key: Box::new(vault_signer_key),
+        stop_orders: if market_state.has_stop_orders() {
+            Some(Box::new(Pubkey::new(transmute_one_to_bytes(
+                &market_state.stop_orders,
+            ))))
+        } else {
+            None
+        },
+        pegged_orders: if market_state.has_pegged_orders() {
+            Some(Box::new(Pubkey::new(transmute_one_to_bytes(
+                &market_state.pegged_orders,
+            ))))
+        } else {
+            None
+        },
+        market_stats: if market_state.has_market_stats() {
+            Some(Box::new(Pubkey::new(transmute_one_to_bytes(
+                &market_state.market_stats,
+            ))))
+        } else {
+            None
+        },
     })
 }
 
@@ -530,7 +584,11 @@ fn consume_events_loop(
                 "Total event queue length: {}, market {}, coin {}, pc {}",
                 event_q_len, market, coin_wallet, pc_wallet
             );
-            let accounts = seg0.iter().chain(seg1.iter()).map(|event| event.owner);
+            let accounts = seg0
+                .iter()
+                .chain(seg1.iter())
+                .filter(|event| !matches!(event.as_view(), Ok(EventView::MarketParams { .. })))
+                .map(|event| event.owner);
             let mut used_accounts = BTreeSet::new();
             for account in accounts {
                 used_accounts.insert(account);
@@ -698,7 +756,11 @@ fn consume_events(
     } else {
         info!("Total event queue length: {}", seg0.len() + seg1.len());
     }
-    let accounts = seg0.iter().chain(seg1.iter()).map(|event| event.owner);
+    let accounts = seg0
+        .iter()
+        .chain(seg1.iter())
+        .filter(|event| !matches!(event.as_view(), Ok(EventView::MarketParams { .. })))
+        .map(|event| event.owner);
     let mut orders_accounts: Vec<_> = accounts.collect();
     orders_accounts.sort_unstable();
     orders_accounts.dedup();
@@ -963,6 +1025,65 @@ fn settle_funds(
     Ok(())
 }
 
+fn settle_funds_to_wallets(
+    client: &RpcClient,
+    program_id: &Pubkey,
+    payer: &Keypair,
+    state: &MarketPubkeys,
+    orders: &Pubkey,
+) -> Result<()> {
+    let account_data: Vec<u8> = client.get_account_data(orders)?;
+    let words: Cow<[u64]> = remove_dex_account_padding(&account_data)?;
+    let (header_words, extension_words) = words.split_at(size_of::<OpenOrders>() >> 3);
+    let open_orders: OpenOrders = transmute_one::<OpenOrders>(transmute_to_bytes(header_words))
+        .map_err(|e| e.without_src())?;
+    // only accounts with the V2 layout have an extension, and it follows the header
+    let extension: Option<OpenOrdersExtension> = if open_orders.is_v2() {
+        let extension_words = &extension_words[..size_of::<OpenOrdersExtension>() >> 3];
+        Some(
+            transmute_one::<OpenOrdersExtension>(transmute_to_bytes(extension_words))
+                .map_err(|e| e.without_src())?,
+        )
+    } else {
+        None
+    };
+    let extension = match extension {
+        Some(extension) if extension.settle_coin_wallet != [0; 4] => extension,
+        _ => {
+            warn!("{} has no registered wallets, skipping", orders);
+            return Ok(());
+        }
+    };
+    let coin_wallet = Pubkey::new(transmute_one_to_bytes(&extension.settle_coin_wallet));
+    let pc_wallet = Pubkey::new(transmute_one_to_bytes(&extension.settle_pc_wallet));
+
+    let data = MarketInstruction::SettleFundsToWallets.pack();
+    let instruction = Instruction {
+        program_id: *program_id,
+        data,
+        accounts: vec![
+            AccountMeta::new(*state.market, false),
+            AccountMeta::new(*orders, false),
+            AccountMeta::new(*state.coin_vault, false),
+            AccountMeta::new(*state.pc_vault, false),
+            AccountMeta::new(coin_wallet, false),
+            AccountMeta::new(pc_wallet, false),
+            AccountMeta::new_readonly(*state.vault_signer_key, false),
+            AccountMeta::new_readonly(spl_token::ID, false),
+        ],
+    };
+    let (recent_hash, _fee_calc) = client.get_recent_blockhash()?;
+    let txn = Transaction::new_signed_with_payer(
+        &[instruction],
+        Some(&payer.pubkey()),
+        &[payer],
+        recent_hash,
+    );
+    debug_println!("Settling {} ...", orders);
+    send_txn(client, &txn, false)?;
+    Ok(())
+}
+
 fn list_market(
     client: &RpcClient,
     program_id: &Pubkey,
@@ -1001,10 +1122,14 @@ fn list_market(
         &asks_key.pubkey(),
         &req_q_key.pubkey(),
         &event_q_key.pubkey(),
+        None,
+        None,
         coin_lot_size,
         pc_lot_size,
         vault_signer_nonce,
         100,
+        Some(FeeSchedule::default()),
+        None,
     )?;
     debug_println!(
         "initialize_market_instruction: {:#?}",
@@ -1046,6 +1171,9 @@ fn list_market(
         coin_vault: Box::new(coin_vault.pubkey()),
         pc_vault: Box::new(pc_vault.pubkey()),
         vault_signer_key: Box::new(vault_signer_pk),
+        stop_orders: None,
+        pegged_orders: None,
+        market_stats: None,
     })
 }
 
@@ -1066,8 +1194,14 @@ fn gen_listing_params(
     coin_mint: &Pubkey,
     pc_mint: &Pubkey,
 ) -> Result<(ListingKeys, Vec<Instruction>)> {
-    let (market_key, create_market) = create_dex_account(client, program_id, payer, 376)?;
-    let (req_q_key, create_req_q) = create_dex_account(client, program_id, payer, 640)?;
+    let (market_key, create_market) =
+        create_dex_account(client, program_id, payer, size_of::<MarketState>())?;
+    let (req_q_key, create_req_q) = create_dex_account(
+        client,
+        program_id,
+        payer,
+        size_of::<RequestQueueHeader>() + 16 * size_of::<Request>(),
+    )?;
     let (event_q_key, create_event_q) = create_dex_account(client, program_id, payer, 1 << 20)?;
     let (bids_key, create_bids) = create_dex_account(client, program_id, payer, 1 << 16)?;
     let (asks_key, create_asks) = create_dex_account(client, program_id, payer, 1 << 16)?;
@@ -1126,19 +1260,51 @@ fn match_orders(
     coin_wallet: &Pubkey,
     pc_wallet: &Pubkey,
 ) -> Result<()> {
-    let instruction_data: Vec<u8> = MarketInstruction::MatchOrders(2).pack();
+    let limit = 2;
+    let instruction_data: Vec<u8> = MarketInstruction::MatchOrders(limit).pack();
+
+    let mut accounts = vec![
+        AccountMeta::new(*state.market, false),
+        AccountMeta::new(*state.req_q, false),
+        AccountMeta::new(*state.event_q, false),
+        AccountMeta::new(*state.bids, false),
+        AccountMeta::new(*state.asks, false),
+        AccountMeta::new(*coin_wallet, false),
+        AccountMeta::new(*pc_wallet, false),
+        AccountMeta::new_readonly(solana_sdk::sysvar::clock::ID, false),
+    ];
+    accounts.extend(
+        [
+            &state.stop_orders,
+            &state.pegged_orders,
+            &state.market_stats,
+        ]
+        .iter()
+        .filter_map(|key| key.as_deref())
+        .map(|key| AccountMeta::new(*key, false)),
+    );
+    // markets that auto-consume apply the events of the requests about to be matched
+    // to their owners' OpenOrders accounts right away; other markets ignore these
+    let req_q_data = client.get_account_data(&state.req_q)?;
+    let req_q_words: Cow<[u64]> = remove_dex_account_padding(&req_q_data)?;
+    let (_header, seg0, seg1) = parse_req_queue(&req_q_words)?;
+    let mut owners = BTreeSet::new();
+    for request in seg0.iter().chain(seg1).take(limit as usize) {
+        let owner = match request.as_view()? {
+            RequestView::NewOrder { owner, .. } => owner,
+            RequestView::CancelOrder { expected_owner, .. } => expected_owner,
+        };
+        owners.insert(Pubkey::new(transmute_to_bytes(owner)));
+    }
+    accounts.extend(
+        owners
+            .into_iter()
+            .map(|owner| AccountMeta::new(owner, false)),
+    );
 
     let instruction = Instruction {
         program_id: *program_id,
-        accounts: vec![
-            AccountMeta::new(*state.market, false),
-            AccountMeta::new(*state.req_q, false),
-            AccountMeta::new(*state.event_q, false),
-            AccountMeta::new(*state.bids, false),
-            AccountMeta::new(*state.asks, false),
-            AccountMeta::new(*coin_wallet, false),
-            AccountMeta::new(*pc_wallet, false),
-        ],
+        accounts,
         data: instruction_data,
     };
 
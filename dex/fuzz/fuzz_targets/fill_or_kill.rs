@@ -0,0 +1,210 @@
+#![no_main]
+
+use std::mem::size_of;
+use std::num::NonZeroU64;
+
+use arbitrary::Arbitrary;
+use bumpalo::Bump;
+use libfuzzer_sys::fuzz_target;
+use solana_sdk::account_info::AccountInfo;
+
+use serum_dex::instruction::{MarketInstruction, NewOrderInstructionV2, SelfTradeBehavior};
+use serum_dex::matching::{OrderType, Side};
+use serum_dex::state::{strip_header, EventQueue, EventView, OpenOrders, Queue, ToAlignedBytes};
+use serum_dex_fuzz::{
+    new_dex_owned_account_with_lamports, new_sol_account, new_token_account, process_instruction,
+    setup_market, MarketAccounts, COIN_LOT_SIZE,
+};
+
+#[derive(Debug, Arbitrary)]
+struct RestingOrder {
+    limit_price: u16,
+    max_qty: u16,
+}
+
+#[derive(Debug, Arbitrary)]
+struct FillOrKillScenario {
+    taker_side: Side,
+    taker_limit_price: u16,
+    taker_max_qty: u16,
+    resting_orders: Vec<RestingOrder>,
+}
+
+struct Owner<'bump> {
+    signer_account: AccountInfo<'bump>,
+    orders_account: AccountInfo<'bump>,
+    coin_account: AccountInfo<'bump>,
+    pc_account: AccountInfo<'bump>,
+}
+
+const INITIAL_BALANCE: u64 = 1 << 60;
+
+impl<'bump> Owner<'bump> {
+    fn new(market_accounts: &MarketAccounts<'bump>, bump: &'bump Bump) -> Self {
+        let signer_account = new_sol_account(10, &bump);
+        let orders_account = new_dex_owned_account_with_lamports(
+            size_of::<OpenOrders>(),
+            10000000000,
+            market_accounts.market.owner,
+            &bump,
+        );
+        let coin_account = new_token_account(
+            market_accounts.coin_mint.key,
+            signer_account.key,
+            INITIAL_BALANCE,
+            &bump,
+        );
+        let pc_account = new_token_account(
+            market_accounts.pc_mint.key,
+            signer_account.key,
+            INITIAL_BALANCE,
+            &bump,
+        );
+        Self {
+            signer_account,
+            orders_account,
+            coin_account,
+            pc_account,
+        }
+    }
+
+    fn place_order(&self, market_accounts: &MarketAccounts<'bump>, order: NewOrderInstructionV2) {
+        process_instruction(
+            market_accounts.market.owner,
+            &[
+                market_accounts.market.clone(),
+                self.orders_account.clone(),
+                market_accounts.req_q.clone(),
+                if order.side == Side::Bid {
+                    self.pc_account.clone()
+                } else {
+                    self.coin_account.clone()
+                },
+                self.signer_account.clone(),
+                market_accounts.coin_vault.clone(),
+                market_accounts.pc_vault.clone(),
+                market_accounts.spl_token_program.clone(),
+                market_accounts.rent_sysvar.clone(),
+            ],
+            &MarketInstruction::NewOrderV2(order).pack(),
+        )
+        .unwrap();
+        match_orders(market_accounts);
+    }
+}
+
+fn match_orders(market_accounts: &MarketAccounts) {
+    process_instruction(
+        market_accounts.market.owner,
+        &[
+            market_accounts.market.clone(),
+            market_accounts.req_q.clone(),
+            market_accounts.event_q.clone(),
+            market_accounts.bids.clone(),
+            market_accounts.asks.clone(),
+            market_accounts.coin_vault.clone(),
+            market_accounts.pc_vault.clone(),
+            market_accounts.clock_sysvar.clone(),
+        ],
+        &MarketInstruction::MatchOrders(1000).pack(),
+    )
+    .unwrap();
+}
+
+fuzz_target!(|scenario: FillOrKillScenario| { run_scenario(scenario) });
+
+fn run_scenario(scenario: FillOrKillScenario) {
+    let (taker_limit_price, taker_max_qty) = match (
+        NonZeroU64::new(scenario.taker_limit_price as u64),
+        NonZeroU64::new(scenario.taker_max_qty as u64),
+    ) {
+        (Some(limit_price), Some(max_qty)) => (limit_price, max_qty),
+        _ => return,
+    };
+
+    let bump = Bump::new();
+    let market_accounts = setup_market(&bump);
+    let maker = Owner::new(&market_accounts, &bump);
+    let taker = Owner::new(&market_accounts, &bump);
+
+    let maker_side = match scenario.taker_side {
+        Side::Bid => Side::Ask,
+        Side::Ask => Side::Bid,
+    };
+    let mut crossing_qty = 0;
+    for resting_order in scenario.resting_orders.iter().take(32) {
+        let (limit_price, max_qty) = match (
+            NonZeroU64::new(resting_order.limit_price as u64),
+            NonZeroU64::new(resting_order.max_qty as u64),
+        ) {
+            (Some(limit_price), Some(max_qty)) => (limit_price, max_qty),
+            _ => continue,
+        };
+        let crosses = match scenario.taker_side {
+            Side::Bid => limit_price <= taker_limit_price,
+            Side::Ask => limit_price >= taker_limit_price,
+        };
+        if crosses {
+            crossing_qty += max_qty.get();
+        }
+        maker.place_order(
+            &market_accounts,
+            NewOrderInstructionV2 {
+                side: maker_side,
+                limit_price,
+                max_qty,
+                order_type: OrderType::PostOnly,
+                client_id: 0,
+                self_trade_behavior: SelfTradeBehavior::DecrementTake,
+                expiry: None,
+            },
+        );
+    }
+
+    taker.place_order(
+        &market_accounts,
+        NewOrderInstructionV2 {
+            side: scenario.taker_side,
+            limit_price: taker_limit_price,
+            max_qty: taker_max_qty,
+            order_type: OrderType::FillOrKill,
+            client_id: 0,
+            self_trade_behavior: SelfTradeBehavior::DecrementTake,
+            expiry: None,
+        },
+    );
+
+    let taker_address = taker.orders_account.key.to_aligned_bytes();
+    let (header, buf) = strip_header(&market_accounts.event_q, false).unwrap();
+    let events: EventQueue = Queue::new(header, buf);
+    let mut filled_qty = 0;
+    for event in events.iter() {
+        match event.as_view().unwrap() {
+            EventView::Fill {
+                side,
+                maker: false,
+                native_qty_paid,
+                native_qty_received,
+                owner,
+                ..
+            } => {
+                assert_eq!(owner, &taker_address);
+                filled_qty += match side {
+                    Side::Bid => native_qty_received / COIN_LOT_SIZE,
+                    Side::Ask => native_qty_paid / COIN_LOT_SIZE,
+                };
+            }
+            _ => {}
+        }
+    }
+
+    assert!(
+        filled_qty == 0 || filled_qty == taker_max_qty.get(),
+        "fill-or-kill order partially filled: {} of {}",
+        filled_qty,
+        taker_max_qty
+    );
+    if crossing_qty < taker_max_qty.get() {
+        assert_eq!(filled_qty, 0);
+    }
+}
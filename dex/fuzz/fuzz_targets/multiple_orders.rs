@@ -175,7 +175,7 @@ fn run_actions(actions: Vec<Action>) {
     let mut actions = Vec::new();
     for (owner_id, owner) in owners.iter().sorted_by_key(|(order_id, _)| *order_id) {
         if let Some(orders) = owner.open_orders() {
-            for (slot, order_id) in orders.orders.iter().enumerate() {
+            for (slot, order_id) in orders.slots.orders.iter().enumerate() {
                 if *order_id > 0 {
                     if actions.len() % 8 == 0 {
                         actions.push(Action::MatchOrders(100));
@@ -364,6 +364,7 @@ fn run_action<'bump>(
             .map_err(|e| match e {
                 DexError::ErrorCode(DexErrorCode::InsufficientFunds) => {}
                 DexError::ErrorCode(DexErrorCode::RequestQueueFull) => {}
+                DexError::ErrorCode(DexErrorCode::InvalidOrderExpiry) => {}
                 e => Err(e).unwrap(),
             })
             .ok();
@@ -385,11 +386,11 @@ fn run_action<'bump>(
             };
             let (side, order_id, client_order_id) = {
                 if let Some(orders) = owner.open_orders() {
-                    if let Some(side) = orders.slot_side(slot) {
+                    if let Some(side) = orders.slots.slot_side(slot as usize) {
                         (
                             side,
-                            orders.orders[slot as usize],
-                            orders.client_order_ids[slot as usize],
+                            orders.slots.orders[slot as usize],
+                            orders.slots.client_order_ids[slot as usize],
                         )
                     } else {
                         return;
@@ -411,7 +412,7 @@ fn run_action<'bump>(
                     side,
                     order_id,
                     owner: [0u64; 4],
-                    owner_slot: slot,
+                    owner_slot: slot as u16,
                 })
             };
             process_instruction(
@@ -450,6 +451,7 @@ fn run_action<'bump>(
                 market_accounts.asks.clone(),
                 market_accounts.coin_vault.clone(),
                 market_accounts.pc_vault.clone(),
+                market_accounts.clock_sysvar.clone(),
             ],
             &MarketInstruction::MatchOrders(limit).pack(),
         )
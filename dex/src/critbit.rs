@@ -1,9 +1,11 @@
 use crate::{
     error::{DexErrorCode, DexResult},
     fees::FeeTier,
+    instruction::{OrderExpiry, SelfTradeBehavior},
+    matching::OrderType,
 };
 use arrayref::{array_refs, mut_array_refs};
-use bytemuck::{cast, cast_mut, cast_ref, cast_slice, cast_slice_mut, Pod, Zeroable};
+use bytemuck::{bytes_of_mut, cast, cast_mut, cast_ref, cast_slice, cast_slice_mut, Pod, Zeroable};
 
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use static_assertions::const_assert_eq;
@@ -32,7 +34,7 @@ struct InnerNode {
     prefix_len: u32,
     key: u128,
     children: [u32; 2],
-    _padding: [u64; 5],
+    _padding: [u64; 10],
 }
 unsafe impl Zeroable for InnerNode {}
 unsafe impl Pod for InnerNode {}
@@ -45,17 +47,40 @@ impl InnerNode {
     }
 }
 
+// Pegged orders share their price fields with iceberg orders, so they're told apart
+// by an explicit tag. Zeroed leaves are ordinary orders.
+#[derive(IntoPrimitive, TryFromPrimitive)]
+#[repr(u8)]
+enum LeafKind {
+    Order = 0,
+    Pegged = 1,
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[repr(C, align(8))]
 pub struct LeafNode {
     tag: u32,
+    // the low byte of the owner slot is where the whole of it was in 72-byte nodes
     owner_slot: u8,
     fee_tier: u8,
-    padding: [u8; 2],
+    owner_slot_hi: u8,
+    order_type: u8,
     key: u128,
     owner: [u64; 4],
     quantity: u64,
     client_order_id: u64,
+    // Orders waiting in the stop order slab, where the key is derived from the
+    // trigger price rather than the limit price, keep their limit price and locked
+    // pc here. Iceberg orders on the book keep their display size and hidden reserve.
+    // Pegged orders keep their cap price in the first field, and their entries in the
+    // pegged orders slab keep the price of the order id they go by in the second.
+    limit_price_or_display_qty: u64,
+    native_pc_qty_locked_or_hidden_qty: u64,
+    expiry_slot: u64,
+    expiry_unix_timestamp: i64,
+    self_trade_behavior: u8,
+    kind: u8,
+    _padding: [u8; 6],
 }
 unsafe impl Zeroable for LeafNode {}
 unsafe impl Pod for LeafNode {}
@@ -63,25 +88,77 @@ unsafe impl Pod for LeafNode {}
 impl LeafNode {
     #[inline]
     pub fn new(
-        owner_slot: u8,
+        owner_slot: u16,
         key: &u128,
         owner: &[u64; 4],
         quantity: u64,
         fee_tier: FeeTier,
         client_order_id: u64,
     ) -> Self {
+        let [owner_slot, owner_slot_hi] = owner_slot.to_le_bytes();
         LeafNode {
             tag: NodeTag::LeafNode.into(),
             owner_slot,
+            owner_slot_hi,
             fee_tier: fee_tier.into(),
-            padding: [0; 2],
+            order_type: OrderType::Limit.into(),
+            self_trade_behavior: SelfTradeBehavior::DecrementTake.into(),
+            kind: LeafKind::Order.into(),
             key: *key,
             owner: *owner,
             quantity,
             client_order_id,
+            limit_price_or_display_qty: 0,
+            native_pc_qty_locked_or_hidden_qty: 0,
+            expiry_slot: 0,
+            expiry_unix_timestamp: 0,
+            _padding: Zeroable::zeroed(),
         }
     }
 
+    #[inline]
+    pub fn set_expiry(&mut self, expiry: Option<OrderExpiry>) {
+        let (expiry_slot, expiry_unix_timestamp) = OrderExpiry::into_parts(expiry);
+        self.expiry_slot = expiry_slot;
+        self.expiry_unix_timestamp = expiry_unix_timestamp;
+    }
+
+    #[inline]
+    pub fn set_self_trade_behavior(&mut self, self_trade_behavior: SelfTradeBehavior) {
+        self.self_trade_behavior = self_trade_behavior.into();
+    }
+
+    #[inline]
+    pub fn set_stop_order_params(
+        &mut self,
+        order_type: OrderType,
+        self_trade_behavior: SelfTradeBehavior,
+        limit_price: u64,
+        native_pc_qty_locked: u64,
+    ) {
+        self.order_type = order_type.into();
+        self.self_trade_behavior = self_trade_behavior.into();
+        self.limit_price_or_display_qty = limit_price;
+        self.native_pc_qty_locked_or_hidden_qty = native_pc_qty_locked;
+    }
+
+    #[inline]
+    pub fn set_iceberg_params(&mut self, display_qty: u64, hidden_qty: u64) {
+        self.limit_price_or_display_qty = display_qty;
+        self.native_pc_qty_locked_or_hidden_qty = hidden_qty;
+    }
+
+    #[inline]
+    pub fn set_pegged_order_params(&mut self, cap_price: Option<NonZeroU64>) {
+        self.kind = LeafKind::Pegged.into();
+        self.limit_price_or_display_qty = cap_price.map_or(0, NonZeroU64::get);
+    }
+
+    #[inline]
+    pub fn set_peg_price(&mut self, price: u64) {
+        self.native_pc_qty_locked_or_hidden_qty = price;
+    }
+
     #[inline]
     pub fn fee_tier(&self) -> FeeTier {
         FeeTier::try_from_primitive(self.fee_tier).unwrap()
@@ -113,14 +190,83 @@ impl LeafNode {
     }
 
     #[inline]
-    pub fn owner_slot(&self) -> u8 {
-        self.owner_slot
+    pub fn owner_slot(&self) -> u16 {
+        u16::from_le_bytes([self.owner_slot, self.owner_slot_hi])
     }
 
     #[inline]
     pub fn client_order_id(&self) -> u64 {
         self.client_order_id
     }
+
+    #[inline]
+    pub fn order_type(&self) -> OrderType {
+        OrderType::try_from_primitive(self.order_type).unwrap()
+    }
+
+    #[inline]
+    pub fn self_trade_behavior(&self) -> SelfTradeBehavior {
+        SelfTradeBehavior::try_from_primitive(self.self_trade_behavior).unwrap()
+    }
+
+    #[inline]
+    pub fn limit_price(&self) -> u64 {
+        self.limit_price_or_display_qty
+    }
+
+    #[inline]
+    pub fn native_pc_qty_locked(&self) -> u64 {
+        self.native_pc_qty_locked_or_hidden_qty
+    }
+
+    #[inline]
+    pub fn display_qty(&self) -> u64 {
+        self.limit_price_or_display_qty
+    }
+
+    #[inline]
+    pub fn hidden_qty(&self) -> u64 {
+        if self.is_pegged() {
+            return 0;
+        }
+        self.native_pc_qty_locked_or_hidden_qty
+    }
+
+    #[inline]
+    pub fn is_pegged(&self) -> bool {
+        self.kind == u8::from(LeafKind::Pegged)
+    }
+
+    #[inline]
+    pub fn cap_price(&self) -> Option<NonZeroU64> {
+        NonZeroU64::new(self.limit_price_or_display_qty)
+    }
+
+    #[inline]
+    pub fn peg_price(&self) -> u64 {
+        self.native_pc_qty_locked_or_hidden_qty
+    }
+
+    /// The price a bid's pc is locked at. Pegged bids lock at their cap, so that
+    /// they can follow the book up to it.
+    #[inline]
+    pub fn lock_price(&self) -> NonZeroU64 {
+        match self.cap_price() {
+            Some(cap_price) if self.is_pegged() => cap_price,
+            _ => self.price(),
+        }
+    }
+
+    /// The visible quantity plus any hidden reserve.
+    #[inline]
+    pub fn total_quantity(&self) -> u64 {
+        self.quantity + self.hidden_qty()
+    }
+
+    #[inline]
+    pub fn expiry(&self) -> Option<OrderExpiry> {
+        OrderExpiry::from_parts(self.expiry_slot, self.expiry_unix_timestamp)
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -128,7 +274,7 @@ impl LeafNode {
 struct FreeNode {
     tag: u32,
     next: u32,
-    _padding: [u64; 8],
+    _padding: [u64; 13],
 }
 unsafe impl Zeroable for FreeNode {}
 unsafe impl Pod for FreeNode {}
@@ -141,7 +287,12 @@ const fn _const_max(a: usize, b: usize) -> usize {
 const _INNER_NODE_SIZE: usize = size_of::<InnerNode>();
 const _LEAF_NODE_SIZE: usize = size_of::<LeafNode>();
 const _FREE_NODE_SIZE: usize = size_of::<FreeNode>();
-const _NODE_SIZE: usize = 72;
+// Nodes were 72 bytes until leaves grew to hold stop order, expiry, iceberg and
+// pegged order fields. Each node starts with its old layout and the new fields are
+// zero for what the old nodes could hold, so Slab::copy_legacy_nodes only has to
+// move them apart.
+const _NODE_SIZE: usize = 112;
+const LEGACY_NODE_SIZE: usize = 72;
 
 const _INNER_NODE_ALIGN: usize = align_of::<InnerNode>();
 const _LEAF_NODE_ALIGN: usize = align_of::<LeafNode>();
@@ -160,7 +311,7 @@ const_assert_eq!(_NODE_ALIGN, _FREE_NODE_ALIGN);
 #[repr(C, align(8))]
 pub struct AnyNode {
     tag: u32,
-    padding: [u32; 17],
+    padding: [u32; 27],
 }
 unsafe impl Zeroable for AnyNode {}
 unsafe impl Pod for AnyNode {}
@@ -183,7 +334,6 @@ impl AnyNode {
         }
     }
 
-    #[cfg(test)]
     fn prefix_len(&self) -> u32 {
         match self.case().unwrap() {
             NodeRef::Inner(&InnerNode { prefix_len, .. }) => prefix_len,
@@ -214,6 +364,14 @@ impl AnyNode {
         }
     }
 
+    #[inline]
+    pub fn as_leaf(&self) -> Option<&LeafNode> {
+        match self.case() {
+            Some(NodeRef::Leaf(leaf_ref)) => Some(leaf_ref),
+            _ => None,
+        }
+    }
+
     #[inline]
     pub fn as_leaf_mut(&mut self) -> Option<&mut LeafNode> {
         match self.case_mut() {
@@ -252,7 +410,7 @@ struct SlabHeader {
 unsafe impl Zeroable for SlabHeader {}
 unsafe impl Pod for SlabHeader {}
 
-const SLAB_HEADER_LEN: usize = size_of::<SlabHeader>();
+pub(crate) const SLAB_HEADER_LEN: usize = size_of::<SlabHeader>();
 
 #[cfg(debug_assertions)]
 unsafe fn invariant(check: bool) {
@@ -269,6 +427,56 @@ unsafe fn invariant(check: bool) {
     }
 }
 
+fn read_legacy_node(nodes_bytes: &[u8], handle: NodeHandle) -> AnyNode {
+    let offset = handle as usize * LEGACY_NODE_SIZE;
+    let mut node = AnyNode::zeroed();
+    bytes_of_mut(&mut node)[..LEGACY_NODE_SIZE]
+        .copy_from_slice(&nodes_bytes[offset..offset + LEGACY_NODE_SIZE]);
+    node
+}
+
+#[cfg(test)]
+fn write_legacy_node(nodes_bytes: &mut [u8], handle: NodeHandle, node: &AnyNode) {
+    let offset = handle as usize * LEGACY_NODE_SIZE;
+    nodes_bytes[offset..offset + LEGACY_NODE_SIZE]
+        .copy_from_slice(&bytemuck::bytes_of(node)[..LEGACY_NODE_SIZE]);
+}
+
+pub struct Leaves<'a> {
+    slab: &'a Slab,
+    descending: bool,
+    // each inner node has a longer prefix than its parent, so a path from the root
+    // passes at most 128 of them, and the stack holds one sibling for each
+    stack: [NodeHandle; 130],
+    stack_len: usize,
+}
+
+impl<'a> Iterator for Leaves<'a> {
+    type Item = &'a LeafNode;
+
+    fn next(&mut self) -> Option<&'a LeafNode> {
+        while self.stack_len > 0 {
+            self.stack_len -= 1;
+            match self
+                .slab
+                .get(self.stack[self.stack_len])
+                .unwrap()
+                .case()
+                .unwrap()
+            {
+                NodeRef::Leaf(leaf) => return Some(leaf),
+                NodeRef::Inner(inner) => {
+                    let (first, second) = if self.descending { (1, 0) } else { (0, 1) };
+                    self.stack[self.stack_len] = inner.children[second];
+                    self.stack[self.stack_len + 1] = inner.children[first];
+                    self.stack_len += 2;
+                }
+            }
+        }
+        None
+    }
+}
+
 #[repr(transparent)]
 pub struct Slab([u8]);
 
@@ -292,6 +500,27 @@ impl Slab {
         slab
     }
 
+    /// Copies a slab of 72-byte nodes, as slabs were before leaves grew, into this
+    /// empty one. Every node keeps its handle, so this slab has to have room for all
+    /// the nodes the old one has used.
+    pub fn copy_legacy_nodes(&mut self, legacy_bytes: &[u8]) -> DexResult {
+        assert!(legacy_bytes.len() >= SLAB_HEADER_LEN);
+        let (legacy_header_bytes, legacy_nodes_bytes) =
+            array_refs![legacy_bytes, SLAB_HEADER_LEN; .. ;];
+        let legacy_header: &SlabHeader = cast_ref(legacy_header_bytes);
+        let bump_index = legacy_header.bump_index as usize;
+        assert!(bump_index * LEGACY_NODE_SIZE <= legacy_nodes_bytes.len());
+        if bump_index > self.nodes().len() {
+            Err(DexErrorCode::SlabTooSmall)?
+        }
+        let (header, nodes) = self.parts_mut();
+        *header = *legacy_header;
+        for (handle, node) in nodes[..bump_index].iter_mut().enumerate() {
+            *node = read_legacy_node(legacy_nodes_bytes, handle as NodeHandle);
+        }
+        Ok(())
+    }
+
     #[inline]
     pub fn assert_minimum_capacity(&self, capacity: u32) -> DexResult {
         if self.nodes().len() <= (capacity as usize) * 2 {
@@ -490,7 +719,10 @@ impl Slab {
     }
 
     fn find_min_max(&self, find_max: bool) -> Option<NodeHandle> {
-        let mut root: NodeHandle = self.root()?;
+        self.find_min_max_under(self.root()?, find_max)
+    }
+
+    fn find_min_max_under(&self, mut root: NodeHandle, find_max: bool) -> Option<NodeHandle> {
         loop {
             let root_contents = self.get(root).unwrap();
             match root_contents.case().unwrap() {
@@ -504,6 +736,15 @@ impl Slab {
     }
 
     #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.header().leaf_count == 0
+    }
+
+    #[inline]
+    pub fn leaf_count(&self) -> u64 {
+        self.header().leaf_count
+    }
+
     pub fn find_min(&self) -> Option<NodeHandle> {
         self.find_min_max(false)
     }
@@ -513,6 +754,36 @@ impl Slab {
         self.find_min_max(true)
     }
 
+    /// Finds the leaf with the lowest key above the given one, so a walk over the
+    /// leaves can stop and pick up again while the slab changes in between.
+    pub fn find_min_above(&self, search_key: &u128) -> Option<NodeHandle> {
+        let mut node_handle: NodeHandle = self.root()?;
+        // the right sibling last passed on the way down, whose keys are all above
+        let mut next_subtree = None;
+        loop {
+            let node_ref = self.get(node_handle).unwrap();
+            let node_key = node_ref.key().unwrap();
+            let common_prefix_len = (*search_key ^ node_key).leading_zeros();
+            if common_prefix_len < node_ref.prefix_len() {
+                if node_key > *search_key {
+                    return self.find_min_max_under(node_handle, false);
+                }
+                break;
+            }
+            match node_ref.case().unwrap() {
+                NodeRef::Leaf(_) => break,
+                NodeRef::Inner(inner) => {
+                    let (child_handle, crit_bit) = inner.walk_down(search_key);
+                    if !crit_bit {
+                        next_subtree = Some(inner.children[1]);
+                    }
+                    node_handle = child_handle;
+                }
+            }
+        }
+        self.find_min_max_under(next_subtree?, false)
+    }
+
     #[inline]
     pub fn insert_leaf(
         &mut self,
@@ -588,8 +859,7 @@ impl Slab {
         }
     }
 
-    #[cfg(test)]
-    fn find_by_key(&self, search_key: &u128) -> Option<NodeHandle> {
+    pub fn find_by_key(&self, search_key: &u128) -> Option<NodeHandle> {
         let mut node_handle: NodeHandle = self.root()?;
         loop {
             let node_ref = self.get(node_handle).unwrap();
@@ -659,6 +929,36 @@ impl Slab {
         Some(cast(self.remove(child_h).unwrap()))
     }
 
+    /// Walks the leaves in ascending key order and returns the first one
+    /// matching the predicate.
+    pub fn find_leaf_by(&self, mut predicate: impl FnMut(&LeafNode) -> bool) -> Option<&LeafNode> {
+        self.leaves(false).find(|leaf| predicate(leaf))
+    }
+
+    /// Walks the leaves in descending key order and returns the first one
+    /// matching the predicate.
+    pub fn rfind_leaf_by(&self, mut predicate: impl FnMut(&LeafNode) -> bool) -> Option<&LeafNode> {
+        self.leaves(true).find(|leaf| predicate(leaf))
+    }
+
+    /// A cursor over the leaves in key order, which needs no allocation.
+    pub fn leaves(&self, descending: bool) -> Leaves<'_> {
+        let mut stack: [NodeHandle; 130] = [0; 130];
+        let stack_len = match self.root() {
+            Some(root) => {
+                stack[0] = root;
+                1
+            }
+            None => 0,
+        };
+        Leaves {
+            slab: self,
+            descending,
+            stack,
+            stack_len,
+        }
+    }
+
     #[inline]
     pub fn remove_min(&mut self) -> Option<LeafNode> {
         self.remove_by_key(&self.get(self.find_min()?)?.key()?)
@@ -702,6 +1002,24 @@ impl Slab {
         hexdump::hexdump(cast_slice(self.nodes()));
     }
 
+    /// The slab cut down to 72-byte nodes, as a program from before the upgrade would
+    /// have stored it, in a buffer with room for exactly the nodes it has used.
+    #[cfg(test)]
+    pub(crate) fn to_legacy_nodes(&self) -> Vec<u64> {
+        let bump_index = self.header().bump_index as usize;
+        let mut legacy_buf = vec![0u64; (SLAB_HEADER_LEN + bump_index * LEGACY_NODE_SIZE) / 8];
+        let legacy_bytes: &mut [u8] = cast_slice_mut(legacy_buf.as_mut_slice());
+        legacy_bytes[..SLAB_HEADER_LEN].copy_from_slice(bytemuck::bytes_of(self.header()));
+        for (handle, node) in self.nodes()[..bump_index].iter().enumerate() {
+            write_legacy_node(
+                &mut legacy_bytes[SLAB_HEADER_LEN..],
+                handle as NodeHandle,
+                node,
+            );
+        }
+        legacy_buf
+    }
+
     #[cfg(test)]
     fn check_invariants(&self) {
         // first check the live tree contents
@@ -774,6 +1092,7 @@ mod tests {
     #[test]
     fn simulate_find_min() {
         use std::collections::BTreeMap;
+        use std::ops::Bound::{Excluded, Unbounded};
 
         for trial in 0..10u64 {
             let mut aligned_buf = vec![0u64; 10_000];
@@ -826,6 +1145,18 @@ mod tests {
                 let slab_max = slab.get(slab.find_max().unwrap()).unwrap();
                 let model_max = model.iter().next_back().unwrap().1;
                 assert_eq!(bytes_of(slab_max), bytes_of(model_max));
+
+                // test find_min_above
+                for search_key in &[valid_search_key, invalid_search_key] {
+                    let slab_next = slab
+                        .find_min_above(search_key)
+                        .map(|h| bytes_of(slab.get(h).unwrap()));
+                    let model_next = model
+                        .range((Excluded(*search_key), Unbounded))
+                        .next()
+                        .map(|(_, leaf)| bytes_of(leaf));
+                    assert_eq!(slab_next, model_next);
+                }
             }
         }
     }
@@ -941,6 +1272,55 @@ mod tests {
         }
     }
 
+    #[test]
+    fn copies_legacy_nodes() {
+        use std::collections::BTreeMap;
+
+        let mut aligned_buf = vec![0u64; 10_000];
+        let slab: &mut Slab = Slab::new(cast_slice_mut(aligned_buf.as_mut_slice()));
+        let mut model: BTreeMap<u128, LeafNode> = BTreeMap::new();
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let owner_slot = rng.gen::<u8>() as u16;
+            let key = rng.gen();
+            let leaf = LeafNode::new(owner_slot, &key, &rng.gen(), rng.gen(), FeeTier::Base, 0);
+            slab.insert_leaf(&leaf).unwrap();
+            model.insert(key, leaf);
+        }
+        // leave some free nodes behind
+        let keys: Vec<u128> = model.keys().copied().collect();
+        for key in keys.choose_multiple(&mut rng, 30) {
+            slab.remove_by_key(key).unwrap();
+            model.remove(key);
+        }
+        let legacy_buf = slab.to_legacy_nodes();
+        let legacy_bytes: &[u8] = cast_slice(legacy_buf.as_slice());
+        let bump_index = slab.header().bump_index as usize;
+
+        // a slab without room for every used node is too small, even if the tree fits
+        let mut small_buf = vec![0u64; (SLAB_HEADER_LEN + (bump_index - 1) * _NODE_SIZE) / 8];
+        let small_slab = Slab::new(cast_slice_mut(small_buf.as_mut_slice()));
+        assert!(small_slab.capacity() as usize >= model.len() * 2);
+        assert_eq!(
+            small_slab.copy_legacy_nodes(legacy_bytes),
+            Err(DexErrorCode::SlabTooSmall.into())
+        );
+
+        let mut copied_buf = vec![0u64; (SLAB_HEADER_LEN + bump_index * _NODE_SIZE) / 8];
+        let copied = Slab::new(cast_slice_mut(copied_buf.as_mut_slice()));
+        copied.copy_legacy_nodes(legacy_bytes).unwrap();
+        copied.check_invariants();
+        assert_eq!(copied.traverse(), model.values().collect::<Vec<_>>());
+
+        // every node not in the tree can be used again
+        let owner = [0; 4];
+        while copied
+            .insert_leaf(&LeafNode::new(0, &rng.gen(), &owner, 1, FeeTier::Base, 0))
+            .is_ok()
+        {}
+        assert!(copied.leaf_count() * 2 >= copied.capacity());
+    }
+
     #[test]
     #[should_panic]
     fn panics_unaligned() {
@@ -106,6 +106,43 @@ pub enum DexErrorCode {
     WrongRentSysvarAccount,
     RentNotProvided,
     OrdersNotRentExempt,
+    WrongStopOrdersAccount,
+
+    InvalidTriggerPrice = 60,
+    StopOrdersFull,
+    InvalidOrderExpiry,
+    WrongClockSysvarAccount,
+    OrderNotFound,
+
+    ReplacedOrderSideMismatch = 65,
+    InvalidIcebergOrder,
+    WrongPeggedOrdersAccount,
+    InvalidPeggedOrder,
+    PeggedOrdersFull,
+
+    PositionLimitExceeded = 70,
+    InvalidFeeSchedule,
+    WrongMarketAuthority,
+    MarketNotEmpty,
+    OpenOrdersNotEmpty,
+
+    DelegateNotProvided = 75,
+    NotWrappedSolMarket,
+    SettleWalletsNotRegistered,
+    WrongSettleWallet,
+    WrongMarketStatsAccount,
+
+    MarketIsCancelOnly = 80,
+    MarketIsPostOnly,
+    MarketInAuction,
+    MarketNotInAuction,
+    RequestQueueNotEmpty,
+
+    LegacyOrdersAccount = 85,
+    AuctionInProgress,
+    MarketIsHalted,
+    FillOrKillOverLimit,
+    MarketNotMigrated,
 
     Unknown = 1000,
 
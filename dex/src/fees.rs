@@ -1,4 +1,6 @@
+use bytemuck::{Pod, Zeroable};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
+use serde::{Deserialize, Serialize};
 use std::convert::TryInto;
 
 #[cfg(test)]
@@ -60,10 +62,10 @@ const fn fee_bps(bps: u64) -> U64F64 {
     U64F64(((bps as u128) << 64) / 10_000)
 }
 
-// #[inline(always)]
-// const fn rebate_bps(bps: u64) -> U64F64 {
-//     U64F64(fee_bps(bps).0 + 1)
-// }
+#[inline(always)]
+const fn rebate_bps(bps: u64) -> U64F64 {
+    U64F64(fee_bps(bps).0 + 1)
+}
 
 impl FeeTier {
     #[inline]
@@ -79,42 +81,76 @@ impl FeeTier {
             () => FeeTier::Base,
         }
     }
+}
+
+pub const MAX_TAKER_FEE_BPS: u16 = 1_000;
+
+/// A market's taker fees and maker rebates, in basis points, indexed by `FeeTier`.
+/// The last entry of each table is unused and must be zero.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+#[repr(C)]
+pub struct FeeSchedule {
+    pub taker_fee_bps: [u16; 8],
+    pub maker_rebate_bps: [u16; 8],
+}
+unsafe impl Zeroable for FeeSchedule {}
+unsafe impl Pod for FeeSchedule {}
+
+impl Default for FeeSchedule {
+    fn default() -> Self {
+        FeeSchedule {
+            taker_fee_bps: [22, 20, 18, 16, 14, 12, 10, 0],
+            maker_rebate_bps: [3, 3, 3, 3, 3, 3, 5, 0],
+        }
+    }
+}
+
+impl FeeSchedule {
+    /// The same taker fee for every tier, with no maker rebates.
+    pub fn flat(taker_fee_bps: u16) -> Self {
+        let mut schedule = FeeSchedule::zeroed();
+        for bps in &mut schedule.taker_fee_bps[..7] {
+            *bps = taker_fee_bps;
+        }
+        schedule
+    }
+
+    /// The exchange must never pay out more than it collects: every maker rebate,
+    /// plus the referrer's cut of the taker fee, has to fit inside every taker fee.
+    pub fn is_valid(&self) -> bool {
+        let (taker_fee_bps, unused_taker_fee_bps) = self.taker_fee_bps.split_at(7);
+        let (maker_rebate_bps, unused_maker_rebate_bps) = self.maker_rebate_bps.split_at(7);
+        let min_taker_fee_bps = *taker_fee_bps.iter().min().unwrap() as u64;
+        let max_taker_fee_bps = *taker_fee_bps.iter().max().unwrap();
+        let max_maker_rebate_bps = *maker_rebate_bps.iter().max().unwrap() as u64;
+        unused_taker_fee_bps == [0]
+            && unused_maker_rebate_bps == [0]
+            && max_taker_fee_bps <= MAX_TAKER_FEE_BPS
+            && 5 * max_maker_rebate_bps <= 4 * min_taker_fee_bps
+    }
 
     #[inline]
-    pub fn maker_rebate(self, _pc_qty: u64) -> u64 {
-        0
-        // use FeeTier::*;
-        // let rate: U64F64 = match self {
-        //     MSRM => rebate_bps(5),
-        //     Base | SRM2 | SRM3 | SRM4 | SRM5 | SRM6 => rebate_bps(3),
-        // };
-        // rate.mul_u64(pc_qty).floor()
+    pub fn maker_rebate(&self, tier: FeeTier, pc_qty: u64) -> u64 {
+        let rate = rebate_bps(self.maker_rebate_bps[tier as usize] as u64);
+        rate.mul_u64(pc_qty).floor()
     }
 
-    fn taker_rate(self) -> U64F64 {
-        fee_bps(0)
-        // use FeeTier::*;
-        // match self {
-        //     Base => fee_bps(22),
-        //     SRM2 => fee_bps(20),
-        //     SRM3 => fee_bps(18),
-        //     SRM4 => fee_bps(16),
-        //     SRM5 => fee_bps(14),
-        //     SRM6 => fee_bps(12),
-        //     MSRM => fee_bps(10),
-        // }
+    fn taker_rate(&self, tier: FeeTier) -> U64F64 {
+        fee_bps(self.taker_fee_bps[tier as usize] as u64)
     }
 
     #[inline]
-    pub fn taker_fee(self, pc_qty: u64) -> u64 {
-        let rate = self.taker_rate();
+    pub fn taker_fee(&self, tier: FeeTier, pc_qty: u64) -> u64 {
+        let rate = self.taker_rate(tier);
         let exact_fee: U64F64 = rate.mul_u64(pc_qty);
         exact_fee.floor() + ((exact_fee.frac_part() != 0) as u64)
     }
 
     #[inline]
-    pub fn remove_taker_fee(self, pc_qty_incl_fee: u64) -> u64 {
-        let rate = self.taker_rate();
+    pub fn remove_taker_fee(&self, tier: FeeTier, pc_qty_incl_fee: u64) -> u64 {
+        let rate = self.taker_rate(tier);
         U64F64::from_int(pc_qty_incl_fee)
             .div(U64F64::ONE.add(rate))
             .try_into()
@@ -123,8 +159,8 @@ impl FeeTier {
 }
 
 #[inline]
-pub fn referrer_rebate(_amount: u64) -> u64 {
-    0
+pub fn referrer_rebate(amount: u64) -> u64 {
+    amount / 5
 }
 
 #[cfg(test)]
@@ -135,8 +171,9 @@ mod tests {
     proptest! {
         #[test]
         fn positive_net_fees(tt: FeeTier, mt: FeeTier, qty in 1..=std::u64::MAX) {
-            let fee = tt.taker_fee(qty);
-            let rebate = mt.maker_rebate(qty) + referrer_rebate(fee);
+            let schedule = FeeSchedule::default();
+            let fee = schedule.taker_fee(tt, qty);
+            let rebate = schedule.maker_rebate(mt, qty) + referrer_rebate(fee);
             assert!(fee > rebate);
             let net_bps_u64f64 = (fee - rebate) as u128 * 10_000;
             let three_bps = (qty as u128) * 3;
@@ -144,6 +181,26 @@ mod tests {
             assert!(net_bps_u64f64 + dust_qty_u64f64 > three_bps, "{:x}, {:x}, {:x}", qty, net_bps_u64f64, three_bps);
         }
 
+        #[test]
+        fn valid_schedules_never_pay_out_more_than_they_collect(
+            taker_fee_bps in proptest::collection::vec(0..=MAX_TAKER_FEE_BPS, 7),
+            maker_rebate_pct in proptest::collection::vec(0..=100u16, 7),
+            tt: FeeTier,
+            mt: FeeTier,
+            qty: u64,
+        ) {
+            let mut schedule = FeeSchedule::zeroed();
+            schedule.taker_fee_bps[..7].copy_from_slice(&taker_fee_bps);
+            let max_maker_rebate_bps = 4 * *taker_fee_bps.iter().min().unwrap() as u32 / 5;
+            for (bps, pct) in schedule.maker_rebate_bps.iter_mut().zip(maker_rebate_pct) {
+                *bps = (max_maker_rebate_bps * pct as u32 / 100) as u16;
+            }
+            assert!(schedule.is_valid());
+            let fee = schedule.taker_fee(tt, qty);
+            let rebate = schedule.maker_rebate(mt, qty) + referrer_rebate(fee);
+            assert!(fee >= rebate, "fee = {}, rebate = {}", fee, rebate);
+        }
+
         #[test]
         fn fee_bps_approx(bps in 1..100u64) {
             let rate = fee_bps(bps);
@@ -156,8 +213,9 @@ mod tests {
 
         #[test]
         fn market_order_cannot_cheat(tier: FeeTier, qty: u64) {
-            let qty_without_fees = tier.remove_taker_fee(qty);
-            let required_fee = tier.taker_fee(qty_without_fees) as i128;
+            let schedule = FeeSchedule::default();
+            let qty_without_fees = schedule.remove_taker_fee(tier, qty);
+            let required_fee = schedule.taker_fee(tier, qty_without_fees) as i128;
             let actual_fee = qty as i128 - qty_without_fees as i128;
             assert!([required_fee + 1, required_fee].contains(&actual_fee),
                     "actual_fee = {}, required_fee = {}",
@@ -166,8 +224,9 @@ mod tests {
 
         #[test]
         fn test_add_remove_fees(tier: FeeTier, qty in 1..=(std::u64::MAX >> 1)) {
-            let qty_with_fees = qty + tier.taker_fee(qty);
-            let qty2 = tier.remove_taker_fee(qty_with_fees);
+            let schedule = FeeSchedule::default();
+            let qty_with_fees = qty + schedule.taker_fee(tier, qty);
+            let qty2 = schedule.remove_taker_fee(tier, qty_with_fees);
             assert!([-1, 0, 1].contains(&(qty as i128 - qty2 as i128)))
         }
     }
@@ -1,9 +1,11 @@
 #![cfg_attr(not(feature = "program"), allow(unused))]
 use crate::error::DexError;
+use crate::fees::FeeSchedule;
 use crate::matching::{OrderType, Side};
 use bytemuck::cast;
 use serde::{Deserialize, Serialize};
 use solana_sdk::{
+    clock::Clock,
     instruction::{AccountMeta, Instruction},
     pubkey::Pubkey,
 };
@@ -38,10 +40,11 @@ pub mod fee_sweeper {
     declare_id!("DeqYsmBd9BnrbgUwQjVH4sQWK71dEgE6eoZFw3Rp4ftE");
 }
 
+pub const MAX_ORDERS_PER_BATCH: usize = 32;
+
 #[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
 #[cfg_attr(test, derive(Arbitrary))]
 #[cfg_attr(test, proptest(no_params))]
-#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
 pub struct InitializeMarketInstruction {
     // In the matching engine, all prices and balances are integers.
     // This only works if the smallest representable quantity of the coin
@@ -56,6 +59,168 @@ pub struct InitializeMarketInstruction {
     pub fee_rate_bps: u16,
     pub vault_signer_nonce: u64,
     pub pc_dust_threshold: u64,
+    // Defaults to a flat `fee_rate_bps` for every taker, so a market listed with neither
+    // charges no fees.
+    pub fee_schedule: Option<FeeSchedule>,
+    // The key allowed to change the market's parameters later on, if any.
+    #[cfg_attr(
+        test,
+        proptest(
+            strategy = "proptest::option::of(any::<[u8; 32]>().prop_map(|key| Pubkey::new(&key)))"
+        )
+    )]
+    pub authority: Option<Pubkey>,
+    // Which of the optional order slabs follow the mints. Older clients leave this out
+    // and pass the stop orders slab, if any, before the pegged orders slab.
+    pub order_slabs: Option<OrderSlabs>,
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+pub struct OrderSlabs {
+    pub stop_orders: bool,
+    pub pegged_orders: bool,
+}
+
+fn unpack_bool(data: &[u8]) -> Option<bool> {
+    match data {
+        [0] => Some(false),
+        [1] => Some(true),
+        _ => None,
+    }
+}
+
+fn unpack_option<'a, T>(
+    data: &'a [u8],
+    len: usize,
+    unpack_value: impl FnOnce(&'a [u8]) -> Option<T>,
+) -> Option<(Option<T>, &'a [u8])> {
+    match data {
+        [0, rest @ ..] => Some((None, rest)),
+        [1, rest @ ..] if rest.len() >= len => {
+            let (value, rest) = rest.split_at(len);
+            Some((Some(unpack_value(value)?), rest))
+        }
+        _ => None,
+    }
+}
+
+fn unpack_fee_schedule(data: &[u8]) -> Option<FeeSchedule> {
+    let mut bps = [0u16; 16];
+    for (bps, bytes) in bps.iter_mut().zip(data.chunks(2)) {
+        *bps = u16::from_le_bytes(*array_ref![bytes, 0, 2]);
+    }
+    let (&taker_fee_bps, &maker_rebate_bps) = array_refs![&bps, 8, 8];
+    Some(FeeSchedule {
+        taker_fee_bps,
+        maker_rebate_bps,
+    })
+}
+
+fn unpack_nonzero_u64(data: &[u8]) -> Option<NonZeroU64> {
+    NonZeroU64::new(u64::from_le_bytes(*array_ref![data, 0, 8]))
+}
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct SetMarketParamsInstruction {
+    pub fee_schedule: Option<FeeSchedule>,
+    pub pc_dust_threshold: Option<u64>,
+    // Lot sizes can only change while nothing rests on the book.
+    #[cfg_attr(
+        test,
+        proptest(
+            strategy = "proptest::option::of((1u64..=std::u64::MAX).prop_map(|x| NonZeroU64::new(x).unwrap()))"
+        )
+    )]
+    pub coin_lot_size: Option<NonZeroU64>,
+    #[cfg_attr(
+        test,
+        proptest(
+            strategy = "proptest::option::of((1u64..=std::u64::MAX).prop_map(|x| NonZeroU64::new(x).unwrap()))"
+        )
+    )]
+    pub pc_lot_size: Option<NonZeroU64>,
+    // Whether MatchOrders applies events to the OpenOrders accounts passed to it.
+    pub auto_consume: Option<bool>,
+    pub price_band: Option<PriceBand>,
+    pub mode: Option<MarketMode>,
+}
+
+/// What the market accepts besides cancels and settlement.
+#[derive(
+    PartialEq, Eq, Copy, Clone, Debug, TryFromPrimitive, IntoPrimitive, Serialize, Deserialize,
+)]
+#[cfg_attr(test, derive(Arbitrary))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+#[repr(u8)]
+pub enum MarketMode {
+    Normal = 0,
+    /// New orders are rejected, and queued ones are cancelled instead of matched.
+    CancelOnly = 1,
+    /// Orders may rest on the book but not cross it.
+    PostOnly = 2,
+    /// Orders rest on the book without matching, even when they cross, until
+    /// `RunAuction` matches them all at one price. Only `RunAuction` ends this mode, and
+    /// the market can only enter it with nothing on the book or in the request queue.
+    Auction = 3,
+}
+
+/// Orders stop matching once the price is more than `max_deviation_bps` away from the
+/// last fill, and whatever is left of them is cancelled. If `halt_slots` is nonzero,
+/// hitting the band also stops the market taking new orders for that many slots.
+/// Orders placed without the clock sysvar are rejected until `MatchOrders` runs after
+/// the halt. A `max_deviation_bps` of 0 turns the band off.
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+pub struct PriceBand {
+    pub max_deviation_bps: u64,
+    pub halt_slots: u64,
+}
+
+impl SetMarketParamsInstruction {
+    fn unpack(data: &[u8]) -> Option<Self> {
+        let (fee_schedule, rest) = unpack_option(data, 32, unpack_fee_schedule)?;
+        let (pc_dust_threshold, rest) = unpack_option(rest, 8, |data| {
+            Some(u64::from_le_bytes(*array_ref![data, 0, 8]))
+        })?;
+        let (coin_lot_size, rest) = unpack_option(rest, 8, unpack_nonzero_u64)?;
+        let (pc_lot_size, rest) = unpack_option(rest, 8, unpack_nonzero_u64)?;
+        let (auto_consume, rest) = match rest {
+            [] => (None, rest),
+            _ => unpack_option(rest, 1, unpack_bool)?,
+        };
+        let (price_band, rest) = match rest {
+            [] => (None, rest),
+            _ => unpack_option(rest, 16, |data| {
+                Some(PriceBand {
+                    max_deviation_bps: u64::from_le_bytes(*array_ref![data, 0, 8]),
+                    halt_slots: u64::from_le_bytes(*array_ref![data, 8, 8]),
+                })
+            })?,
+        };
+        let (mode, rest) = match rest {
+            [] => (None, rest),
+            _ => unpack_option(rest, 4, |data| {
+                let mode = u32::from_le_bytes(*array_ref![data, 0, 4]);
+                MarketMode::try_from_primitive(mode.try_into().ok()?).ok()
+            })?,
+        };
+        if !rest.is_empty() {
+            return None;
+        }
+        Some(SetMarketParamsInstruction {
+            fee_schedule,
+            pc_dust_threshold,
+            coin_lot_size,
+            pc_lot_size,
+            auto_consume,
+            price_band,
+            mode,
+        })
+    }
 }
 
 #[derive(
@@ -67,6 +232,96 @@ pub struct InitializeMarketInstruction {
 pub enum SelfTradeBehavior {
     DecrementTake = 0,
     CancelProvide = 1,
+    /// Cancels whatever is left of the incoming order.
+    CancelTake = 2,
+    /// Cancels the resting order and whatever is left of the incoming order.
+    CancelBoth = 3,
+}
+
+impl SelfTradeBehavior {
+    /// Whether an order with this behavior is cancelled when it would trade
+    /// against a resting order from the same owner.
+    pub fn cancels_take(self) -> bool {
+        match self {
+            SelfTradeBehavior::DecrementTake | SelfTradeBehavior::CancelProvide => false,
+            SelfTradeBehavior::CancelTake | SelfTradeBehavior::CancelBoth => true,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+pub enum OrderExpiry {
+    Slot(u64),
+    UnixTimestamp(i64),
+}
+
+impl OrderExpiry {
+    /// An order is good through its expiry slot or timestamp, inclusive.
+    #[inline]
+    pub fn has_passed(self, clock: &Clock) -> bool {
+        match self {
+            OrderExpiry::Slot(slot) => clock.slot > slot,
+            OrderExpiry::UnixTimestamp(unix_timestamp) => clock.unix_timestamp > unix_timestamp,
+        }
+    }
+
+    #[inline]
+    pub fn is_valid(self) -> bool {
+        match self {
+            OrderExpiry::Slot(slot) => slot > 0,
+            OrderExpiry::UnixTimestamp(unix_timestamp) => unix_timestamp > 0,
+        }
+    }
+
+    // Order books and queues store the expiry as a slot and a timestamp, where zero
+    // means unset, so at most one of the two is non-zero.
+    #[inline]
+    pub(crate) fn into_parts(expiry: Option<Self>) -> (u64, i64) {
+        match expiry {
+            None => (0, 0),
+            Some(OrderExpiry::Slot(slot)) => (slot, 0),
+            Some(OrderExpiry::UnixTimestamp(unix_timestamp)) => (0, unix_timestamp),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn from_parts(slot: u64, unix_timestamp: i64) -> Option<Self> {
+        match (slot, unix_timestamp) {
+            (0, 0) => None,
+            (0, unix_timestamp) => Some(OrderExpiry::UnixTimestamp(unix_timestamp)),
+            (slot, _) => Some(OrderExpiry::Slot(slot)),
+        }
+    }
+
+    fn unpack(data: &[u8]) -> Option<Option<Self>> {
+        // sent by clients that predate order expiry
+        if data.is_empty() {
+            return Some(None);
+        }
+        match Self::unpack_prefix(data)? {
+            (expiry, []) => Some(expiry),
+            _ => None,
+        }
+    }
+
+    fn unpack_prefix(data: &[u8]) -> Option<(Option<Self>, &[u8])> {
+        match data {
+            [0, rest @ ..] => Some((None, rest)),
+            [1, rest @ ..] if rest.len() >= 12 => {
+                let (expiry_data, rest) = array_refs![rest, 12; .. ;];
+                let (&discrim, &value) = array_refs![expiry_data, 4, 8];
+                let expiry = match u32::from_le_bytes(discrim) {
+                    0 => OrderExpiry::Slot(u64::from_le_bytes(value)),
+                    1 => OrderExpiry::UnixTimestamp(i64::from_le_bytes(value)),
+                    _ => return None,
+                };
+                Some((Some(expiry), rest))
+            }
+            _ => None,
+        }
+    }
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
@@ -86,6 +341,116 @@ pub struct NewOrderInstructionV2 {
     pub order_type: OrderType,
     pub client_id: u64,
     pub self_trade_behavior: SelfTradeBehavior,
+    pub expiry: Option<OrderExpiry>,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct NewOrderInstructionV3 {
+    pub side: Side,
+    #[cfg_attr(
+        test,
+        proptest(strategy = "(1u64..=std::u64::MAX).prop_map(|x| NonZeroU64::new(x).unwrap())")
+    )]
+    pub limit_price: NonZeroU64,
+    #[cfg_attr(
+        test,
+        proptest(strategy = "(1u64..=std::u64::MAX).prop_map(|x| NonZeroU64::new(x).unwrap())")
+    )]
+    pub max_qty: NonZeroU64,
+    pub order_type: OrderType,
+    pub client_id: u64,
+    pub self_trade_behavior: SelfTradeBehavior,
+    #[cfg_attr(
+        test,
+        proptest(strategy = "(1u64..=std::u64::MAX).prop_map(|x| NonZeroU64::new(x).unwrap())")
+    )]
+    pub trigger_price: NonZeroU64,
+    pub expiry: Option<OrderExpiry>,
+}
+
+impl NewOrderInstructionV2 {
+    pub fn add_trigger_price(self, trigger_price: NonZeroU64) -> NewOrderInstructionV3 {
+        let NewOrderInstructionV2 {
+            side,
+            limit_price,
+            max_qty,
+            order_type,
+            client_id,
+            self_trade_behavior,
+            expiry,
+        } = self;
+        NewOrderInstructionV3 {
+            side,
+            limit_price,
+            max_qty,
+            order_type,
+            client_id,
+            self_trade_behavior,
+            trigger_price,
+            expiry,
+        }
+    }
+}
+
+impl NewOrderInstructionV3 {
+    pub fn remove_trigger_price(self) -> (NewOrderInstructionV2, NonZeroU64) {
+        let NewOrderInstructionV3 {
+            side,
+            limit_price,
+            max_qty,
+            order_type,
+            client_id,
+            self_trade_behavior,
+            trigger_price,
+            expiry,
+        } = self;
+        let v2 = NewOrderInstructionV2 {
+            side,
+            limit_price,
+            max_qty,
+            order_type,
+            client_id,
+            self_trade_behavior,
+            expiry,
+        };
+        (v2, trigger_price)
+    }
+}
+
+impl NewOrderInstructionV2 {
+    fn unpack_without_expiry(data: &[u8; 36]) -> Option<Self> {
+        let (v1_data_arr, v2_data_arr) = array_refs![data, 32, 4];
+        let v1_instr = NewOrderInstructionV1::unpack(v1_data_arr)?;
+        let self_trade_behavior = SelfTradeBehavior::try_from_primitive(
+            u32::from_le_bytes(*v2_data_arr).try_into().ok()?,
+        )
+        .ok()?;
+        Some(v1_instr.add_self_trade_behavior(self_trade_behavior))
+    }
+
+    fn unpack(data: &[u8]) -> Option<Self> {
+        if data.len() < 36 {
+            return None;
+        }
+        let (data_arr, expiry_data) = array_refs![data, 36; .. ;];
+        let mut v2_instr = Self::unpack_without_expiry(data_arr)?;
+        v2_instr.expiry = OrderExpiry::unpack(expiry_data)?;
+        Some(v2_instr)
+    }
+
+    // Orders inside a batch are always followed by the expiry, so they can be read
+    // back to back.
+    fn unpack_prefix(data: &[u8]) -> Option<(Self, &[u8])> {
+        if data.len() < 36 {
+            return None;
+        }
+        let (data_arr, rest) = array_refs![data, 36; .. ;];
+        let mut v2_instr = Self::unpack_without_expiry(data_arr)?;
+        let (expiry, rest) = OrderExpiry::unpack_prefix(rest)?;
+        v2_instr.expiry = expiry;
+        Some((v2_instr, rest))
+    }
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
@@ -125,6 +490,7 @@ impl NewOrderInstructionV1 {
             order_type,
             client_id,
             self_trade_behavior,
+            expiry: None,
         }
     }
 }
@@ -145,6 +511,7 @@ impl NewOrderInstructionV1 {
             0 => OrderType::Limit,
             1 => OrderType::ImmediateOrCancel,
             2 => OrderType::PostOnly,
+            3 => OrderType::FillOrKill,
             _ => return None,
         };
         Some(NewOrderInstructionV1 {
@@ -157,6 +524,175 @@ impl NewOrderInstructionV1 {
     }
 }
 
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+pub enum ReplacedOrder {
+    OrderId(u128),
+    ClientId(u64),
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+pub struct ReplaceOrderInstruction {
+    pub replaced_order: ReplacedOrder,
+    pub new_order: NewOrderInstructionV2,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct NewIcebergOrderInstruction {
+    #[cfg_attr(
+        test,
+        proptest(strategy = "(1u64..=std::u64::MAX).prop_map(|x| NonZeroU64::new(x).unwrap())")
+    )]
+    pub display_qty: NonZeroU64,
+    pub new_order: NewOrderInstructionV2,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct NewPeggedOrderInstruction {
+    pub side: Side,
+    /// Ticks added to the best price on the order's own side.
+    pub peg_offset: i64,
+    /// The highest price a pegged bid may rest at, or the lowest for an ask.
+    /// Required for bids, which lock funds at this price.
+    #[cfg_attr(
+        test,
+        proptest(
+            strategy = "proptest::option::of((1u64..=std::u64::MAX).prop_map(|x| NonZeroU64::new(x).unwrap()))"
+        )
+    )]
+    pub cap_price: Option<NonZeroU64>,
+    #[cfg_attr(
+        test,
+        proptest(strategy = "(1u64..=std::u64::MAX).prop_map(|x| NonZeroU64::new(x).unwrap())")
+    )]
+    pub max_qty: NonZeroU64,
+    pub client_id: u64,
+}
+
+impl NewPeggedOrderInstruction {
+    fn unpack(data: &[u8]) -> Option<Self> {
+        if data.len() < 13 {
+            return None;
+        }
+        let (&side_arr, &peg_offset_arr, rest) = array_refs![data, 4, 8; .. ;];
+        let side = match u32::from_le_bytes(side_arr) {
+            0 => Side::Bid,
+            1 => Side::Ask,
+            _ => return None,
+        };
+        let (cap_price, rest) = match rest {
+            [0, rest @ ..] => (None, rest),
+            [1, rest @ ..] if rest.len() >= 8 => {
+                let (&cap_price, rest) = array_refs![rest, 8; .. ;];
+                (Some(NonZeroU64::new(u64::from_le_bytes(cap_price))?), rest)
+            }
+            _ => return None,
+        };
+        if rest.len() != 16 {
+            return None;
+        }
+        let (&max_qty, &client_id) = array_refs![array_ref![rest, 0, 16], 8, 8];
+        Some(NewPeggedOrderInstruction {
+            side,
+            peg_offset: i64::from_le_bytes(peg_offset_arr),
+            cap_price,
+            max_qty: NonZeroU64::new(u64::from_le_bytes(max_qty))?,
+            client_id: u64::from_le_bytes(client_id),
+        })
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct NewMarketBuyInstruction {
+    #[cfg_attr(
+        test,
+        proptest(strategy = "(1u64..=std::u64::MAX).prop_map(|x| NonZeroU64::new(x).unwrap())")
+    )]
+    pub max_native_pc_qty_including_fees: NonZeroU64,
+    pub client_id: u64,
+    pub self_trade_behavior: SelfTradeBehavior,
+}
+
+impl NewMarketBuyInstruction {
+    /// A market buy is an immediate-or-cancel bid at any price, for as much as its
+    /// pc budget buys.
+    pub fn to_new_order(&self) -> NewOrderInstructionV2 {
+        NewOrderInstructionV2 {
+            side: Side::Bid,
+            limit_price: NonZeroU64::new(std::u64::MAX).unwrap(),
+            max_qty: NonZeroU64::new(std::u64::MAX).unwrap(),
+            order_type: OrderType::ImmediateOrCancel,
+            client_id: self.client_id,
+            self_trade_behavior: self.self_trade_behavior,
+            expiry: None,
+        }
+    }
+
+    fn unpack(data: &[u8; 20]) -> Option<Self> {
+        let (&max_native_pc_qty_arr, &client_id_arr, &self_trade_behavior_arr) =
+            array_refs![data, 8, 8, 4];
+        let self_trade_behavior = SelfTradeBehavior::try_from_primitive(
+            u32::from_le_bytes(self_trade_behavior_arr)
+                .try_into()
+                .ok()?,
+        )
+        .ok()?;
+        Some(NewMarketBuyInstruction {
+            max_native_pc_qty_including_fees: NonZeroU64::new(u64::from_le_bytes(
+                max_native_pc_qty_arr,
+            ))?,
+            client_id: u64::from_le_bytes(client_id_arr),
+            self_trade_behavior,
+        })
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct SetPositionLimitsInstruction {
+    /// The most coin, in native units, the account may hold once its bids fill.
+    #[cfg_attr(
+        test,
+        proptest(
+            strategy = "proptest::option::of((1u64..=std::u64::MAX).prop_map(|x| NonZeroU64::new(x).unwrap()))"
+        )
+    )]
+    pub max_native_coin_position: Option<NonZeroU64>,
+    /// Only allow asks that the account's free coin already covers.
+    pub reduce_only: bool,
+}
+
+impl SetPositionLimitsInstruction {
+    fn unpack(data: &[u8]) -> Option<Self> {
+        let (max_native_coin_position, rest) = match data {
+            [0, rest @ ..] => (None, rest),
+            [1, rest @ ..] if rest.len() >= 8 => {
+                let (&max_position, rest) = array_refs![rest, 8; .. ;];
+                (
+                    Some(NonZeroU64::new(u64::from_le_bytes(max_position))?),
+                    rest,
+                )
+            }
+            _ => return None,
+        };
+        let reduce_only = match rest {
+            [0] => false,
+            [1] => true,
+            _ => return None,
+        };
+        Some(SetPositionLimitsInstruction {
+            max_native_coin_position,
+            reduce_only,
+        })
+    }
+}
+
 #[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
 #[cfg_attr(test, derive(Arbitrary))]
 #[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
@@ -164,11 +700,12 @@ pub struct CancelOrderInstruction {
     pub side: Side,
     pub order_id: u128,
     pub owner: [u64; 4], // Unused
-    pub owner_slot: u8,
+    pub owner_slot: u16,
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
 #[cfg_attr(test, derive(Arbitrary))]
+#[cfg_attr(test, proptest(no_params))]
 #[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
 pub enum MarketInstruction {
     /// 0. `[writable]` the market to initialize
@@ -180,6 +717,12 @@ pub enum MarketInstruction {
     /// 6. `[writable]` spl-token account for the price currency
     /// 7. `[]` coin currency Mint
     /// 8. `[]` price currency Mint
+    /// 9. `[writable]` (optional) zeroed out stop orders
+    /// 10. `[writable]` (optional) zeroed out pegged orders
+    ///
+    /// `order_slabs` says which of the last two are passed, so a market with pegged
+    /// orders but no stop orders passes its pegged orders slab as account 9. Without
+    /// it, account 9 is the stop orders slab.
     InitializeMarket(InitializeMarketInstruction),
     /// 0. `[writable]` the market
     /// 1. `[writable]` the OpenOrders account to use
@@ -190,8 +733,12 @@ pub enum MarketInstruction {
     /// 6. `[writable]` pc vault
     /// 7. `[]` spl token program
     /// 8. `[]` the rent sysvar
-    /// 9. `[writable]` (optional) the (M)SRM account used for fee discounts
+    /// 9. `[]` (optional) the clock sysvar, which lets orders in once a price band
+    ///     halt is over
+    /// 10. `[writable]` (optional) the (M)SRM account used for fee discounts
     NewOrder(NewOrderInstructionV1),
+    /// The clock sysvar is required, since expired orders mustn't be filled.
+    ///
     /// 0. `[writable]` market
     /// 1. `[writable]` req_q
     /// 2. `[writable]` event_q
@@ -199,6 +746,16 @@ pub enum MarketInstruction {
     /// 4. `[writable]` asks
     /// 5. `[writable]` coin fee receivable account
     /// 6. `[writable]` pc fee receivable account
+    /// 7. `[]` the clock sysvar
+    /// 8. `[writable]` stop orders (if the market has them)
+    /// 9. `[writable]` pegged orders (if the market has them)
+    /// 10. `[writable]` market stats (if the market has them)
+    /// 11. `[writable]` (optional) OpenOrders accounts to apply events to right away,
+    ///     if the market auto-consumes. Events for other accounts, for accounts that
+    ///     still have events queued, and all events while more than
+    ///     `MAX_AUTO_CONSUME_QUEUED_EVENTS` are queued, are queued as usual. Applied
+    ///     events skip the queue, so they are applied ahead of other accounts' queued
+    ///     events.
     MatchOrders(u16),
     /// ... `[writable]` OpenOrders
     /// accounts.len() - 4 `[writable]` market
@@ -246,8 +803,326 @@ pub enum MarketInstruction {
     /// 6. `[writable]` pc vault
     /// 7. `[]` spl token program
     /// 8. `[]` the rent sysvar
-    /// 9. `[writable]` (optional) the (M)SRM account used for fee discounts
+    /// 9. `[]` (optional) the clock sysvar, which lets orders in once a price band
+    ///     halt is over
+    /// 10. `[writable]` (optional) the (M)SRM account used for fee discounts
     NewOrderV2(NewOrderInstructionV2),
+    /// Places an order that waits in the stop orders slab until a fill trades
+    /// at or through `trigger_price` (at or above for bids, at or below for asks).
+    /// Until then the order goes by its key in the stop orders slab; once triggered
+    /// it gets a new order id, which the OpenOrders account picks up when the event
+    /// queue is consumed.
+    ///
+    /// 0. `[writable]` the market
+    /// 1. `[writable]` the OpenOrders account to use
+    /// 2. `[writable]` the request queue
+    /// 3. `[writable]` the (coin or price currency) account paying for the order
+    /// 4. `[signer]` owner of the OpenOrders account
+    /// 5. `[writable]` coin vault
+    /// 6. `[writable]` pc vault
+    /// 7. `[]` spl token program
+    /// 8. `[]` the rent sysvar
+    /// 9. `[writable]` stop orders
+    /// 10. `[]` (optional) the clock sysvar, which lets orders in once a price band
+    ///     halt is over
+    /// 11. `[writable]` (optional) the (M)SRM account used for fee discounts
+    NewOrderV3(NewOrderInstructionV3),
+    /// Cancels an order in the OpenOrders account and places `new_order` in its
+    /// place as a single request, so the book never shows both or neither. The new
+    /// order must be on the same side, and is cancelled if the old order has already
+    /// left the book by the time the request is matched.
+    ///
+    /// 0. `[writable]` the market
+    /// 1. `[writable]` the OpenOrders account to use
+    /// 2. `[writable]` the request queue
+    /// 3. `[writable]` the (coin or price currency) account paying for the order
+    /// 4. `[signer]` owner of the OpenOrders account
+    /// 5. `[writable]` coin vault
+    /// 6. `[writable]` pc vault
+    /// 7. `[]` spl token program
+    /// 8. `[]` the rent sysvar
+    /// 9. `[]` (optional) the clock sysvar, which lets orders in once a price band
+    ///     halt is over
+    /// 10. `[writable]` (optional) the (M)SRM account used for fee discounts
+    ReplaceOrder(ReplaceOrderInstruction),
+    /// Places up to `MAX_ORDERS_PER_BATCH` orders, with one deposit per currency
+    /// for the whole batch.
+    ///
+    /// 0. `[writable]` the market
+    /// 1. `[writable]` the OpenOrders account to use
+    /// 2. `[writable]` the request queue
+    /// 3. `[writable]` the coin currency account paying for asks
+    /// 4. `[writable]` the price currency account paying for bids
+    /// 5. `[signer]` owner of the OpenOrders account
+    /// 6. `[writable]` coin vault
+    /// 7. `[writable]` pc vault
+    /// 8. `[]` spl token program
+    /// 9. `[]` the rent sysvar
+    /// 10. `[]` (optional) the clock sysvar, which lets orders in once a price band
+    ///     halt is over
+    /// 11. `[writable]` (optional) the (M)SRM account used for fee discounts
+    NewOrdersBatch(
+        #[cfg_attr(
+            test,
+            proptest(
+                strategy = "proptest::collection::vec(any::<NewOrderInstructionV2>(), 1..=MAX_ORDERS_PER_BATCH)"
+            )
+        )]
+        Vec<NewOrderInstructionV2>,
+    ),
+    /// Cancels up to `MAX_ORDERS_PER_BATCH` orders by order id. Ids that are not in
+    /// the OpenOrders account are skipped.
+    ///
+    /// 0. `[]` market
+    /// 1. `[writable]` OpenOrders
+    /// 2. `[writable]` the request queue
+    /// 3. `[signer]` the OpenOrders owner
+    CancelOrdersBatch(
+        #[cfg_attr(
+            test,
+            proptest(
+                strategy = "proptest::collection::vec(any::<u128>(), 1..=MAX_ORDERS_PER_BATCH)"
+            )
+        )]
+        Vec<u128>,
+    ),
+    /// Cancels every order in the OpenOrders account, or only those on the given
+    /// side.
+    ///
+    /// 0. `[]` market
+    /// 1. `[writable]` OpenOrders
+    /// 2. `[writable]` the request queue
+    /// 3. `[signer]` the OpenOrders owner
+    CancelAllOrders(Option<Side>),
+    /// Places a limit or post-only order that shows at most `display_qty` on the book
+    /// and keeps the rest in a hidden reserve. Whenever the displayed part fills, the
+    /// next slice of the reserve is displayed under a new order id, at the back of
+    /// its price level. The OpenOrders account picks up the new id when the event
+    /// queue is consumed.
+    ///
+    /// 0. `[writable]` the market
+    /// 1. `[writable]` the OpenOrders account to use
+    /// 2. `[writable]` the request queue
+    /// 3. `[writable]` the (coin or price currency) account paying for the order
+    /// 4. `[signer]` owner of the OpenOrders account
+    /// 5. `[writable]` coin vault
+    /// 6. `[writable]` pc vault
+    /// 7. `[]` spl token program
+    /// 8. `[]` the rent sysvar
+    /// 9. `[]` (optional) the clock sysvar, which lets orders in once a price band
+    ///     halt is over
+    /// 10. `[writable]` (optional) the (M)SRM account used for fee discounts
+    NewIcebergOrder(NewIcebergOrderInstruction),
+    /// Places an order that rests `peg_offset` ticks from the best non-pegged price
+    /// on its own side of the book, and is moved whenever that price changes. A
+    /// pegged order is never priced through its cap or the opposite side of the book,
+    /// so it only ever provides liquidity. It waits in the pegged orders slab, going
+    /// by its key there, until there is a price to peg to, and each move gives it a
+    /// new order id, which the OpenOrders account picks up when the event queue is
+    /// consumed.
+    ///
+    /// 0. `[writable]` the market
+    /// 1. `[writable]` the OpenOrders account to use
+    /// 2. `[writable]` the request queue
+    /// 3. `[writable]` the (coin or price currency) account paying for the order
+    /// 4. `[signer]` owner of the OpenOrders account
+    /// 5. `[writable]` coin vault
+    /// 6. `[writable]` pc vault
+    /// 7. `[]` spl token program
+    /// 8. `[]` the rent sysvar
+    /// 9. `[writable]` pegged orders
+    /// 10. `[]` (optional) the clock sysvar, which lets orders in once a price band
+    ///     halt is over
+    /// 11. `[writable]` (optional) the (M)SRM account used for fee discounts
+    NewPeggedOrder(NewPeggedOrderInstruction),
+    /// Buys as much coin as `max_native_pc_qty_including_fees` pays for, taker fees
+    /// included, at whatever prices are on the book. Whatever is left of the budget
+    /// is unlocked once the order has matched, and nothing is ever placed on the book.
+    ///
+    /// 0. `[writable]` the market
+    /// 1. `[writable]` the OpenOrders account to use
+    /// 2. `[writable]` the request queue
+    /// 3. `[writable]` the price currency account paying for the order
+    /// 4. `[signer]` owner of the OpenOrders account
+    /// 5. `[writable]` coin vault
+    /// 6. `[writable]` pc vault
+    /// 7. `[]` spl token program
+    /// 8. `[]` the rent sysvar
+    /// 9. `[]` (optional) the clock sysvar, which lets orders in once a price band
+    ///     halt is over
+    /// 10. `[writable]` (optional) the (M)SRM account used for fee discounts
+    NewMarketBuy(NewMarketBuyInstruction),
+    /// Limits the orders an OpenOrders account may place from here on. Bids are
+    /// rejected if the coin the account holds, plus the coin its open bids could still
+    /// buy, plus the bid's full size would exceed the maximum position, and a
+    /// reduce-only account may not bid at all, nor ask for more coin than it has free.
+    /// Only `OpenOrdersV2` accounts have room for a maximum position.
+    ///
+    /// 0. `[]` the market
+    /// 1. `[writable]` the OpenOrders account to limit
+    /// 2. `[signer]` owner of the OpenOrders account
+    SetPositionLimits(SetPositionLimitsInstruction),
+    /// Changes whichever parameters are given. Every change is recorded in the event queue.
+    ///
+    /// 0. `[writable]` market
+    /// 1. `[writable]` event queue
+    /// 2. `[signer]` market authority
+    /// 3. `[]` request queue
+    /// 4. `[]` bids
+    /// 5. `[]` asks
+    /// 6. `[]` the clock sysvar, required if the lot sizes change
+    /// 7. `[]` stop orders, if the market has them
+    /// 8. `[]` pegged orders, if the market has them
+    SetMarketParams(SetMarketParamsInstruction),
+    /// Undoes `DisableMarket`. Like `CloseMarket`, it is signed by the market authority,
+    /// or by the disable authority if the market has none.
+    ///
+    /// 0. `[writable]` market
+    /// 1. `[signer]` market authority or disable authority
+    EnableMarket,
+    /// Tears down a market that nothing is deposited in and nothing rests on, returning
+    /// the lamports of all of its accounts to the signer. Fees and referrer rebates have
+    /// to be swept or settled first. The market stats account is closed along with the
+    /// rest, so read off the last fill price and volume beforehand if they are still
+    /// needed. Markets without an authority are closed by the disable authority.
+    ///
+    /// The vaults are closed too. Tokens that were sent to them directly rather than
+    /// deposited go to the given wallets first. OpenOrders accounts can still be closed
+    /// with `CloseOpenOrders` afterwards.
+    ///
+    /// 0. `[writable]` market
+    /// 1. `[writable, signer]` market authority or disable authority
+    /// 2. `[writable]` request queue
+    /// 3. `[writable]` event queue
+    /// 4. `[writable]` bids
+    /// 5. `[writable]` asks
+    /// 6. `[writable]` coin vault
+    /// 7. `[writable]` pc vault
+    /// 8. `[writable]` coin wallet for tokens left in the coin vault
+    /// 9. `[writable]` pc wallet for tokens left in the pc vault
+    /// 10. `[]` vault signer
+    /// 11. `[]` spl token program
+    /// 12. `[writable]` stop orders, if the market has them
+    /// 13. `[writable]` pegged orders, if the market has them
+    /// 14. `[writable]` market stats, if the market has them
+    CloseMarket,
+    /// Closes an OpenOrders account with no orders and nothing left to settle,
+    /// sending its lamports wherever the owner chooses. The market may already
+    /// have been closed.
+    ///
+    /// 0. `[writable]` OpenOrders
+    /// 1. `[signer]` the OpenOrders owner
+    /// 2. `[writable]` the destination for the OpenOrders account's lamports
+    /// 3. `[]` market
+    CloseOpenOrders,
+    /// Lets another key place and cancel orders for an OpenOrders account.
+    /// Only the owner can settle funds, so the delegate can't move them anywhere.
+    /// Legacy accounts without the `OpenOrdersV2` layout can't have a delegate.
+    ///
+    /// 0. `[]` market
+    /// 1. `[writable]` OpenOrders
+    /// 2. `[signer]` the OpenOrders owner
+    /// 3. `[]` the new delegate
+    SetDelegate,
+    /// 0. `[]` market
+    /// 1. `[writable]` OpenOrders
+    /// 2. `[signer]` the OpenOrders owner
+    RevokeDelegate,
+    /// Like `SettleFunds` on a market where one of the currencies is wrapped SOL, but
+    /// pays that side out as SOL to the owner's system account. It's unwrapped through
+    /// a temporary token account, which is created and closed within the instruction.
+    ///
+    /// 0. `[writable]` market
+    /// 1. `[writable]` OpenOrders
+    /// 2. `[writable, signer]` the OpenOrders owner, which receives the SOL
+    /// 3. `[writable]` coin vault
+    /// 4. `[writable]` pc vault
+    /// 5. `[writable]` wallet for the currency that isn't wrapped SOL
+    /// 6. `[]` vault signer
+    /// 7. `[]` spl token program
+    /// 8. `[writable, signer]` an unused address for the temporary account
+    /// 9. `[]` the wrapped SOL mint
+    /// 10. `[]` system program
+    /// 11. `[]` the rent sysvar
+    /// 12. `[writable]` (optional) referrer pc wallet
+    SettleFundsNative,
+    /// Registers the wallets that `SettleFundsToWallets` pays out to.
+    /// Passing neither wallet unregisters them. Needs an `OpenOrdersV2` account.
+    ///
+    /// 0. `[]` market
+    /// 1. `[writable]` OpenOrders
+    /// 2. `[signer]` the OpenOrders owner
+    /// 3. `[]` coin wallet
+    /// 4. `[]` pc wallet
+    SetSettleWallets,
+    /// Settles an OpenOrders account into the wallets its owner registered, without
+    /// the owner's signature. Referrer rebates are left for the owner to settle.
+    ///
+    /// 0. `[writable]` market
+    /// 1. `[writable]` OpenOrders
+    /// 2. `[writable]` coin vault
+    /// 3. `[writable]` pc vault
+    /// 4. `[writable]` the registered coin wallet
+    /// 5. `[writable]` the registered pc wallet
+    /// 6. `[]` vault signer
+    /// 7. `[]` spl token program
+    SettleFundsToWallets,
+    /// Attaches a `MarketStats` account, which `MatchOrders` keeps up to date from then on.
+    /// The argument is how many slots each candle covers.
+    ///
+    /// 0. `[writable]` market
+    /// 1. `[writable]` the zeroed stats account, owned by the dex
+    /// 2. `[signer]` market authority
+    InitializeMarketStats(u64),
+    /// Ends the opening auction, filling whatever crosses on the book at the single price
+    /// that trades the most, and switches the market to continuous matching.
+    /// Queued orders have to be matched onto the book first. Crossing orders of the same
+    /// owner are first settled by the later order's self-trade behavior.
+    ///
+    /// Fees don't follow the continuous book's rule, where the later of two crossing
+    /// orders takes, since every order crosses at once here. Asks pay the taker fee of
+    /// their tier on the pc they receive. Bids are filled as makers, with no fee and no
+    /// rebate: a resting bid only has its price locked, so there is nothing to take a
+    /// fee from.
+    ///
+    /// At most `limit` orders are filled or self-trades settled per call. If that isn't
+    /// enough, the market keeps the clearing price and the lots left to fill, and stays
+    /// in auction mode until further calls finish the job. `MatchOrders` is rejected in
+    /// the meantime, since the book has to stay as the clearing price found it.
+    ///
+    /// 0. `[writable]` market
+    /// 1. `[signer]` market authority
+    /// 2. `[writable]` request queue
+    /// 3. `[writable]` event queue
+    /// 4. `[writable]` bids
+    /// 5. `[writable]` asks
+    /// 6. `[]` the clock sysvar
+    /// 7. `[writable]` stop orders (if the market has them)
+    /// 8. `[writable]` pegged orders (if the market has them)
+    /// 9. `[writable]` market stats (if the market has them)
+    RunAuction(u16),
+    /// Moves a market whose request queue, bids and asks predate the `RequestQueueV2`
+    /// and `OrderBookV2` flags into new accounts with the current layout, copying over
+    /// every queued request and resting order. The old accounts' lamports go to the
+    /// signer. Until a market is migrated, new orders, cancels, `MatchOrders` and
+    /// `CloseMarket` fail with `MarketNotMigrated`; settling and consuming events keep
+    /// working.
+    ///
+    /// The new accounts have to be zeroed, owned by the dex and big enough for what the
+    /// old ones hold: the request queue for every queued request, and each slab for as
+    /// many nodes as the old one has ever used. Otherwise the migration fails and
+    /// nothing changes.
+    ///
+    /// 0. `[writable]` market
+    /// 1. `[writable, signer]` market authority, or the disable authority if the market
+    ///    has none
+    /// 2. `[writable]` the old request queue
+    /// 3. `[writable]` the old bids
+    /// 4. `[writable]` the old asks
+    /// 5. `[writable]` the new request queue
+    /// 6. `[writable]` the new bids
+    /// 7. `[writable]` the new asks
+    MigrateMarket,
 }
 
 impl MarketInstruction {
@@ -256,7 +1131,7 @@ impl MarketInstruction {
     }
 
     pub fn unpack(versioned_bytes: &[u8]) -> Option<Self> {
-        if versioned_bytes.len() < 5 || versioned_bytes.len() > 58 {
+        if versioned_bytes.len() < 5 {
             return None;
         }
         let (&[version], &discrim, data) = array_refs![versioned_bytes, 1, 4; ..;];
@@ -265,15 +1140,39 @@ impl MarketInstruction {
         }
         let discrim = u32::from_le_bytes(discrim);
         Some(match (discrim, data.len()) {
-            (0, 34) => MarketInstruction::InitializeMarket({
-                let data_array = array_ref![data, 0, 34];
+            (0, len) if len >= 34 => MarketInstruction::InitializeMarket({
+                let (data_array, rest) = array_refs![data, 34; .. ;];
                 let fields = array_refs![data_array, 8, 8, 2, 8, 8];
+                // older clients leave out the fee schedule and the authority entirely
+                let (fee_schedule, rest) = match rest {
+                    [] => (None, rest),
+                    _ => unpack_option(rest, 32, unpack_fee_schedule)?,
+                };
+                let (authority, rest) = match rest {
+                    [] => (None, rest),
+                    _ => unpack_option(rest, 32, |key| Some(Pubkey::new(key)))?,
+                };
+                let (order_slabs, rest) = match rest {
+                    [] => (None, rest),
+                    _ => unpack_option(rest, 2, |data| {
+                        Some(OrderSlabs {
+                            stop_orders: unpack_bool(&data[..1])?,
+                            pegged_orders: unpack_bool(&data[1..])?,
+                        })
+                    })?,
+                };
+                if !rest.is_empty() {
+                    return None;
+                }
                 InitializeMarketInstruction {
                     coin_lot_size: u64::from_le_bytes(*fields.0),
                     pc_lot_size: u64::from_le_bytes(*fields.1),
                     fee_rate_bps: u16::from_le_bytes(*fields.2),
                     vault_signer_nonce: u64::from_le_bytes(*fields.3),
                     pc_dust_threshold: u64::from_le_bytes(*fields.4),
+                    fee_schedule,
+                    authority,
+                    order_slabs,
                 }
             }),
             (1, 32) => MarketInstruction::NewOrder({
@@ -288,9 +1187,9 @@ impl MarketInstruction {
                 let limit = array_ref![data, 0, 2];
                 MarketInstruction::ConsumeEvents(u16::from_le_bytes(*limit))
             }
-            (4, 53) => MarketInstruction::CancelOrder({
-                let data_array = array_ref![data, 0, 53];
-                let fields = array_refs![data_array, 4, 16, 32, 1];
+            (4, 53) | (4, 54) => MarketInstruction::CancelOrder({
+                let (data_array, owner_slot_data) = array_refs![data, 52; .. ;];
+                let fields = array_refs![data_array, 4, 16, 32];
                 let side = match u32::from_le_bytes(*fields.0) {
                     0 => Side::Bid,
                     1 => Side::Ask,
@@ -298,7 +1197,12 @@ impl MarketInstruction {
                 };
                 let order_id = u128::from_le_bytes(*fields.1);
                 let owner = cast(*fields.2);
-                let &[owner_slot] = fields.3;
+                // older clients send the slot as a single byte
+                let owner_slot = match *owner_slot_data {
+                    [owner_slot] => owner_slot as u16,
+                    [lo, hi] => u16::from_le_bytes([lo, hi]),
+                    _ => return None,
+                };
                 CancelOrderInstruction {
                     side,
                     order_id,
@@ -313,16 +1217,114 @@ impl MarketInstruction {
             }
             (7, 0) => MarketInstruction::DisableMarket,
             (8, 0) => MarketInstruction::SweepFees,
-            (9, 36) => MarketInstruction::NewOrderV2({
-                let data_arr = array_ref![data, 0, 36];
-                let (v1_data_arr, v2_data_arr) = array_refs![data_arr, 32, 4];
+            (9, _) => MarketInstruction::NewOrderV2(NewOrderInstructionV2::unpack(data)?),
+            (10, len) if len >= 44 => MarketInstruction::NewOrderV3({
+                let (data_arr, expiry_data) = array_refs![data, 44; .. ;];
+                let (v1_data_arr, v2_data_arr, v3_data_arr) = array_refs![data_arr, 32, 4, 8];
                 let v1_instr = NewOrderInstructionV1::unpack(v1_data_arr)?;
                 let self_trade_behavior = SelfTradeBehavior::try_from_primitive(
                     u32::from_le_bytes(*v2_data_arr).try_into().ok()?,
                 )
                 .ok()?;
-                v1_instr.add_self_trade_behavior(self_trade_behavior)
+                let trigger_price = NonZeroU64::new(u64::from_le_bytes(*v3_data_arr))?;
+                let mut v3_instr = v1_instr
+                    .add_self_trade_behavior(self_trade_behavior)
+                    .add_trigger_price(trigger_price);
+                v3_instr.expiry = OrderExpiry::unpack(expiry_data)?;
+                v3_instr
+            }),
+            (11, len) if len >= 4 => MarketInstruction::ReplaceOrder({
+                let (&discrim, rest) = array_refs![data, 4; .. ;];
+                let (replaced_order, new_order_data) = match u32::from_le_bytes(discrim) {
+                    0 if rest.len() >= 16 => {
+                        let (&order_id, new_order_data) = array_refs![rest, 16; .. ;];
+                        let order_id = u128::from_le_bytes(order_id);
+                        (ReplacedOrder::OrderId(order_id), new_order_data)
+                    }
+                    1 if rest.len() >= 8 => {
+                        let (&client_id, new_order_data) = array_refs![rest, 8; .. ;];
+                        let client_id = u64::from_le_bytes(client_id);
+                        (ReplacedOrder::ClientId(client_id), new_order_data)
+                    }
+                    _ => return None,
+                };
+                ReplaceOrderInstruction {
+                    replaced_order,
+                    new_order: NewOrderInstructionV2::unpack(new_order_data)?,
+                }
+            }),
+            (12, len) if len >= 8 => MarketInstruction::NewOrdersBatch({
+                let (&count, mut rest) = array_refs![data, 8; .. ;];
+                let count = u64::from_le_bytes(count) as usize;
+                if count == 0 || count > MAX_ORDERS_PER_BATCH {
+                    return None;
+                }
+                let mut orders = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let (order, tail) = NewOrderInstructionV2::unpack_prefix(rest)?;
+                    orders.push(order);
+                    rest = tail;
+                }
+                if !rest.is_empty() {
+                    return None;
+                }
+                orders
+            }),
+            (13, len) if len >= 8 => MarketInstruction::CancelOrdersBatch({
+                let (&count, order_ids) = array_refs![data, 8; .. ;];
+                let count = u64::from_le_bytes(count) as usize;
+                if count == 0 || count > MAX_ORDERS_PER_BATCH || order_ids.len() != count * 16 {
+                    return None;
+                }
+                order_ids
+                    .chunks_exact(16)
+                    .map(|order_id| u128::from_le_bytes(*array_ref![order_id, 0, 16]))
+                    .collect()
+            }),
+            (14, 1) if data[0] == 0 => MarketInstruction::CancelAllOrders(None),
+            (14, 5) if data[0] == 1 => MarketInstruction::CancelAllOrders(Some({
+                let side = array_ref![data, 1, 4];
+                match u32::from_le_bytes(*side) {
+                    0 => Side::Bid,
+                    1 => Side::Ask,
+                    _ => return None,
+                }
+            })),
+            (15, len) if len >= 8 => MarketInstruction::NewIcebergOrder({
+                let (&display_qty, new_order_data) = array_refs![data, 8; .. ;];
+                NewIcebergOrderInstruction {
+                    display_qty: NonZeroU64::new(u64::from_le_bytes(display_qty))?,
+                    new_order: NewOrderInstructionV2::unpack(new_order_data)?,
+                }
+            }),
+            (16, _) => MarketInstruction::NewPeggedOrder(NewPeggedOrderInstruction::unpack(data)?),
+            (17, 20) => MarketInstruction::NewMarketBuy({
+                let data_arr = array_ref![data, 0, 20];
+                NewMarketBuyInstruction::unpack(data_arr)?
+            }),
+            (18, _) => {
+                MarketInstruction::SetPositionLimits(SetPositionLimitsInstruction::unpack(data)?)
+            }
+            (19, _) => {
+                MarketInstruction::SetMarketParams(SetMarketParamsInstruction::unpack(data)?)
+            }
+            (20, 0) => MarketInstruction::EnableMarket,
+            (21, 0) => MarketInstruction::CloseMarket,
+            (22, 0) => MarketInstruction::CloseOpenOrders,
+            (23, 0) => MarketInstruction::SetDelegate,
+            (24, 0) => MarketInstruction::RevokeDelegate,
+            (25, 0) => MarketInstruction::SettleFundsNative,
+            (26, 0) => MarketInstruction::SetSettleWallets,
+            (27, 0) => MarketInstruction::SettleFundsToWallets,
+            (28, 8) => MarketInstruction::InitializeMarketStats({
+                let slots_per_candle = array_ref![data, 0, 8];
+                u64::from_le_bytes(*slots_per_candle)
             }),
+            (29, 2) => {
+                let limit = array_ref![data, 0, 2];
+                MarketInstruction::RunAuction(u16::from_le_bytes(*limit))
+            }
+            (30, 0) => MarketInstruction::MigrateMarket,
             _ => return None,
         })
     }
@@ -350,10 +1352,14 @@ pub fn initialize_market(
     asks_pk: &Pubkey,
     req_q_pk: &Pubkey,
     event_q_pk: &Pubkey,
+    stop_orders_pk: Option<&Pubkey>,
+    pegged_orders_pk: Option<&Pubkey>,
     coin_lot_size: u64,
     pc_lot_size: u64,
     vault_signer_nonce: u64,
     pc_dust_threshold: u64,
+    fee_schedule: Option<FeeSchedule>,
+    authority_pk: Option<&Pubkey>,
 ) -> Result<solana_sdk::instruction::Instruction, DexError> {
     let data = MarketInstruction::InitializeMarket(InitializeMarketInstruction {
         coin_lot_size,
@@ -361,6 +1367,12 @@ pub fn initialize_market(
         fee_rate_bps: 0,
         vault_signer_nonce,
         pc_dust_threshold,
+        fee_schedule,
+        authority: authority_pk.copied(),
+        order_slabs: Some(OrderSlabs {
+            stop_orders: stop_orders_pk.is_some(),
+            pegged_orders: pegged_orders_pk.is_some(),
+        }),
     })
     .pack();
 
@@ -377,7 +1389,7 @@ pub fn initialize_market(
     let coin_mint = AccountMeta::new_readonly(*coin_mint_pk, false);
     let pc_mint = AccountMeta::new_readonly(*pc_mint_pk, false);
 
-    let accounts = vec![
+    let mut accounts = vec![
         market_account,
         req_q,
         event_q,
@@ -390,6 +1402,12 @@ pub fn initialize_market(
         pc_mint,
         //srm_mint,
     ];
+    if let Some(stop_orders_pk) = stop_orders_pk {
+        accounts.push(AccountMeta::new(*stop_orders_pk, false));
+    }
+    if let Some(pegged_orders_pk) = pegged_orders_pk {
+        accounts.push(AccountMeta::new(*pegged_orders_pk, false));
+    }
 
     Ok(Instruction {
         program_id: *program_id,
@@ -432,6 +1450,7 @@ mod fuzzing {
         pub order_type: OrderType,
         pub client_id: u64,
         pub self_trade_behavior: SelfTradeBehavior,
+        pub expiry: Option<OrderExpiry>,
     }
 
     impl TryFrom<NewOrderInstructionU64> for NewOrderInstructionV2 {
@@ -445,6 +1464,7 @@ mod fuzzing {
                 order_type: value.order_type,
                 client_id: value.client_id,
                 self_trade_behavior: value.self_trade_behavior,
+                expiry: value.expiry,
             })
         }
     }
@@ -472,6 +1492,7 @@ mod fuzzing {
                 order_type: value.order_type,
                 client_id: value.client_id,
                 self_trade_behavior: SelfTradeBehavior::DecrementTake,
+                expiry: None,
             }
         }
     }
@@ -485,6 +1506,7 @@ mod fuzzing {
                 order_type: value.order_type,
                 client_id: value.client_id,
                 self_trade_behavior: value.self_trade_behavior,
+                expiry: value.expiry,
             }
         }
     }
@@ -530,4 +1552,174 @@ mod fuzzing {
             )
         }
     }
+
+    impl arbitrary::Arbitrary for NewOrderInstructionV3 {
+        fn arbitrary(u: &mut Unstructured<'_>) -> Result<Self, arbitrary::Error> {
+            let v2 = <NewOrderInstructionV2 as arbitrary::Arbitrary>::arbitrary(u)?;
+            let trigger_price = <u64 as arbitrary::Arbitrary>::arbitrary(u)?
+                .try_into()
+                .map_err(|_| arbitrary::Error::IncorrectFormat)?;
+            Ok(v2.add_trigger_price(trigger_price))
+        }
+
+        fn size_hint(depth: usize) -> (usize, Option<usize>) {
+            arbitrary::size_hint::and(
+                <NewOrderInstructionV2 as arbitrary::Arbitrary>::size_hint(depth),
+                <u64 as arbitrary::Arbitrary>::size_hint(depth),
+            )
+        }
+    }
+
+    impl arbitrary::Arbitrary for NewIcebergOrderInstruction {
+        fn arbitrary(u: &mut Unstructured<'_>) -> Result<Self, arbitrary::Error> {
+            let display_qty = <u64 as arbitrary::Arbitrary>::arbitrary(u)?
+                .try_into()
+                .map_err(|_| arbitrary::Error::IncorrectFormat)?;
+            let new_order = <NewOrderInstructionV2 as arbitrary::Arbitrary>::arbitrary(u)?;
+            Ok(NewIcebergOrderInstruction {
+                display_qty,
+                new_order,
+            })
+        }
+
+        fn size_hint(depth: usize) -> (usize, Option<usize>) {
+            arbitrary::size_hint::and(
+                <u64 as arbitrary::Arbitrary>::size_hint(depth),
+                <NewOrderInstructionV2 as arbitrary::Arbitrary>::size_hint(depth),
+            )
+        }
+    }
+
+    impl arbitrary::Arbitrary for InitializeMarketInstruction {
+        fn arbitrary(u: &mut Unstructured<'_>) -> Result<Self, arbitrary::Error> {
+            Ok(InitializeMarketInstruction {
+                coin_lot_size: <u64 as arbitrary::Arbitrary>::arbitrary(u)?,
+                pc_lot_size: <u64 as arbitrary::Arbitrary>::arbitrary(u)?,
+                fee_rate_bps: <u16 as arbitrary::Arbitrary>::arbitrary(u)?,
+                vault_signer_nonce: <u64 as arbitrary::Arbitrary>::arbitrary(u)?,
+                pc_dust_threshold: <u64 as arbitrary::Arbitrary>::arbitrary(u)?,
+                fee_schedule: <Option<FeeSchedule> as arbitrary::Arbitrary>::arbitrary(u)?,
+                authority: <Option<[u8; 32]> as arbitrary::Arbitrary>::arbitrary(u)?
+                    .map(|key| Pubkey::new(&key)),
+                order_slabs: <Option<OrderSlabs> as arbitrary::Arbitrary>::arbitrary(u)?,
+            })
+        }
+
+        fn size_hint(depth: usize) -> (usize, Option<usize>) {
+            arbitrary::size_hint::and_all(&[
+                <u64 as arbitrary::Arbitrary>::size_hint(depth),
+                <u64 as arbitrary::Arbitrary>::size_hint(depth),
+                <u16 as arbitrary::Arbitrary>::size_hint(depth),
+                <u64 as arbitrary::Arbitrary>::size_hint(depth),
+                <u64 as arbitrary::Arbitrary>::size_hint(depth),
+                <Option<FeeSchedule> as arbitrary::Arbitrary>::size_hint(depth),
+                <Option<[u8; 32]> as arbitrary::Arbitrary>::size_hint(depth),
+                <Option<OrderSlabs> as arbitrary::Arbitrary>::size_hint(depth),
+            ])
+        }
+    }
+
+    impl arbitrary::Arbitrary for SetMarketParamsInstruction {
+        fn arbitrary(u: &mut Unstructured<'_>) -> Result<Self, arbitrary::Error> {
+            Ok(SetMarketParamsInstruction {
+                fee_schedule: <Option<FeeSchedule> as arbitrary::Arbitrary>::arbitrary(u)?,
+                pc_dust_threshold: <Option<u64> as arbitrary::Arbitrary>::arbitrary(u)?,
+                coin_lot_size: <Option<u64> as arbitrary::Arbitrary>::arbitrary(u)?
+                    .and_then(NonZeroU64::new),
+                pc_lot_size: <Option<u64> as arbitrary::Arbitrary>::arbitrary(u)?
+                    .and_then(NonZeroU64::new),
+                auto_consume: <Option<bool> as arbitrary::Arbitrary>::arbitrary(u)?,
+                price_band: <Option<PriceBand> as arbitrary::Arbitrary>::arbitrary(u)?,
+                mode: <Option<MarketMode> as arbitrary::Arbitrary>::arbitrary(u)?,
+            })
+        }
+
+        fn size_hint(depth: usize) -> (usize, Option<usize>) {
+            arbitrary::size_hint::and_all(&[
+                <Option<FeeSchedule> as arbitrary::Arbitrary>::size_hint(depth),
+                <Option<u64> as arbitrary::Arbitrary>::size_hint(depth),
+                <Option<u64> as arbitrary::Arbitrary>::size_hint(depth),
+                <Option<u64> as arbitrary::Arbitrary>::size_hint(depth),
+                <Option<bool> as arbitrary::Arbitrary>::size_hint(depth),
+                <Option<PriceBand> as arbitrary::Arbitrary>::size_hint(depth),
+                <Option<MarketMode> as arbitrary::Arbitrary>::size_hint(depth),
+            ])
+        }
+    }
+
+    impl arbitrary::Arbitrary for SetPositionLimitsInstruction {
+        fn arbitrary(u: &mut Unstructured<'_>) -> Result<Self, arbitrary::Error> {
+            let max_native_coin_position =
+                <u64 as arbitrary::Arbitrary>::arbitrary(u)?.try_into().ok();
+            Ok(SetPositionLimitsInstruction {
+                max_native_coin_position,
+                reduce_only: <bool as arbitrary::Arbitrary>::arbitrary(u)?,
+            })
+        }
+
+        fn size_hint(depth: usize) -> (usize, Option<usize>) {
+            arbitrary::size_hint::and(
+                <u64 as arbitrary::Arbitrary>::size_hint(depth),
+                <bool as arbitrary::Arbitrary>::size_hint(depth),
+            )
+        }
+    }
+
+    impl arbitrary::Arbitrary for NewMarketBuyInstruction {
+        fn arbitrary(u: &mut Unstructured<'_>) -> Result<Self, arbitrary::Error> {
+            let max_native_pc_qty_including_fees = <u64 as arbitrary::Arbitrary>::arbitrary(u)?
+                .try_into()
+                .map_err(|_| arbitrary::Error::IncorrectFormat)?;
+            Ok(NewMarketBuyInstruction {
+                max_native_pc_qty_including_fees,
+                client_id: <u64 as arbitrary::Arbitrary>::arbitrary(u)?,
+                self_trade_behavior: <SelfTradeBehavior as arbitrary::Arbitrary>::arbitrary(u)?,
+            })
+        }
+
+        fn size_hint(depth: usize) -> (usize, Option<usize>) {
+            arbitrary::size_hint::and_all(&[
+                <u64 as arbitrary::Arbitrary>::size_hint(depth),
+                <u64 as arbitrary::Arbitrary>::size_hint(depth),
+                <SelfTradeBehavior as arbitrary::Arbitrary>::size_hint(depth),
+            ])
+        }
+    }
+
+    #[derive(arbitrary::Arbitrary)]
+    struct NewPeggedOrderInstructionU64 {
+        pub side: Side,
+        pub peg_offset: i64,
+        pub cap_price: Option<u64>,
+        pub max_qty: u64,
+        pub client_id: u64,
+    }
+
+    impl arbitrary::Arbitrary for NewPeggedOrderInstruction {
+        fn arbitrary(u: &mut Unstructured<'_>) -> Result<Self, arbitrary::Error> {
+            let x = <NewPeggedOrderInstructionU64 as arbitrary::Arbitrary>::arbitrary(u)?;
+            let cap_price = match x.cap_price {
+                Some(cap_price) => Some(
+                    cap_price
+                        .try_into()
+                        .map_err(|_| arbitrary::Error::IncorrectFormat)?,
+                ),
+                None => None,
+            };
+            Ok(NewPeggedOrderInstruction {
+                side: x.side,
+                peg_offset: x.peg_offset,
+                cap_price,
+                max_qty: x
+                    .max_qty
+                    .try_into()
+                    .map_err(|_| arbitrary::Error::IncorrectFormat)?,
+                client_id: x.client_id,
+            })
+        }
+
+        fn size_hint(depth: usize) -> (usize, Option<usize>) {
+            <NewPeggedOrderInstructionU64 as arbitrary::Arbitrary>::size_hint(depth)
+        }
+    }
 }
@@ -1,10 +1,12 @@
 use std::num::NonZeroU64;
 
-use crate::instruction::SelfTradeBehavior;
+use crate::instruction::{OrderExpiry, SelfTradeBehavior};
+use bytemuck::cast;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 #[cfg(test)]
 use proptest_derive::Arbitrary;
 use serde::{Deserialize, Serialize};
+use solana_sdk::clock::Clock;
 #[cfg(feature = "program")]
 use solana_sdk::info;
 
@@ -14,7 +16,9 @@ use crate::{
     critbit::{LeafNode, NodeHandle, Slab, SlabView},
     error::DexError,
     fees::{self, FeeTier},
-    state::{Event, EventQueue, EventView, MarketState, Request, RequestQueue, RequestView},
+    state::{
+        Event, EventQueue, EventView, MarketState, MarketStats, Request, RequestQueue, RequestView,
+    },
 };
 
 #[cfg(not(feature = "program"))]
@@ -44,17 +48,103 @@ pub enum OrderType {
     Limit = 0,
     ImmediateOrCancel = 1,
     PostOnly = 2,
+    FillOrKill = 3,
 }
 
 fn extract_price_from_order_id(order_id: &u128) -> u64 {
     (order_id >> 64) as u64
 }
 
+// Buy and sell stops share one slab. Sell stop keys have the top bit set, so the
+// buy stop with the lowest trigger price is always the minimum of the slab and the
+// sell stop with the highest trigger price is always the maximum.
+const STOP_ASK_KEY_BIT: u128 = 1 << 127;
+pub const MAX_TRIGGER_PRICE: u64 = std::u64::MAX >> 1;
+
+// The low bits of a stop order key are the inverted low bits of the order id, which
+// keeps stops with the same trigger price in time priority on both sides.
+pub fn stop_order_key(order_id: &u128, side: Side, trigger_price: u64) -> u128 {
+    let key = ((trigger_price as u128) << 64) | (!(*order_id as u64) as u128);
+    match side {
+        Side::Bid => key,
+        Side::Ask => key | STOP_ASK_KEY_BIT,
+    }
+}
+
+fn stop_order_side(key: &u128) -> Side {
+    if key & STOP_ASK_KEY_BIT == 0 {
+        Side::Bid
+    } else {
+        Side::Ask
+    }
+}
+
+fn stop_order_trigger_price(key: &u128) -> u64 {
+    ((key & !STOP_ASK_KEY_BIT) >> 64) as u64
+}
+
+fn order_id_from_stop_order(stop_order: &LeafNode) -> u128 {
+    ((stop_order.limit_price() as u128) << 64) | (!(*stop_order.order_id() as u64) as u128)
+}
+
+// Pegged orders are keyed by their offset from the price they peg to, with the sell
+// side's entries in the upper half of the slab like the stop orders. The low bits
+// are those of the order id the pegged order currently goes by.
+const PEG_ASK_KEY_BIT: u128 = 1 << 127;
+pub const MAX_PEG_OFFSET: i64 = std::i64::MAX >> 1;
+
+pub fn pegged_order_key(order_id: &u128, side: Side, peg_offset: i64) -> u128 {
+    let biased_offset = (peg_offset + MAX_PEG_OFFSET + 1) as u64;
+    let key = ((biased_offset as u128) << 64) | (*order_id as u64 as u128);
+    match side {
+        Side::Bid => key,
+        Side::Ask => key | PEG_ASK_KEY_BIT,
+    }
+}
+
+fn pegged_order_side(key: &u128) -> Side {
+    if key & PEG_ASK_KEY_BIT == 0 {
+        Side::Bid
+    } else {
+        Side::Ask
+    }
+}
+
+fn pegged_order_offset(key: &u128) -> i64 {
+    ((key & !PEG_ASK_KEY_BIT) >> 64) as i64 - MAX_PEG_OFFSET - 1
+}
+
+fn order_id_from_pegged_order(pegged_order: &LeafNode) -> u128 {
+    ((pegged_order.peg_price() as u128) << 64) | (*pegged_order.order_id() as u64 as u128)
+}
+
+fn order_expired(expiry: Option<OrderExpiry>, clock: &Clock) -> bool {
+    expiry.map_or(false, |expiry| expiry.has_passed(clock))
+}
+
+// The best order on each side and how many orders there are. The prices pegged orders
+// peg to can't move unless one of these does, or an order is replaced.
+#[derive(PartialEq, Eq, Copy, Clone)]
+struct BookTop {
+    best_order_ids: (Option<u128>, Option<u128>),
+    order_counts: (u64, u64),
+}
+
+// What the pegged orders were last moved to follow.
+struct PeggedTo {
+    book_top: BookTop,
+    peg_prices: (Option<u64>, Option<u64>),
+}
+
 pub struct OrderBookState<'a> {
     // first byte of a key is 0xaa or 0xbb, disambiguating bids and asks
     pub bids: &'a mut Slab,
     pub asks: &'a mut Slab,
+    pub stop_orders: Option<&'a mut Slab>,
+    pub pegged_orders: Option<&'a mut Slab>,
+    pub market_stats: Option<&'a mut MarketStats>,
     pub market_state: &'a mut MarketState,
+    pub clock: &'a Clock,
 }
 
 impl<'ob> OrderBookState<'ob> {
@@ -65,6 +155,13 @@ impl<'ob> OrderBookState<'ob> {
         }
     }
 
+    pub fn orders(&self, side: Side) -> &Slab {
+        match side {
+            Side::Bid => self.bids,
+            Side::Ask => self.asks,
+        }
+    }
+
     pub fn find_bbo(&self, side: Side) -> Option<NodeHandle> {
         match side {
             Side::Bid => self.bids.find_max(),
@@ -72,34 +169,409 @@ impl<'ob> OrderBookState<'ob> {
         }
     }
 
+    fn record_fill(&mut self, price: u64, qty: u64) {
+        // legacy markets have nowhere to keep it
+        if self.market_state.is_v2() {
+            self.market_state.last_fill_price = price;
+        }
+        if let Some(market_stats) = self.market_stats.as_deref_mut() {
+            market_stats.record_fill(
+                self.clock.slot,
+                price,
+                qty.saturating_mul(self.market_state.coin_lot_size),
+                qty.saturating_mul(price)
+                    .saturating_mul(self.market_state.pc_lot_size),
+            );
+        }
+    }
+
+    // The furthest price an order on this side may trade at, if the market has a band.
+    fn price_band_limit(&self, side: Side) -> Option<u64> {
+        let band_bps = self.market_state.price_band_bps;
+        let reference_price = self.market_state.last_fill_price;
+        if band_bps == 0 || reference_price == 0 {
+            return None;
+        }
+        let max_deviation = (reference_price as u128 * band_bps as u128 / 10_000) as u64;
+        Some(match side {
+            Side::Bid => reference_price.saturating_add(max_deviation),
+            Side::Ask => reference_price.saturating_sub(max_deviation),
+        })
+    }
+
+    fn hit_price_band(&mut self) {
+        let halt_slots = self.market_state.price_band_halt_slots;
+        if halt_slots > 0 {
+            self.market_state.halted_until_slot = self.clock.slot.saturating_add(halt_slots);
+        }
+    }
+
+    // either by the authority or for a while after the price band was hit
+    fn is_cancel_only(&self) -> bool {
+        self.market_state.is_cancel_only() || self.clock.slot < self.market_state.halted_until_slot
+    }
+
+    fn find_best_price(&self, side: Side) -> Option<u64> {
+        let best_order = self.orders(side).get(self.find_bbo(side)?)?.as_leaf()?;
+        Some(best_order.price().get())
+    }
+
     pub fn process_requests(
         &mut self,
         req_q: &mut RequestQueue,
         event_q: &mut EventQueue,
         limit: u16,
     ) -> Result<(), DexError> {
+        // the price only changes while matching, so it held since the last time we got here
+        self.market_state.accumulate_price(self.clock.slot);
+        // so orders placed without the clock are taken again
+        if self.clock.slot >= self.market_state.halted_until_slot {
+            self.market_state.halted_until_slot = 0;
+        }
         let mut limit_remaining = limit;
+        let mut last_pegged_to = None;
         while limit_remaining > 0 {
-            let request = match req_q.peek_front_mut() {
-                Some(r) => r,
+            self.activate_stop_orders(req_q, event_q)?;
+            self.update_pegged_orders(&mut last_pegged_to, &mut limit_remaining, req_q, event_q)?;
+            if limit_remaining == 0 {
+                break;
+            }
+            // matching may draw new order ids from the queue, so work on a copy
+            let request = match req_q.peek_front() {
+                Some(r) => *r,
                 None => break,
             };
-            match self.process_orderbook_request(request, event_q, &mut limit_remaining)? {
+            match self.process_orderbook_request(&request, req_q, event_q, &mut limit_remaining)? {
                 Some(remaining_request) => {
-                    *request = remaining_request;
+                    *req_q.peek_front_mut().unwrap() = remaining_request;
                 }
                 None => {
                     req_q.pop_front().unwrap();
                 }
             };
+            // a replaced order can move the price pegged orders peg to without changing
+            // the best orders or how many orders there are
+            if let RequestView::NewOrder {
+                replaces: Some(_), ..
+            } = request.as_view()?
+            {
+                last_pegged_to = None;
+            }
         }
 
         Ok(())
     }
 
+    fn find_triggered_stop_order(&self) -> Option<LeafNode> {
+        let last_fill_price = self.market_state.last_fill_price;
+        let stop_orders = self.stop_orders.as_deref()?;
+        if last_fill_price == 0 {
+            return None;
+        }
+        let buy_stop = stop_orders
+            .find_min()
+            .and_then(|h| stop_orders.get(h)?.as_leaf().copied())
+            .filter(|leaf| {
+                stop_order_side(leaf.order_id()) == Side::Bid
+                    && stop_order_trigger_price(leaf.order_id()) <= last_fill_price
+            });
+        let sell_stop = stop_orders
+            .find_max()
+            .and_then(|h| stop_orders.get(h)?.as_leaf().copied())
+            .filter(|leaf| {
+                stop_order_side(leaf.order_id()) == Side::Ask
+                    && stop_order_trigger_price(leaf.order_id()) >= last_fill_price
+            });
+        buy_stop.or(sell_stop)
+    }
+
+    // Moves every stop order whose trigger price has traded into the request queue,
+    // from where it enters the book like any other new order. Until then the order goes
+    // by its stop orders key, so a Requeue event tells the OpenOrders account its id.
+    fn activate_stop_orders(
+        &mut self,
+        req_q: &mut RequestQueue,
+        event_q: &mut EventQueue,
+    ) -> DexResult {
+        while !req_q.full() {
+            let stop_order = match self.find_triggered_stop_order() {
+                Some(stop_order) => stop_order,
+                None => break,
+            };
+            let stop_orders = self.stop_orders.as_deref_mut().unwrap();
+            stop_orders.remove_by_key(stop_order.order_id()).unwrap();
+
+            let order_id = order_id_from_stop_order(&stop_order);
+            let request = Request::new(RequestView::NewOrder {
+                side: stop_order_side(stop_order.order_id()),
+                order_type: stop_order.order_type(),
+                order_id: &order_id,
+                owner_slot: stop_order.owner_slot(),
+                fee_tier: stop_order.fee_tier(),
+                owner: stop_order.owner(),
+                max_coin_qty: NonZeroU64::new(stop_order.quantity()).ok_or(assertion_error!())?,
+                native_pc_qty_locked: NonZeroU64::new(stop_order.native_pc_qty_locked()),
+                client_order_id: NonZeroU64::new(stop_order.client_order_id()),
+                self_trade_behavior: stop_order.self_trade_behavior(),
+                expiry: stop_order.expiry(),
+                replaces: None,
+                display_qty: None,
+            });
+            req_q
+                .push_back(request)
+                .map_err(|_| DexErrorCode::RequestQueueFull)?;
+            event_q
+                .push_back(Event::new(EventView::Requeue {
+                    side: stop_order_side(stop_order.order_id()),
+                    order_id: stop_order.order_id(),
+                    new_order_id: order_id,
+                    owner: stop_order.owner(),
+                    owner_slot: stop_order.owner_slot(),
+                    client_order_id: NonZeroU64::new(stop_order.client_order_id()),
+                }))
+                .map_err(|_| DexErrorCode::EventQueueFull)?;
+        }
+        Ok(())
+    }
+
+    // Pegged orders peg to the best order on their side of the book that isn't pegged
+    // itself.
+    fn find_peg_price(&self, side: Side) -> Option<u64> {
+        // usually the best order, which saves walking past pegged ones
+        let best_order = self.orders(side).get(self.find_bbo(side)?)?.as_leaf()?;
+        if !best_order.is_pegged() {
+            return Some(best_order.price().get());
+        }
+        let best_order = match side {
+            Side::Bid => self.bids.rfind_leaf_by(|leaf| !leaf.is_pegged()),
+            Side::Ask => self.asks.find_leaf_by(|leaf| !leaf.is_pegged()),
+        };
+        best_order.map(|leaf| leaf.price().get())
+    }
+
+    // Clamps a pegged order's price to its cap and keeps it clear of the opposite side
+    // of the book, so that it never takes.
+    fn pegged_order_price(
+        &self,
+        side: Side,
+        peg_price: u64,
+        peg_offset: i64,
+        cap_price: Option<NonZeroU64>,
+    ) -> Option<u64> {
+        let price = peg_price as i128 + peg_offset as i128;
+        let opposite_price = match side {
+            Side::Bid => self.find_best_price(Side::Ask),
+            Side::Ask => self.find_best_price(Side::Bid),
+        }
+        .map(|price| price as i128);
+        let price = match side {
+            Side::Bid => {
+                let price = cap_price.map_or(price, |cap| price.min(cap.get() as i128));
+                opposite_price.map_or(price, |ask| price.min(ask - 1))
+            }
+            Side::Ask => {
+                let price = cap_price.map_or(price, |cap| price.max(cap.get() as i128));
+                opposite_price.map_or(price, |bid| price.max(bid + 1))
+            }
+        };
+        if price < 1 || price > std::u64::MAX as i128 {
+            return None;
+        }
+        Some(price as u64)
+    }
+
+    fn book_top(&self) -> BookTop {
+        let best_order_id = |side| {
+            let best_order = self.orders(side).get(self.find_bbo(side)?)?.as_leaf()?;
+            Some(*best_order.order_id())
+        };
+        BookTop {
+            best_order_ids: (best_order_id(Side::Bid), best_order_id(Side::Ask)),
+            order_counts: (self.bids.leaf_count(), self.asks.leaf_count()),
+        }
+    }
+
+    // Moves pegged orders to follow the prices they peg to, and puts the ones still
+    // waiting in the pegged orders slab on the book once there is a price to peg to.
+    // This only runs when a peg price has moved since the last time, which is only
+    // looked for once the top of the book changes, and the first time in each call to
+    // process_requests, which also picks up newly placed pegged orders and drops the
+    // entries of pegged orders that have left the book. Each order moved takes one
+    // unit of the limit, and a pass that runs out of it carries on from the market's
+    // cursor in the next call.
+    fn update_pegged_orders(
+        &mut self,
+        last_pegged_to: &mut Option<PeggedTo>,
+        limit: &mut u16,
+
+        req_q: &mut RequestQueue,
+        event_q: &mut EventQueue,
+    ) -> DexResult {
+        match self.pegged_orders.as_deref() {
+            Some(pegged_orders) if !pegged_orders.is_empty() => (),
+            _ => {
+                self.market_state.pegged_orders_cursor = [0; 2];
+                return Ok(());
+            }
+        };
+        let mut last_key: u128 = cast(self.market_state.pegged_orders_cursor);
+        let resumed = last_key != 0;
+        let book_top = self.book_top();
+        if !resumed {
+            if let Some(last_pegged_to) = last_pegged_to.as_ref() {
+                if last_pegged_to.book_top == book_top {
+                    return Ok(());
+                }
+            }
+        }
+        let peg_prices = (
+            self.find_peg_price(Side::Bid),
+            self.find_peg_price(Side::Ask),
+        );
+        let peg_prices_moved = last_pegged_to.as_ref().map_or(true, |last_pegged_to| {
+            last_pegged_to.peg_prices != peg_prices
+        });
+        *last_pegged_to = Some(PeggedTo {
+            book_top,
+            peg_prices,
+        });
+        if !resumed && !peg_prices_moved {
+            return Ok(());
+        }
+
+        // keys are never 0, so a pass that wasn't resumed starts from the lowest
+        loop {
+            if *limit == 0 {
+                check_assert!(last_key != 0)?;
+                self.market_state.pegged_orders_cursor = cast(last_key);
+                return Ok(());
+            }
+            let pegged_orders = self.pegged_orders.as_deref().ok_or(assertion_error!())?;
+            let pegged_order = match pegged_orders.find_min_above(&last_key) {
+                Some(h) => *pegged_orders
+                    .get(h)
+                    .and_then(|node| node.as_leaf())
+                    .ok_or(assertion_error!())?,
+                None => break,
+            };
+            let key = *pegged_order.order_id();
+            last_key = key;
+            let side = pegged_order_side(&key);
+            let peg_offset = pegged_order_offset(&key);
+            let order_id = order_id_from_pegged_order(&pegged_order);
+
+            // pegged orders already on the book have no quantity left in the slab, and
+            // until they first get there they go by their key
+            let on_book = pegged_order.quantity() == 0;
+            let requeued_order_id = if on_book { order_id } else { key };
+            let coin_qty = if on_book {
+                let order = self
+                    .orders(side)
+                    .find_by_key(&order_id)
+                    .and_then(|h| self.orders(side).get(h)?.as_leaf().copied());
+                match order {
+                    Some(order) => order.quantity(),
+                    None => {
+                        self.pegged_orders
+                            .as_deref_mut()
+                            .ok_or(assertion_error!())?
+                            .remove_by_key(&key)
+                            .ok_or(assertion_error!())?;
+                        continue;
+                    }
+                }
+            } else {
+                pegged_order.quantity()
+            };
+
+            let peg_price = match side {
+                Side::Bid => peg_prices.0,
+                Side::Ask => peg_prices.1,
+            };
+            let price = match peg_price.and_then(|peg_price| {
+                self.pegged_order_price(side, peg_price, peg_offset, pegged_order.cap_price())
+            }) {
+                Some(price) => price,
+                None => continue,
+            };
+            if on_book && price == pegged_order.peg_price() {
+                continue;
+            }
+
+            let new_order_id = req_q.gen_order_id(price, side);
+            let mut new_order = LeafNode::new(
+                pegged_order.owner_slot(),
+                &new_order_id,
+                pegged_order.owner(),
+                coin_qty,
+                pegged_order.fee_tier(),
+                pegged_order.client_order_id(),
+            );
+            new_order.set_pegged_order_params(pegged_order.cap_price());
+            if on_book {
+                self.orders_mut(side)
+                    .remove_by_key(&order_id)
+                    .ok_or(assertion_error!())?;
+            }
+            if self.orders_mut(side).insert_leaf(&new_order).is_err() {
+                // only waiting orders can run out of space; they wait for the book
+                // to make room
+                continue;
+            }
+
+            let new_key = pegged_order_key(&new_order_id, side, peg_offset);
+            let mut new_pegged_order = LeafNode::new(
+                pegged_order.owner_slot(),
+                &new_key,
+                pegged_order.owner(),
+                0,
+                pegged_order.fee_tier(),
+                pegged_order.client_order_id(),
+            );
+            new_pegged_order.set_pegged_order_params(pegged_order.cap_price());
+            new_pegged_order.set_peg_price(price);
+            let pegged_orders = self
+                .pegged_orders
+                .as_deref_mut()
+                .ok_or(assertion_error!())?;
+            pegged_orders
+                .remove_by_key(&key)
+                .ok_or(assertion_error!())?;
+            pegged_orders
+                .insert_leaf(&new_pegged_order)
+                .map_err(|_| assertion_error!())?;
+
+            event_q
+                .push_back(Event::new(EventView::Requeue {
+                    side,
+                    order_id: &requeued_order_id,
+                    new_order_id,
+                    owner: pegged_order.owner(),
+                    owner_slot: pegged_order.owner_slot(),
+                    client_order_id: NonZeroU64::new(pegged_order.client_order_id()),
+                }))
+                .map_err(|_| DexErrorCode::EventQueueFull)?;
+            *limit -= 1;
+        }
+        self.market_state.pegged_orders_cursor = [0; 2];
+        if resumed {
+            // the orders moved in the last call may have pegged to older prices, so
+            // they're all looked at again
+            *last_pegged_to = None;
+            return Ok(());
+        }
+        // the pegged orders moved themselves, which doesn't move the peg prices
+        let book_top = self.book_top();
+        if let Some(last_pegged_to) = last_pegged_to.as_mut() {
+            last_pegged_to.book_top = book_top;
+        }
+        Ok(())
+    }
+
     fn process_orderbook_request(
         &mut self,
         request: &Request,
+        req_q: &mut RequestQueue,
         event_q: &mut EventQueue,
         limit: &mut u16,
     ) -> DexResult<Option<Request>> {
@@ -116,6 +588,9 @@ impl<'ob> OrderBookState<'ob> {
                 native_pc_qty_locked,
                 client_order_id,
                 self_trade_behavior,
+                expiry,
+                replaces,
+                display_qty,
             } => self
                 .new_order(
                     NewOrderParams {
@@ -129,7 +604,11 @@ impl<'ob> OrderBookState<'ob> {
                         native_pc_qty_locked,
                         client_order_id: client_order_id.map_or(0, NonZeroU64::get),
                         self_trade_behavior,
+                        expiry,
+                        replaces,
+                        display_qty,
                     },
+                    req_q,
                     event_q,
                     limit,
                 )?
@@ -145,6 +624,9 @@ impl<'ob> OrderBookState<'ob> {
                         native_pc_qty_locked: remaining.native_pc_qty_remaining,
                         client_order_id,
                         self_trade_behavior,
+                        expiry,
+                        replaces: None,
+                        display_qty,
                     })
                 }),
             RequestView::CancelOrder {
@@ -162,6 +644,7 @@ impl<'ob> OrderBookState<'ob> {
                     expected_owner,
                     expected_owner_slot,
                     client_order_id,
+                    req_q,
                     event_q,
                 )?;
                 None
@@ -175,12 +658,15 @@ struct NewOrderParams<'a> {
     order_type: OrderType,
     order_id: &'a u128,
     owner: &'a [u64; 4],
-    owner_slot: u8,
+    owner_slot: u16,
     fee_tier: FeeTier,
     max_coin_qty: NonZeroU64,
     native_pc_qty_locked: Option<NonZeroU64>,
     client_order_id: u64,
     self_trade_behavior: SelfTradeBehavior,
+    expiry: Option<OrderExpiry>,
+    replaces: Option<(&'a u128, u16)>,
+    display_qty: Option<NonZeroU64>,
 }
 
 struct OrderRemaining {
@@ -194,6 +680,7 @@ impl<'ob> OrderBookState<'ob> {
 
         params: NewOrderParams,
 
+        req_q: &mut RequestQueue,
         event_q: &mut EventQueue,
         limit: &mut u16,
     ) -> DexResult<Option<OrderRemaining>> {
@@ -208,15 +695,96 @@ impl<'ob> OrderBookState<'ob> {
             mut native_pc_qty_locked,
             client_order_id,
             self_trade_behavior,
+            expiry,
+            replaces,
+            display_qty,
         } = params;
+        // A replacement only goes on the book if the order it replaces was still there.
+        // The old order is cancelled first so it can't count towards a fill-or-kill check.
+        let replaced = match replaces {
+            Some((replaced_order_id, replaced_owner_slot)) => self.cancel_order(
+                side,
+                replaced_order_id,
+                owner,
+                replaced_owner_slot,
+                None,
+                req_q,
+                event_q,
+            )?,
+            None => true,
+        };
         let (post_only, post_allowed) = match order_type {
             OrderType::Limit => (false, true),
             OrderType::ImmediateOrCancel => (false, false),
             OrderType::PostOnly => (true, true),
+            OrderType::FillOrKill => (false, false),
         };
+        // orders queued before the market went post-only mustn't cross either
+        let post_only = post_only || self.market_state.is_post_only();
         let limit_price = extract_price_from_order_id(order_id);
-        while *limit > 0 {
+        let expired = order_expired(expiry, self.clock);
+        // fixed for the whole order, so its own fills don't drag the band along
+        let price_band = self.price_band_limit(side);
+        let unfillable = order_type == OrderType::FillOrKill && {
+            let (max_pc_qty, limit_price) = match side {
+                Side::Bid => (
+                    Some(
+                        self.market_state
+                            .fee_schedule
+                            .remove_taker_fee(fee_tier, native_pc_qty_locked.unwrap().get())
+                            / self.market_state.pc_lot_size,
+                    ),
+                    price_band.map_or(limit_price, |band| band.min(limit_price)),
+                ),
+                Side::Ask => (
+                    None,
+                    price_band.map_or(limit_price, |band| band.max(limit_price)),
+                ),
+            };
+            let steps = self.fill_or_kill_steps(
+                side,
+                max_coin_qty.get(),
+                max_pc_qty,
+                limit_price,
+                owner,
+                self_trade_behavior,
+            );
+            // Only missing liquidity kills the order. Every step pushes at most three
+            // events, and the last one the taker's out, so one that can't finish within
+            // this instruction waits for a crank with a higher limit or an emptier queue.
+            match steps {
+                None => true,
+                Some(steps) if steps > *limit as u64 => Err(DexErrorCode::FillOrKillOverLimit)?,
+                Some(steps) if steps * 3 + 1 > event_q.free_slots() => {
+                    Err(DexErrorCode::EventQueueFull)?
+                }
+                Some(_) => false,
+            }
+        };
+        if !replaced || expired || unfillable || self.is_cancel_only() {
             *limit -= 1;
+            let native_qty_unlocked = match side {
+                Side::Bid => native_pc_qty_locked.unwrap().get(),
+                Side::Ask => max_coin_qty.get() * self.market_state.coin_lot_size,
+            };
+            event_q
+                .push_back(Event::new(EventView::Out {
+                    side,
+                    native_qty_unlocked,
+                    native_qty_still_locked: 0,
+                    native_coin_qty_cancelled: max_coin_qty
+                        .get()
+                        .saturating_mul(self.market_state.coin_lot_size),
+                    order_id,
+                    owner,
+                    owner_slot,
+                    client_order_id: NonZeroU64::new(client_order_id),
+                }))
+                .map_err(|_| DexErrorCode::EventQueueFull)?;
+            return Ok(None);
+        }
+        loop {
+            *limit = limit.saturating_sub(1);
             let remaining_order = match side {
                 Side::Bid => self.new_bid(
                     NewBidParams {
@@ -231,7 +799,11 @@ impl<'ob> OrderBookState<'ob> {
                         post_allowed,
                         client_order_id,
                         self_trade_behavior,
+                        expiry,
+                        display_qty,
+                        price_band,
                     },
+                    req_q,
                     event_q,
                 ),
                 Side::Ask => {
@@ -248,7 +820,11 @@ impl<'ob> OrderBookState<'ob> {
                             post_allowed,
                             client_order_id,
                             self_trade_behavior,
+                            expiry,
+                            display_qty,
+                            price_band,
                         },
+                        req_q,
                         event_q,
                     )
                 }
@@ -268,23 +844,140 @@ impl<'ob> OrderBookState<'ob> {
     }
 }
 
+// The fill-or-kill check walks the opposite side of the book the same way new_bid and
+// new_ask would, counting the matching steps the order needs. Each step takes one resting
+// order, or one displayed part of an iceberg. Resting orders from the same owner only
+// count as liquidity under DecrementTake, since CancelProvide cancels them instead of
+// trading against them, and CancelTake and CancelBoth stop the incoming order as soon as
+// it reaches one. A replenished iceberg goes to the back of its price level, so on a
+// level holding one the count assumes every order there may be reached.
+impl<'ob> OrderBookState<'ob> {
+    fn fill_or_kill_steps(
+        &self,
+        side: Side,
+        max_coin_qty: u64,
+        max_pc_qty: Option<u64>,
+        limit_price: u64,
+        owner: &[u64; 4],
+        self_trade_behavior: SelfTradeBehavior,
+    ) -> Option<u64> {
+        let (offers, descending) = match side {
+            Side::Bid => (&self.asks, false),
+            Side::Ask => (&self.bids, true),
+        };
+        let mut coin_qty_remaining = max_coin_qty;
+        let mut pc_qty_remaining = max_pc_qty;
+        let mut steps = 0u64;
+
+        // the price level being walked and the quantity still wanted on reaching it
+        let mut level_price = None;
+        let mut level_qty = 0;
+        let mut level_steps = 0u64;
+        let mut level_max_steps = 0u64;
+        let mut level_has_iceberg = false;
+        let mut level_blocked = false;
+        for offer in offers.leaves(descending) {
+            let price = offer.price().get();
+            let crossed = match side {
+                Side::Bid => price <= limit_price,
+                Side::Ask => price >= limit_price,
+            };
+            if !crossed {
+                break;
+            }
+            if level_price != Some(price) {
+                if coin_qty_remaining == 0 {
+                    break;
+                }
+                steps += if level_has_iceberg {
+                    level_max_steps
+                } else {
+                    level_steps
+                };
+                level_price = Some(price);
+                level_qty = coin_qty_remaining;
+                level_steps = 0;
+                level_max_steps = 0;
+                level_has_iceberg = false;
+            }
+
+            let expired = order_expired(offer.expiry(), self.clock);
+            let own = offer.owner() == owner;
+            if own && self_trade_behavior.cancels_take() && !expired {
+                if coin_qty_remaining > 0 {
+                    return None;
+                }
+                level_blocked = true;
+                continue;
+            }
+            let trades =
+                !expired && (!own || self_trade_behavior == SelfTradeBehavior::DecrementTake);
+            let offer_steps = |trade_qty: u64| {
+                if !trades || trade_qty <= offer.quantity() {
+                    1
+                } else {
+                    1 + (trade_qty - offer.quantity() + offer.display_qty() - 1)
+                        / offer.display_qty()
+                }
+            };
+            level_has_iceberg |= offer.hidden_qty() > 0;
+            level_max_steps += offer_steps(offer.total_quantity().min(level_qty));
+            if coin_qty_remaining == 0 {
+                continue;
+            }
+            if !trades {
+                level_steps += 1;
+                continue;
+            }
+            let trade_qty = offer
+                .total_quantity()
+                .min(coin_qty_remaining)
+                .min(pc_qty_remaining.map_or(u64::MAX, |pc_qty| pc_qty / price));
+            if trade_qty == 0 {
+                return None;
+            }
+            level_steps += offer_steps(trade_qty);
+            coin_qty_remaining -= trade_qty;
+            if let Some(pc_qty_remaining) = pc_qty_remaining.as_mut() {
+                *pc_qty_remaining -= trade_qty * price;
+            }
+        }
+        if coin_qty_remaining > 0 || (level_has_iceberg && level_blocked) {
+            return None;
+        }
+        Some(
+            steps
+                + if level_has_iceberg {
+                    level_max_steps
+                } else {
+                    level_steps
+                },
+        )
+    }
+}
+
 struct NewAskParams<'a> {
     max_qty: NonZeroU64,
     limit_price: NonZeroU64,
     order_id: &'a u128,
     owner: &'a [u64; 4],
-    owner_slot: u8,
+    owner_slot: u16,
     fee_tier: FeeTier,
     post_only: bool,
     post_allowed: bool,
     client_order_id: u64,
     self_trade_behavior: SelfTradeBehavior,
+    expiry: Option<OrderExpiry>,
+    display_qty: Option<NonZeroU64>,
+    // the lowest price the order may fill at
+    price_band: Option<u64>,
 }
 
 impl<'ob> OrderBookState<'ob> {
     fn new_ask(
         &mut self,
         params: NewAskParams,
+        req_q: &mut RequestQueue,
         event_q: &mut EventQueue,
     ) -> DexResult<Option<OrderRemaining>> {
         let NewAskParams {
@@ -298,16 +991,26 @@ impl<'ob> OrderBookState<'ob> {
             post_allowed,
             client_order_id,
             self_trade_behavior,
+            expiry,
+            display_qty,
+            price_band,
         } = params;
         let mut unfilled_qty = max_qty.get();
         let mut accum_fill_price = 0;
 
         let pc_lot_size = self.market_state.pc_lot_size;
+        let fee_schedule = self.market_state.fee_schedule;
         let coin_lot_size = self.market_state.coin_lot_size;
+        let clock = self.clock;
 
         let mut accum_maker_rebates = 0;
         let crossed;
         let done = loop {
+            // during the opening auction orders go onto the book whether they cross or not
+            if self.market_state.is_auction() {
+                crossed = false;
+                break true;
+            }
             let best_bid_h = match self.find_bbo(Side::Bid) {
                 None => {
                     crossed = false;
@@ -326,10 +1029,22 @@ impl<'ob> OrderBookState<'ob> {
             let trade_price = best_bid_ref.price();
             crossed = limit_price <= trade_price;
 
+            if order_expired(best_bid_ref.expiry(), clock) {
+                let best_bid_id = *best_bid_ref.order_id();
+                self.evict_expired_order(Side::Bid, &best_bid_id, event_q)?;
+                break false;
+            }
+
             if !crossed || post_only {
                 break true;
             }
 
+            // still crossed, so the rest of the order is cancelled rather than posted
+            if price_band.map_or(false, |band| trade_price.get() < band) {
+                self.hit_price_band();
+                break true;
+            }
+
             let bid_size = best_bid_ref.quantity();
             let trade_qty = bid_size.min(unfilled_qty);
 
@@ -349,32 +1064,47 @@ impl<'ob> OrderBookState<'ob> {
                         cancelled_take_qty = trade_qty;
                     }
                     SelfTradeBehavior::CancelProvide => {
-                        cancelled_provide_qty = best_bid_ref.quantity();
+                        cancelled_provide_qty = best_bid_ref.total_quantity();
                         cancelled_take_qty = 0;
                     }
+                    SelfTradeBehavior::CancelTake => {
+                        cancelled_provide_qty = 0;
+                        cancelled_take_qty = unfilled_qty;
+                    }
+                    SelfTradeBehavior::CancelBoth => {
+                        cancelled_provide_qty = best_bid_ref.total_quantity();
+                        cancelled_take_qty = unfilled_qty;
+                    }
                 };
 
-                let remaining_provide_size = bid_size - cancelled_provide_qty;
-                let provide_out = Event::new(EventView::Out {
-                    side: Side::Bid,
-                    native_qty_unlocked: cancelled_provide_qty * trade_price.get() * pc_lot_size,
-                    native_qty_still_locked: remaining_provide_size
-                        * trade_price.get()
-                        * pc_lot_size,
-                    order_id: &best_bid_id,
-                    owner: best_bid_ref.owner(),
-                    owner_slot: best_bid_ref.owner_slot(),
-                    client_order_id: NonZeroU64::new(best_bid_ref.client_order_id()),
-                });
-                event_q
-                    .push_back(provide_out)
-                    .map_err(|_| DexErrorCode::EventQueueFull)?;
-                if remaining_provide_size == 0 {
-                    self.orders_mut(Side::Bid)
-                        .remove_by_key(&best_bid_id)
-                        .unwrap();
-                } else {
-                    *best_bid_ref.quantity_mut() = remaining_provide_size;
+                if cancelled_provide_qty > 0 {
+                    let remaining_provide_size =
+                        best_bid_ref.total_quantity() - cancelled_provide_qty;
+                    let bid_lock_price = best_bid_ref.lock_price().get();
+                    let provide_out = Event::new(EventView::Out {
+                        side: Side::Bid,
+                        native_qty_unlocked: cancelled_provide_qty * bid_lock_price * pc_lot_size,
+                        native_qty_still_locked: remaining_provide_size
+                            * bid_lock_price
+                            * pc_lot_size,
+                        native_coin_qty_cancelled: cancelled_provide_qty * coin_lot_size,
+                        order_id: &best_bid_id,
+                        owner: best_bid_ref.owner(),
+                        owner_slot: best_bid_ref.owner_slot(),
+                        client_order_id: NonZeroU64::new(best_bid_ref.client_order_id()),
+                    });
+                    event_q
+                        .push_back(provide_out)
+                        .map_err(|_| DexErrorCode::EventQueueFull)?;
+                    if remaining_provide_size == 0 {
+                        self.orders_mut(Side::Bid)
+                            .remove_by_key(&best_bid_id)
+                            .unwrap();
+                    } else if cancelled_provide_qty < bid_size {
+                        *best_bid_ref.quantity_mut() = bid_size - cancelled_provide_qty;
+                    } else {
+                        self.replenish_iceberg_order(Side::Bid, &best_bid_id, req_q, event_q)?;
+                    }
                 }
 
                 unfilled_qty -= cancelled_take_qty;
@@ -382,6 +1112,7 @@ impl<'ob> OrderBookState<'ob> {
                     side: Side::Ask,
                     native_qty_unlocked: cancelled_take_qty * coin_lot_size,
                     native_qty_still_locked: unfilled_qty,
+                    native_coin_qty_cancelled: cancelled_take_qty * coin_lot_size,
                     order_id,
                     owner,
                     owner_slot,
@@ -401,7 +1132,8 @@ impl<'ob> OrderBookState<'ob> {
 
             let maker_fee_tier = best_bid_ref.fee_tier();
             let native_maker_pc_qty = trade_qty * trade_price.get() * pc_lot_size;
-            let native_maker_rebate = maker_fee_tier.maker_rebate(native_maker_pc_qty);
+            let native_maker_rebate =
+                fee_schedule.maker_rebate(maker_fee_tier, native_maker_pc_qty);
             accum_maker_rebates += native_maker_rebate;
 
             let maker_fill = Event::new(EventView::Fill {
@@ -424,29 +1156,43 @@ impl<'ob> OrderBookState<'ob> {
             unfilled_qty -= trade_qty;
             accum_fill_price += trade_qty * trade_price.get();
 
-            if best_bid_ref.quantity() == 0 {
+            // pegged bids lock pc at their cap, so filling below it frees the difference
+            let bid_lock_price = best_bid_ref.lock_price().get();
+            let native_maker_pc_unlocked =
+                trade_qty * (bid_lock_price - trade_price.get()) * pc_lot_size;
+
+            if best_bid_ref.quantity() == 0 && best_bid_ref.hidden_qty() > 0 {
+                let best_bid_id = *best_bid_ref.order_id();
+                self.replenish_iceberg_order(Side::Bid, &best_bid_id, req_q, event_q)?;
+            } else if best_bid_ref.quantity() == 0 || native_maker_pc_unlocked > 0 {
                 let best_bid_id = *best_bid_ref.order_id();
                 event_q
                     .push_back(Event::new(EventView::Out {
                         side: Side::Bid,
-                        native_qty_unlocked: 0,
-                        native_qty_still_locked: 0,
+                        native_qty_unlocked: native_maker_pc_unlocked,
+                        native_qty_still_locked: best_bid_ref.quantity()
+                            * bid_lock_price
+                            * pc_lot_size,
+                        native_coin_qty_cancelled: 0,
                         order_id: &best_bid_id,
                         owner: best_bid_ref.owner(),
                         owner_slot: best_bid_ref.owner_slot(),
                         client_order_id: NonZeroU64::new(best_bid_ref.client_order_id()),
                     }))
                     .map_err(|_| DexErrorCode::EventQueueFull)?;
-                self.orders_mut(Side::Bid)
-                    .remove_by_key(&best_bid_id)
-                    .unwrap();
+                if best_bid_ref.quantity() == 0 {
+                    self.orders_mut(Side::Bid)
+                        .remove_by_key(&best_bid_id)
+                        .unwrap();
+                }
             }
+            self.record_fill(trade_price.get(), trade_qty);
 
             break false;
         };
 
         let native_taker_pc_qty = accum_fill_price * pc_lot_size;
-        let native_taker_fee = fee_tier.taker_fee(native_taker_pc_qty);
+        let native_taker_fee = fee_schedule.taker_fee(fee_tier, native_taker_pc_qty);
         if native_taker_pc_qty > 0 {
             let taker_fill = Event::new(EventView::Fill {
                 side: Side::Ask,
@@ -484,7 +1230,7 @@ impl<'ob> OrderBookState<'ob> {
 
         if post_allowed && !crossed && unfilled_qty > 0 {
             let offers = self.orders_mut(Side::Ask);
-            let new_order = LeafNode::new(
+            let mut new_order = LeafNode::new(
                 owner_slot,
                 order_id,
                 owner,
@@ -492,6 +1238,12 @@ impl<'ob> OrderBookState<'ob> {
                 fee_tier,
                 client_order_id,
             );
+            new_order.set_expiry(expiry);
+            new_order.set_self_trade_behavior(self_trade_behavior);
+            if let Some(display_qty) = display_qty.filter(|d| d.get() < unfilled_qty) {
+                *new_order.quantity_mut() = display_qty.get();
+                new_order.set_iceberg_params(display_qty.get(), unfilled_qty - display_qty.get());
+            }
             let insert_result = offers.insert_leaf(&new_order);
             if let Err(SlabTreeError::OutOfSpace) = insert_result {
                 // boot out the least aggressive offer
@@ -499,8 +1251,9 @@ impl<'ob> OrderBookState<'ob> {
                 let order = offers.remove_max().unwrap();
                 let out = Event::new(EventView::Out {
                     side: Side::Ask,
-                    native_qty_unlocked: order.quantity() * coin_lot_size,
+                    native_qty_unlocked: order.total_quantity() * coin_lot_size,
                     native_qty_still_locked: 0,
+                    native_coin_qty_cancelled: order.total_quantity() * coin_lot_size,
                     order_id: order.order_id(),
                     owner: order.owner(),
                     owner_slot: order.owner_slot(),
@@ -518,6 +1271,7 @@ impl<'ob> OrderBookState<'ob> {
                 side: Side::Ask,
                 native_qty_unlocked: unfilled_qty * coin_lot_size,
                 native_qty_still_locked: 0,
+                native_coin_qty_cancelled: unfilled_qty * coin_lot_size,
                 order_id,
                 owner,
                 owner_slot,
@@ -538,18 +1292,23 @@ struct NewBidParams<'a> {
     limit_price: Option<NonZeroU64>,
     order_id: &'a u128,
     owner: &'a [u64; 4],
-    owner_slot: u8,
+    owner_slot: u16,
     fee_tier: FeeTier,
     post_only: bool,
     post_allowed: bool,
     client_order_id: u64,
     self_trade_behavior: SelfTradeBehavior,
+    expiry: Option<OrderExpiry>,
+    display_qty: Option<NonZeroU64>,
+    // the highest price the order may fill at
+    price_band: Option<u64>,
 }
 
 impl<'ob> OrderBookState<'ob> {
     fn new_bid(
         &mut self,
         params: NewBidParams,
+        req_q: &mut RequestQueue,
         event_q: &mut EventQueue,
     ) -> DexResult<Option<OrderRemaining>> {
         let NewBidParams {
@@ -564,22 +1323,33 @@ impl<'ob> OrderBookState<'ob> {
             post_allowed,
             client_order_id,
             self_trade_behavior,
+            expiry,
+            display_qty,
+            price_band,
         } = params;
         if post_allowed {
             check_assert!(limit_price.is_some())?;
         }
 
         let pc_lot_size = self.market_state.pc_lot_size;
+        let fee_schedule = self.market_state.fee_schedule;
         let coin_lot_size = self.market_state.coin_lot_size;
 
-        let max_pc_qty = fee_tier.remove_taker_fee(native_pc_qty_locked.get()) / pc_lot_size;
+        let max_pc_qty =
+            fee_schedule.remove_taker_fee(fee_tier, native_pc_qty_locked.get()) / pc_lot_size;
 
         let mut coin_qty_remaining = max_coin_qty.get();
         let mut pc_qty_remaining = max_pc_qty;
         let mut accum_maker_rebates = 0;
+        let clock = self.clock;
 
         let crossed;
         let done = loop {
+            // during the opening auction orders go onto the book whether they cross or not
+            if self.market_state.is_auction() {
+                crossed = false;
+                break true;
+            }
             let best_offer_h = match self.find_bbo(Side::Ask) {
                 None => {
                     crossed = false;
@@ -596,13 +1366,27 @@ impl<'ob> OrderBookState<'ob> {
                 .unwrap();
 
             let trade_price = best_offer_ref.price();
+            // market buys carry no limit price and are bounded only by their pc budget
             crossed = limit_price
                 .map(|limit_price| limit_price >= trade_price)
                 .unwrap_or(true);
+
+            if order_expired(best_offer_ref.expiry(), clock) {
+                let best_offer_id = *best_offer_ref.order_id();
+                self.evict_expired_order(Side::Ask, &best_offer_id, event_q)?;
+                break false;
+            }
+
             if !crossed || post_only {
                 break true;
             }
 
+            // still crossed, so the rest of the order is cancelled rather than posted
+            if price_band.map_or(false, |band| trade_price.get() > band) {
+                self.hit_price_band();
+                break true;
+            }
+
             let offer_size = best_offer_ref.quantity();
             let trade_qty = offer_size
                 .min(coin_qty_remaining)
@@ -622,67 +1406,89 @@ impl<'ob> OrderBookState<'ob> {
                 match self_trade_behavior {
                     SelfTradeBehavior::CancelProvide => {
                         cancelled_take_qty = 0;
-                        cancelled_provide_qty = best_offer_ref.quantity();
+                        cancelled_provide_qty = best_offer_ref.total_quantity();
                     }
                     SelfTradeBehavior::DecrementTake => {
                         cancelled_take_qty = trade_qty;
                         cancelled_provide_qty = trade_qty;
                     }
+                    SelfTradeBehavior::CancelTake => {
+                        cancelled_take_qty = coin_qty_remaining;
+                        cancelled_provide_qty = 0;
+                    }
+                    SelfTradeBehavior::CancelBoth => {
+                        cancelled_take_qty = coin_qty_remaining;
+                        cancelled_provide_qty = best_offer_ref.total_quantity();
+                    }
                 };
 
-                let remaining_provide_qty = best_offer_ref.quantity() - cancelled_provide_qty;
-                let provide_out = Event::new(EventView::Out {
-                    side: Side::Ask,
-                    native_qty_unlocked: cancelled_provide_qty * coin_lot_size,
-                    native_qty_still_locked: remaining_provide_qty * coin_lot_size,
-                    order_id: &best_offer_id,
-                    owner: best_offer_ref.owner(),
-                    owner_slot: best_offer_ref.owner_slot(),
-                    client_order_id: NonZeroU64::new(best_offer_ref.client_order_id()),
-                });
-                event_q
-                    .push_back(provide_out)
-                    .map_err(|_| DexErrorCode::EventQueueFull)?;
-                if remaining_provide_qty == 0 {
-                    self.orders_mut(Side::Ask)
-                        .remove_by_key(&best_offer_id)
-                        .unwrap();
-                } else {
-                    *best_offer_ref.quantity_mut() = remaining_provide_qty;
+                if cancelled_provide_qty > 0 {
+                    let remaining_provide_qty =
+                        best_offer_ref.total_quantity() - cancelled_provide_qty;
+                    let provide_out = Event::new(EventView::Out {
+                        side: Side::Ask,
+                        native_qty_unlocked: cancelled_provide_qty * coin_lot_size,
+                        native_qty_still_locked: remaining_provide_qty * coin_lot_size,
+                        native_coin_qty_cancelled: cancelled_provide_qty * coin_lot_size,
+                        order_id: &best_offer_id,
+                        owner: best_offer_ref.owner(),
+                        owner_slot: best_offer_ref.owner_slot(),
+                        client_order_id: NonZeroU64::new(best_offer_ref.client_order_id()),
+                    });
+                    event_q
+                        .push_back(provide_out)
+                        .map_err(|_| DexErrorCode::EventQueueFull)?;
+                    if remaining_provide_qty == 0 {
+                        self.orders_mut(Side::Ask)
+                            .remove_by_key(&best_offer_id)
+                            .unwrap();
+                    } else if cancelled_provide_qty < offer_size {
+                        *best_offer_ref.quantity_mut() = offer_size - cancelled_provide_qty;
+                    } else {
+                        self.replenish_iceberg_order(Side::Ask, &best_offer_id, req_q, event_q)?;
+                    }
                 }
 
-                let native_taker_pc_unlocked = cancelled_take_qty * trade_price.get() * pc_lot_size;
-                let native_taker_pc_still_locked =
-                    native_pc_qty_locked.get() - native_taker_pc_unlocked;
-
-                let order_remaining = (|| {
-                    Some(OrderRemaining {
-                        coin_qty_remaining: NonZeroU64::new(
-                            coin_qty_remaining - cancelled_take_qty,
-                        )?,
-                        native_pc_qty_remaining: Some(NonZeroU64::new(
-                            native_taker_pc_still_locked,
-                        )?),
-                    })
-                })();
+                // cancelling the whole take releases all of its locked funds, so there is
+                // no need to price the cancelled quantity (which may be unbounded for
+                // market orders)
+                let order_remaining = if cancelled_take_qty == coin_qty_remaining {
+                    None
+                } else {
+                    let native_taker_pc_unlocked =
+                        cancelled_take_qty * trade_price.get() * pc_lot_size;
+                    let native_taker_pc_still_locked =
+                        native_pc_qty_locked.get() - native_taker_pc_unlocked;
+                    (|| {
+                        Some(OrderRemaining {
+                            coin_qty_remaining: NonZeroU64::new(
+                                coin_qty_remaining - cancelled_take_qty,
+                            )?,
+                            native_pc_qty_remaining: Some(NonZeroU64::new(
+                                native_taker_pc_still_locked,
+                            )?),
+                        })
+                    })()
+                };
 
                 let take_out = {
-                    let native_qty_unlocked;
-                    let native_qty_still_locked;
-                    match order_remaining {
-                        Some(_) => {
-                            native_qty_unlocked = native_taker_pc_unlocked;
-                            native_qty_still_locked = native_taker_pc_still_locked;
-                        }
-                        None => {
-                            native_qty_unlocked = native_pc_qty_locked.get();
-                            native_qty_still_locked = 0;
-                        }
-                    };
+                    let native_qty_still_locked = order_remaining
+                        .as_ref()
+                        .and_then(|remaining| remaining.native_pc_qty_remaining)
+                        .map_or(0, NonZeroU64::get);
+                    let native_qty_unlocked = native_pc_qty_locked.get() - native_qty_still_locked;
+                    let coin_qty_cancelled = coin_qty_remaining
+                        - order_remaining
+                            .as_ref()
+                            .map_or(0, |remaining| remaining.coin_qty_remaining.get());
+                    // market buys go by their pc budget, so their coin qty may be unbounded
+                    let native_coin_qty_cancelled =
+                        coin_qty_cancelled.saturating_mul(coin_lot_size);
                     Event::new(EventView::Out {
                         side: Side::Bid,
                         native_qty_unlocked,
                         native_qty_still_locked,
+                        native_coin_qty_cancelled,
                         order_id,
                         owner,
                         owner_slot,
@@ -697,7 +1503,8 @@ impl<'ob> OrderBookState<'ob> {
             }
             let maker_fee_tier = best_offer_ref.fee_tier();
             let native_maker_pc_qty = trade_qty * trade_price.get() * pc_lot_size;
-            let native_maker_rebate = maker_fee_tier.maker_rebate(native_maker_pc_qty);
+            let native_maker_rebate =
+                fee_schedule.maker_rebate(maker_fee_tier, native_maker_pc_qty);
             accum_maker_rebates += native_maker_rebate;
 
             let maker_fill = Event::new(EventView::Fill {
@@ -720,13 +1527,17 @@ impl<'ob> OrderBookState<'ob> {
             coin_qty_remaining -= trade_qty;
             pc_qty_remaining -= trade_qty * trade_price.get();
 
-            if best_offer_ref.quantity() == 0 {
+            if best_offer_ref.quantity() == 0 && best_offer_ref.hidden_qty() > 0 {
+                let best_offer_id = *best_offer_ref.order_id();
+                self.replenish_iceberg_order(Side::Ask, &best_offer_id, req_q, event_q)?;
+            } else if best_offer_ref.quantity() == 0 {
                 let best_offer_id = *best_offer_ref.order_id();
                 event_q
                     .push_back(Event::new(EventView::Out {
                         side: Side::Ask,
                         native_qty_unlocked: 0,
                         native_qty_still_locked: 0,
+                        native_coin_qty_cancelled: 0,
                         order_id: &best_offer_id,
                         owner: best_offer_ref.owner(),
                         owner_slot: best_offer_ref.owner_slot(),
@@ -737,12 +1548,13 @@ impl<'ob> OrderBookState<'ob> {
                     .remove_by_key(&best_offer_id)
                     .unwrap();
             }
+            self.record_fill(trade_price.get(), trade_qty);
 
             break false;
         };
 
         let native_accum_fill_price = (max_pc_qty - pc_qty_remaining) * pc_lot_size;
-        let native_taker_fee = fee_tier.taker_fee(native_accum_fill_price);
+        let native_taker_fee = fee_schedule.taker_fee(fee_tier, native_accum_fill_price);
         let native_pc_qty_remaining =
             native_pc_qty_locked.get() - native_accum_fill_price - native_taker_fee;
 
@@ -795,10 +1607,13 @@ impl<'ob> OrderBookState<'ob> {
         let out = {
             let native_qty_still_locked = pc_qty_to_keep_locked * pc_lot_size;
             let native_qty_unlocked = native_pc_qty_remaining - native_qty_still_locked;
+            let native_coin_qty_cancelled =
+                (coin_qty_remaining - coin_qty_to_post).saturating_mul(coin_lot_size);
             Event::new(EventView::Out {
                 side: Side::Bid,
                 native_qty_unlocked,
                 native_qty_still_locked,
+                native_coin_qty_cancelled,
                 order_id,
                 owner,
                 owner_slot,
@@ -811,7 +1626,7 @@ impl<'ob> OrderBookState<'ob> {
 
         if pc_qty_to_keep_locked > 0 {
             let bids = self.orders_mut(Side::Bid);
-            let new_leaf = LeafNode::new(
+            let mut new_leaf = LeafNode::new(
                 owner_slot,
                 order_id,
                 owner,
@@ -819,6 +1634,13 @@ impl<'ob> OrderBookState<'ob> {
                 fee_tier,
                 client_order_id,
             );
+            new_leaf.set_expiry(expiry);
+            new_leaf.set_self_trade_behavior(self_trade_behavior);
+            if let Some(display_qty) = display_qty.filter(|d| d.get() < coin_qty_to_post) {
+                *new_leaf.quantity_mut() = display_qty.get();
+                new_leaf
+                    .set_iceberg_params(display_qty.get(), coin_qty_to_post - display_qty.get());
+            }
             let insert_result = bids.insert_leaf(&new_leaf);
             if let Err(SlabTreeError::OutOfSpace) = insert_result {
                 // boot out the least aggressive bid
@@ -826,8 +1648,11 @@ impl<'ob> OrderBookState<'ob> {
                 let order = bids.remove_min().unwrap();
                 let out = Event::new(EventView::Out {
                     side: Side::Bid,
-                    native_qty_unlocked: order.quantity() * order.price().get() * pc_lot_size,
+                    native_qty_unlocked: order.total_quantity()
+                        * order.lock_price().get()
+                        * pc_lot_size,
                     native_qty_still_locked: 0,
+                    native_coin_qty_cancelled: order.total_quantity() * coin_lot_size,
                     order_id: order.order_id(),
                     owner: order.owner(),
                     owner_slot: order.owner_slot(),
@@ -850,12 +1675,14 @@ impl<'ob> OrderBookState<'ob> {
         side: Side,
         order_id: &u128,
         expected_owner: &[u64; 4],
-        expected_owner_slot: u8,
+        expected_owner_slot: u16,
         client_order_id: Option<NonZeroU64>,
 
+        req_q: &mut RequestQueue,
         event_q: &mut EventQueue,
-    ) -> DexResult<()> {
-        if let Some(leaf_node) = self.orders_mut(side).remove_by_key(order_id) {
+    ) -> DexResult<bool> {
+        let leaf_node = self.orders_mut(side).remove_by_key(order_id);
+        if let Some(leaf_node) = leaf_node {
             if leaf_node.owner() == expected_owner && leaf_node.owner_slot() == expected_owner_slot
             {
                 if let Some(client_id) = client_order_id {
@@ -863,27 +1690,714 @@ impl<'ob> OrderBookState<'ob> {
                 }
                 let native_qty_unlocked = match side {
                     Side::Bid => {
-                        leaf_node.quantity()
-                            * leaf_node.price().get()
+                        leaf_node.total_quantity()
+                            * leaf_node.lock_price().get()
                             * self.market_state.pc_lot_size
                     }
-                    Side::Ask => leaf_node.quantity() * self.market_state.coin_lot_size,
+                    Side::Ask => leaf_node.total_quantity() * self.market_state.coin_lot_size,
                 };
                 event_q
                     .push_back(Event::new(EventView::Out {
                         side,
                         native_qty_unlocked,
                         native_qty_still_locked: 0,
-                        order_id,
+                        native_coin_qty_cancelled: leaf_node.total_quantity()
+                            * self.market_state.coin_lot_size,
+                        order_id: leaf_node.order_id(),
                         owner: expected_owner,
                         owner_slot: expected_owner_slot,
                         client_order_id: NonZeroU64::new(leaf_node.client_order_id()),
                     }))
                     .map_err(|_| DexErrorCode::EventQueueFull)?;
+                Ok(true)
             } else {
                 self.orders_mut(side).insert_leaf(&leaf_node).unwrap();
+                Ok(false)
+            }
+        } else if self.cancel_stop_order(
+            side,
+            order_id,
+            expected_owner,
+            expected_owner_slot,
+            event_q,
+        )? || self.cancel_pegged_order(
+            side,
+            order_id,
+            expected_owner,
+            expected_owner_slot,
+            event_q,
+        )? {
+            Ok(true)
+        } else {
+            match self.find_requeued_order_id(side, expected_owner, expected_owner_slot) {
+                Some(new_order_id) if new_order_id != *order_id => self.cancel_order(
+                    side,
+                    &new_order_id,
+                    expected_owner,
+                    expected_owner_slot,
+                    client_order_id,
+                    req_q,
+                    event_q,
+                ),
+                _ => self.cancel_triggered_stop_order(
+                    side,
+                    expected_owner,
+                    expected_owner_slot,
+                    req_q,
+                    event_q,
+                ),
+            }
+        }
+    }
+
+    // Iceberg, pegged and triggered stop orders get a new order id each time they're
+    // requeued, which only reaches the OpenOrders account once the Requeue event is
+    // consumed. Until then they're cancelled under an older id. An order keeps its owner
+    // slot through every requeue, and cancels only name a slot that holds the order
+    // they cancel, so whatever order is still in the slot is the one to cancel.
+    fn find_requeued_order_id(
+        &self,
+        side: Side,
+        owner: &[u64; 4],
+        owner_slot: u16,
+    ) -> Option<u128> {
+        let in_slot = |leaf: &LeafNode| leaf.owner() == owner && leaf.owner_slot() == owner_slot;
+        if let Some(order) = self.orders(side).leaves(false).find(|leaf| in_slot(leaf)) {
+            return Some(*order.order_id());
+        }
+        // pegged orders still waiting for the book go by their key
+        let pegged_orders = self.pegged_orders.as_deref()?;
+        let pegged_order = pegged_orders.leaves(false).find(|leaf| {
+            pegged_order_side(leaf.order_id()) == side && leaf.quantity() > 0 && in_slot(leaf)
+        })?;
+        Some(*pegged_order.order_id())
+    }
+
+    // A triggered stop order waits in the request queue until it gets to the book, so a
+    // cancel queued before it was triggered takes it out of the queue.
+    fn cancel_triggered_stop_order(
+        &mut self,
+        side: Side,
+        expected_owner: &[u64; 4],
+        expected_owner_slot: u16,
+
+        req_q: &mut RequestQueue,
+        event_q: &mut EventQueue,
+    ) -> DexResult<bool> {
+        let is_stop_order = |request: &Request| -> DexResult<bool> {
+            Ok(match request.as_view()? {
+                RequestView::NewOrder {
+                    side: order_side,
+                    owner,
+                    owner_slot,
+                    ..
+                } => {
+                    order_side == side
+                        && owner == expected_owner
+                        && owner_slot == expected_owner_slot
+                }
+                RequestView::CancelOrder { .. } => false,
+            })
+        };
+        // the front of the queue is the request being processed
+        let mut stop_order = None;
+        for request in req_q.iter().skip(1) {
+            if is_stop_order(request)? {
+                stop_order = Some(*request);
+                break;
             }
         }
+        let stop_order = match stop_order {
+            Some(stop_order) => stop_order,
+            None => return Ok(false),
+        };
+        let (order_id, max_coin_qty, native_pc_qty_locked, client_order_id) =
+            match stop_order.as_view()? {
+                RequestView::NewOrder {
+                    order_id,
+                    max_coin_qty,
+                    native_pc_qty_locked,
+                    client_order_id,
+                    ..
+                } => (
+                    *order_id,
+                    max_coin_qty.get(),
+                    native_pc_qty_locked,
+                    client_order_id,
+                ),
+                RequestView::CancelOrder { .. } => Err(assertion_error!())?,
+            };
+        req_q.retain_pushes(1, |request| Ok(!is_stop_order(request)?))?;
+
+        let coin_lot_size = self.market_state.coin_lot_size;
+        let native_qty_unlocked = match side {
+            Side::Bid => native_pc_qty_locked.ok_or(assertion_error!())?.get(),
+            Side::Ask => max_coin_qty * coin_lot_size,
+        };
+        event_q
+            .push_back(Event::new(EventView::Out {
+                side,
+                native_qty_unlocked,
+                native_qty_still_locked: 0,
+                native_coin_qty_cancelled: max_coin_qty * coin_lot_size,
+                order_id: &order_id,
+                owner: expected_owner,
+                owner_slot: expected_owner_slot,
+                client_order_id,
+            }))
+            .map_err(|_| DexErrorCode::EventQueueFull)?;
+        Ok(true)
+    }
+
+    fn cancel_stop_order(
+        &mut self,
+        side: Side,
+        order_id: &u128,
+        expected_owner: &[u64; 4],
+        expected_owner_slot: u16,
+
+        event_q: &mut EventQueue,
+    ) -> DexResult<bool> {
+        let coin_lot_size = self.market_state.coin_lot_size;
+        let stop_orders = match self.stop_orders.as_deref_mut() {
+            Some(stop_orders) => stop_orders,
+            None => return Ok(false),
+        };
+        // stop orders go by their key until they're triggered
+        let stop_order = match stop_orders
+            .find_by_key(order_id)
+            .and_then(|h| stop_orders.get(h)?.as_leaf().copied())
+        {
+            Some(leaf) => leaf,
+            None => return Ok(false),
+        };
+        if stop_order_side(order_id) != side
+            || stop_order.owner() != expected_owner
+            || stop_order.owner_slot() != expected_owner_slot
+        {
+            return Ok(false);
+        }
+        stop_orders.remove_by_key(stop_order.order_id()).unwrap();
+
+        let native_qty_unlocked = match side {
+            Side::Bid => stop_order.native_pc_qty_locked(),
+            Side::Ask => stop_order.quantity() * coin_lot_size,
+        };
+        event_q
+            .push_back(Event::new(EventView::Out {
+                side,
+                native_qty_unlocked,
+                native_qty_still_locked: 0,
+                native_coin_qty_cancelled: stop_order.quantity() * coin_lot_size,
+                order_id,
+                owner: expected_owner,
+                owner_slot: expected_owner_slot,
+                client_order_id: NonZeroU64::new(stop_order.client_order_id()),
+            }))
+            .map_err(|_| DexErrorCode::EventQueueFull)?;
+        Ok(true)
+    }
+
+    // Pegged orders on the book are cancelled like any other order. This cancels the
+    // ones still waiting in the pegged orders slab.
+    fn cancel_pegged_order(
+        &mut self,
+        side: Side,
+        order_id: &u128,
+        expected_owner: &[u64; 4],
+        expected_owner_slot: u16,
+
+        event_q: &mut EventQueue,
+    ) -> DexResult<bool> {
+        let coin_lot_size = self.market_state.coin_lot_size;
+        let pc_lot_size = self.market_state.pc_lot_size;
+        let pegged_orders = match self.pegged_orders.as_deref_mut() {
+            Some(pegged_orders) => pegged_orders,
+            None => return Ok(false),
+        };
+        // waiting pegged orders go by their key
+        let pegged_order = match pegged_orders
+            .find_by_key(order_id)
+            .and_then(|h| pegged_orders.get(h)?.as_leaf().copied())
+        {
+            Some(leaf) => leaf,
+            None => return Ok(false),
+        };
+        if pegged_order_side(order_id) != side
+            || pegged_order.quantity() == 0
+            || pegged_order.owner() != expected_owner
+            || pegged_order.owner_slot() != expected_owner_slot
+        {
+            return Ok(false);
+        }
+        pegged_orders
+            .remove_by_key(pegged_order.order_id())
+            .unwrap();
+
+        let native_qty_unlocked = match side {
+            Side::Bid => {
+                pegged_order.quantity()
+                    * pegged_order.cap_price().ok_or(assertion_error!())?.get()
+                    * pc_lot_size
+            }
+            Side::Ask => pegged_order.quantity() * coin_lot_size,
+        };
+        event_q
+            .push_back(Event::new(EventView::Out {
+                side,
+                native_qty_unlocked,
+                native_qty_still_locked: 0,
+                native_coin_qty_cancelled: pegged_order.quantity() * coin_lot_size,
+                order_id,
+                owner: expected_owner,
+                owner_slot: expected_owner_slot,
+                client_order_id: NonZeroU64::new(pegged_order.client_order_id()),
+            }))
+            .map_err(|_| DexErrorCode::EventQueueFull)?;
+        Ok(true)
+    }
+
+    // Puts the next slice of an iceberg order's reserve on the book once its displayed
+    // quantity is gone. The slice gets a new order id, which sends it to the back of
+    // its price level.
+    fn replenish_iceberg_order(
+        &mut self,
+        side: Side,
+        order_id: &u128,
+
+        req_q: &mut RequestQueue,
+        event_q: &mut EventQueue,
+    ) -> DexResult<()> {
+        let order = self
+            .orders_mut(side)
+            .remove_by_key(order_id)
+            .ok_or(assertion_error!())?;
+        let new_order_id = req_q.gen_order_id(order.price().get(), side);
+        let display_qty = order.display_qty();
+        let visible_qty = display_qty.min(order.hidden_qty());
+        let mut new_order = LeafNode::new(
+            order.owner_slot(),
+            &new_order_id,
+            order.owner(),
+            visible_qty,
+            order.fee_tier(),
+            order.client_order_id(),
+        );
+        new_order.set_expiry(order.expiry());
+        new_order.set_self_trade_behavior(order.self_trade_behavior());
+        new_order.set_iceberg_params(display_qty, order.hidden_qty() - visible_qty);
+        self.orders_mut(side)
+            .insert_leaf(&new_order)
+            .map_err(|_| assertion_error!())?;
+        event_q
+            .push_back(Event::new(EventView::Requeue {
+                side,
+                order_id,
+                new_order_id,
+                owner: order.owner(),
+                owner_slot: order.owner_slot(),
+                client_order_id: NonZeroU64::new(order.client_order_id()),
+            }))
+            .map_err(|_| DexErrorCode::EventQueueFull)?;
         Ok(())
     }
+
+    // Expired orders are only removed once they reach the top of the book, where they
+    // would otherwise be matched against.
+    fn evict_expired_order(
+        &mut self,
+        side: Side,
+        order_id: &u128,
+
+        event_q: &mut EventQueue,
+    ) -> DexResult<()> {
+        let pc_lot_size = self.market_state.pc_lot_size;
+        let coin_lot_size = self.market_state.coin_lot_size;
+        let order = self
+            .orders_mut(side)
+            .remove_by_key(order_id)
+            .ok_or(assertion_error!())?;
+        let native_qty_unlocked = match side {
+            Side::Bid => order.total_quantity() * order.lock_price().get() * pc_lot_size,
+            Side::Ask => order.total_quantity() * coin_lot_size,
+        };
+        event_q
+            .push_back(Event::new(EventView::Out {
+                side,
+                native_qty_unlocked,
+                native_qty_still_locked: 0,
+                native_coin_qty_cancelled: order.total_quantity() * coin_lot_size,
+                order_id,
+                owner: order.owner(),
+                owner_slot: order.owner_slot(),
+                client_order_id: NonZeroU64::new(order.client_order_id()),
+            }))
+            .map_err(|_| DexErrorCode::EventQueueFull)?;
+        Ok(())
+    }
+}
+
+// The opening auction. Orders pile up on the book without matching until it's run, then
+// everything that crosses is filled at one price. Bids rest with only their price locked,
+// so each ask pays the taker fee for its tier out of what it receives. Filling can take
+// several calls, which pick up where the last one stopped.
+impl<'ob> OrderBookState<'ob> {
+    // Fills at most `limit` orders, returning whether the auction is over.
+    pub fn run_auction(
+        &mut self,
+        req_q: &mut RequestQueue,
+        event_q: &mut EventQueue,
+        mut limit: u16,
+    ) -> DexResult<bool> {
+        let now = self.clock;
+        self.market_state.accumulate_price(now.slot);
+        if !self.market_state.auction_in_progress() {
+            if !self.cancel_auction_self_trades(&mut limit, req_q, event_q)? {
+                return Ok(false);
+            }
+            let (price, qty) = match self.find_clearing_price() {
+                Some(clearing) => clearing,
+                None => return Ok(true),
+            };
+            self.market_state.auction_price = price;
+            self.market_state.auction_bid_qty_left = qty;
+            self.market_state.auction_ask_qty_left = qty;
+            self.market_state.auction_slot = now.slot;
+            self.market_state.auction_unix_timestamp = now.unix_timestamp;
+            self.record_fill(price, qty);
+        }
+
+        // an order that expires partway through was still counted in the lots to fill
+        let clock = Clock {
+            slot: self.market_state.auction_slot,
+            unix_timestamp: self.market_state.auction_unix_timestamp,
+            unused: now.unused,
+            epoch: now.epoch,
+            leader_schedule_epoch: now.leader_schedule_epoch,
+        };
+        let price = self.market_state.auction_price;
+        self.market_state.auction_bid_qty_left = self.fill_at_clearing_price(
+            Side::Bid,
+            price,
+            self.market_state.auction_bid_qty_left,
+            &clock,
+            &mut limit,
+            req_q,
+            event_q,
+        )?;
+        self.market_state.auction_ask_qty_left = self.fill_at_clearing_price(
+            Side::Ask,
+            price,
+            self.market_state.auction_ask_qty_left,
+            &clock,
+            &mut limit,
+            req_q,
+            event_q,
+        )?;
+
+        let done = self.market_state.auction_bid_qty_left == 0
+            && self.market_state.auction_ask_qty_left == 0;
+        if done {
+            self.market_state.auction_price = 0;
+        }
+        Ok(done)
+    }
+
+    // Orders that cross an order of the same owner on the other side are settled by the
+    // later order's self-trade behavior, as if it had been matched against the earlier
+    // one, before anything counts toward the clearing price. Each pair takes a unit of
+    // `limit`, and this returns whether none are left.
+    fn cancel_auction_self_trades(
+        &mut self,
+        limit: &mut u16,
+        req_q: &mut RequestQueue,
+        event_q: &mut EventQueue,
+    ) -> DexResult<bool> {
+        loop {
+            let (bid, ask) = match self.find_auction_self_trade() {
+                Some(orders) => orders,
+                None => return Ok(true),
+            };
+            if *limit == 0 {
+                return Ok(false);
+            }
+            *limit -= 1;
+
+            let bid_seq_num = !(*bid.order_id() as u64);
+            let ask_seq_num = *ask.order_id() as u64;
+            let (take, provide) = if bid_seq_num > ask_seq_num {
+                ((Side::Bid, bid), (Side::Ask, ask))
+            } else {
+                ((Side::Ask, ask), (Side::Bid, bid))
+            };
+            let (cancel_take, cancel_provide) = match take.1.self_trade_behavior() {
+                SelfTradeBehavior::DecrementTake => {
+                    let qty = bid.quantity().min(ask.quantity());
+                    self.decrement_order(Side::Bid, &bid, qty, req_q, event_q)?;
+                    self.decrement_order(Side::Ask, &ask, qty, req_q, event_q)?;
+                    continue;
+                }
+                SelfTradeBehavior::CancelProvide => (false, true),
+                SelfTradeBehavior::CancelTake => (true, false),
+                SelfTradeBehavior::CancelBoth => (true, true),
+            };
+            for &(cancel, (side, order)) in &[(cancel_take, take), (cancel_provide, provide)] {
+                if cancel {
+                    let cancelled = self.cancel_order(
+                        side,
+                        order.order_id(),
+                        order.owner(),
+                        order.owner_slot(),
+                        None,
+                        req_q,
+                        event_q,
+                    )?;
+                    check_assert!(cancelled)?;
+                }
+            }
+        }
+    }
+
+    // The highest crossed bid that crosses an ask of the same owner, and the lowest such
+    // ask. Expired orders are left out of the auction anyway.
+    fn find_auction_self_trade(&self) -> Option<(LeafNode, LeafNode)> {
+        let lowest_ask = self.find_best_price(Side::Ask)?;
+        self.bids
+            .leaves(true)
+            .take_while(|bid| bid.price().get() >= lowest_ask)
+            .filter(|bid| !order_expired(bid.expiry(), self.clock))
+            .find_map(|bid| {
+                let ask = self
+                    .asks
+                    .leaves(false)
+                    .take_while(|ask| ask.price() <= bid.price())
+                    .find(|ask| {
+                        ask.owner() == bid.owner() && !order_expired(ask.expiry(), self.clock)
+                    })?;
+                Some((*bid, *ask))
+            })
+    }
+
+    // Cancels `qty` of the visible lots of a resting order.
+    fn decrement_order(
+        &mut self,
+        side: Side,
+        order: &LeafNode,
+        qty: u64,
+
+        req_q: &mut RequestQueue,
+        event_q: &mut EventQueue,
+    ) -> DexResult<()> {
+        let order_id = *order.order_id();
+        let qty_left = order.total_quantity() - qty;
+        let (native_qty_unlocked, native_qty_still_locked) = match side {
+            Side::Bid => {
+                let native_lot_price = order.lock_price().get() * self.market_state.pc_lot_size;
+                (qty * native_lot_price, qty_left * native_lot_price)
+            }
+            Side::Ask => {
+                let coin_lot_size = self.market_state.coin_lot_size;
+                (qty * coin_lot_size, qty_left * coin_lot_size)
+            }
+        };
+        event_q
+            .push_back(Event::new(EventView::Out {
+                side,
+                native_qty_unlocked,
+                native_qty_still_locked,
+                native_coin_qty_cancelled: qty * self.market_state.coin_lot_size,
+                order_id: &order_id,
+                owner: order.owner(),
+                owner_slot: order.owner_slot(),
+                client_order_id: NonZeroU64::new(order.client_order_id()),
+            }))
+            .map_err(|_| DexErrorCode::EventQueueFull)?;
+
+        if qty_left == 0 {
+            self.orders_mut(side)
+                .remove_by_key(&order_id)
+                .ok_or(assertion_error!())?;
+            return Ok(());
+        }
+        let orders = self.orders_mut(side);
+        let order = orders
+            .find_by_key(&order_id)
+            .and_then(|handle| orders.get_mut(handle))
+            .and_then(|node| node.as_leaf_mut())
+            .ok_or(assertion_error!())?;
+        *order.quantity_mut() -= qty;
+        if order.quantity() == 0 {
+            self.replenish_iceberg_order(side, &order_id, req_q, event_q)?;
+        }
+        Ok(())
+    }
+
+    // The price that matches the most lots, breaking ties by the fewest lots left over
+    // on the heavier side and then by the lowest price. None if nothing crosses.
+    fn find_clearing_price(&self) -> Option<(u64, u64)> {
+        let lowest_ask = self.find_best_price(Side::Ask)?;
+        let highest_bid = self.find_best_price(Side::Bid)?;
+        let clock = self.clock;
+
+        // the lots that could trade, walked upwards by price along with the asks
+        let mut bids = self
+            .bids
+            .leaves(false)
+            .filter(|bid| bid.price().get() >= lowest_ask)
+            .filter(|bid| !order_expired(bid.expiry(), clock))
+            .peekable();
+        let mut asks = self
+            .asks
+            .leaves(false)
+            .take_while(|ask| ask.price().get() <= highest_bid)
+            .filter(|ask| !order_expired(ask.expiry(), clock))
+            .peekable();
+        let mut bid_qty: u64 = self
+            .bids
+            .leaves(true)
+            .take_while(|bid| bid.price().get() >= lowest_ask)
+            .filter(|bid| !order_expired(bid.expiry(), clock))
+            .map(|bid| bid.total_quantity())
+            .sum();
+        let mut ask_qty: u64 = 0;
+
+        // at each price, bids at or above it and asks at or below it can trade
+        let mut best: Option<(u64, u64, u64)> = None;
+        loop {
+            let price = match (bids.peek(), asks.peek()) {
+                (Some(bid), Some(ask)) => bid.price().min(ask.price()).get(),
+                (Some(bid), None) => bid.price().get(),
+                (None, Some(ask)) => ask.price().get(),
+                (None, None) => break,
+            };
+            while let Some(ask) = asks.peek().filter(|ask| ask.price().get() == price) {
+                ask_qty += ask.total_quantity();
+                asks.next();
+            }
+            let matched_qty = bid_qty.min(ask_qty);
+            let imbalance = bid_qty.max(ask_qty) - matched_qty;
+            let better = match best {
+                None => matched_qty > 0,
+                Some((_, best_matched_qty, best_imbalance)) => {
+                    matched_qty > best_matched_qty
+                        || (matched_qty == best_matched_qty && imbalance < best_imbalance)
+                }
+            };
+            if better {
+                best = Some((price, matched_qty, imbalance));
+            }
+            while let Some(bid) = bids.peek().filter(|bid| bid.price().get() == price) {
+                bid_qty -= bid.total_quantity();
+                bids.next();
+            }
+        }
+        best.map(|(price, matched_qty, _)| (price, matched_qty))
+    }
+
+    // Fills `qty` lots from the top of one side of the book at the clearing price, one
+    // order per unit of `limit`, returning the lots it didn't get to. Asks pay the taker
+    // fee and bids pay nothing, see RunAuction.
+    fn fill_at_clearing_price(
+        &mut self,
+        side: Side,
+        price: u64,
+        qty: u64,
+        clock: &Clock,
+        limit: &mut u16,
+
+        req_q: &mut RequestQueue,
+        event_q: &mut EventQueue,
+    ) -> DexResult<u64> {
+        let pc_lot_size = self.market_state.pc_lot_size;
+        let coin_lot_size = self.market_state.coin_lot_size;
+        let fee_schedule = self.market_state.fee_schedule;
+
+        let mut qty_remaining = qty;
+        while qty_remaining > 0 && *limit > 0 {
+            *limit -= 1;
+            let best_h = self.find_bbo(side).ok_or(assertion_error!())?;
+            let order = *self
+                .orders(side)
+                .get(best_h)
+                .and_then(|node| node.as_leaf())
+                .ok_or(assertion_error!())?;
+            let order_id = *order.order_id();
+            if order_expired(order.expiry(), clock) {
+                self.evict_expired_order(side, &order_id, event_q)?;
+                continue;
+            }
+
+            let trade_qty = order.quantity().min(qty_remaining);
+            qty_remaining -= trade_qty;
+            let native_coin_qty = trade_qty * coin_lot_size;
+            let native_pc_qty = trade_qty * price * pc_lot_size;
+            let (native_qty_paid, native_qty_received, native_fee) = match side {
+                Side::Bid => (native_pc_qty, native_coin_qty, 0),
+                Side::Ask => {
+                    let native_fee = fee_schedule.taker_fee(order.fee_tier(), native_pc_qty);
+                    (native_coin_qty, native_pc_qty - native_fee, native_fee)
+                }
+            };
+            let referrer_rebate = fees::referrer_rebate(native_fee);
+            self.market_state.referrer_rebates_accrued += referrer_rebate;
+            self.market_state.pc_fees_accrued += native_fee - referrer_rebate;
+            self.market_state.pc_deposits_total -= native_fee;
+            event_q
+                .push_back(Event::new(EventView::Fill {
+                    side,
+                    maker: side == Side::Bid,
+                    native_qty_paid,
+                    native_qty_received,
+                    native_fee_or_rebate: native_fee,
+                    order_id: &order_id,
+                    owner: order.owner(),
+                    owner_slot: order.owner_slot(),
+                    fee_tier: order.fee_tier(),
+                    client_order_id: NonZeroU64::new(order.client_order_id()),
+                }))
+                .map_err(|_| DexErrorCode::EventQueueFull)?;
+
+            // bids were locked at their own price, so filling below it frees the difference
+            let (native_qty_unlocked, native_qty_still_locked) = match side {
+                Side::Bid => {
+                    let lock_price = order.lock_price().get();
+                    (
+                        trade_qty * (lock_price - price) * pc_lot_size,
+                        (order.total_quantity() - trade_qty) * lock_price * pc_lot_size,
+                    )
+                }
+                Side::Ask => (0, (order.total_quantity() - trade_qty) * coin_lot_size),
+            };
+            if native_qty_unlocked > 0 || native_qty_still_locked == 0 {
+                event_q
+                    .push_back(Event::new(EventView::Out {
+                        side,
+                        native_qty_unlocked,
+                        native_qty_still_locked,
+                        native_coin_qty_cancelled: 0,
+                        order_id: &order_id,
+                        owner: order.owner(),
+                        owner_slot: order.owner_slot(),
+                        client_order_id: NonZeroU64::new(order.client_order_id()),
+                    }))
+                    .map_err(|_| DexErrorCode::EventQueueFull)?;
+            }
+
+            if trade_qty < order.quantity() {
+                let order = self
+                    .orders_mut(side)
+                    .get_mut(best_h)
+                    .and_then(|node| node.as_leaf_mut())
+                    .ok_or(assertion_error!())?;
+                *order.quantity_mut() -= trade_qty;
+            } else if order.hidden_qty() > 0 {
+                self.replenish_iceberg_order(side, &order_id, req_q, event_q)?;
+            } else {
+                self.orders_mut(side)
+                    .remove_by_key(&order_id)
+                    .ok_or(assertion_error!())?;
+            }
+        }
+        Ok(qty_remaining)
+    }
 }
@@ -1,6 +1,12 @@
 #![cfg_attr(not(feature = "program"), allow(unused))]
 use num_enum::TryFromPrimitive;
-use std::{cell::RefMut, convert::TryInto, mem::size_of, num::NonZeroU64, ops::DerefMut};
+use std::{
+    cell::RefMut,
+    convert::TryInto,
+    mem::size_of,
+    num::NonZeroU64,
+    ops::{Deref, DerefMut},
+};
 
 use arrayref::{array_ref, array_refs, mut_array_refs};
 
@@ -22,23 +28,30 @@ macro_rules! info {
 
 use solana_sdk::{
     account_info::AccountInfo,
+    clock::Clock,
     program_error::ProgramError,
     pubkey::Pubkey,
     rent::Rent,
+    system_instruction, system_program,
     sysvar::{Sysvar, SysvarId},
 };
 use spl_token::error::TokenError;
 use spl_token::pack::Pack;
 
 use crate::{
-    critbit::Slab,
+    critbit::{LeafNode, Slab, SLAB_HEADER_LEN},
     error::{DexErrorCode, DexResult, SourceFileId},
-    fees::{self, FeeTier},
+    fees::{self, FeeSchedule, FeeTier},
     instruction::{
         disable_authority, fee_sweeper, msrm_token, srm_token, CancelOrderInstruction,
-        InitializeMarketInstruction, MarketInstruction, NewOrderInstructionV2, SelfTradeBehavior,
+        InitializeMarketInstruction, MarketInstruction, MarketMode, NewOrderInstructionV2,
+        NewPeggedOrderInstruction, OrderExpiry, ReplacedOrder, SelfTradeBehavior,
+        SetMarketParamsInstruction, SetPositionLimitsInstruction,
+    },
+    matching::{
+        pegged_order_key, stop_order_key, OrderBookState, OrderType, Side, MAX_PEG_OFFSET,
+        MAX_TRIGGER_PRICE,
     },
-    matching::{OrderBookState, OrderType, Side},
 };
 
 declare_check_assert_macros!(SourceFileId::State);
@@ -65,6 +78,18 @@ pub enum AccountFlag {
     Bids = 1u64 << 5,
     Asks = 1u64 << 6,
     Disabled = 1u64 << 7,
+    StopOrders = 1u64 << 8,
+    PeggedOrders = 1u64 << 9,
+    ReduceOnly = 1u64 << 10,
+    OpenOrdersV2 = 1u64 << 11,
+    AutoConsume = 1u64 << 12,
+    MarketStats = 1u64 << 13,
+    CancelOnly = 1u64 << 14,
+    PostOnly = 1u64 << 15,
+    Auction = 1u64 << 16,
+    MarketV2 = 1u64 << 17,
+    OrderBookV2 = 1u64 << 18,
+    RequestQueueV2 = 1u64 << 19,
 }
 
 #[cfg_attr(target_endian = "little", derive(Debug))]
@@ -72,7 +97,7 @@ pub enum AccountFlag {
 #[repr(C)]
 pub struct MarketState {
     // 0
-    pub account_flags: u64, // Initialized, Market
+    pub account_flags: u64, // Initialized, Market, MarketV2
 
     // 1
     pub own_address: [u64; 4],
@@ -120,6 +145,57 @@ pub struct MarketState {
     pub fee_rate_bps: u64,
     // 46
     pub referrer_rebates_accrued: u64,
+
+    // 47
+    // markets without MarketV2 end here, see MarketStateMut
+    pub stop_orders: [u64; 4],
+    // 51
+    pub last_fill_price: u64,
+
+    // 52
+    pub pegged_orders: [u64; 4],
+
+    // 56
+    pub fee_schedule: FeeSchedule,
+
+    // 60
+    pub authority: [u64; 4],
+
+    // 64
+    pub market_stats: [u64; 4],
+
+    // 68
+    // last_fill_price summed over every slot up to price_cumulative_slot, wrapping
+    pub price_cumulative: u64,
+    // 69
+    pub price_cumulative_slot: u64,
+
+    // 70
+    // 0 if fills aren't bounded
+    pub price_band_bps: u64,
+    // 71
+    pub price_band_halt_slots: u64,
+    // 72
+    // no new orders are taken before this slot
+    pub halted_until_slot: u64,
+
+    // 73
+    // the clearing price of an auction RunAuction is partway through filling, 0 if none
+    pub auction_price: u64,
+    // 74
+    // the lots still to fill on each side at auction_price
+    pub auction_bid_qty_left: u64,
+    // 75
+    pub auction_ask_qty_left: u64,
+    // 76
+    // the clock as of when auction_price was found, which orders are checked for expiry
+    // against until the auction is filled
+    pub auction_slot: u64,
+    // 77
+    pub auction_unix_timestamp: i64,
+    // 78
+    // the last pegged order key moved by a pass that ran out of limit, 0 if none
+    pub pegged_orders_cursor: [u64; 2],
 }
 #[cfg(target_endian = "little")]
 unsafe impl Zeroable for MarketState {}
@@ -193,25 +269,100 @@ pub fn strip_header<'a, H: Pod, D: Pod>(
     Ok((header, inner))
 }
 
+fn load_order_book_mut<'a>(
+    account: &'a AccountInfo,
+    side_flag: AccountFlag,
+) -> DexResult<RefMut<'a, Slab>> {
+    let (header, buf) = strip_header::<OrderBookStateHeader, u8>(account, false)?;
+    let flags = BitFlags::from_bits(header.account_flags).unwrap();
+    if flags == AccountFlag::Initialized | side_flag {
+        Err(DexErrorCode::MarketNotMigrated)?
+    }
+    check_assert_eq!(
+        &flags,
+        &(AccountFlag::Initialized | side_flag | AccountFlag::OrderBookV2)
+    )?;
+    Ok(RefMut::map(buf, Slab::new))
+}
+
+/// The length of the market accounts created before the `MarketV2` flag, which end
+/// at `referrer_rebates_accrued`.
+pub const LEGACY_MARKET_STATE_LEN: usize = 47 * size_of::<u64>();
+
+/// A market account. For markets without the `MarketV2` flag this is a copy of the
+/// account with every field past `referrer_rebates_accrued` zero, so they keep charging
+/// no fees, and those fields stay zero. The fields the account has are written back
+/// when it's dropped.
+pub enum MarketStateMut<'a> {
+    V2(RefMut<'a, MarketState>),
+    Legacy(RefMut<'a, [u64]>, Box<MarketState>),
+}
+
+impl<'a> Deref for MarketStateMut<'a> {
+    type Target = MarketState;
+
+    fn deref(&self) -> &MarketState {
+        match self {
+            MarketStateMut::V2(state) => state,
+            MarketStateMut::Legacy(_, state) => state,
+        }
+    }
+}
+
+impl<'a> DerefMut for MarketStateMut<'a> {
+    fn deref_mut(&mut self) -> &mut MarketState {
+        match self {
+            MarketStateMut::V2(state) => state,
+            MarketStateMut::Legacy(_, state) => state,
+        }
+    }
+}
+
+impl<'a> Drop for MarketStateMut<'a> {
+    fn drop(&mut self) {
+        if let MarketStateMut::Legacy(data, state) = self {
+            // nothing may be set that the account can't keep
+            debug_assert!(bytes_of(&**state)[LEGACY_MARKET_STATE_LEN..]
+                .iter()
+                .all(|&byte| byte == 0));
+            let data: &mut [u8] = cast_slice_mut(data);
+            data.copy_from_slice(&bytes_of(&**state)[..LEGACY_MARKET_STATE_LEN]);
+        }
+    }
+}
+
 impl MarketState {
     #[inline]
     pub fn load<'a>(
         market_account: &'a AccountInfo,
         program_id: &Pubkey,
-    ) -> DexResult<RefMut<'a, Self>> {
+    ) -> DexResult<MarketStateMut<'a>> {
         check_assert_eq!(market_account.owner, program_id)?;
         let mut account_data: RefMut<'a, [u8]>;
-        let state: RefMut<'a, Self>;
+        let data: RefMut<'a, [u64]>;
+        let state: MarketStateMut<'a>;
 
         account_data = RefMut::map(market_account.try_borrow_mut_data()?, |data| *data);
         check_account_padding(&mut account_data)?;
-        state = RefMut::map(account_data, |data| {
-            from_bytes_mut(cast_slice_mut(
-                check_account_padding(data).unwrap_or_else(|_| unreachable!()),
-            ))
+        data = RefMut::map(account_data, |data| {
+            check_account_padding(data).unwrap_or_else(|_| unreachable!())
         });
+        let is_legacy = data.len() * size_of::<u64>() == LEGACY_MARKET_STATE_LEN;
+        state = if is_legacy {
+            let mut legacy_state: Box<MarketState> = Box::new(Zeroable::zeroed());
+            bytes_of_mut(&mut *legacy_state)[..LEGACY_MARKET_STATE_LEN]
+                .copy_from_slice(cast_slice(&data));
+            MarketStateMut::Legacy(data, legacy_state)
+        } else {
+            MarketStateMut::V2(RefMut::map(data, |data| {
+                from_bytes_mut(cast_slice_mut(data))
+            }))
+        };
 
         state.check_flags()?;
+        if state.is_v2() == is_legacy {
+            Err(DexErrorCode::InvalidMarketFlags)?
+        }
         Ok(state)
     }
 
@@ -220,26 +371,109 @@ impl MarketState {
         let flags = BitFlags::from_bits(self.account_flags)
             .map_err(|_| DexErrorCode::InvalidMarketFlags)?;
         let required_flags = AccountFlag::Initialized | AccountFlag::Market;
-        if flags != required_flags {
+        let optional_flags = AccountFlag::Disabled
+            | AccountFlag::AutoConsume
+            | AccountFlag::CancelOnly
+            | AccountFlag::PostOnly
+            | AccountFlag::Auction
+            | AccountFlag::MarketV2;
+        if !flags.contains(required_flags)
+            || flags.bits() & !(required_flags | optional_flags).bits() != 0
+        {
             Err(DexErrorCode::InvalidMarketFlags)?
         }
         Ok(())
     }
 
+    /// Whether the market has the fields past `referrer_rebates_accrued`. The ones
+    /// set by the market authority can't be set on other markets, as they have none.
+    #[inline]
+    pub fn is_v2(&self) -> bool {
+        let flags = BitFlags::from_bits(self.account_flags).unwrap();
+        flags.contains(AccountFlag::MarketV2)
+    }
+
     pub fn load_orders_mut<'a>(
         &self,
         orders_account: &'a AccountInfo,
         owner_account: Option<&AccountInfo>,
         program_id: &Pubkey,
         rent: Option<Rent>,
-    ) -> DexResult<RefMut<'a, OpenOrders>> {
+    ) -> DexResult<OpenOrdersMut<'a>> {
+        self.load_orders_mut_inner(orders_account, owner_account, program_id, rent, false)
+    }
+
+    /// Like `load_orders_mut`, but the signer may also be the account's delegate,
+    /// for instructions that only place or cancel orders.
+    pub fn load_orders_mut_as_trader<'a>(
+        &self,
+        orders_account: &'a AccountInfo,
+        trader_account: &AccountInfo,
+        program_id: &Pubkey,
+        rent: Option<Rent>,
+    ) -> DexResult<OpenOrdersMut<'a>> {
+        self.load_orders_mut_inner(orders_account, Some(trader_account), program_id, rent, true)
+    }
+
+    fn load_orders_mut_inner<'a>(
+        &self,
+        orders_account: &'a AccountInfo,
+        owner_account: Option<&AccountInfo>,
+        program_id: &Pubkey,
+        rent: Option<Rent>,
+        allow_delegate: bool,
+    ) -> DexResult<OpenOrdersMut<'a>> {
         check_assert_eq!(orders_account.owner, program_id)?;
-        let mut open_orders: RefMut<'a, OpenOrders>;
+        let mut open_orders: OpenOrdersMut<'a>;
 
         let open_orders_data_len = orders_account.data_len();
         let open_orders_lamports = orders_account.lamports();
         let (_, data) = strip_header::<[u8; 0], u8>(orders_account, true)?;
-        open_orders = RefMut::map(data, |data| from_bytes_mut(data));
+        if data.len() < size_of::<OpenOrders>() {
+            Err(DexErrorCode::WrongOrdersAccount)?
+        }
+        // the version flag picks the layout; an account that's still zeroed gets the
+        // legacy one only if that's exactly what fits
+        let is_v2 = match u64::from_le_bytes(*array_ref![data, 0, 8]) {
+            0 => data.len() != size_of::<OpenOrders>(),
+            account_flags => account_flags & AccountFlag::OpenOrdersV2 as u64 != 0,
+        };
+        let extension_len = if is_v2 {
+            size_of::<OpenOrdersExtension>()
+        } else {
+            0
+        };
+        // anything past the extension is whole pages of extra slots
+        let extra_slots_len = data
+            .len()
+            .checked_sub(size_of::<OpenOrders>() + extension_len)
+            .ok_or(DexErrorCode::WrongOrdersAccount)?;
+        if (!is_v2 && extra_slots_len != 0)
+            || extra_slots_len % size_of::<OrderSlots>() != 0
+            || extra_slots_len / size_of::<OrderSlots>() >= MAX_ORDER_SLOT_PAGES
+        {
+            Err(DexErrorCode::WrongOrdersAccount)?
+        }
+        let (header, rest): (RefMut<OpenOrders>, RefMut<[u8]>) = RefMut::map_split(data, |data| {
+            let (header, rest) = data.split_at_mut(size_of::<OpenOrders>());
+            (from_bytes_mut(header), rest)
+        });
+        let (extension, extra_slots): (RefMut<[u8]>, RefMut<[OrderSlots]>) =
+            RefMut::map_split(rest, |rest| {
+                let (extension, extra_slots) = rest.split_at_mut(extension_len);
+                (extension, cast_slice_mut(extra_slots))
+            });
+        open_orders = OpenOrdersMut {
+            header,
+            extension: if is_v2 {
+                Some(RefMut::map(extension, |extension| {
+                    from_bytes_mut(extension)
+                }))
+            } else {
+                None
+            },
+            extra_slots,
+        };
 
         if open_orders.account_flags == 0 {
             let rent = rent.ok_or(DexErrorCode::RentNotProvided)?;
@@ -253,8 +487,12 @@ impl MarketState {
         check_assert_eq!(&open_orders.market, &self.own_address)
             .map_err(|_| DexErrorCode::WrongOrdersAccount)?;
         if let Some(owner) = owner_account {
-            check_assert_eq!(&open_orders.owner, &owner.key.to_aligned_bytes())
-                .map_err(|_| DexErrorCode::WrongOrdersAccount)?;
+            let signer = owner.key.to_aligned_bytes();
+            let is_delegate = allow_delegate && open_orders.delegate() == Some(&signer);
+            if !is_delegate {
+                check_assert_eq!(&open_orders.owner, &signer)
+                    .map_err(|_| DexErrorCode::WrongOrdersAccount)?;
+            }
         }
 
         Ok(open_orders)
@@ -263,21 +501,56 @@ impl MarketState {
     pub fn load_bids_mut<'a>(&self, bids: &'a AccountInfo) -> DexResult<RefMut<'a, Slab>> {
         check_assert_eq!(&bids.key.to_aligned_bytes(), &self.bids)
             .map_err(|_| DexErrorCode::WrongBidsAccount)?;
-        let (header, buf) = strip_header::<OrderBookStateHeader, u8>(bids, false)?;
-        let flags = BitFlags::from_bits(header.account_flags).unwrap();
-        check_assert_eq!(&flags, &(AccountFlag::Initialized | AccountFlag::Bids))?;
-        Ok(RefMut::map(buf, Slab::new))
+        load_order_book_mut(bids, AccountFlag::Bids)
     }
 
     pub fn load_asks_mut<'a>(&self, asks: &'a AccountInfo) -> DexResult<RefMut<'a, Slab>> {
         check_assert_eq!(&asks.key.to_aligned_bytes(), &self.asks)
             .map_err(|_| DexErrorCode::WrongAsksAccount)?;
-        let (header, buf) = strip_header::<OrderBookStateHeader, u8>(asks, false)?;
+        load_order_book_mut(asks, AccountFlag::Asks)
+    }
+
+    pub fn load_stop_orders_mut<'a>(
+        &self,
+        stop_orders: &'a AccountInfo,
+    ) -> DexResult<RefMut<'a, Slab>> {
+        check_assert_eq!(&stop_orders.key.to_aligned_bytes(), &self.stop_orders)
+            .map_err(|_| DexErrorCode::WrongStopOrdersAccount)?;
+        let (header, buf) = strip_header::<OrderBookStateHeader, u8>(stop_orders, false)?;
+        let flags = BitFlags::from_bits(header.account_flags).unwrap();
+        check_assert_eq!(
+            &flags,
+            &(AccountFlag::Initialized | AccountFlag::StopOrders)
+        )?;
+        Ok(RefMut::map(buf, Slab::new))
+    }
+
+    pub fn load_pegged_orders_mut<'a>(
+        &self,
+        pegged_orders: &'a AccountInfo,
+    ) -> DexResult<RefMut<'a, Slab>> {
+        check_assert_eq!(&pegged_orders.key.to_aligned_bytes(), &self.pegged_orders)
+            .map_err(|_| DexErrorCode::WrongPeggedOrdersAccount)?;
+        let (header, buf) = strip_header::<OrderBookStateHeader, u8>(pegged_orders, false)?;
         let flags = BitFlags::from_bits(header.account_flags).unwrap();
-        check_assert_eq!(&flags, &(AccountFlag::Initialized | AccountFlag::Asks))?;
+        check_assert_eq!(
+            &flags,
+            &(AccountFlag::Initialized | AccountFlag::PeggedOrders)
+        )?;
         Ok(RefMut::map(buf, Slab::new))
     }
 
+    pub fn load_market_stats_mut<'a>(
+        &self,
+        market_stats: &'a AccountInfo,
+    ) -> DexResult<RefMut<'a, MarketStats>> {
+        check_assert_eq!(&market_stats.key.to_aligned_bytes(), &self.market_stats)
+            .map_err(|_| DexErrorCode::WrongMarketStatsAccount)?;
+        let (stats, _) = strip_header::<MarketStats, u8>(market_stats, false)?;
+        stats.check_flags()?;
+        Ok(stats)
+    }
+
     pub fn load_request_queue_mut<'a>(
         &self,
         queue: &'a AccountInfo,
@@ -285,13 +558,19 @@ impl MarketState {
         check_assert_eq!(&queue.key.to_aligned_bytes(), &self.req_q)
             .map_err(|_| DexErrorCode::WrongRequestQueueAccount)?;
 
-        let (header, buf) = strip_header::<RequestQueueHeader, Request>(queue, false)?;
+        let (header, buf) = strip_header::<RequestQueueHeader, u8>(queue, false)?;
         let flags = BitFlags::from_bits(header.account_flags).unwrap();
+        if flags == AccountFlag::Initialized | AccountFlag::RequestQueue {
+            Err(DexErrorCode::MarketNotMigrated)?
+        }
         check_assert_eq!(
             &flags,
-            &(AccountFlag::Initialized | AccountFlag::RequestQueue)
+            &(AccountFlag::Initialized | AccountFlag::RequestQueue | AccountFlag::RequestQueueV2)
         )?;
-        Ok(Queue { header, buf })
+        Ok(Queue {
+            header,
+            buf: RefMut::map(buf, remove_slop_mut),
+        })
     }
 
     pub fn load_event_queue_mut<'a>(&self, queue: &'a AccountInfo) -> DexResult<EventQueue<'a>> {
@@ -375,9 +654,191 @@ impl MarketState {
         Ok(())
     }
 
+    // Without the clock a halt counts until the crank notices it has passed.
+    fn check_not_halted(&self, clock: Option<&Clock>) -> DexResult {
+        let halted = match clock {
+            Some(clock) => clock.slot < self.halted_until_slot,
+            None => self.halted_until_slot != 0,
+        };
+        if halted {
+            return Err(DexErrorCode::MarketIsHalted.into());
+        }
+        Ok(())
+    }
+
+    /// Whether `MatchOrders` applies events straight to the OpenOrders accounts passed to it.
+    #[inline]
+    pub fn auto_consumes(&self) -> bool {
+        let flags = BitFlags::from_bits(self.account_flags).unwrap();
+        flags.contains(AccountFlag::AutoConsume)
+    }
+
+    fn set_auto_consume(&mut self, auto_consume: bool) {
+        if auto_consume {
+            self.account_flags |= AccountFlag::AutoConsume as u64;
+        } else {
+            self.account_flags &= !(AccountFlag::AutoConsume as u64);
+        }
+    }
+
+    #[inline]
+    pub fn is_cancel_only(&self) -> bool {
+        let flags = BitFlags::from_bits(self.account_flags).unwrap();
+        flags.contains(AccountFlag::CancelOnly)
+    }
+
+    #[inline]
+    pub fn is_post_only(&self) -> bool {
+        let flags = BitFlags::from_bits(self.account_flags).unwrap();
+        flags.contains(AccountFlag::PostOnly)
+    }
+
+    #[inline]
+    pub fn is_auction(&self) -> bool {
+        let flags = BitFlags::from_bits(self.account_flags).unwrap();
+        flags.contains(AccountFlag::Auction)
+    }
+
+    /// Whether `RunAuction` has found a clearing price but not yet filled every order at it.
+    #[inline]
+    pub fn auction_in_progress(&self) -> bool {
+        self.auction_price != 0
+    }
+
+    fn set_mode(&mut self, mode: MarketMode) {
+        self.account_flags &= !(AccountFlag::CancelOnly as u64
+            | AccountFlag::PostOnly as u64
+            | AccountFlag::Auction as u64);
+        match mode {
+            MarketMode::Normal => (),
+            MarketMode::CancelOnly => self.account_flags |= AccountFlag::CancelOnly as u64,
+            MarketMode::PostOnly => self.account_flags |= AccountFlag::PostOnly as u64,
+            MarketMode::Auction => self.account_flags |= AccountFlag::Auction as u64,
+        }
+    }
+
+    fn check_order_type_allowed(&self, order_type: OrderType) -> DexResult {
+        if self.is_cancel_only() {
+            Err(DexErrorCode::MarketIsCancelOnly)?
+        }
+        match order_type {
+            OrderType::Limit | OrderType::PostOnly => (),
+            OrderType::ImmediateOrCancel | OrderType::FillOrKill => {
+                if self.is_post_only() {
+                    Err(DexErrorCode::MarketIsPostOnly)?
+                }
+                if self.is_auction() {
+                    Err(DexErrorCode::MarketInAuction)?
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn pubkey(&self) -> Pubkey {
         Pubkey::new(cast_slice(&self.own_address as &[_]))
     }
+
+    #[inline]
+    pub fn has_stop_orders(&self) -> bool {
+        self.stop_orders != [0; 4]
+    }
+
+    #[inline]
+    pub fn has_pegged_orders(&self) -> bool {
+        self.pegged_orders != [0; 4]
+    }
+
+    #[inline]
+    pub fn has_market_stats(&self) -> bool {
+        self.market_stats != [0; 4]
+    }
+
+    #[inline]
+    pub fn has_authority(&self) -> bool {
+        self.authority != [0; 4]
+    }
+
+    fn check_authority(&self, authority: &AccountInfo) -> DexResult {
+        if !self.has_authority() || authority.key.to_aligned_bytes() != self.authority {
+            Err(DexErrorCode::WrongMarketAuthority)?
+        }
+        Ok(())
+    }
+
+    // The disable authority stands in for the market authority when enabling or closing,
+    // so markets without one aren't stuck. It has no say over markets that have one.
+    fn check_authority_or_disable_authority(&self, authority: &AccountInfo) -> DexResult {
+        if !self.has_authority() && authority.key == &disable_authority::ID {
+            return Ok(());
+        }
+        self.check_authority(authority)
+    }
+
+    pub(crate) fn accumulate_price(&mut self, slot: u64) {
+        if !self.is_v2() {
+            return;
+        }
+        let elapsed_slots = slot.saturating_sub(self.price_cumulative_slot);
+        self.price_cumulative = self
+            .price_cumulative
+            .wrapping_add(self.last_fill_price.wrapping_mul(elapsed_slots));
+        self.price_cumulative_slot = self.price_cumulative_slot.max(slot);
+    }
+
+    /// The cumulative price as of `slot`, counting the last fill price for every
+    /// slot since the market was last matched.
+    pub fn observe_price(&self, slot: u64) -> PriceObservation {
+        let mut market = *self;
+        market.accumulate_price(slot);
+        PriceObservation {
+            slot: market.price_cumulative_slot,
+            price_cumulative: market.price_cumulative,
+        }
+    }
+
+    // Coin for asks, and price currency including the worst case taker fee for bids.
+    fn native_qty_to_lock(
+        &self,
+        instruction: &NewOrderInstructionV2,
+        fee_tier: FeeTier,
+    ) -> DexResult<u64> {
+        let max_qty = instruction.max_qty.get();
+        let native_qty = match instruction.side {
+            Side::Bid => {
+                let native_lock_qty_before_fee = max_qty
+                    .checked_mul(instruction.limit_price.get())
+                    .and_then(|lock_qty_lots| lock_qty_lots.checked_mul(self.pc_lot_size))
+                    .ok_or(DexErrorCode::InsufficientFunds)?;
+                native_lock_qty_before_fee
+                    .checked_add(
+                        self.fee_schedule
+                            .taker_fee(fee_tier, native_lock_qty_before_fee),
+                    )
+                    .ok_or(DexErrorCode::InsufficientFunds)?
+            }
+            Side::Ask => max_qty
+                .checked_mul(self.coin_lot_size)
+                .ok_or(DexErrorCode::InsufficientFunds)?,
+        };
+        Ok(native_qty)
+    }
+}
+
+/// Two observations of the same market, taken some slots apart, give the time-weighted
+/// average price between them. Prices are in lots, like `MarketState::last_fill_price`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PriceObservation {
+    pub slot: u64,
+    pub price_cumulative: u64,
+}
+
+impl PriceObservation {
+    /// None unless `earlier` really was observed before `self`.
+    pub fn twap_since(&self, earlier: &PriceObservation) -> Option<u64> {
+        let elapsed_slots = self.slot.checked_sub(earlier.slot).filter(|&s| s > 0)?;
+        Some(self.price_cumulative.wrapping_sub(earlier.price_cumulative) / elapsed_slots)
+    }
 }
 
 #[cfg_attr(feature = "fuzz", derive(Debug))]
@@ -394,27 +855,98 @@ pub struct OpenOrders {
     pub native_pc_free: u64,
     pub native_pc_total: u64,
 
+    pub slots: OrderSlots,
+    pub referrer_rebates_accrued: u64,
+}
+unsafe impl Pod for OpenOrders {}
+unsafe impl Zeroable for OpenOrders {}
+unsafe impl TriviallyTransmutable for OpenOrders {}
+
+/// Follows the `OpenOrders` header in accounts with the `OpenOrdersV2` layout. Legacy
+/// accounts don't have room for it, so they can't set position limits, a delegate or
+/// settle wallets.
+#[cfg_attr(feature = "fuzz", derive(Debug))]
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct OpenOrdersExtension {
+    // 0 if bids aren't limited
+    pub max_native_coin_position: u64,
+    // the coin the account's open bids could still buy, which counts toward the position
+    pub native_coin_bid: u64,
+
+    // may place and cancel orders on the owner's behalf, but not settle; 0 if unset
+    pub delegate: [u64; 4],
+
+    // where anyone may settle free balances to; 0 if unset
+    pub settle_coin_wallet: [u64; 4],
+    pub settle_pc_wallet: [u64; 4],
+}
+unsafe impl Pod for OpenOrdersExtension {}
+unsafe impl Zeroable for OpenOrdersExtension {}
+
+#[cfg_attr(feature = "fuzz", derive(Debug))]
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct OrderSlots {
     pub free_slot_bits: u128,
     pub is_bid_bits: u128,
     pub orders: [u128; 128],
     // Using Option<NonZeroU64> in a pod type requires nightly
     pub client_order_ids: [u64; 128],
-    pub referrer_rebates_accrued: u64,
 }
-unsafe impl Pod for OpenOrders {}
-unsafe impl Zeroable for OpenOrders {}
+unsafe impl Pod for OrderSlots {}
+unsafe impl Zeroable for OrderSlots {}
+
+impl OrderSlots {
+    pub fn slot_side(&self, index: usize) -> Option<Side> {
+        let slot_mask = 1u128 << index;
+        if self.free_slot_bits & slot_mask != 0 {
+            None
+        } else if self.is_bid_bits & slot_mask != 0 {
+            Some(Side::Bid)
+        } else {
+            Some(Side::Ask)
+        }
+    }
+}
+
+/// Finding an order and cancelling all of them scan every occupied slot, so an account
+/// can only have as many pages of 128 as one instruction can get through.
+pub const MAX_ORDER_SLOT_PAGES: usize = 8;
 
 impl OpenOrders {
+    /// The data length of an `OpenOrdersV2` account with `slot_pages` pages of 128 slots,
+    /// the first of which is in the header.
+    pub fn v2_len(slot_pages: usize) -> usize {
+        size_of::<OpenOrders>()
+            + size_of::<OpenOrdersExtension>()
+            + slot_pages.saturating_sub(1) * size_of::<OrderSlots>()
+    }
+
     fn check_flags(&self) -> DexResult {
         let flags = BitFlags::from_bits(self.account_flags)
             .map_err(|_| DexErrorCode::InvalidMarketFlags)?;
         let required_flags = AccountFlag::Initialized | AccountFlag::OpenOrders;
-        if flags != required_flags {
+        let optional_flags = AccountFlag::ReduceOnly | AccountFlag::OpenOrdersV2;
+        if !flags.contains(required_flags)
+            || flags.bits() & !(required_flags | optional_flags).bits() != 0
+        {
             Err(DexErrorCode::WrongOrdersAccount)?
         }
         Ok(())
     }
 
+    #[inline]
+    pub fn is_v2(&self) -> bool {
+        let flags = BitFlags::from_bits(self.account_flags).unwrap();
+        flags.contains(AccountFlag::OpenOrdersV2)
+    }
+
+    pub fn is_reduce_only(&self) -> bool {
+        let flags = BitFlags::from_bits(self.account_flags).unwrap();
+        flags.contains(AccountFlag::ReduceOnly)
+    }
+
     fn init(&mut self, market: &[u64; 4], owner: &[u64; 4]) -> DexResult<()> {
         check_assert_eq!(&self.account_flags, &0)?;
         self.account_flags = (AccountFlag::Initialized | AccountFlag::OpenOrders).bits();
@@ -424,7 +956,7 @@ impl OpenOrders {
         self.native_coin_free = 0;
         self.native_pc_total = 0;
         self.native_pc_free = 0;
-        self.free_slot_bits = std::u128::MAX;
+        self.slots.free_slot_bits = std::u128::MAX;
         Ok(())
     }
 
@@ -449,92 +981,353 @@ impl OpenOrders {
     fn lock_free_pc(&mut self, native_pc_amount: u64) {
         self.native_pc_free = self.native_pc_free.checked_sub(native_pc_amount).unwrap();
     }
+}
 
-    fn slot_is_free(&self, slot: u8) -> bool {
-        let slot_mask = 1u128 << slot;
-        self.free_slot_bits & slot_mask != 0
-    }
+/// An OpenOrders account, along with the extension and the pages of order slots past
+/// the first 128 that follow it in accounts with the `OpenOrdersV2` layout.
+pub struct OpenOrdersMut<'a> {
+    header: RefMut<'a, OpenOrders>,
+    extension: Option<RefMut<'a, OpenOrdersExtension>>,
+    extra_slots: RefMut<'a, [OrderSlots]>,
+}
 
-    pub fn slot_side(&self, slot: u8) -> Option<Side> {
-        let slot_mask = 1u128 << slot;
-        if self.free_slot_bits & slot_mask != 0 {
-            None
-        } else if self.is_bid_bits & slot_mask != 0 {
-            Some(Side::Bid)
-        } else {
-            Some(Side::Ask)
-        }
-    }
+impl<'a> Deref for OpenOrdersMut<'a> {
+    type Target = OpenOrders;
 
-    fn remove_order(&mut self, slot: u8) -> DexResult {
-        check_assert!(slot < 128)?;
-        check_assert!(!self.slot_is_free(slot))?;
+    fn deref(&self) -> &OpenOrders {
+        &self.header
+    }
+}
 
-        let slot_mask = 1u128 << slot;
-        self.orders[slot as usize] = 0;
-        self.client_order_ids[slot as usize] = 0;
-        self.free_slot_bits |= slot_mask;
-        self.is_bid_bits &= !slot_mask;
+impl<'a> DerefMut for OpenOrdersMut<'a> {
+    fn deref_mut(&mut self) -> &mut OpenOrders {
+        &mut self.header
+    }
+}
 
+impl<'a> OpenOrdersMut<'a> {
+    fn init(&mut self, market: &[u64; 4], owner: &[u64; 4]) -> DexResult<()> {
+        self.header.init(market, owner)?;
+        if self.extension.is_some() {
+            self.header.account_flags |= AccountFlag::OpenOrdersV2 as u64;
+        }
+        for page in self.extra_slots.iter_mut() {
+            page.free_slot_bits = std::u128::MAX;
+        }
         Ok(())
     }
 
-    fn add_order(&mut self, id: u128, side: Side) -> DexResult<u8> {
-        if self.free_slot_bits == 0 {
-            Err(DexErrorCode::TooManyOpenOrders)?;
+    fn clear(&mut self) {
+        *self.header = Zeroable::zeroed();
+        if let Some(extension) = self.extension.as_deref_mut() {
+            *extension = Zeroable::zeroed();
+        }
+        for page in self.extra_slots.iter_mut() {
+            *page = Zeroable::zeroed();
         }
-        let slot = self.free_slot_bits.trailing_zeros();
-        check_assert!(self.slot_is_free(slot as u8))?;
-        let slot_mask = 1u128 << slot;
-        self.free_slot_bits &= !slot_mask;
-        match side {
-            Side::Bid => {
-                self.is_bid_bits |= slot_mask;
-            }
-            Side::Ask => {
-                self.is_bid_bits &= !slot_mask;
-            }
-        };
-        self.orders[slot as usize] = id;
-        Ok(slot as u8)
     }
-}
 
-pub trait QueueHeader: Pod {
-    type Item: Pod + Copy;
+    fn extension_mut(&mut self) -> DexResult<&mut OpenOrdersExtension> {
+        Ok(self
+            .extension
+            .as_deref_mut()
+            .ok_or(DexErrorCode::LegacyOrdersAccount)?)
+    }
 
-    fn head(&self) -> u64;
-    fn head_mut(&mut self) -> &mut u64;
-    fn count(&self) -> u64;
-    fn count_mut(&mut self) -> &mut u64;
+    #[inline]
+    pub fn max_native_coin_position(&self) -> u64 {
+        self.extension
+            .as_ref()
+            .map_or(0, |extension| extension.max_native_coin_position)
+    }
 
-    fn incr_event_id(&mut self);
-    fn decr_event_id(&mut self, n: u64);
-}
+    #[inline]
+    pub fn native_coin_bid(&self) -> u64 {
+        self.extension
+            .as_ref()
+            .map_or(0, |extension| extension.native_coin_bid)
+    }
 
-pub struct Queue<'a, H: QueueHeader> {
-    header: RefMut<'a, H>,
-    buf: RefMut<'a, [H::Item]>,
-}
+    // Bids count toward the position from when they're placed until they fill or leave
+    // the book. Legacy accounts can't be limited, so there is nothing to track.
+    fn add_native_coin_bid(&mut self, native_coin_qty: u64) {
+        if let Some(extension) = self.extension.as_deref_mut() {
+            extension.native_coin_bid = extension.native_coin_bid.saturating_add(native_coin_qty);
+        }
+    }
 
-impl<'a, H: QueueHeader> Queue<'a, H> {
-    pub fn new(header: RefMut<'a, H>, buf: RefMut<'a, [H::Item]>) -> Self {
-        Self { header, buf }
+    fn remove_native_coin_bid(&mut self, native_coin_qty: u64) {
+        if let Some(extension) = self.extension.as_deref_mut() {
+            extension.native_coin_bid = extension.native_coin_bid.saturating_sub(native_coin_qty);
+        }
     }
 
     #[inline]
-    pub fn len(&self) -> u64 {
-        self.header.count()
+    pub fn delegate(&self) -> Option<&[u64; 4]> {
+        self.extension
+            .as_deref()
+            .map(|extension| &extension.delegate)
+            .filter(|&delegate| *delegate != [0; 4])
     }
 
-    #[inline]
-    pub fn full(&self) -> bool {
-        self.header.count() as usize == self.buf.len()
+    fn set_delegate(&mut self, delegate: Option<&Pubkey>) -> DexResult {
+        match delegate {
+            Some(delegate) => self.extension_mut()?.delegate = delegate.to_aligned_bytes(),
+            // a legacy account never has a delegate to revoke
+            None => {
+                if let Some(extension) = self.extension.as_deref_mut() {
+                    extension.delegate = [0; 4];
+                }
+            }
+        }
+        Ok(())
     }
 
+    /// The coin and pc wallets registered for `SettleFundsToWallets`, if any.
     #[inline]
-    pub fn empty(&self) -> bool {
-        self.header.count() == 0
+    pub fn settle_wallets(&self) -> Option<(&[u64; 4], &[u64; 4])> {
+        self.extension
+            .as_deref()
+            .filter(|extension| extension.settle_coin_wallet != [0; 4])
+            .map(|extension| (&extension.settle_coin_wallet, &extension.settle_pc_wallet))
+    }
+
+    fn set_settle_wallets(&mut self, wallets: Option<(&Pubkey, &Pubkey)>) -> DexResult {
+        let (coin_wallet, pc_wallet) = match wallets {
+            Some((coin, pc)) => (coin.to_aligned_bytes(), pc.to_aligned_bytes()),
+            None if self.extension.is_none() => return Ok(()),
+            None => ([0; 4], [0; 4]),
+        };
+        let extension = self.extension_mut()?;
+        extension.settle_coin_wallet = coin_wallet;
+        extension.settle_pc_wallet = pc_wallet;
+        Ok(())
+    }
+
+    fn set_position_limits(
+        &mut self,
+        max_native_coin_position: u64,
+        reduce_only: bool,
+    ) -> DexResult {
+        match self.extension.as_deref_mut() {
+            Some(extension) => extension.max_native_coin_position = max_native_coin_position,
+            None if max_native_coin_position != 0 => Err(DexErrorCode::LegacyOrdersAccount)?,
+            None => {}
+        }
+        if reduce_only {
+            self.account_flags |= AccountFlag::ReduceOnly as u64;
+        } else {
+            self.account_flags &= !(AccountFlag::ReduceOnly as u64);
+        }
+        Ok(())
+    }
+
+    /// Checks an order for `native_coin_qty` against the account's position limits.
+    /// For bids, this is the most coin the order could buy, on top of what the account's
+    /// open bids could buy already.
+    fn check_position_limits(&self, side: Side, native_coin_qty: u64) -> DexResult {
+        let max_native_coin_position = self.max_native_coin_position();
+        let within_limits = match side {
+            Side::Bid => {
+                !self.is_reduce_only()
+                    && (max_native_coin_position == 0
+                        || self
+                            .native_coin_total
+                            .checked_add(self.native_coin_bid())
+                            .and_then(|position| position.checked_add(native_coin_qty))
+                            .map_or(false, |position| position <= max_native_coin_position))
+            }
+            Side::Ask => !self.is_reduce_only() || native_coin_qty <= self.native_coin_free,
+        };
+        if !within_limits {
+            Err(DexErrorCode::PositionLimitExceeded)?
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn slot_count(&self) -> usize {
+        (1 + self.extra_slots.len()) * 128
+    }
+
+    #[inline]
+    fn page(&self, slot: u16) -> (&OrderSlots, usize) {
+        let (page, index) = (slot as usize / 128, slot as usize % 128);
+        match page {
+            0 => (&self.header.slots, index),
+            _ => (&self.extra_slots[page - 1], index),
+        }
+    }
+
+    #[inline]
+    fn page_mut(&mut self, slot: u16) -> (&mut OrderSlots, usize) {
+        let (page, index) = (slot as usize / 128, slot as usize % 128);
+        match page {
+            0 => (&mut self.header.slots, index),
+            _ => (&mut self.extra_slots[page - 1], index),
+        }
+    }
+
+    fn pages(&self) -> impl Iterator<Item = &OrderSlots> {
+        std::iter::once(&self.header.slots).chain(self.extra_slots.iter())
+    }
+
+    /// The slots that hold an order, skipping pages with none.
+    fn occupied_slots(&self) -> impl Iterator<Item = u16> + '_ {
+        self.pages()
+            .enumerate()
+            .filter(|(_, page)| page.free_slot_bits != std::u128::MAX)
+            .flat_map(|(page_index, page)| {
+                let first_slot = (page_index * 128) as u16;
+                let mut occupied_bits = !page.free_slot_bits;
+                std::iter::from_fn(move || {
+                    if occupied_bits == 0 {
+                        return None;
+                    }
+                    let index = occupied_bits.trailing_zeros() as u16;
+                    occupied_bits &= occupied_bits - 1;
+                    Some(first_slot + index)
+                })
+            })
+    }
+
+    fn free_slot_count(&self) -> usize {
+        self.pages()
+            .map(|page| page.free_slot_bits.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn has_no_orders(&self) -> bool {
+        self.pages()
+            .all(|page| page.free_slot_bits == std::u128::MAX)
+    }
+
+    fn slot_is_free(&self, slot: u16) -> bool {
+        let (page, index) = self.page(slot);
+        let slot_mask = 1u128 << index;
+        page.free_slot_bits & slot_mask != 0
+    }
+
+    pub fn slot_side(&self, slot: u16) -> Option<Side> {
+        if slot as usize >= self.slot_count() {
+            return None;
+        }
+        let (page, index) = self.page(slot);
+        page.slot_side(index)
+    }
+
+    #[inline]
+    pub fn order_id(&self, slot: u16) -> &u128 {
+        let (page, index) = self.page(slot);
+        &page.orders[index]
+    }
+
+    #[inline]
+    fn set_order_id(&mut self, slot: u16, order_id: u128) {
+        let (page, index) = self.page_mut(slot);
+        page.orders[index] = order_id;
+    }
+
+    #[inline]
+    pub fn client_order_id(&self, slot: u16) -> u64 {
+        let (page, index) = self.page(slot);
+        page.client_order_ids[index]
+    }
+
+    #[inline]
+    fn set_client_order_id(&mut self, slot: u16, client_order_id: u64) {
+        let (page, index) = self.page_mut(slot);
+        page.client_order_ids[index] = client_order_id;
+    }
+
+    fn remove_order(&mut self, slot: u16) -> DexResult {
+        check_assert!((slot as usize) < self.slot_count())?;
+        check_assert!(!self.slot_is_free(slot))?;
+
+        let (page, index) = self.page_mut(slot);
+        let slot_mask = 1u128 << index;
+        page.orders[index] = 0;
+        page.client_order_ids[index] = 0;
+        page.free_slot_bits |= slot_mask;
+        page.is_bid_bits &= !slot_mask;
+
+        Ok(())
+    }
+
+    fn find_order_slot(&self, order_id: u128) -> Option<u16> {
+        self.occupied_slots()
+            .find(|&slot| *self.order_id(slot) == order_id)
+    }
+
+    fn find_client_order_slot(&self, client_order_id: u64) -> Option<u16> {
+        self.occupied_slots()
+            .find(|&slot| self.client_order_id(slot) == client_order_id)
+    }
+
+    fn add_order(&mut self, id: u128, side: Side) -> DexResult<u16> {
+        let page = self
+            .pages()
+            .position(|page| page.free_slot_bits != 0)
+            .ok_or(DexErrorCode::TooManyOpenOrders)?;
+        let first_slot = (page * 128) as u16;
+        let slot = first_slot + self.page(first_slot).0.free_slot_bits.trailing_zeros() as u16;
+        check_assert!(self.slot_is_free(slot))?;
+        let (page, index) = self.page_mut(slot);
+        let slot_mask = 1u128 << index;
+        page.free_slot_bits &= !slot_mask;
+        match side {
+            Side::Bid => {
+                page.is_bid_bits |= slot_mask;
+            }
+            Side::Ask => {
+                page.is_bid_bits &= !slot_mask;
+            }
+        };
+        page.orders[index] = id;
+        Ok(slot)
+    }
+}
+
+pub trait QueueHeader: Pod {
+    type Item: Pod + Copy;
+
+    fn head(&self) -> u64;
+    fn head_mut(&mut self) -> &mut u64;
+    fn count(&self) -> u64;
+    fn count_mut(&mut self) -> &mut u64;
+
+    fn incr_event_id(&mut self);
+    fn decr_event_id(&mut self, n: u64);
+}
+
+pub struct Queue<'a, H: QueueHeader> {
+    header: RefMut<'a, H>,
+    buf: RefMut<'a, [H::Item]>,
+}
+
+impl<'a, H: QueueHeader> Queue<'a, H> {
+    pub fn new(header: RefMut<'a, H>, buf: RefMut<'a, [H::Item]>) -> Self {
+        Self { header, buf }
+    }
+
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.header.count()
+    }
+
+    #[inline]
+    pub fn full(&self) -> bool {
+        self.header.count() as usize == self.buf.len()
+    }
+
+    #[inline]
+    pub fn empty(&self) -> bool {
+        self.header.count() == 0
+    }
+
+    #[inline]
+    pub fn free_slots(&self) -> u64 {
+        self.buf.len() as u64 - self.header.count()
     }
 
     #[inline]
@@ -586,10 +1379,32 @@ impl<'a, H: QueueHeader> Queue<'a, H> {
         Ok(())
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = &H::Item> {
+    /// Drops the items pushed since the queue was `len` long that `keep` returns false
+    /// for, leaving the rest in the order they were pushed.
+    pub fn retain_pushes(
+        &mut self,
+        len: u64,
+        mut keep: impl FnMut(&H::Item) -> DexResult<bool>,
+    ) -> DexResult<()> {
+        check_assert!(len <= self.header.count())?;
+        let head = self.header.head();
+        let buf_len = self.buf.len() as u64;
+        let mut kept_len = len;
+        for index in len..self.header.count() {
+            let item = self.buf[((head + index) % buf_len) as usize];
+            if keep(&item)? {
+                self.buf[((head + kept_len) % buf_len) as usize] = item;
+                kept_len += 1;
+            }
+        }
+        self.revert_pushes(kept_len)
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &H::Item> {
         QueueIterator {
             queue: self,
             index: 0,
+            end: self.len(),
         }
     }
 }
@@ -597,22 +1412,39 @@ impl<'a, H: QueueHeader> Queue<'a, H> {
 struct QueueIterator<'a, 'b, H: QueueHeader> {
     queue: &'b Queue<'a, H>,
     index: u64,
+    end: u64,
+}
+
+impl<'a, 'b, H: QueueHeader> QueueIterator<'a, 'b, H> {
+    fn item(&self, index: u64) -> &'b H::Item {
+        &self.queue.buf[(self.queue.header.head() + index) as usize % self.queue.buf.len()]
+    }
 }
 
 impl<'a, 'b, H: QueueHeader> Iterator for QueueIterator<'a, 'b, H> {
     type Item = &'b H::Item;
     fn next(&mut self) -> Option<Self::Item> {
-        if self.index == self.queue.len() {
+        if self.index == self.end {
             None
         } else {
-            let item = &self.queue.buf
-                [(self.queue.header.head() + self.index) as usize % self.queue.buf.len()];
+            let item = self.item(self.index);
             self.index += 1;
             Some(item)
         }
     }
 }
 
+impl<'a, 'b, H: QueueHeader> DoubleEndedIterator for QueueIterator<'a, 'b, H> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.index == self.end {
+            None
+        } else {
+            self.end -= 1;
+            Some(self.item(self.end))
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 #[repr(C)]
 pub struct RequestQueueHeader {
@@ -648,7 +1480,7 @@ impl QueueHeader for RequestQueueHeader {
 pub type RequestQueue<'a> = Queue<'a, RequestQueueHeader>;
 
 impl RequestQueue<'_> {
-    fn gen_order_id(&mut self, limit_price: u64, side: Side) -> u128 {
+    pub fn gen_order_id(&mut self, limit_price: u64, side: Side) -> u128 {
         let seq_num = self.gen_seq_num();
         let upper = (limit_price as u128) << 64;
         let lower = match side {
@@ -658,6 +1490,33 @@ impl RequestQueue<'_> {
         upper | (lower as u128)
     }
 
+    /// Copies the requests of a queue of 80-byte requests, as request queues were
+    /// before they grew, into this empty one, in the order they were queued.
+    pub fn copy_legacy_requests(
+        &mut self,
+        legacy_header: &RequestQueueHeader,
+        legacy_buf: &[u8],
+    ) -> DexResult {
+        let legacy_capacity = (legacy_buf.len() / LEGACY_REQUEST_SIZE) as u64;
+        check_assert!(self.empty())?;
+        check_assert!(legacy_header.head < legacy_capacity)?;
+        check_assert!(legacy_header.count <= legacy_capacity)?;
+        if legacy_header.count > self.buf.len() as u64 {
+            Err(DexErrorCode::RequestQueueFull)?
+        }
+        for index in 0..legacy_header.count {
+            let offset =
+                ((legacy_header.head + index) % legacy_capacity) as usize * LEGACY_REQUEST_SIZE;
+            let mut request = Request::zeroed();
+            bytes_of_mut(&mut request)[..LEGACY_REQUEST_SIZE]
+                .copy_from_slice(&legacy_buf[offset..offset + LEGACY_REQUEST_SIZE]);
+            self.push_back(request)
+                .map_err(|_| DexErrorCode::RequestQueueFull)?;
+        }
+        self.header.next_seq_num = legacy_header.next_seq_num;
+        Ok(())
+    }
+
     fn gen_seq_num(&mut self) -> u64 {
         let seq_num = self.header.next_seq_num;
         self.header.next_seq_num += 1;
@@ -674,21 +1533,30 @@ enum RequestFlag {
     PostOnly = 0x08,
     ImmediateOrCancel = 0x10,
     DecrementTakeOnSelfTrade = 0x20,
+    FillOrKill = 0x40,
+    ReplaceOrder = 0x80,
 }
 
 #[derive(Copy, Clone, Debug)]
 #[repr(C)]
 pub struct Request {
     request_flags: u8,
+    // the low byte of the owner slot is where the whole of it was in 80-byte requests
     owner_slot: u8,
     fee_tier: u8,
     self_trade_behavior: u8,
-    padding: [u8; 4],
+    owner_slot_hi: u8,
+    padding: [u8; 1],
+    replaced_owner_slot: u16,
     max_coin_qty_or_cancel_id: u64,
     native_pc_qty_locked: u64,
     order_id: u128,
     owner: [u64; 4],
     client_order_id: u64,
+    expiry_slot: u64,
+    expiry_unix_timestamp: i64,
+    replaced_order_id: u128,
+    display_qty: u64,
 }
 unsafe impl Zeroable for Request {}
 unsafe impl Pod for Request {}
@@ -698,7 +1566,7 @@ pub enum RequestView<'a> {
     NewOrder {
         side: Side,
         order_type: OrderType,
-        owner_slot: u8,
+        owner_slot: u16,
         fee_tier: FeeTier,
         order_id: &'a u128,
         max_coin_qty: NonZeroU64,
@@ -706,12 +1574,17 @@ pub enum RequestView<'a> {
         owner: &'a [u64; 4],
         client_order_id: Option<NonZeroU64>,
         self_trade_behavior: SelfTradeBehavior,
+        expiry: Option<OrderExpiry>,
+        // the order id and owner slot of the order this one replaces
+        replaces: Option<(&'a u128, u16)>,
+        // the visible size of an iceberg order
+        display_qty: Option<NonZeroU64>,
     },
     CancelOrder {
         side: Side,
         order_id: &'a u128,
         cancel_id: u64,
-        expected_owner_slot: u8,
+        expected_owner_slot: u16,
         expected_owner: &'a [u64; 4],
         client_order_id: Option<NonZeroU64>,
     },
@@ -732,28 +1605,45 @@ impl Request {
                 native_pc_qty_locked,
                 client_order_id,
                 self_trade_behavior,
+                expiry,
+                replaces,
+                display_qty,
             } => {
                 let mut flags = BitFlags::from_flag(RequestFlag::NewOrder);
                 if side == Side::Bid {
                     flags.insert(RequestFlag::Bid);
                 }
+                if replaces.is_some() {
+                    flags.insert(RequestFlag::ReplaceOrder);
+                }
                 match order_type {
                     OrderType::PostOnly => flags |= RequestFlag::PostOnly,
                     OrderType::ImmediateOrCancel => flags |= RequestFlag::ImmediateOrCancel,
+                    OrderType::FillOrKill => flags |= RequestFlag::FillOrKill,
                     OrderType::Limit => (),
                 };
 
+                let (expiry_slot, expiry_unix_timestamp) = OrderExpiry::into_parts(expiry);
+                let (replaced_order_id, replaced_owner_slot) =
+                    replaces.map_or((0, 0), |(order_id, owner_slot)| (*order_id, owner_slot));
+                let [owner_slot, owner_slot_hi] = owner_slot.to_le_bytes();
                 Request {
                     request_flags: flags.bits(),
                     owner_slot,
+                    owner_slot_hi,
                     fee_tier: fee_tier.into(),
                     self_trade_behavior: self_trade_behavior.into(),
+                    replaced_owner_slot,
                     padding: Zeroable::zeroed(),
                     order_id: *order_id,
                     owner: *owner,
                     max_coin_qty_or_cancel_id: max_coin_qty.get(),
                     native_pc_qty_locked: native_pc_qty_locked.map_or(0, NonZeroU64::get),
                     client_order_id: client_order_id.map_or(0, NonZeroU64::get),
+                    expiry_slot,
+                    expiry_unix_timestamp,
+                    replaced_order_id,
+                    display_qty: display_qty.map_or(0, NonZeroU64::get),
                 }
             }
             RequestView::CancelOrder {
@@ -768,22 +1658,34 @@ impl Request {
                 if side == Side::Bid {
                     flags.insert(RequestFlag::Bid);
                 }
+                let [owner_slot, owner_slot_hi] = expected_owner_slot.to_le_bytes();
                 Request {
                     request_flags: flags.bits(),
                     max_coin_qty_or_cancel_id: cancel_id,
                     order_id: *order_id,
-                    owner_slot: expected_owner_slot,
+                    owner_slot,
+                    owner_slot_hi,
                     fee_tier: 0,
                     self_trade_behavior: 0,
                     owner: *expected_owner,
                     native_pc_qty_locked: 0,
+                    replaced_owner_slot: 0,
                     padding: Zeroable::zeroed(),
                     client_order_id: client_order_id.map_or(0, NonZeroU64::get),
+                    expiry_slot: 0,
+                    expiry_unix_timestamp: 0,
+                    replaced_order_id: 0,
+                    display_qty: 0,
                 }
             }
         }
     }
 
+    #[inline]
+    fn owner_slot(&self) -> u16 {
+        u16::from_le_bytes([self.owner_slot, self.owner_slot_hi])
+    }
+
     #[inline(always)]
     pub fn as_view(&self) -> DexResult<RequestView> {
         let flags = BitFlags::from_bits(self.request_flags).unwrap();
@@ -795,16 +1697,18 @@ impl Request {
         if flags.contains(RequestFlag::NewOrder) {
             let allowed_flags = {
                 use RequestFlag::*;
-                NewOrder | Bid | PostOnly | ImmediateOrCancel
+                NewOrder | Bid | PostOnly | ImmediateOrCancel | FillOrKill | ReplaceOrder
             };
             check_assert!(allowed_flags.contains(flags))?;
             let post_only = flags.contains(RequestFlag::PostOnly);
             let ioc = flags.contains(RequestFlag::ImmediateOrCancel);
-            let order_type = match (post_only, ioc) {
-                (true, false) => OrderType::PostOnly,
-                (false, true) => OrderType::ImmediateOrCancel,
-                (false, false) => OrderType::Limit,
-                (true, true) => unreachable!(),
+            let fok = flags.contains(RequestFlag::FillOrKill);
+            let order_type = match (post_only, ioc, fok) {
+                (true, false, false) => OrderType::PostOnly,
+                (false, true, false) => OrderType::ImmediateOrCancel,
+                (false, false, true) => OrderType::FillOrKill,
+                (false, false, false) => OrderType::Limit,
+                _ => unreachable!(),
             };
             let fee_tier = FeeTier::try_from_primitive(self.fee_tier).or(check_unreachable!())?;
             let self_trade_behavior =
@@ -813,7 +1717,7 @@ impl Request {
             Ok(RequestView::NewOrder {
                 side,
                 order_type,
-                owner_slot: self.owner_slot,
+                owner_slot: self.owner_slot(),
                 fee_tier,
                 self_trade_behavior,
                 order_id: &self.order_id,
@@ -821,6 +1725,13 @@ impl Request {
                 max_coin_qty: NonZeroU64::new(self.max_coin_qty_or_cancel_id).unwrap(),
                 native_pc_qty_locked: NonZeroU64::new(self.native_pc_qty_locked),
                 client_order_id: NonZeroU64::new(self.client_order_id),
+                expiry: OrderExpiry::from_parts(self.expiry_slot, self.expiry_unix_timestamp),
+                replaces: if flags.contains(RequestFlag::ReplaceOrder) {
+                    Some((&self.replaced_order_id, self.replaced_owner_slot))
+                } else {
+                    None
+                },
+                display_qty: NonZeroU64::new(self.display_qty),
             })
         } else {
             check_assert!(flags.contains(RequestFlag::CancelOrder))?;
@@ -833,7 +1744,7 @@ impl Request {
                 side,
                 cancel_id: self.max_coin_qty_or_cancel_id,
                 order_id: &self.order_id,
-                expected_owner_slot: self.owner_slot,
+                expected_owner_slot: self.owner_slot(),
                 expected_owner: &self.owner,
                 client_order_id: NonZeroU64::new(self.client_order_id),
             })
@@ -880,6 +1791,10 @@ impl QueueHeader for EventQueueHeader {
 
 pub type EventQueue<'a> = Queue<'a, EventQueueHeader>;
 
+/// MatchOrders only applies events right away while at most this many are queued, so
+/// that checking which accounts still have events waiting stays cheap.
+pub const MAX_AUTO_CONSUME_QUEUED_EVENTS: u64 = 64;
+
 #[derive(Copy, Clone, BitFlags, Debug)]
 #[repr(u8)]
 enum EventFlag {
@@ -887,6 +1802,8 @@ enum EventFlag {
     Out = 0x2,
     Bid = 0x4,
     Maker = 0x8,
+    Requeue = 0x10,
+    MarketParams = 0x20,
 }
 
 impl EventFlag {
@@ -912,11 +1829,14 @@ impl EventFlag {
 #[repr(C)]
 pub struct Event {
     event_flags: u8,
+    // the low byte of the owner slot is where the whole of it was before slots went
+    // past 256
     owner_slot: u8,
 
     fee_tier: u8,
+    owner_slot_hi: u8,
 
-    _padding: [u8; 5],
+    _padding: [u8; 4],
 
     native_qty_released: u64,
     native_qty_paid: u64,
@@ -955,9 +1875,11 @@ impl Event {
                 };
                 let event_flags =
                     (EventFlag::from_side(side) | EventFlag::Fill).bits() | maker_flag;
+                let [owner_slot, owner_slot_hi] = owner_slot.to_le_bytes();
                 Event {
                     event_flags,
                     owner_slot,
+                    owner_slot_hi,
                     fee_tier: fee_tier.into(),
 
                     _padding: Zeroable::zeroed(),
@@ -977,21 +1899,76 @@ impl Event {
                 side,
                 native_qty_unlocked,
                 native_qty_still_locked,
+                native_coin_qty_cancelled,
                 order_id,
                 owner,
                 owner_slot,
                 client_order_id,
             } => {
                 let event_flags = (EventFlag::from_side(side) | EventFlag::Out).bits();
+                let [owner_slot, owner_slot_hi] = owner_slot.to_le_bytes();
                 Event {
                     event_flags,
                     owner_slot,
+                    owner_slot_hi,
                     fee_tier: 0,
 
                     _padding: Zeroable::zeroed(),
 
                     native_qty_released: native_qty_unlocked,
                     native_qty_paid: native_qty_still_locked,
+                    native_fee_or_rebate: native_coin_qty_cancelled,
+
+                    order_id: *order_id,
+                    owner: *owner,
+                    client_order_id: client_order_id.map_or(0, NonZeroU64::get),
+                }
+            }
+
+            EventView::MarketParams {
+                fee_schedule,
+                pc_dust_threshold,
+                coin_lot_size,
+                pc_lot_size,
+            } => Event {
+                event_flags: BitFlags::from_flag(EventFlag::MarketParams).bits(),
+                owner_slot: 0,
+                owner_slot_hi: 0,
+                fee_tier: 0,
+
+                _padding: Zeroable::zeroed(),
+
+                native_qty_released: pc_dust_threshold,
+                native_qty_paid: 0,
+                native_fee_or_rebate: 0,
+
+                // there is no owner, so the fee schedule takes its place
+                order_id: ((coin_lot_size as u128) << 64) | pc_lot_size as u128,
+                owner: cast(fee_schedule),
+                client_order_id: 0,
+            },
+
+            EventView::Requeue {
+                side,
+                order_id,
+                new_order_id,
+                owner,
+                owner_slot,
+                client_order_id,
+            } => {
+                let event_flags = (EventFlag::from_side(side) | EventFlag::Requeue).bits();
+                let [owner_slot, owner_slot_hi] = owner_slot.to_le_bytes();
+                Event {
+                    event_flags,
+                    owner_slot,
+                    owner_slot_hi,
+                    fee_tier: 0,
+
+                    _padding: Zeroable::zeroed(),
+
+                    // the new order id is split across the quantity fields
+                    native_qty_released: new_order_id as u64,
+                    native_qty_paid: (new_order_id >> 64) as u64,
                     native_fee_or_rebate: 0,
 
                     order_id: *order_id,
@@ -1002,6 +1979,11 @@ impl Event {
         }
     }
 
+    #[inline]
+    fn owner_slot(&self) -> u16 {
+        u16::from_le_bytes([self.owner_slot, self.owner_slot_hi])
+    }
+
     #[inline(always)]
     pub fn as_view(&self) -> DexResult<EventView> {
         let flags = BitFlags::from_bits(self.event_flags).unwrap();
@@ -1024,11 +2006,39 @@ impl Event {
                 order_id: &self.order_id,
                 owner: &self.owner,
 
-                owner_slot: self.owner_slot,
+                owner_slot: self.owner_slot(),
                 fee_tier: self.fee_tier.try_into().or(check_unreachable!())?,
                 client_order_id,
             });
         }
+        if flags.contains(EventFlag::MarketParams) {
+            check_assert_eq!(&flags, &BitFlags::from_flag(EventFlag::MarketParams))?;
+
+            return Ok(EventView::MarketParams {
+                fee_schedule: cast(self.owner),
+                pc_dust_threshold: self.native_qty_released,
+                coin_lot_size: (self.order_id >> 64) as u64,
+                pc_lot_size: self.order_id as u64,
+            });
+        }
+        if flags.contains(EventFlag::Requeue) {
+            let allowed_flags = {
+                use EventFlag::*;
+                Requeue | Bid
+            };
+            check_assert!(allowed_flags.contains(flags))?;
+
+            return Ok(EventView::Requeue {
+                side,
+                order_id: &self.order_id,
+                new_order_id: ((self.native_qty_paid as u128) << 64)
+                    | self.native_qty_released as u128,
+                owner: &self.owner,
+
+                owner_slot: self.owner_slot(),
+                client_order_id,
+            });
+        }
         let allowed_flags = {
             use EventFlag::*;
             Out | Bid | Maker
@@ -1038,11 +2048,12 @@ impl Event {
             side,
             native_qty_unlocked: self.native_qty_released,
             native_qty_still_locked: self.native_qty_paid,
+            native_coin_qty_cancelled: self.native_fee_or_rebate,
 
             order_id: &self.order_id,
             owner: &self.owner,
 
-            owner_slot: self.owner_slot,
+            owner_slot: self.owner_slot(),
             client_order_id,
         })
     }
@@ -1058,7 +2069,7 @@ pub enum EventView<'a> {
         native_fee_or_rebate: u64,
         order_id: &'a u128,
         owner: &'a [u64; 4],
-        owner_slot: u8,
+        owner_slot: u16,
         fee_tier: FeeTier,
         client_order_id: Option<NonZeroU64>,
     },
@@ -1066,17 +2077,40 @@ pub enum EventView<'a> {
         side: Side,
         native_qty_unlocked: u64,
         native_qty_still_locked: u64,
+        // the coin the order no longer stands to buy or sell, 0 if it only filled
+        native_coin_qty_cancelled: u64,
+        order_id: &'a u128,
+        owner: &'a [u64; 4],
+        owner_slot: u16,
+        client_order_id: Option<NonZeroU64>,
+    },
+    // An order went back on the book under `new_order_id`: an iceberg order whose
+    // displayed quantity filled shows the next slice of its reserve, and a pegged order
+    // moves to a new price (or leaves the pegged orders slab for the book).
+    Requeue {
+        side: Side,
         order_id: &'a u128,
+        new_order_id: u128,
         owner: &'a [u64; 4],
-        owner_slot: u8,
+        owner_slot: u16,
         client_order_id: Option<NonZeroU64>,
     },
+    // The market authority changed the market's parameters, which are now these.
+    MarketParams {
+        fee_schedule: FeeSchedule,
+        pc_dust_threshold: u64,
+        coin_lot_size: u64,
+        pc_lot_size: u64,
+    },
 }
 
 impl<'a> EventView<'a> {
-    fn side(&self) -> Side {
+    fn side(&self) -> Option<Side> {
         match self {
-            &EventView::Fill { side, .. } | &EventView::Out { side, .. } => side,
+            &EventView::Fill { side, .. }
+            | &EventView::Out { side, .. }
+            | &EventView::Requeue { side, .. } => Some(side),
+            &EventView::MarketParams { .. } => None,
         }
     }
 }
@@ -1089,6 +2123,120 @@ struct OrderBookStateHeader {
 unsafe impl Zeroable for OrderBookStateHeader {}
 unsafe impl Pod for OrderBookStateHeader {}
 
+pub const MARKET_STATS_CANDLES: usize = 96;
+
+/// Prices are in lots, like `MarketState::last_fill_price`. A candle that hasn't
+/// seen a fill yet has an `open` of 0.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[repr(C)]
+pub struct Candle {
+    pub start_slot: u64,
+    pub open: u64,
+    pub high: u64,
+    pub low: u64,
+    pub close: u64,
+    pub native_coin_volume: u64,
+    pub native_pc_volume: u64,
+}
+unsafe impl Zeroable for Candle {}
+unsafe impl Pod for Candle {}
+
+/// Kept up to date by `MatchOrders` for other programs to read prices from.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct MarketStats {
+    pub account_flags: u64, // Initialized, MarketStats
+    pub market: [u64; 4],
+    pub slots_per_candle: u64,
+
+    pub last_fill_price: u64,
+    pub last_fill_slot: u64,
+
+    // these wrap, so take the difference of two readings with wrapping_sub
+    pub native_coin_volume: u64,
+    pub native_pc_volume: u64,
+
+    // the candles are a ring, with the newest at this index
+    pub newest_candle: u64,
+    pub candles: [Candle; MARKET_STATS_CANDLES],
+}
+unsafe impl Zeroable for MarketStats {}
+unsafe impl Pod for MarketStats {}
+
+impl MarketStats {
+    fn check_flags(&self) -> DexResult {
+        let flags = BitFlags::from_bits(self.account_flags)
+            .map_err(|_| DexErrorCode::WrongMarketStatsAccount)?;
+        if flags != AccountFlag::Initialized | AccountFlag::MarketStats {
+            Err(DexErrorCode::WrongMarketStatsAccount)?
+        }
+        Ok(())
+    }
+
+    pub fn newest_candle(&self) -> &Candle {
+        &self.candles[self.newest_candle as usize % MARKET_STATS_CANDLES]
+    }
+
+    /// Iterates over the candles that have seen fills, newest first.
+    pub fn candles(&self) -> impl Iterator<Item = &Candle> {
+        let newest = self.newest_candle as usize % MARKET_STATS_CANDLES;
+        (0..MARKET_STATS_CANDLES)
+            .map(move |age| {
+                &self.candles[(newest + MARKET_STATS_CANDLES - age) % MARKET_STATS_CANDLES]
+            })
+            .take_while(|candle| candle.open != 0)
+    }
+
+    /// Coin and price currency volume in candles starting at or after `slot`,
+    /// e.g. a day's worth of slots ago for 24 hour volume. Only as far back as
+    /// the oldest candle still kept.
+    pub fn volume_since(&self, slot: u64) -> (u64, u64) {
+        self.candles()
+            .take_while(|candle| candle.start_slot >= slot)
+            .fold((0, 0), |(coin, pc), candle| {
+                (
+                    coin.wrapping_add(candle.native_coin_volume),
+                    pc.wrapping_add(candle.native_pc_volume),
+                )
+            })
+    }
+
+    pub(crate) fn record_fill(&mut self, slot: u64, price: u64, native_coin: u64, native_pc: u64) {
+        self.last_fill_price = price;
+        self.last_fill_slot = slot;
+        self.native_coin_volume = self.native_coin_volume.wrapping_add(native_coin);
+        self.native_pc_volume = self.native_pc_volume.wrapping_add(native_pc);
+
+        let start_slot = slot - slot % self.slots_per_candle;
+        let newest = self.newest_candle as usize % MARKET_STATS_CANDLES;
+        let current = self.candles[newest];
+        let index = if current.open == 0 || current.start_slot == start_slot {
+            newest
+        } else {
+            (newest + 1) % MARKET_STATS_CANDLES
+        };
+        self.newest_candle = index as u64;
+
+        let candle = &mut self.candles[index];
+        if candle.open == 0 || candle.start_slot != start_slot {
+            *candle = Candle {
+                start_slot,
+                open: price,
+                high: price,
+                low: price,
+                close: price,
+                native_coin_volume: 0,
+                native_pc_volume: 0,
+            };
+        }
+        candle.high = candle.high.max(price);
+        candle.low = candle.low.min(price);
+        candle.close = price;
+        candle.native_coin_volume = candle.native_coin_volume.wrapping_add(native_coin);
+        candle.native_pc_volume = candle.native_pc_volume.wrapping_add(native_pc);
+    }
+}
+
 pub enum State {}
 
 fn gen_vault_signer_seeds<'a>(nonce: &'a u64, market: &'a Pubkey) -> [&'a [u8]; 2] {
@@ -1116,7 +2264,7 @@ pub fn gen_vault_signer_key(
     Ok(Pubkey::default())
 }
 
-#[cfg(not(feature = "fuzz"))]
+#[cfg(not(any(test, feature = "fuzz")))]
 #[cfg(feature = "program")]
 fn invoke_spl_token(
     instruction: &solana_sdk::instruction::Instruction,
@@ -1126,11 +2274,92 @@ fn invoke_spl_token(
     solana_sdk::program::invoke_signed(instruction, account_infos, signers_seeds)
 }
 
+#[cfg(not(any(test, feature = "fuzz")))]
+#[cfg(feature = "program")]
+fn invoke_system(
+    instruction: &solana_sdk::instruction::Instruction,
+    account_infos: &[AccountInfo],
+) -> solana_sdk::entrypoint::ProgramResult {
+    solana_sdk::program::invoke(instruction, account_infos)
+}
+
+#[cfg(feature = "fuzz")]
+fn invoke_system(
+    instruction: &solana_sdk::instruction::Instruction,
+    _account_infos: &[AccountInfo],
+) -> solana_sdk::entrypoint::ProgramResult {
+    assert_eq!(instruction.program_id, system_program::ID);
+    // the fuzzer's accounts can't be resized or handed to another owner, so system
+    // instructions fail the way they would on an account that can't be created
+    Err(ProgramError::InvalidArgument)
+}
+
 #[cfg(feature = "fuzz")]
 fn invoke_spl_token(
     instruction: &solana_sdk::instruction::Instruction,
     account_infos: &[AccountInfo],
     _signers_seeds: &[&[&[u8]]],
+) -> solana_sdk::entrypoint::ProgramResult {
+    process_spl_token_instruction(instruction, account_infos)
+}
+
+#[cfg(test)]
+thread_local! {
+    // Most tests don't fund their token accounts, so invoked programs only run for the
+    // tests that turn this on and check balances.
+    pub(crate) static RUN_INVOKED_PROGRAMS: std::cell::Cell<bool> = std::cell::Cell::new(false);
+}
+
+#[cfg(all(test, not(feature = "fuzz")))]
+fn invoke_spl_token(
+    instruction: &solana_sdk::instruction::Instruction,
+    account_infos: &[AccountInfo],
+    _signers_seeds: &[&[&[u8]]],
+) -> solana_sdk::entrypoint::ProgramResult {
+    if !RUN_INVOKED_PROGRAMS.with(|run| run.get()) {
+        return Ok(());
+    }
+    process_spl_token_instruction(instruction, account_infos)
+}
+
+#[cfg(all(test, not(feature = "fuzz")))]
+fn invoke_system(
+    instruction: &solana_sdk::instruction::Instruction,
+    account_infos: &[AccountInfo],
+) -> solana_sdk::entrypoint::ProgramResult {
+    if !RUN_INVOKED_PROGRAMS.with(|run| run.get()) {
+        return Ok(());
+    }
+    assert_eq!(instruction.program_id, system_program::ID);
+    let find_account = |meta: &solana_sdk::instruction::AccountMeta| {
+        account_infos
+            .iter()
+            .find(|info| *info.key == meta.pubkey)
+            .unwrap()
+    };
+    // only accounts are ever created, and tests pass them already sized and owned
+    match bincode::deserialize(&instruction.data) {
+        Ok(system_instruction::SystemInstruction::CreateAccount {
+            lamports,
+            space,
+            owner,
+        }) => {
+            let from = find_account(&instruction.accounts[0]);
+            let to = find_account(&instruction.accounts[1]);
+            assert_eq!(to.data_len(), space as usize);
+            assert_eq!(to.owner, &owner);
+            **from.try_borrow_mut_lamports()? -= lamports;
+            **to.try_borrow_mut_lamports()? += lamports;
+            Ok(())
+        }
+        _ => Err(ProgramError::InvalidInstructionData),
+    }
+}
+
+#[cfg(any(test, feature = "fuzz"))]
+fn process_spl_token_instruction(
+    instruction: &solana_sdk::instruction::Instruction,
+    account_infos: &[AccountInfo],
 ) -> solana_sdk::entrypoint::ProgramResult {
     assert_eq!(instruction.program_id, spl_token::ID);
     let account_infos: Vec<AccountInfo> = instruction
@@ -1182,6 +2411,152 @@ fn send_from_vault<'a, 'b: 'a>(
     Ok(())
 }
 
+// the vault's rent goes to `destination`
+#[cfg(not(feature = "client"))]
+fn close_vault<'a, 'b: 'a>(
+    vault: account_parser::TokenAccount<'a, 'b>,
+    destination: account_parser::SignerAccount<'a, 'b>,
+    spl_token_program: account_parser::SplTokenProgram<'a, 'b>,
+    vault_signer: account_parser::VaultSigner<'a, 'b>,
+    vault_signer_seeds: &[&[u8]],
+) -> DexResult {
+    let close_instruction = spl_token::instruction::close_account(
+        &spl_token::ID,
+        vault.inner().key,
+        destination.inner().key,
+        vault_signer.inner().key,
+        &[],
+    )?;
+    let accounts: &[AccountInfo] = &[
+        vault.inner().clone(),
+        destination.inner().clone(),
+        vault_signer.inner().clone(),
+        spl_token_program.inner().clone(),
+    ];
+    invoke_spl_token(&close_instruction, accounts, &[vault_signer_seeds])
+        .map_err(|_| DexErrorCode::TransferFailed)?;
+    Ok(())
+}
+
+/// Sends wrapped SOL from `vault` to `owner` as plain lamports, by way of a token
+/// account at `native_account` that only exists for the duration of the call.
+#[cfg(feature = "program")]
+fn unwrap_from_vault<'a, 'b: 'a>(
+    native_amount: u64,
+    owner: account_parser::SignerAccount<'a, 'b>,
+    native_account: account_parser::SignerAccount<'a, 'b>,
+    native_mint: account_parser::TokenMint<'a, 'b>,
+    vault: account_parser::TokenAccount<'a, 'b>,
+    spl_token_program: account_parser::SplTokenProgram<'a, 'b>,
+    system_program: account_parser::SystemProgram<'a, 'b>,
+    rent_sysvar: account_parser::RentSysvarAccount<'a, 'b>,
+    rent: &Rent,
+    vault_signer: account_parser::VaultSigner<'a, 'b>,
+    vault_signer_seeds: &[&[u8]],
+) -> DexResult {
+    let owner = owner.inner();
+    let native_account = native_account.inner();
+    let token_account_len = spl_token::state::Account::LEN;
+
+    let create_instruction = system_instruction::create_account(
+        owner.key,
+        native_account.key,
+        rent.minimum_balance(token_account_len),
+        token_account_len as u64,
+        &spl_token::ID,
+    );
+    let accounts: &[AccountInfo] = &[
+        owner.clone(),
+        native_account.clone(),
+        system_program.inner().clone(),
+    ];
+    invoke_system(&create_instruction, accounts).map_err(|_| DexErrorCode::TransferFailed)?;
+
+    let init_instruction = spl_token::instruction::initialize_account(
+        &spl_token::ID,
+        native_account.key,
+        native_mint.inner().key,
+        owner.key,
+    )?;
+    let accounts: &[AccountInfo] = &[
+        native_account.clone(),
+        native_mint.inner().clone(),
+        owner.clone(),
+        rent_sysvar.inner().clone(),
+        spl_token_program.inner().clone(),
+    ];
+    invoke_spl_token(&init_instruction, accounts, &[]).map_err(|_| DexErrorCode::TransferFailed)?;
+
+    let transfer_instruction = spl_token::instruction::transfer(
+        &spl_token::ID,
+        vault.inner().key,
+        native_account.key,
+        vault_signer.inner().key,
+        &[],
+        native_amount,
+    )?;
+    let accounts: &[AccountInfo] = &[
+        vault.inner().clone(),
+        native_account.clone(),
+        vault_signer.inner().clone(),
+        spl_token_program.inner().clone(),
+    ];
+    invoke_spl_token(&transfer_instruction, accounts, &[vault_signer_seeds])
+        .map_err(|_| DexErrorCode::TransferFailed)?;
+
+    // closing a wrapped SOL account hands its rent and balance back as lamports
+    let close_instruction = spl_token::instruction::close_account(
+        &spl_token::ID,
+        native_account.key,
+        owner.key,
+        owner.key,
+        &[],
+    )?;
+    let accounts: &[AccountInfo] = &[
+        native_account.clone(),
+        owner.clone(),
+        spl_token_program.inner().clone(),
+    ];
+    invoke_spl_token(&close_instruction, accounts, &[])
+        .map_err(|_| DexErrorCode::TransferFailed)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "client"))]
+fn deposit_to_vault<'a, 'b: 'a>(
+    native_amount: u64,
+    payer: account_parser::TokenAccount<'a, 'b>,
+    vault: account_parser::TokenAccount<'a, 'b>,
+    owner: account_parser::SignerAccount<'a, 'b>,
+    spl_token_program: account_parser::SplTokenProgram<'a, 'b>,
+) -> DexResult {
+    // pull balances from payer, signed by owner
+    let deposit_instruction = spl_token::instruction::transfer(
+        &spl_token::ID,
+        payer.inner().key,
+        vault.inner().key,
+        owner.inner().key,
+        &[],
+        native_amount,
+    )
+    .unwrap();
+    assert_eq!(*spl_token_program.inner().key, spl_token::ID);
+    let accounts: &[AccountInfo] = &[
+        payer.inner().clone(),
+        vault.inner().clone(),
+        owner.inner().clone(),
+        spl_token_program.inner().clone(),
+    ];
+    invoke_spl_token(&deposit_instruction, &accounts[..], &[]).map_err(|err| match err {
+        ProgramError::Custom(i) => match TokenError::from_u32(i) {
+            Some(TokenError::InsufficientFunds) => DexErrorCode::InsufficientFunds,
+            _ => DexErrorCode::TransferFailed,
+        },
+        _ => DexErrorCode::TransferFailed,
+    })?;
+    Ok(())
+}
+
 pub mod account_parser {
     use super::*;
 
@@ -1265,6 +2640,29 @@ pub mod account_parser {
         Ok(())
     });
 
+    declare_validated_account_wrapper!(ClockSysvarAccount, |account: &AccountInfo| {
+        check_assert!(Clock::check_id(account.key))?;
+        Ok(())
+    });
+
+    // Older clients don't pass the clock, so it's recognized by its key among the optional accounts.
+    fn split_optional_clock<'a, 'b>(
+        accounts: &'a [AccountInfo<'b>],
+    ) -> DexResult<(Option<Clock>, &'a [AccountInfo<'b>])> {
+        match accounts.split_first() {
+            Some((account, rest)) if Clock::check_id(account.key) => {
+                let clock = Clock::from_account_info(account).or(check_unreachable!())?;
+                Ok((Some(clock), rest))
+            }
+            _ => Ok((None, accounts)),
+        }
+    }
+
+    declare_validated_account_wrapper!(SystemProgram, |account: &AccountInfo| {
+        check_assert!(system_program::check_id(account.key))?;
+        Ok(())
+    });
+
     declare_validated_account_wrapper!(SignerAccount, |account: &AccountInfo| {
         check_assert!(account.is_signer)?;
         Ok(())
@@ -1354,10 +2752,24 @@ pub mod account_parser {
         }
     }
 
+    fn check_uninitialized(account: &AccountInfo, program_id: &Pubkey) -> DexResult {
+        check_assert_eq!(account.owner, program_id)?;
+        let data = account.try_borrow_data()?;
+        check_assert_eq!(data.len() % 8, 4)?;
+        check_assert!(data.len() >= 20)?;
+        let (padding5, header, _, padding7) = array_refs![&data, 5, 8; .. ; 7];
+        check_assert_eq!(*padding5, [0u8; 5])?;
+        check_assert_eq!(*header, [0u8; 8])?;
+        check_assert_eq!(*padding7, [0u8; 7])?;
+        Ok(())
+    }
+
     pub struct InitializeMarketArgs<'a, 'b: 'a> {
         pub program_id: &'a Pubkey,
         pub instruction: &'a InitializeMarketInstruction,
         serum_dex_accounts: &'a [AccountInfo<'b>; 5],
+        stop_orders: Option<&'a AccountInfo<'b>>,
+        pegged_orders: Option<&'a AccountInfo<'b>>,
         pub coin_vault_and_mint: TokenAccountAndMint<'a, 'b>,
         pub pc_vault_and_mint: TokenAccountAndMint<'a, 'b>,
     }
@@ -1368,20 +2780,38 @@ pub mod account_parser {
             instruction: &'a InitializeMarketInstruction,
             accounts: &'a [AccountInfo<'b>],
         ) -> DexResult<Self> {
-            check_assert_eq!(accounts.len(), 9)?;
-            let accounts = array_ref![accounts, 0, 9];
+            check_assert!(accounts.len() >= 9 && accounts.len() <= 11)?;
+            let (accounts, unchecked_order_slabs): (
+                &'a [AccountInfo<'b>; 9],
+                &'a [AccountInfo<'b>],
+            ) = array_refs![accounts, 9; .. ;];
             let (unchecked_serum_dex_accounts, unchecked_vaults, unchecked_mints) =
                 array_refs![accounts, 5, 2, 2];
+            let (stop_orders, pegged_orders) = match instruction.order_slabs {
+                Some(order_slabs) => {
+                    let mut unchecked_order_slabs = unchecked_order_slabs.iter();
+                    let mut next_if = |passed: bool| -> DexResult<_> {
+                        if !passed {
+                            return Ok(None);
+                        }
+                        Ok(Some(
+                            unchecked_order_slabs.next().ok_or(assertion_error!())?,
+                        ))
+                    };
+                    let stop_orders = next_if(order_slabs.stop_orders)?;
+                    let pegged_orders = next_if(order_slabs.pegged_orders)?;
+                    check_assert!(unchecked_order_slabs.next().is_none())?;
+                    (stop_orders, pegged_orders)
+                }
+                None => (unchecked_order_slabs.get(0), unchecked_order_slabs.get(1)),
+            };
             let mut checked_vaults = [None, None];
-            for account in unchecked_serum_dex_accounts {
-                check_assert_eq!(account.owner, program_id)?;
-                let data = account.try_borrow_data()?;
-                check_assert_eq!(data.len() % 8, 4)?;
-                check_assert!(data.len() >= 20)?;
-                let (padding5, header, _, padding7) = array_refs![&data, 5, 8; .. ; 7];
-                check_assert_eq!(*padding5, [0u8; 5])?;
-                check_assert_eq!(*header, [0u8; 8])?;
-                check_assert_eq!(*padding7, [0u8; 7])?;
+            for account in unchecked_serum_dex_accounts
+                .iter()
+                .chain(stop_orders)
+                .chain(pegged_orders)
+            {
+                check_uninitialized(account, program_id)?;
             }
             let serum_dex_accounts = unchecked_serum_dex_accounts;
             let vault_owner_key_bytes = gen_vault_signer_key(
@@ -1414,6 +2844,8 @@ pub mod account_parser {
                 program_id,
                 instruction,
                 serum_dex_accounts,
+                stop_orders,
+                pegged_orders,
                 coin_vault_and_mint,
                 pc_vault_and_mint,
             })
@@ -1442,12 +2874,21 @@ pub mod account_parser {
         pub fn get_asks(&self) -> &'a AccountInfo<'b> {
             &self.serum_dex_accounts[4]
         }
+
+        pub fn get_stop_orders(&self) -> Option<&'a AccountInfo<'b>> {
+            self.stop_orders
+        }
+
+        pub fn get_pegged_orders(&self) -> Option<&'a AccountInfo<'b>> {
+            self.pegged_orders
+        }
     }
 
     pub struct NewOrderArgs<'a, 'b: 'a> {
         pub instruction: &'a NewOrderInstructionV2,
+        pub trigger_price: Option<NonZeroU64>,
         pub market: &'a mut MarketState,
-        pub open_orders: &'a mut OpenOrders,
+        pub open_orders: OpenOrdersMut<'a>,
         pub open_orders_address: &'a [u64; 4],
         pub owner: SignerAccount<'a, 'b>,
         pub req_q: RequestQueue<'a>,
@@ -1456,16 +2897,19 @@ pub mod account_parser {
         pub pc_vault: PcVault<'a, 'b>,
         pub spl_token_program: SplTokenProgram<'a, 'b>,
         pub fee_tier: FeeTier,
+        pub stop_orders: Option<&'a mut Slab>,
     }
     impl<'a, 'b: 'a> NewOrderArgs<'a, 'b> {
         pub fn with_parsed_args<T>(
             program_id: &'a Pubkey,
             instruction: &'a NewOrderInstructionV2,
+            trigger_price: Option<NonZeroU64>,
             accounts: &'a [AccountInfo<'b>],
             f: impl FnOnce(NewOrderArgs) -> DexResult<T>,
         ) -> DexResult<T> {
-            check_assert!(accounts.len() == 9 || accounts.len() == 10)?;
-            let (fixed_accounts, fee_discount_account): (
+            let fixed_accounts_len = 9 + trigger_price.is_some() as usize;
+            check_assert!((fixed_accounts_len..=fixed_accounts_len + 2).contains(&accounts.len()))?;
+            let (fixed_accounts, remaining_accounts): (
                 &'a [AccountInfo<'b>; 9],
                 &'a [AccountInfo<'b>],
             ) = array_refs![accounts, 9; .. ;];
@@ -1480,13 +2924,22 @@ pub mod account_parser {
                 ref spl_token_program_acc,
                 ref rent_sysvar_acc,
             ]: &'a [AccountInfo<'b>; 9] = fixed_accounts;
+            let (stop_orders_acc, fee_discount_account) = match trigger_price {
+                None => (None, remaining_accounts),
+                Some(_) => {
+                    let (stop_orders_acc, fee_discount_account) =
+                        remaining_accounts.split_first().ok_or(assertion_error!())?;
+                    (Some(stop_orders_acc), fee_discount_account)
+                }
+            };
+            let (clock, fee_discount_account) = split_optional_clock(fee_discount_account)?;
             let srm_or_msrm_account = match fee_discount_account {
                 &[] => None,
                 &[ref account] => Some(TokenAccount::new(account)?),
                 _ => check_unreachable!()?,
             };
 
-            let mut market: RefMut<'a, MarketState> = MarketState::load(market_acc, program_id)?;
+            let mut market: MarketStateMut<'a> = MarketState::load(market_acc, program_id)?;
             let rent = {
                 let rent_sysvar = RentSysvarAccount::new(rent_sysvar_acc)?;
                 Rent::from_account_info(rent_sysvar.inner()).or(check_unreachable!())?
@@ -1494,14 +2947,18 @@ pub mod account_parser {
             let owner = SignerAccount::new(owner_acc)?;
             let fee_tier =
                 market.load_fee_tier(&owner.inner().key.to_aligned_bytes(), srm_or_msrm_account)?;
-            let mut open_orders = market.load_orders_mut(
+            let open_orders = market.load_orders_mut_as_trader(
                 open_orders_acc,
-                Some(owner.inner()),
+                owner.inner(),
                 program_id,
                 Some(rent),
             )?;
             let ref open_orders_address = open_orders_acc.key.to_aligned_bytes();
             let req_q = market.load_request_queue_mut(req_q_acc)?;
+            let mut stop_orders = match stop_orders_acc {
+                Some(stop_orders_acc) => Some(market.load_stop_orders_mut(stop_orders_acc)?),
+                None => None,
+            };
 
             let payer = TokenAccount::new(payer_acc)?;
             match instruction.side {
@@ -1511,11 +2968,104 @@ pub mod account_parser {
             let coin_vault = CoinVault::from_account(coin_vault_acc, &market)?;
             let pc_vault = PcVault::from_account(pc_vault_acc, &market)?;
             market.check_enabled()?;
+            market.check_not_halted(clock.as_ref())?;
             let spl_token_program = SplTokenProgram::new(spl_token_program_acc)?;
             let args = NewOrderArgs {
+                instruction,
+                trigger_price,
+                market: market.deref_mut(),
+                open_orders,
+                open_orders_address,
+                owner,
+                req_q,
+                payer,
+                coin_vault,
+                pc_vault,
+                spl_token_program,
+                fee_tier,
+                stop_orders: stop_orders.as_deref_mut(),
+            };
+            f(args)
+        }
+    }
+
+    pub struct NewPeggedOrderArgs<'a, 'b: 'a> {
+        pub instruction: &'a NewPeggedOrderInstruction,
+        pub market: &'a mut MarketState,
+        pub open_orders: OpenOrdersMut<'a>,
+        pub open_orders_address: &'a [u64; 4],
+        pub owner: SignerAccount<'a, 'b>,
+        pub req_q: RequestQueue<'a>,
+        pub payer: TokenAccount<'a, 'b>,
+        pub coin_vault: CoinVault<'a, 'b>,
+        pub pc_vault: PcVault<'a, 'b>,
+        pub spl_token_program: SplTokenProgram<'a, 'b>,
+        pub fee_tier: FeeTier,
+        pub pegged_orders: &'a mut Slab,
+    }
+    impl<'a, 'b: 'a> NewPeggedOrderArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            instruction: &'a NewPeggedOrderInstruction,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(NewPeggedOrderArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert!((10..=12).contains(&accounts.len()))?;
+            let (fixed_accounts, remaining_accounts): (
+                &'a [AccountInfo<'b>; 10],
+                &'a [AccountInfo<'b>],
+            ) = array_refs![accounts, 10; .. ;];
+            let &[
+                ref market_acc,
+                ref open_orders_acc,
+                ref req_q_acc,
+                ref payer_acc,
+                ref owner_acc,
+                ref coin_vault_acc,
+                ref pc_vault_acc,
+                ref spl_token_program_acc,
+                ref rent_sysvar_acc,
+                ref pegged_orders_acc,
+            ]: &'a [AccountInfo<'b>; 10] = fixed_accounts;
+            let (clock, fee_discount_account) = split_optional_clock(remaining_accounts)?;
+            let srm_or_msrm_account = match fee_discount_account {
+                &[] => None,
+                &[ref account] => Some(TokenAccount::new(account)?),
+                _ => check_unreachable!()?,
+            };
+
+            let mut market: MarketStateMut<'a> = MarketState::load(market_acc, program_id)?;
+            let rent = {
+                let rent_sysvar = RentSysvarAccount::new(rent_sysvar_acc)?;
+                Rent::from_account_info(rent_sysvar.inner()).or(check_unreachable!())?
+            };
+            let owner = SignerAccount::new(owner_acc)?;
+            let fee_tier =
+                market.load_fee_tier(&owner.inner().key.to_aligned_bytes(), srm_or_msrm_account)?;
+            let open_orders = market.load_orders_mut_as_trader(
+                open_orders_acc,
+                owner.inner(),
+                program_id,
+                Some(rent),
+            )?;
+            let ref open_orders_address = open_orders_acc.key.to_aligned_bytes();
+            let req_q = market.load_request_queue_mut(req_q_acc)?;
+            let mut pegged_orders = market.load_pegged_orders_mut(pegged_orders_acc)?;
+
+            let payer = TokenAccount::new(payer_acc)?;
+            match instruction.side {
+                Side::Bid => market.check_pc_payer(payer).or(check_unreachable!())?,
+                Side::Ask => market.check_coin_payer(payer).or(check_unreachable!())?,
+            };
+            let coin_vault = CoinVault::from_account(coin_vault_acc, &market)?;
+            let pc_vault = PcVault::from_account(pc_vault_acc, &market)?;
+            market.check_enabled()?;
+            market.check_not_halted(clock.as_ref())?;
+            let spl_token_program = SplTokenProgram::new(spl_token_program_acc)?;
+            let args = NewPeggedOrderArgs {
                 instruction,
                 market: market.deref_mut(),
-                open_orders: open_orders.deref_mut(),
+                open_orders,
                 open_orders_address,
                 owner,
                 req_q,
@@ -1524,6 +3074,7 @@ pub mod account_parser {
                 pc_vault,
                 spl_token_program,
                 fee_tier,
+                pegged_orders: pegged_orders.deref_mut(),
             };
             f(args)
         }
@@ -1534,6 +3085,7 @@ pub mod account_parser {
         pub order_book_state: OrderBookState<'a>,
         pub req_q: RequestQueue<'a>,
         pub event_q: EventQueue<'a>,
+        pub open_orders_accounts: Vec<([u64; 4], OpenOrdersMut<'a>)>,
     }
     impl<'a> MatchOrdersArgs<'a> {
         pub fn with_parsed_args<'b, T>(
@@ -1542,29 +3094,76 @@ pub mod account_parser {
             limit: u16,
             f: impl FnOnce(MatchOrdersArgs) -> DexResult<T>,
         ) -> DexResult<T> {
-            check_assert!(accounts.len() >= 5)?;
+            check_assert!(accounts.len() >= 7)?;
             #[rustfmt::skip]
-            let &[
+            let (&[
                 ref market_acc,
                 ref req_q_acc,
                 ref event_q_acc,
                 ref bids_acc,
-                ref asks_acc
-            ] = array_ref![accounts, 0, 5];
+                ref asks_acc,
+                _,
+                _,
+            ], optional_accounts) = array_refs![accounts, 7; .. ;];
             let mut market = MarketState::load(market_acc, program_id).or(check_unreachable!())?;
-            let req_q = market
-                .load_request_queue_mut(req_q_acc)
-                .or(check_unreachable!())?;
             let event_q = market
                 .load_event_queue_mut(event_q_acc)
                 .or(check_unreachable!())?;
-            let mut bids = market.load_bids_mut(bids_acc).or(check_unreachable!())?;
-            let mut asks = market.load_asks_mut(asks_acc).or(check_unreachable!())?;
+            let req_q = market.load_request_queue_mut(req_q_acc)?;
+            let mut bids = market.load_bids_mut(bids_acc)?;
+            let mut asks = market.load_asks_mut(asks_acc)?;
+            // expired orders are only told apart with the clock, so it's required
+            let (clock_sysvar_acc, optional_accounts) = optional_accounts
+                .split_first()
+                .ok_or(DexErrorCode::WrongClockSysvarAccount)?;
+            let clock = ClockSysvarAccount::new(clock_sysvar_acc)
+                .map_err(|_| DexErrorCode::WrongClockSysvarAccount)?;
+            let clock = Clock::from_account_info(clock.inner()).or(check_unreachable!())?;
+            let mut optional_accounts = optional_accounts.iter();
+            let mut stop_orders = if market.has_stop_orders() {
+                let stop_orders_acc = optional_accounts
+                    .next()
+                    .ok_or(DexErrorCode::WrongStopOrdersAccount)?;
+                Some(market.load_stop_orders_mut(stop_orders_acc)?)
+            } else {
+                None
+            };
+            let mut pegged_orders = if market.has_pegged_orders() {
+                let pegged_orders_acc = optional_accounts
+                    .next()
+                    .ok_or(DexErrorCode::WrongPeggedOrdersAccount)?;
+                Some(market.load_pegged_orders_mut(pegged_orders_acc)?)
+            } else {
+                None
+            };
+            let mut market_stats = if market.has_market_stats() {
+                let market_stats_acc = optional_accounts
+                    .next()
+                    .ok_or(DexErrorCode::WrongMarketStatsAccount)?;
+                Some(market.load_market_stats_mut(market_stats_acc)?)
+            } else {
+                None
+            };
+            let open_orders_accounts = if market.auto_consumes() {
+                optional_accounts
+                    .map(|account| {
+                        let open_orders =
+                            market.load_orders_mut(account, None, program_id, None)?;
+                        Ok((account.key.to_aligned_bytes(), open_orders))
+                    })
+                    .collect::<DexResult<Vec<_>>>()?
+            } else {
+                vec![]
+            };
 
             let order_book_state = OrderBookState {
                 bids: bids.deref_mut(),
                 asks: asks.deref_mut(),
+                stop_orders: stop_orders.as_deref_mut(),
+                pegged_orders: pegged_orders.as_deref_mut(),
+                market_stats: market_stats.as_deref_mut(),
                 market_state: market.deref_mut(),
+                clock: &clock,
             };
 
             let args = MatchOrdersArgs {
@@ -1572,6 +3171,135 @@ pub mod account_parser {
                 order_book_state,
                 req_q,
                 event_q,
+                open_orders_accounts,
+            };
+            f(args)
+        }
+    }
+
+    pub struct RunAuctionArgs<'a, 'b: 'a> {
+        pub limit: u16,
+        pub order_book_state: OrderBookState<'a>,
+        pub req_q: RequestQueue<'a>,
+        pub event_q: EventQueue<'a>,
+        pub authority: SignerAccount<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> RunAuctionArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            limit: u16,
+            f: impl FnOnce(RunAuctionArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert!(accounts.len() >= 7)?;
+            #[rustfmt::skip]
+            let (&[
+                ref market_acc,
+                ref authority_acc,
+                ref req_q_acc,
+                ref event_q_acc,
+                ref bids_acc,
+                ref asks_acc,
+                ref clock_sysvar_acc,
+            ], optional_accounts) = array_refs![accounts, 7; .. ;];
+            let mut market = MarketState::load(market_acc, program_id)?;
+            let authority = SignerAccount::new(authority_acc)?;
+            market.check_authority(authority.inner())?;
+            let event_q = market.load_event_queue_mut(event_q_acc)?;
+            let req_q = market.load_request_queue_mut(req_q_acc)?;
+            let mut bids = market.load_bids_mut(bids_acc)?;
+            let mut asks = market.load_asks_mut(asks_acc)?;
+            let clock = ClockSysvarAccount::new(clock_sysvar_acc)
+                .map_err(|_| DexErrorCode::WrongClockSysvarAccount)?;
+            let clock = Clock::from_account_info(clock.inner()).or(check_unreachable!())?;
+
+            let mut optional_accounts = optional_accounts.iter();
+            let mut stop_orders = if market.has_stop_orders() {
+                let stop_orders_acc = optional_accounts
+                    .next()
+                    .ok_or(DexErrorCode::WrongStopOrdersAccount)?;
+                Some(market.load_stop_orders_mut(stop_orders_acc)?)
+            } else {
+                None
+            };
+            let mut pegged_orders = if market.has_pegged_orders() {
+                let pegged_orders_acc = optional_accounts
+                    .next()
+                    .ok_or(DexErrorCode::WrongPeggedOrdersAccount)?;
+                Some(market.load_pegged_orders_mut(pegged_orders_acc)?)
+            } else {
+                None
+            };
+            let mut market_stats = if market.has_market_stats() {
+                let market_stats_acc = optional_accounts
+                    .next()
+                    .ok_or(DexErrorCode::WrongMarketStatsAccount)?;
+                Some(market.load_market_stats_mut(market_stats_acc)?)
+            } else {
+                None
+            };
+            check_assert!(optional_accounts.next().is_none())?;
+
+            let order_book_state = OrderBookState {
+                bids: bids.deref_mut(),
+                asks: asks.deref_mut(),
+                stop_orders: stop_orders.as_deref_mut(),
+                pegged_orders: pegged_orders.as_deref_mut(),
+                market_stats: market_stats.as_deref_mut(),
+                market_state: market.deref_mut(),
+                clock: &clock,
+            };
+
+            let args = RunAuctionArgs {
+                limit,
+                order_book_state,
+                req_q,
+                event_q,
+                authority,
+            };
+            f(args)
+        }
+    }
+
+    pub struct MigrateMarketArgs<'a, 'b: 'a> {
+        pub market: &'a mut MarketState,
+        pub authority: SignerAccount<'a, 'b>,
+        // the request queue, bids and asks
+        pub old_accounts: &'a [AccountInfo<'b>; 3],
+        pub new_accounts: &'a [AccountInfo<'b>; 3],
+    }
+    impl<'a, 'b: 'a> MigrateMarketArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(MigrateMarketArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert_eq!(accounts.len(), 8)?;
+            let accounts = array_ref![accounts, 0, 8];
+            #[rustfmt::skip]
+            let (&[
+                ref market_acc,
+                ref authority_acc,
+            ], old_accounts, new_accounts) = array_refs![accounts, 2, 3, 3];
+            let mut market = MarketState::load(market_acc, program_id)?;
+            let authority = SignerAccount::new(authority_acc)?;
+            market.check_authority_or_disable_authority(authority.inner())?;
+            let &[ref old_req_q_acc, ref old_bids_acc, ref old_asks_acc] = old_accounts;
+            check_assert_eq!(&old_req_q_acc.key.to_aligned_bytes(), &market.req_q)
+                .map_err(|_| DexErrorCode::WrongRequestQueueAccount)?;
+            check_assert_eq!(&old_bids_acc.key.to_aligned_bytes(), &market.bids)
+                .map_err(|_| DexErrorCode::WrongBidsAccount)?;
+            check_assert_eq!(&old_asks_acc.key.to_aligned_bytes(), &market.asks)
+                .map_err(|_| DexErrorCode::WrongAsksAccount)?;
+            for account in new_accounts {
+                check_uninitialized(account, program_id)?;
+            }
+
+            let args = MigrateMarketArgs {
+                market: market.deref_mut(),
+                authority,
+                old_accounts,
+                new_accounts,
             };
             f(args)
         }
@@ -1615,7 +3343,7 @@ pub mod account_parser {
 
     pub struct CancelOrderArgs<'a, 'b: 'a> {
         pub instruction: &'a CancelOrderInstruction,
-        pub open_orders: &'a mut OpenOrders,
+        pub open_orders: OpenOrdersMut<'a>,
         pub open_orders_address: &'a [u64; 4],
         pub req_q: RequestQueue<'a>,
         pub orders_owner: SignerAccount<'a, 'b>,
@@ -1635,15 +3363,19 @@ pub mod account_parser {
                 ref req_q_acc,
                 ref owner_acc
             ] = array_ref![accounts, 0, 4];
-            let market: RefMut<'a, MarketState> = MarketState::load(market_acc, program_id)?;
+            let market: MarketStateMut<'a> = MarketState::load(market_acc, program_id)?;
             let owner = SignerAccount::new(owner_acc)?;
-            let mut open_orders =
-                market.load_orders_mut(open_orders_acc, Some(owner.inner()), program_id, None)?;
+            let open_orders = market.load_orders_mut_as_trader(
+                open_orders_acc,
+                owner.inner(),
+                program_id,
+                None,
+            )?;
             let ref open_orders_address = open_orders_acc.key.to_aligned_bytes();
             let req_q = market.load_request_queue_mut(req_q_acc)?;
             let args = CancelOrderArgs {
                 instruction,
-                open_orders: open_orders.deref_mut(),
+                open_orders,
                 open_orders_address,
                 req_q,
                 orders_owner: owner,
@@ -1652,14 +3384,595 @@ pub mod account_parser {
         }
     }
 
-    pub struct CancelOrderByClientIdArgs<'a, 'b: 'a> {
-        pub client_order_id: NonZeroU64,
-        pub open_orders: &'a mut OpenOrders,
+    pub struct NewOrdersBatchArgs<'a, 'b: 'a> {
+        pub instructions: &'a [NewOrderInstructionV2],
+        pub market: &'a mut MarketState,
+        pub open_orders: OpenOrdersMut<'a>,
         pub open_orders_address: &'a [u64; 4],
+        pub owner: SignerAccount<'a, 'b>,
         pub req_q: RequestQueue<'a>,
-        pub orders_owner: SignerAccount<'a, 'b>,
+        pub coin_payer: TokenAccount<'a, 'b>,
+        pub pc_payer: TokenAccount<'a, 'b>,
+        pub coin_vault: CoinVault<'a, 'b>,
+        pub pc_vault: PcVault<'a, 'b>,
+        pub spl_token_program: SplTokenProgram<'a, 'b>,
+        pub fee_tier: FeeTier,
     }
-    impl<'a, 'b: 'a> CancelOrderByClientIdArgs<'a, 'b> {
+    impl<'a, 'b: 'a> NewOrdersBatchArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            instructions: &'a [NewOrderInstructionV2],
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(NewOrdersBatchArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert!((10..=12).contains(&accounts.len()))?;
+            let (fixed_accounts, remaining_accounts): (
+                &'a [AccountInfo<'b>; 10],
+                &'a [AccountInfo<'b>],
+            ) = array_refs![accounts, 10; .. ;];
+            let &[
+                ref market_acc,
+                ref open_orders_acc,
+                ref req_q_acc,
+                ref coin_payer_acc,
+                ref pc_payer_acc,
+                ref owner_acc,
+                ref coin_vault_acc,
+                ref pc_vault_acc,
+                ref spl_token_program_acc,
+                ref rent_sysvar_acc,
+            ]: &'a [AccountInfo<'b>; 10] = fixed_accounts;
+            let (clock, fee_discount_account) = split_optional_clock(remaining_accounts)?;
+            let srm_or_msrm_account = match fee_discount_account {
+                &[] => None,
+                &[ref account] => Some(TokenAccount::new(account)?),
+                _ => check_unreachable!()?,
+            };
+
+            let mut market: MarketStateMut<'a> = MarketState::load(market_acc, program_id)?;
+            let rent = {
+                let rent_sysvar = RentSysvarAccount::new(rent_sysvar_acc)?;
+                Rent::from_account_info(rent_sysvar.inner()).or(check_unreachable!())?
+            };
+            let owner = SignerAccount::new(owner_acc)?;
+            let fee_tier =
+                market.load_fee_tier(&owner.inner().key.to_aligned_bytes(), srm_or_msrm_account)?;
+            let open_orders = market.load_orders_mut_as_trader(
+                open_orders_acc,
+                owner.inner(),
+                program_id,
+                Some(rent),
+            )?;
+            let ref open_orders_address = open_orders_acc.key.to_aligned_bytes();
+            let req_q = market.load_request_queue_mut(req_q_acc)?;
+
+            let coin_payer = TokenAccount::new(coin_payer_acc)?;
+            market
+                .check_coin_payer(coin_payer)
+                .or(check_unreachable!())?;
+            let pc_payer = TokenAccount::new(pc_payer_acc)?;
+            market.check_pc_payer(pc_payer).or(check_unreachable!())?;
+            let coin_vault = CoinVault::from_account(coin_vault_acc, &market)?;
+            let pc_vault = PcVault::from_account(pc_vault_acc, &market)?;
+            market.check_enabled()?;
+            market.check_not_halted(clock.as_ref())?;
+            let spl_token_program = SplTokenProgram::new(spl_token_program_acc)?;
+            let args = NewOrdersBatchArgs {
+                instructions,
+                market: market.deref_mut(),
+                open_orders,
+                open_orders_address,
+                owner,
+                req_q,
+                coin_payer,
+                pc_payer,
+                coin_vault,
+                pc_vault,
+                spl_token_program,
+                fee_tier,
+            };
+            f(args)
+        }
+    }
+
+    pub struct CancelOrdersBatchArgs<'a, 'b: 'a> {
+        pub order_ids: &'a [u128],
+        pub open_orders: OpenOrdersMut<'a>,
+        pub open_orders_address: &'a [u64; 4],
+        pub req_q: RequestQueue<'a>,
+        pub orders_owner: SignerAccount<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> CancelOrdersBatchArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            order_ids: &'a [u128],
+            f: impl FnOnce(CancelOrdersBatchArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert_eq!(accounts.len(), 4)?;
+            #[rustfmt::skip]
+            let &[
+                ref market_acc,
+                ref open_orders_acc,
+                ref req_q_acc,
+                ref owner_acc
+            ] = array_ref![accounts, 0, 4];
+            let market: MarketStateMut<'a> = MarketState::load(market_acc, program_id)?;
+            let owner = SignerAccount::new(owner_acc)?;
+            let open_orders = market.load_orders_mut_as_trader(
+                open_orders_acc,
+                owner.inner(),
+                program_id,
+                None,
+            )?;
+            let ref open_orders_address = open_orders_acc.key.to_aligned_bytes();
+            let req_q = market.load_request_queue_mut(req_q_acc)?;
+            let args = CancelOrdersBatchArgs {
+                order_ids,
+                open_orders,
+                open_orders_address,
+                req_q,
+                orders_owner: owner,
+            };
+            f(args)
+        }
+    }
+
+    pub struct CancelAllOrdersArgs<'a, 'b: 'a> {
+        pub side: Option<Side>,
+        pub open_orders: OpenOrdersMut<'a>,
+        pub open_orders_address: &'a [u64; 4],
+        pub req_q: RequestQueue<'a>,
+        pub orders_owner: SignerAccount<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> CancelAllOrdersArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            side: Option<Side>,
+            f: impl FnOnce(CancelAllOrdersArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert_eq!(accounts.len(), 4)?;
+            #[rustfmt::skip]
+            let &[
+                ref market_acc,
+                ref open_orders_acc,
+                ref req_q_acc,
+                ref owner_acc
+            ] = array_ref![accounts, 0, 4];
+            let market: MarketStateMut<'a> = MarketState::load(market_acc, program_id)?;
+            let owner = SignerAccount::new(owner_acc)?;
+            let open_orders = market.load_orders_mut_as_trader(
+                open_orders_acc,
+                owner.inner(),
+                program_id,
+                None,
+            )?;
+            let ref open_orders_address = open_orders_acc.key.to_aligned_bytes();
+            let req_q = market.load_request_queue_mut(req_q_acc)?;
+            let args = CancelAllOrdersArgs {
+                side,
+                open_orders,
+                open_orders_address,
+                req_q,
+                orders_owner: owner,
+            };
+            f(args)
+        }
+    }
+
+    pub struct SetPositionLimitsArgs<'a, 'b: 'a> {
+        pub instruction: &'a SetPositionLimitsInstruction,
+        pub open_orders: OpenOrdersMut<'a>,
+        pub orders_owner: SignerAccount<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> SetPositionLimitsArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            instruction: &'a SetPositionLimitsInstruction,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(SetPositionLimitsArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert_eq!(accounts.len(), 3)?;
+            #[rustfmt::skip]
+            let &[
+                ref market_acc,
+                ref open_orders_acc,
+                ref owner_acc
+            ] = array_ref![accounts, 0, 3];
+            let market: MarketStateMut<'a> = MarketState::load(market_acc, program_id)?;
+            let owner = SignerAccount::new(owner_acc)?;
+            let open_orders =
+                market.load_orders_mut(open_orders_acc, Some(owner.inner()), program_id, None)?;
+            let args = SetPositionLimitsArgs {
+                instruction,
+                open_orders,
+                orders_owner: owner,
+            };
+            f(args)
+        }
+    }
+
+    pub struct SetMarketParamsArgs<'a, 'b: 'a> {
+        pub instruction: &'a SetMarketParamsInstruction,
+        pub market: &'a mut MarketState,
+        pub event_q: EventQueue<'a>,
+        pub authority: SignerAccount<'a, 'b>,
+        // nothing rests anywhere on the book and no requests are waiting to be matched
+        pub book_is_empty: bool,
+        pub clock: Option<Clock>,
+    }
+    impl<'a, 'b: 'a> SetMarketParamsArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            instruction: &'a SetMarketParamsInstruction,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(SetMarketParamsArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert!(accounts.len() >= 6)?;
+            #[rustfmt::skip]
+            let (&[
+                ref market_acc,
+                ref event_q_acc,
+                ref authority_acc,
+                ref req_q_acc,
+                ref bids_acc,
+                ref asks_acc,
+            ], optional_accounts) = array_refs![accounts, 6; .. ;];
+            let mut market = MarketState::load(market_acc, program_id)?;
+            let authority = SignerAccount::new(authority_acc)?;
+            market.check_authority(authority.inner())?;
+
+            let event_q = market.load_event_queue_mut(event_q_acc)?;
+            let (clock, optional_accounts) = split_optional_clock(optional_accounts)?;
+            let book_is_empty =
+                book_is_empty(&market, req_q_acc, bids_acc, asks_acc, optional_accounts)?;
+
+            let args = SetMarketParamsArgs {
+                instruction,
+                market: market.deref_mut(),
+                event_q,
+                authority,
+                book_is_empty,
+                clock,
+            };
+            f(args)
+        }
+    }
+
+    pub struct CloseMarketArgs<'a, 'b: 'a> {
+        pub market: &'a mut MarketState,
+        pub market_acc: &'a AccountInfo<'b>,
+        pub authority: SignerAccount<'a, 'b>,
+        // the queues, slabs and optional accounts, which are closed along with the market
+        pub book_accounts: &'a [AccountInfo<'b>],
+        pub optional_accounts: &'a [AccountInfo<'b>],
+        // nothing rests anywhere on the book and no requests or events are outstanding
+        pub book_is_empty: bool,
+        pub coin_vault: CoinVault<'a, 'b>,
+        pub pc_vault: PcVault<'a, 'b>,
+        pub coin_wallet: CoinWallet<'a, 'b>,
+        pub pc_wallet: PcWallet<'a, 'b>,
+        pub vault_signer: VaultSigner<'a, 'b>,
+        pub spl_token_program: SplTokenProgram<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> CloseMarketArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(CloseMarketArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert!(accounts.len() >= 12)?;
+            #[rustfmt::skip]
+            let (&[
+                ref market_acc,
+                ref authority_acc,
+            ], book_accounts, &[
+                ref coin_vault_acc,
+                ref pc_vault_acc,
+                ref coin_wallet_acc,
+                ref pc_wallet_acc,
+                ref vault_signer_acc,
+                ref spl_token_program_acc,
+            ], optional_accounts) = array_refs![accounts, 2, 4, 6; .. ;];
+            let &[ref req_q_acc, ref event_q_acc, ref bids_acc, ref asks_acc] = book_accounts;
+            let mut market = MarketState::load(market_acc, program_id)?;
+            let authority = SignerAccount::new(authority_acc)?;
+            market.check_authority_or_disable_authority(authority.inner())?;
+            let coin_vault = CoinVault::from_account(coin_vault_acc, &market)?;
+            let pc_vault = PcVault::from_account(pc_vault_acc, &market)?;
+            let coin_wallet = CoinWallet::from_account(coin_wallet_acc, &market)?;
+            let pc_wallet = PcWallet::from_account(pc_wallet_acc, &market)?;
+            let vault_signer = VaultSigner::new(vault_signer_acc, &market, program_id)?;
+            let spl_token_program = SplTokenProgram::new(spl_token_program_acc)?;
+
+            // the stats account comes last and is closed along with the book, see the
+            // CloseMarket docs
+            let order_accounts = if market.has_market_stats() {
+                let (market_stats_acc, order_accounts) = optional_accounts
+                    .split_last()
+                    .ok_or(DexErrorCode::WrongMarketStatsAccount)?;
+                market.load_market_stats_mut(market_stats_acc)?;
+                order_accounts
+            } else {
+                optional_accounts
+            };
+            let book_is_empty = market.load_event_queue_mut(event_q_acc)?.empty()
+                && book_is_empty(&market, req_q_acc, bids_acc, asks_acc, order_accounts)?;
+
+            let args = CloseMarketArgs {
+                market: market.deref_mut(),
+                market_acc,
+                authority,
+                book_accounts,
+                optional_accounts,
+                book_is_empty,
+                coin_vault,
+                pc_vault,
+                coin_wallet,
+                pc_wallet,
+                vault_signer,
+                spl_token_program,
+            };
+            f(args)
+        }
+    }
+
+    fn book_is_empty(
+        market: &MarketState,
+        req_q_acc: &AccountInfo,
+        bids_acc: &AccountInfo,
+        asks_acc: &AccountInfo,
+        optional_accounts: &[AccountInfo],
+    ) -> DexResult<bool> {
+        let mut book_is_empty = market.load_request_queue_mut(req_q_acc)?.empty()
+            && market.load_bids_mut(bids_acc)?.is_empty()
+            && market.load_asks_mut(asks_acc)?.is_empty();
+        let mut optional_accounts = optional_accounts.iter();
+        if market.has_stop_orders() {
+            let stop_orders_acc = optional_accounts
+                .next()
+                .ok_or(DexErrorCode::WrongStopOrdersAccount)?;
+            book_is_empty &= market.load_stop_orders_mut(stop_orders_acc)?.is_empty();
+        }
+        if market.has_pegged_orders() {
+            let pegged_orders_acc = optional_accounts
+                .next()
+                .ok_or(DexErrorCode::WrongPeggedOrdersAccount)?;
+            book_is_empty &= market.load_pegged_orders_mut(pegged_orders_acc)?.is_empty();
+        }
+        check_assert!(optional_accounts.next().is_none())?;
+        Ok(book_is_empty)
+    }
+
+    pub struct CloseOpenOrdersArgs<'a, 'b: 'a> {
+        pub open_orders: OpenOrdersMut<'a>,
+        pub open_orders_acc: &'a AccountInfo<'b>,
+        pub orders_owner: SignerAccount<'a, 'b>,
+        pub destination: &'a AccountInfo<'b>,
+    }
+    impl<'a, 'b: 'a> CloseOpenOrdersArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(CloseOpenOrdersArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert_eq!(accounts.len(), 4)?;
+            #[rustfmt::skip]
+            let &[
+                ref open_orders_acc,
+                ref owner_acc,
+                ref destination,
+                ref market_acc
+            ] = array_ref![accounts, 0, 4];
+            let owner = SignerAccount::new(owner_acc)?;
+            // a closed market has no lamports left, so its OpenOrders are checked against
+            // the address alone. Closing it required every deposit to be settled first.
+            let open_orders = if market_acc.owner != program_id || market_acc.lamports() == 0 {
+                let mut closed_market = MarketState::zeroed();
+                closed_market.own_address = market_acc.key.to_aligned_bytes();
+                closed_market.load_orders_mut(
+                    open_orders_acc,
+                    Some(owner.inner()),
+                    program_id,
+                    None,
+                )?
+            } else {
+                let market: MarketStateMut<'a> = MarketState::load(market_acc, program_id)?;
+                market.load_orders_mut(open_orders_acc, Some(owner.inner()), program_id, None)?
+            };
+            let args = CloseOpenOrdersArgs {
+                open_orders,
+                open_orders_acc,
+                orders_owner: owner,
+                destination,
+            };
+            f(args)
+        }
+    }
+
+    pub struct SetDelegateArgs<'a, 'b: 'a> {
+        pub open_orders: OpenOrdersMut<'a>,
+        pub orders_owner: SignerAccount<'a, 'b>,
+        pub delegate: Option<&'a AccountInfo<'b>>,
+    }
+    impl<'a, 'b: 'a> SetDelegateArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(SetDelegateArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert!(accounts.len() == 3 || accounts.len() == 4)?;
+            #[rustfmt::skip]
+            let (&[
+                ref market_acc,
+                ref open_orders_acc,
+                ref owner_acc,
+            ], delegate) = array_refs![accounts, 3; .. ;];
+            let market: MarketStateMut<'a> = MarketState::load(market_acc, program_id)?;
+            let owner = SignerAccount::new(owner_acc)?;
+            let open_orders =
+                market.load_orders_mut(open_orders_acc, Some(owner.inner()), program_id, None)?;
+            let args = SetDelegateArgs {
+                open_orders,
+                orders_owner: owner,
+                delegate: delegate.first(),
+            };
+            f(args)
+        }
+    }
+
+    pub struct SetSettleWalletsArgs<'a, 'b: 'a> {
+        pub open_orders: OpenOrdersMut<'a>,
+        pub orders_owner: SignerAccount<'a, 'b>,
+        pub wallets: Option<(CoinWallet<'a, 'b>, PcWallet<'a, 'b>)>,
+    }
+    impl<'a, 'b: 'a> SetSettleWalletsArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(SetSettleWalletsArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert!(accounts.len() == 3 || accounts.len() == 5)?;
+            #[rustfmt::skip]
+            let (&[
+                ref market_acc,
+                ref open_orders_acc,
+                ref owner_acc,
+            ], wallet_accounts) = array_refs![accounts, 3; .. ;];
+            let market: MarketStateMut<'a> = MarketState::load(market_acc, program_id)?;
+            let owner = SignerAccount::new(owner_acc)?;
+            let wallets = match wallet_accounts {
+                &[] => None,
+                &[ref coin_wallet_acc, ref pc_wallet_acc] => Some((
+                    CoinWallet::from_account(coin_wallet_acc, &market)?,
+                    PcWallet::from_account(pc_wallet_acc, &market)?,
+                )),
+                _ => check_unreachable!()?,
+            };
+            let open_orders =
+                market.load_orders_mut(open_orders_acc, Some(owner.inner()), program_id, None)?;
+            let args = SetSettleWalletsArgs {
+                open_orders,
+                orders_owner: owner,
+                wallets,
+            };
+            f(args)
+        }
+    }
+
+    pub struct SettleFundsToWalletsArgs<'a, 'b: 'a> {
+        pub market: &'a mut MarketState,
+        pub open_orders: OpenOrdersMut<'a>,
+        pub coin_vault: CoinVault<'a, 'b>,
+        pub pc_vault: PcVault<'a, 'b>,
+        pub coin_wallet: CoinWallet<'a, 'b>,
+        pub pc_wallet: PcWallet<'a, 'b>,
+        pub vault_signer: VaultSigner<'a, 'b>,
+        pub spl_token_program: SplTokenProgram<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> SettleFundsToWalletsArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(SettleFundsToWalletsArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert_eq!(accounts.len(), 8)?;
+            #[rustfmt::skip]
+            let &[
+                ref market_acc,
+                ref open_orders_acc,
+                ref coin_vault_acc,
+                ref pc_vault_acc,
+                ref coin_wallet_acc,
+                ref pc_wallet_acc,
+                ref vault_signer_acc,
+                ref spl_token_program_acc,
+            ] = array_ref![accounts, 0, 8];
+            let spl_token_program = SplTokenProgram::new(spl_token_program_acc)?;
+            let mut market = MarketState::load(market_acc, program_id)?;
+
+            let coin_vault = CoinVault::from_account(coin_vault_acc, &market)?;
+            let pc_vault = PcVault::from_account(pc_vault_acc, &market)?;
+            let coin_wallet = CoinWallet::from_account(coin_wallet_acc, &market)?;
+            let pc_wallet = PcWallet::from_account(pc_wallet_acc, &market)?;
+            let vault_signer = VaultSigner::new(vault_signer_acc, &market, program_id)?;
+
+            let open_orders = market.load_orders_mut(open_orders_acc, None, program_id, None)?;
+            let (settle_coin_wallet, settle_pc_wallet) = open_orders
+                .settle_wallets()
+                .ok_or(DexErrorCode::SettleWalletsNotRegistered)?;
+            if *settle_coin_wallet != coin_wallet_acc.key.to_aligned_bytes()
+                || *settle_pc_wallet != pc_wallet_acc.key.to_aligned_bytes()
+            {
+                Err(DexErrorCode::WrongSettleWallet)?
+            }
+
+            let args = SettleFundsToWalletsArgs {
+                market: market.deref_mut(),
+                open_orders,
+                coin_vault,
+                pc_vault,
+                coin_wallet,
+                pc_wallet,
+                vault_signer,
+                spl_token_program,
+            };
+            f(args)
+        }
+    }
+
+    pub struct InitializeMarketStatsArgs<'a, 'b: 'a> {
+        pub slots_per_candle: u64,
+        pub market: &'a mut MarketState,
+        pub market_stats: RefMut<'a, MarketStats>,
+        pub market_stats_acc: &'a AccountInfo<'b>,
+        pub authority: SignerAccount<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> InitializeMarketStatsArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            slots_per_candle: u64,
+            f: impl FnOnce(InitializeMarketStatsArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert!(slots_per_candle > 0)?;
+            check_assert_eq!(accounts.len(), 3)?;
+            #[rustfmt::skip]
+            let &[
+                ref market_acc,
+                ref market_stats_acc,
+                ref authority_acc,
+            ] = array_ref![accounts, 0, 3];
+            let mut market = MarketState::load(market_acc, program_id)?;
+            let authority = SignerAccount::new(authority_acc)?;
+            market.check_authority(authority.inner())?;
+            check_assert!(!market.has_market_stats())?;
+
+            check_assert_eq!(market_stats_acc.owner, program_id)?;
+            check_assert!(market_stats_acc.data_len() >= size_of::<MarketStats>() + 12)?;
+            let (market_stats, _) = strip_header::<MarketStats, u8>(market_stats_acc, true)?;
+            check_assert_eq!(market_stats.account_flags, 0)?;
+
+            let args = InitializeMarketStatsArgs {
+                slots_per_candle,
+                market: market.deref_mut(),
+                market_stats,
+                market_stats_acc,
+                authority,
+            };
+            f(args)
+        }
+    }
+
+    pub struct CancelOrderByClientIdArgs<'a, 'b: 'a> {
+        pub client_order_id: NonZeroU64,
+        pub open_orders: OpenOrdersMut<'a>,
+        pub open_orders_address: &'a [u64; 4],
+        pub req_q: RequestQueue<'a>,
+        pub orders_owner: SignerAccount<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> CancelOrderByClientIdArgs<'a, 'b> {
         pub fn with_parsed_args<T>(
             program_id: &'a Pubkey,
             accounts: &'a [AccountInfo<'b>],
@@ -1675,15 +3988,19 @@ pub mod account_parser {
                 ref req_q_acc,
                 ref owner_acc
             ] = array_ref![accounts, 0, 4];
-            let market: RefMut<'a, MarketState> = MarketState::load(market_acc, program_id)?;
+            let market: MarketStateMut<'a> = MarketState::load(market_acc, program_id)?;
             let owner = SignerAccount::new(owner_acc)?;
-            let mut open_orders =
-                market.load_orders_mut(open_orders_acc, Some(owner.inner()), program_id, None)?;
+            let open_orders = market.load_orders_mut_as_trader(
+                open_orders_acc,
+                owner.inner(),
+                program_id,
+                None,
+            )?;
             let ref open_orders_address = open_orders_acc.key.to_aligned_bytes();
             let req_q = market.load_request_queue_mut(req_q_acc)?;
             let args = CancelOrderByClientIdArgs {
                 client_order_id,
-                open_orders: open_orders.deref_mut(),
+                open_orders,
                 open_orders_address,
                 req_q,
                 orders_owner: owner,
@@ -1694,7 +4011,7 @@ pub mod account_parser {
 
     pub struct SettleFundsArgs<'a, 'b: 'a> {
         pub market: &'a mut MarketState,
-        pub open_orders: &'a mut OpenOrders,
+        pub open_orders: OpenOrdersMut<'a>,
         pub coin_vault: CoinVault<'a, 'b>,
         pub pc_vault: PcVault<'a, 'b>,
         pub coin_wallet: CoinWallet<'a, 'b>,
@@ -1703,13 +4020,89 @@ pub mod account_parser {
         pub spl_token_program: SplTokenProgram<'a, 'b>,
         pub referrer: Option<PcWallet<'a, 'b>>,
     }
-    impl<'a, 'b: 'a> SettleFundsArgs<'a, 'b> {
+    impl<'a, 'b: 'a> SettleFundsArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(SettleFundsArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert!(accounts.len() == 9 || accounts.len() == 10)?;
+            #[rustfmt::skip]
+            let (&[
+                ref market_acc,
+                ref open_orders_acc,
+                ref owner_acc,
+                ref coin_vault_acc,
+                ref pc_vault_acc,
+                ref coin_wallet_acc,
+                ref pc_wallet_acc,
+                ref vault_signer_acc,
+                ref spl_token_program_acc,
+            ], remaining_accounts) = array_refs![accounts, 9; ..;];
+            let spl_token_program = SplTokenProgram::new(spl_token_program_acc)?;
+            let mut market = MarketState::load(market_acc, program_id)?;
+            let owner = SignerAccount::new(owner_acc).or(check_unreachable!())?;
+
+            let coin_vault =
+                CoinVault::from_account(coin_vault_acc, &market).or(check_unreachable!())?;
+            let pc_vault = PcVault::from_account(pc_vault_acc, &market).or(check_unreachable!())?;
+            let coin_wallet =
+                CoinWallet::from_account(coin_wallet_acc, &market).or(check_unreachable!())?;
+            let pc_wallet =
+                PcWallet::from_account(pc_wallet_acc, &market).or(check_unreachable!())?;
+
+            let referrer = match remaining_accounts {
+                &[] => None,
+                &[ref referrer_acc] => {
+                    Some(PcWallet::from_account(referrer_acc, &market).or(check_unreachable!())?)
+                }
+                _ => check_unreachable!()?,
+            };
+
+            let vault_signer = VaultSigner::new(vault_signer_acc, &market, program_id)?;
+
+            let open_orders =
+                market.load_orders_mut(open_orders_acc, Some(owner.inner()), program_id, None)?;
+
+            let args = SettleFundsArgs {
+                market: market.deref_mut(),
+                open_orders,
+                coin_vault,
+                pc_vault,
+                coin_wallet,
+                pc_wallet,
+                vault_signer,
+                spl_token_program,
+                referrer,
+            };
+            f(args)
+        }
+    }
+
+    pub struct SettleFundsNativeArgs<'a, 'b: 'a> {
+        pub market: &'a mut MarketState,
+        pub open_orders: OpenOrdersMut<'a>,
+        pub owner: SignerAccount<'a, 'b>,
+        pub coin_vault: CoinVault<'a, 'b>,
+        pub pc_vault: PcVault<'a, 'b>,
+        pub wallet: TokenAccount<'a, 'b>,
+        pub native_is_coin: bool,
+        pub vault_signer: VaultSigner<'a, 'b>,
+        pub spl_token_program: SplTokenProgram<'a, 'b>,
+        pub native_account: SignerAccount<'a, 'b>,
+        pub native_mint: TokenMint<'a, 'b>,
+        pub system_program: SystemProgram<'a, 'b>,
+        pub rent_sysvar: RentSysvarAccount<'a, 'b>,
+        pub rent: Rent,
+        pub referrer: Option<PcWallet<'a, 'b>>,
+    }
+    impl<'a, 'b: 'a> SettleFundsNativeArgs<'a, 'b> {
         pub fn with_parsed_args<T>(
             program_id: &'a Pubkey,
             accounts: &'a [AccountInfo<'b>],
-            f: impl FnOnce(SettleFundsArgs) -> DexResult<T>,
+            f: impl FnOnce(SettleFundsNativeArgs) -> DexResult<T>,
         ) -> DexResult<T> {
-            check_assert!(accounts.len() == 9 || accounts.len() == 10)?;
+            check_assert!(accounts.len() == 12 || accounts.len() == 13)?;
             #[rustfmt::skip]
             let (&[
                 ref market_acc,
@@ -1717,22 +4110,42 @@ pub mod account_parser {
                 ref owner_acc,
                 ref coin_vault_acc,
                 ref pc_vault_acc,
-                ref coin_wallet_acc,
-                ref pc_wallet_acc,
+                ref wallet_acc,
                 ref vault_signer_acc,
                 ref spl_token_program_acc,
-            ], remaining_accounts) = array_refs![accounts, 9; ..;];
+                ref native_acc,
+                ref native_mint_acc,
+                ref system_program_acc,
+                ref rent_sysvar_acc,
+            ], remaining_accounts) = array_refs![accounts, 12; ..;];
             let spl_token_program = SplTokenProgram::new(spl_token_program_acc)?;
+            let system_program = SystemProgram::new(system_program_acc)?;
+            let rent_sysvar = RentSysvarAccount::new(rent_sysvar_acc)?;
+            let rent = Rent::from_account_info(rent_sysvar.inner()).or(check_unreachable!())?;
             let mut market = MarketState::load(market_acc, program_id)?;
             let owner = SignerAccount::new(owner_acc).or(check_unreachable!())?;
+            let native_account = SignerAccount::new(native_acc)?;
+
+            let native_mint = TokenMint::new(native_mint_acc)?;
+            let native_mint_key = native_mint.inner().key.to_aligned_bytes();
+            check_assert_eq!(native_mint.inner().key, &spl_token::native_mint::ID)
+                .map_err(|_| DexErrorCode::NotWrappedSolMarket)?;
+            let native_is_coin = if market.coin_mint == native_mint_key {
+                true
+            } else if market.pc_mint == native_mint_key {
+                false
+            } else {
+                Err(DexErrorCode::NotWrappedSolMarket)?
+            };
 
             let coin_vault =
                 CoinVault::from_account(coin_vault_acc, &market).or(check_unreachable!())?;
             let pc_vault = PcVault::from_account(pc_vault_acc, &market).or(check_unreachable!())?;
-            let coin_wallet =
-                CoinWallet::from_account(coin_wallet_acc, &market).or(check_unreachable!())?;
-            let pc_wallet =
-                PcWallet::from_account(pc_wallet_acc, &market).or(check_unreachable!())?;
+            let wallet = if native_is_coin {
+                PcWallet::from_account(wallet_acc, &market)?.token_account()
+            } else {
+                CoinWallet::from_account(wallet_acc, &market)?.token_account()
+            };
 
             let referrer = match remaining_accounts {
                 &[] => None,
@@ -1744,18 +4157,24 @@ pub mod account_parser {
 
             let vault_signer = VaultSigner::new(vault_signer_acc, &market, program_id)?;
 
-            let mut open_orders =
+            let open_orders =
                 market.load_orders_mut(open_orders_acc, Some(owner.inner()), program_id, None)?;
 
-            let args = SettleFundsArgs {
+            let args = SettleFundsNativeArgs {
                 market: market.deref_mut(),
-                open_orders: open_orders.deref_mut(),
+                open_orders,
+                owner,
                 coin_vault,
                 pc_vault,
-                coin_wallet,
-                pc_wallet,
+                wallet,
+                native_is_coin,
                 vault_signer,
                 spl_token_program,
+                native_account,
+                native_mint,
+                system_program,
+                rent_sysvar,
+                rent,
                 referrer,
             };
             f(args)
@@ -1785,6 +4204,30 @@ pub mod account_parser {
         }
     }
 
+    pub struct EnableMarketArgs<'a, 'b: 'a> {
+        pub market: &'a mut MarketState,
+        pub authority: SignerAccount<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> EnableMarketArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(EnableMarketArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert_eq!(accounts.len(), 2)?;
+            let &[ref market_acc, ref authority_acc] = array_ref![accounts, 0, 2];
+            let mut market = MarketState::load(market_acc, program_id)?;
+            let authority = SignerAccount::new(authority_acc)?;
+            market.check_authority_or_disable_authority(authority.inner())?;
+
+            let args = EnableMarketArgs {
+                market: market.deref_mut(),
+                authority,
+            };
+            f(args)
+        }
+    }
+
     pub struct SweepFeesArgs<'a, 'b: 'a> {
         pub market: &'a mut MarketState,
         pub pc_vault: PcVault<'a, 'b>,
@@ -1844,6 +4287,10 @@ fn remove_slop_mut<T: Pod>(bytes: &mut [u8]) -> &mut [T] {
     cast_slice_mut(&mut bytes[..new_len])
 }
 
+// Requests were 80 bytes until they grew to hold expiry, replace and iceberg fields,
+// which are zero for the requests the old layout could hold.
+const LEGACY_REQUEST_SIZE: usize = 80;
+
 #[cfg_attr(not(feature = "program"), allow(unused))]
 impl State {
     #[cfg(feature = "program")]
@@ -1858,6 +4305,7 @@ impl State {
                 account_parser::NewOrderArgs::with_parsed_args(
                     program_id,
                     &new_order_v2,
+                    None,
                     accounts,
                     Self::process_new_order,
                 )?
@@ -1866,10 +4314,99 @@ impl State {
                 account_parser::NewOrderArgs::with_parsed_args(
                     program_id,
                     inner,
+                    None,
+                    accounts,
+                    Self::process_new_order,
+                )?
+            }
+            MarketInstruction::NewOrderV3(ref inner) => {
+                let (new_order_v2, trigger_price) = inner.clone().remove_trigger_price();
+                account_parser::NewOrderArgs::with_parsed_args(
+                    program_id,
+                    &new_order_v2,
+                    Some(trigger_price),
                     accounts,
                     Self::process_new_order,
                 )?
             }
+            MarketInstruction::ReplaceOrder(ref inner) => {
+                account_parser::NewOrderArgs::with_parsed_args(
+                    program_id,
+                    &inner.new_order,
+                    None,
+                    accounts,
+                    |args| Self::process_replace_order(inner.replaced_order, args),
+                )?
+            }
+            MarketInstruction::NewIcebergOrder(ref inner) => {
+                account_parser::NewOrderArgs::with_parsed_args(
+                    program_id,
+                    &inner.new_order,
+                    None,
+                    accounts,
+                    |args| Self::process_new_iceberg_order(inner.display_qty, args),
+                )?
+            }
+            MarketInstruction::NewMarketBuy(ref inner) => {
+                let new_order_v2 = inner.to_new_order();
+                account_parser::NewOrderArgs::with_parsed_args(
+                    program_id,
+                    &new_order_v2,
+                    None,
+                    accounts,
+                    |args| {
+                        Self::process_new_market_buy(inner.max_native_pc_qty_including_fees, args)
+                    },
+                )?
+            }
+            MarketInstruction::SetPositionLimits(ref inner) => {
+                account_parser::SetPositionLimitsArgs::with_parsed_args(
+                    program_id,
+                    inner,
+                    accounts,
+                    Self::process_set_position_limits,
+                )?
+            }
+            MarketInstruction::SetMarketParams(ref inner) => {
+                account_parser::SetMarketParamsArgs::with_parsed_args(
+                    program_id,
+                    inner,
+                    accounts,
+                    Self::process_set_market_params,
+                )?
+            }
+            MarketInstruction::NewOrdersBatch(ref inner) => {
+                account_parser::NewOrdersBatchArgs::with_parsed_args(
+                    program_id,
+                    inner,
+                    accounts,
+                    Self::process_new_orders_batch,
+                )?
+            }
+            MarketInstruction::CancelOrdersBatch(ref inner) => {
+                account_parser::CancelOrdersBatchArgs::with_parsed_args(
+                    program_id,
+                    accounts,
+                    inner,
+                    Self::process_cancel_orders_batch,
+                )?
+            }
+            MarketInstruction::CancelAllOrders(side) => {
+                account_parser::CancelAllOrdersArgs::with_parsed_args(
+                    program_id,
+                    accounts,
+                    side,
+                    Self::process_cancel_all_orders,
+                )?
+            }
+            MarketInstruction::NewPeggedOrder(ref inner) => {
+                account_parser::NewPeggedOrderArgs::with_parsed_args(
+                    program_id,
+                    inner,
+                    accounts,
+                    Self::process_new_pegged_order,
+                )?
+            }
             MarketInstruction::MatchOrders(limit) => {
                 account_parser::MatchOrdersArgs::with_parsed_args(
                     program_id,
@@ -1919,6 +4456,77 @@ impl State {
                 accounts,
                 Self::process_sweep_fees,
             )?,
+            MarketInstruction::EnableMarket => account_parser::EnableMarketArgs::with_parsed_args(
+                program_id,
+                accounts,
+                Self::process_enable_market,
+            )?,
+            MarketInstruction::CloseMarket => account_parser::CloseMarketArgs::with_parsed_args(
+                program_id,
+                accounts,
+                Self::process_close_market,
+            )?,
+            MarketInstruction::SetDelegate => account_parser::SetDelegateArgs::with_parsed_args(
+                program_id,
+                accounts,
+                Self::process_set_delegate,
+            )?,
+            MarketInstruction::RevokeDelegate => account_parser::SetDelegateArgs::with_parsed_args(
+                program_id,
+                accounts,
+                Self::process_revoke_delegate,
+            )?,
+            MarketInstruction::CloseOpenOrders => {
+                account_parser::CloseOpenOrdersArgs::with_parsed_args(
+                    program_id,
+                    accounts,
+                    Self::process_close_open_orders,
+                )?
+            }
+            MarketInstruction::SettleFundsNative => {
+                account_parser::SettleFundsNativeArgs::with_parsed_args(
+                    program_id,
+                    accounts,
+                    Self::process_settle_funds_native,
+                )?
+            }
+            MarketInstruction::SetSettleWallets => {
+                account_parser::SetSettleWalletsArgs::with_parsed_args(
+                    program_id,
+                    accounts,
+                    Self::process_set_settle_wallets,
+                )?
+            }
+            MarketInstruction::SettleFundsToWallets => {
+                account_parser::SettleFundsToWalletsArgs::with_parsed_args(
+                    program_id,
+                    accounts,
+                    Self::process_settle_funds_to_wallets,
+                )?
+            }
+            MarketInstruction::InitializeMarketStats(slots_per_candle) => {
+                account_parser::InitializeMarketStatsArgs::with_parsed_args(
+                    program_id,
+                    accounts,
+                    slots_per_candle,
+                    Self::process_initialize_market_stats,
+                )?
+            }
+            MarketInstruction::RunAuction(limit) => {
+                account_parser::RunAuctionArgs::with_parsed_args(
+                    program_id,
+                    accounts,
+                    limit,
+                    Self::process_run_auction,
+                )?
+            }
+            MarketInstruction::MigrateMarket => {
+                account_parser::MigrateMarketArgs::with_parsed_args(
+                    program_id,
+                    accounts,
+                    Self::process_migrate_market,
+                )?
+            }
         };
         Ok(())
     }
@@ -1937,23 +4545,8 @@ impl State {
             referrer,
         } = args;
 
-        let native_coin_amount = open_orders.native_coin_free;
-        let native_pc_amount = open_orders.native_pc_free;
-
-        market.coin_deposits_total -= native_coin_amount;
-        market.pc_deposits_total -= native_pc_amount;
-
-        open_orders.native_coin_free = 0;
-        open_orders.native_pc_free = 0;
-
-        open_orders.native_coin_total = open_orders
-            .native_coin_total
-            .checked_sub(native_coin_amount)
-            .unwrap();
-        open_orders.native_pc_total = open_orders
-            .native_pc_total
-            .checked_sub(native_pc_amount)
-            .unwrap();
+        let (native_coin_amount, native_pc_amount) =
+            Self::withdraw_free_balances(market, &mut open_orders);
 
         let token_infos: [(
             u64,
@@ -1972,20 +4565,185 @@ impl State {
             ),
         ];
 
+        let market_pubkey = market.pubkey();
+        let vault_signer_nonce = market.vault_signer_nonce;
+        let vault_signer_seeds = gen_vault_signer_seeds(&vault_signer_nonce, &market_pubkey);
+
+        for &(token_amount, wallet_account, vault) in token_infos.iter() {
+            send_from_vault(
+                token_amount,
+                wallet_account,
+                vault,
+                spl_token_program,
+                vault_signer,
+                &vault_signer_seeds,
+            )?;
+        }
+
+        Self::settle_referrer_rebates(
+            market,
+            &mut open_orders,
+            referrer,
+            pc_vault,
+            spl_token_program,
+            vault_signer,
+            &vault_signer_seeds,
+        )
+    }
+
+    #[cfg(feature = "program")]
+    fn process_settle_funds_native(args: account_parser::SettleFundsNativeArgs) -> DexResult {
+        let account_parser::SettleFundsNativeArgs {
+            market,
+            mut open_orders,
+            owner,
+            coin_vault,
+            pc_vault,
+            wallet,
+            native_is_coin,
+            vault_signer,
+            spl_token_program,
+            native_account,
+            native_mint,
+            system_program,
+            rent_sysvar,
+            rent,
+            referrer,
+        } = args;
+
+        let (native_coin_amount, native_pc_amount) =
+            Self::withdraw_free_balances(market, &mut open_orders);
+        let (native_amount, native_vault, token_amount, token_vault) = if native_is_coin {
+            (
+                native_coin_amount,
+                coin_vault.token_account(),
+                native_pc_amount,
+                pc_vault.token_account(),
+            )
+        } else {
+            (
+                native_pc_amount,
+                pc_vault.token_account(),
+                native_coin_amount,
+                coin_vault.token_account(),
+            )
+        };
+
+        let market_pubkey = market.pubkey();
+        let vault_signer_nonce = market.vault_signer_nonce;
+        let vault_signer_seeds = gen_vault_signer_seeds(&vault_signer_nonce, &market_pubkey);
+
+        send_from_vault(
+            token_amount,
+            wallet,
+            token_vault,
+            spl_token_program,
+            vault_signer,
+            &vault_signer_seeds,
+        )?;
+        if native_amount > 0 {
+            unwrap_from_vault(
+                native_amount,
+                owner,
+                native_account,
+                native_mint,
+                native_vault,
+                spl_token_program,
+                system_program,
+                rent_sysvar,
+                &rent,
+                vault_signer,
+                &vault_signer_seeds,
+            )?;
+        }
+
+        Self::settle_referrer_rebates(
+            market,
+            &mut open_orders,
+            referrer,
+            pc_vault,
+            spl_token_program,
+            vault_signer,
+            &vault_signer_seeds,
+        )
+    }
+
+    #[cfg(feature = "program")]
+    fn process_settle_funds_to_wallets(
+        args: account_parser::SettleFundsToWalletsArgs,
+    ) -> DexResult {
+        let account_parser::SettleFundsToWalletsArgs {
+            market,
+            mut open_orders,
+            coin_vault,
+            pc_vault,
+            coin_wallet,
+            pc_wallet,
+            vault_signer,
+            spl_token_program,
+        } = args;
+
+        let (native_coin_amount, native_pc_amount) =
+            Self::withdraw_free_balances(market, &mut open_orders);
+
         let market_pubkey = market.pubkey();
         let vault_signer_seeds = gen_vault_signer_seeds(&market.vault_signer_nonce, &market_pubkey);
 
-        for &(token_amount, wallet_account, vault) in token_infos.iter() {
-            send_from_vault(
-                token_amount,
-                wallet_account,
-                vault,
-                spl_token_program,
-                vault_signer,
-                &vault_signer_seeds,
-            )?;
-        }
+        send_from_vault(
+            native_coin_amount,
+            coin_wallet.token_account(),
+            coin_vault.token_account(),
+            spl_token_program,
+            vault_signer,
+            &vault_signer_seeds,
+        )?;
+        send_from_vault(
+            native_pc_amount,
+            pc_wallet.token_account(),
+            pc_vault.token_account(),
+            spl_token_program,
+            vault_signer,
+            &vault_signer_seeds,
+        )
+    }
+
+    /// Takes everything free out of `open_orders`, returning the native coin and pc
+    /// amounts that should be sent from the vaults.
+    fn withdraw_free_balances(
+        market: &mut MarketState,
+        open_orders: &mut OpenOrders,
+    ) -> (u64, u64) {
+        let native_coin_amount = open_orders.native_coin_free;
+        let native_pc_amount = open_orders.native_pc_free;
+
+        market.coin_deposits_total -= native_coin_amount;
+        market.pc_deposits_total -= native_pc_amount;
+
+        open_orders.native_coin_free = 0;
+        open_orders.native_pc_free = 0;
+
+        open_orders.native_coin_total = open_orders
+            .native_coin_total
+            .checked_sub(native_coin_amount)
+            .unwrap();
+        open_orders.native_pc_total = open_orders
+            .native_pc_total
+            .checked_sub(native_pc_amount)
+            .unwrap();
+
+        (native_coin_amount, native_pc_amount)
+    }
 
+    #[cfg(feature = "program")]
+    fn settle_referrer_rebates<'a, 'b: 'a>(
+        market: &mut MarketState,
+        open_orders: &mut OpenOrders,
+        referrer: Option<account_parser::PcWallet<'a, 'b>>,
+        pc_vault: account_parser::PcVault<'a, 'b>,
+        spl_token_program: account_parser::SplTokenProgram<'a, 'b>,
+        vault_signer: account_parser::VaultSigner<'a, 'b>,
+        vault_signer_seeds: &[&[u8]],
+    ) -> DexResult {
         match referrer {
             Some(referrer_pc_wallet) if open_orders.referrer_rebates_accrued > 0 => {
                 send_from_vault(
@@ -1994,7 +4752,7 @@ impl State {
                     pc_vault.token_account(),
                     spl_token_program,
                     vault_signer,
-                    &vault_signer_seeds,
+                    vault_signer_seeds,
                 )?;
             }
             _ => {
@@ -2017,18 +4775,13 @@ impl State {
             mut req_q,
             orders_owner: _,
         } = args;
-        let mut slot = None;
-        for (i, ith_client_order_id) in open_orders.client_order_ids.iter().enumerate() {
-            if *ith_client_order_id == client_order_id.get() && !open_orders.slot_is_free(i as u8) {
-                slot = Some(i);
-                break;
-            }
-        }
-        let expected_open_orders_slot = slot.ok_or(DexErrorCode::ClientIdNotFound)? as u8;
+        let expected_open_orders_slot = open_orders
+            .find_client_order_slot(client_order_id.get())
+            .ok_or(DexErrorCode::ClientIdNotFound)?;
         let side = open_orders
             .slot_side(expected_open_orders_slot)
             .ok_or(DexErrorCode::ClientIdNotFound)?;
-        let ref order_id = open_orders.orders[expected_open_orders_slot as usize];
+        let order_id = open_orders.order_id(expected_open_orders_slot);
         let request = Request::new(RequestView::CancelOrder {
             cancel_id: req_q.gen_seq_num(),
             expected_owner: open_orders_address,
@@ -2046,12 +4799,19 @@ impl State {
     fn process_cancel_order(args: account_parser::CancelOrderArgs) -> DexResult {
         let account_parser::CancelOrderArgs {
             instruction,
-            open_orders: _,
+            open_orders,
             open_orders_address,
             mut req_q,
             orders_owner: _,
         } = args;
 
+        // the order may have been filled or cancelled since, and an order requeued
+        // under a new id is found by its slot, so the slot has to hold the order
+        if open_orders.slot_side(instruction.owner_slot) != Some(instruction.side)
+            || *open_orders.order_id(instruction.owner_slot) != instruction.order_id
+        {
+            return Ok(());
+        }
         let request = Request::new(RequestView::CancelOrder {
             cancel_id: req_q.gen_seq_num(),
             expected_owner: open_orders_address,
@@ -2066,6 +4826,245 @@ impl State {
         Ok(())
     }
 
+    fn process_cancel_orders_batch(args: account_parser::CancelOrdersBatchArgs) -> DexResult {
+        let account_parser::CancelOrdersBatchArgs {
+            order_ids,
+            open_orders,
+            open_orders_address,
+            mut req_q,
+            orders_owner: _,
+        } = args;
+
+        for order_id in order_ids {
+            // the order may have been filled or cancelled since the batch was sent
+            let slot = match open_orders.find_order_slot(*order_id) {
+                Some(slot) => slot,
+                None => continue,
+            };
+            let side = open_orders.slot_side(slot).ok_or(assertion_error!())?;
+            let request = Request::new(RequestView::CancelOrder {
+                cancel_id: req_q.gen_seq_num(),
+                expected_owner: open_orders_address,
+                expected_owner_slot: slot,
+                order_id,
+                side,
+                client_order_id: None,
+            });
+            req_q
+                .push_back(request)
+                .map_err(|_| DexErrorCode::RequestQueueFull)?;
+        }
+        Ok(())
+    }
+
+    fn process_set_position_limits(args: account_parser::SetPositionLimitsArgs) -> DexResult {
+        let account_parser::SetPositionLimitsArgs {
+            instruction,
+            mut open_orders,
+            orders_owner: _,
+        } = args;
+
+        open_orders.set_position_limits(
+            instruction
+                .max_native_coin_position
+                .map_or(0, NonZeroU64::get),
+            instruction.reduce_only,
+        )
+    }
+
+    fn process_set_market_params(args: account_parser::SetMarketParamsArgs) -> DexResult {
+        let account_parser::SetMarketParamsArgs {
+            instruction,
+            market,
+            mut event_q,
+            authority: _,
+            book_is_empty,
+            clock,
+        } = args;
+
+        if let Some(fee_schedule) = instruction.fee_schedule {
+            if !fee_schedule.is_valid() {
+                Err(DexErrorCode::InvalidFeeSchedule)?
+            }
+            market.fee_schedule = fee_schedule;
+        }
+        if let Some(pc_dust_threshold) = instruction.pc_dust_threshold {
+            market.pc_dust_threshold = pc_dust_threshold;
+        }
+        if let Some(auto_consume) = instruction.auto_consume {
+            market.set_auto_consume(auto_consume);
+        }
+        if let Some(mode) = instruction.mode {
+            // the book may be crossed, so only RunAuction can end an auction
+            if market.is_auction() && mode != MarketMode::Auction {
+                Err(DexErrorCode::MarketInAuction)?
+            }
+            // an auction only collects orders, so it can't take over a live book
+            if !market.is_auction() && mode == MarketMode::Auction && !book_is_empty {
+                Err(DexErrorCode::MarketNotEmpty)?
+            }
+            market.set_mode(mode);
+        }
+        if let Some(price_band) = instruction.price_band {
+            market.price_band_bps = price_band.max_deviation_bps;
+            market.price_band_halt_slots = price_band.halt_slots;
+            // and lifts any halt in progress
+            market.halted_until_slot = 0;
+        }
+        if instruction.coin_lot_size.is_some() || instruction.pc_lot_size.is_some() {
+            // resting orders and queued requests are denominated in the old lots
+            if !book_is_empty {
+                Err(DexErrorCode::MarketNotEmpty)?
+            }
+            // the old price held until now, so it's counted before it's cleared
+            let clock = clock.ok_or(DexErrorCode::WrongClockSysvarAccount)?;
+            market.accumulate_price(clock.slot);
+            if let Some(coin_lot_size) = instruction.coin_lot_size {
+                market.coin_lot_size = coin_lot_size.get();
+            }
+            if let Some(pc_lot_size) = instruction.pc_lot_size {
+                market.pc_lot_size = pc_lot_size.get();
+            }
+            market.last_fill_price = 0;
+        }
+
+        event_q
+            .push_back(Event::new(EventView::MarketParams {
+                fee_schedule: market.fee_schedule,
+                pc_dust_threshold: market.pc_dust_threshold,
+                coin_lot_size: market.coin_lot_size,
+                pc_lot_size: market.pc_lot_size,
+            }))
+            .map_err(|_| DexErrorCode::EventQueueFull)?;
+        Ok(())
+    }
+
+    fn process_cancel_all_orders(args: account_parser::CancelAllOrdersArgs) -> DexResult {
+        let account_parser::CancelAllOrdersArgs {
+            side,
+            open_orders,
+            open_orders_address,
+            mut req_q,
+            orders_owner: _,
+        } = args;
+
+        for slot in open_orders.occupied_slots() {
+            let order_side = match open_orders.slot_side(slot) {
+                Some(order_side) => order_side,
+                None => continue,
+            };
+            if side.map_or(false, |side| side != order_side) {
+                continue;
+            }
+            let request = Request::new(RequestView::CancelOrder {
+                cancel_id: req_q.gen_seq_num(),
+                expected_owner: open_orders_address,
+                expected_owner_slot: slot,
+                order_id: open_orders.order_id(slot),
+                side: order_side,
+                client_order_id: None,
+            });
+            req_q
+                .push_back(request)
+                .map_err(|_| DexErrorCode::RequestQueueFull)?;
+        }
+        Ok(())
+    }
+
+    /// Applies an event to the OpenOrders account it belongs to.
+    fn apply_event(open_orders: &mut OpenOrdersMut, event: &Event) -> DexResult {
+        let view = event.as_view()?;
+        check_assert!((event.owner_slot() as usize) < open_orders.slot_count())?;
+        check_assert_eq!(&open_orders.slot_side(event.owner_slot()), &view.side())?;
+        check_assert_eq!(open_orders.order_id(event.owner_slot()), &event.order_id)?;
+
+        // println!("{:#?}", event.as_view()?);
+
+        match event.as_view()? {
+            EventView::Fill {
+                side,
+                maker,
+                native_qty_paid,
+                native_qty_received,
+                native_fee_or_rebate,
+                fee_tier: _,
+                order_id: _,
+                owner: _,
+                owner_slot,
+                client_order_id,
+            } => {
+                match side {
+                    Side::Bid => {
+                        open_orders.native_pc_total -= native_qty_paid;
+                        open_orders.native_coin_total += native_qty_received;
+                        open_orders.native_coin_free += native_qty_received;
+                        open_orders.remove_native_coin_bid(native_qty_received);
+
+                        if maker {
+                            open_orders.native_pc_free += native_fee_or_rebate;
+                        }
+                    }
+                    Side::Ask => {
+                        open_orders.native_coin_total -= native_qty_paid;
+                        open_orders.native_pc_total += native_qty_received;
+                        open_orders.native_pc_free += native_qty_received;
+                    }
+                };
+                if !maker {
+                    let referrer_rebate = fees::referrer_rebate(native_fee_or_rebate);
+                    open_orders.referrer_rebates_accrued += referrer_rebate;
+                }
+                if let Some(client_id) = client_order_id {
+                    debug_assert_eq!(client_id.get(), open_orders.client_order_id(owner_slot));
+                }
+            }
+            EventView::Out {
+                side,
+                native_qty_unlocked,
+                native_qty_still_locked,
+                native_coin_qty_cancelled,
+                order_id: _,
+                owner: _,
+                owner_slot,
+                client_order_id,
+            } => {
+                let fully_out = native_qty_still_locked == 0;
+
+                match side {
+                    Side::Bid => {
+                        open_orders.native_pc_free += native_qty_unlocked;
+                        open_orders.remove_native_coin_bid(native_coin_qty_cancelled);
+                        check_assert!(open_orders.native_pc_free <= open_orders.native_pc_total)?;
+                    }
+                    Side::Ask => {
+                        open_orders.native_coin_free += native_qty_unlocked;
+                        check_assert!(
+                            open_orders.native_coin_free <= open_orders.native_coin_total
+                        )?;
+                    }
+                };
+                if let Some(client_id) = client_order_id {
+                    debug_assert_eq!(client_id.get(), open_orders.client_order_id(owner_slot));
+                }
+                if fully_out {
+                    open_orders.remove_order(owner_slot)?;
+                }
+            }
+            EventView::Requeue {
+                side: _,
+                order_id: _,
+                new_order_id,
+                owner: _,
+                owner_slot,
+                client_order_id: _,
+            } => {
+                open_orders.set_order_id(owner_slot, new_order_id);
+            }
+            EventView::MarketParams { .. } => check_unreachable!()?,
+        };
+        Ok(())
+    }
+
     fn process_consume_events(args: account_parser::ConsumeEventsArgs) -> DexResult {
         let account_parser::ConsumeEventsArgs {
             limit,
@@ -2082,125 +5081,367 @@ impl State {
             };
 
             let view = event.as_view()?;
+            if let EventView::MarketParams { .. } = view {
+                // only there for indexers, no OpenOrders account to update
+                event_q
+                    .pop_front()
+                    .map_err(|()| DexErrorCode::ConsumeEventsQueueFailure)?;
+                continue;
+            }
             let owner: [u64; 4] = event.owner;
             let owner_index: Result<usize, usize> = open_orders_accounts
                 .binary_search_by_key(&owner, |account_info| account_info.key.to_aligned_bytes());
-            let mut open_orders: RefMut<OpenOrders> = match owner_index {
+            let mut open_orders: OpenOrdersMut = match owner_index {
                 Err(_) => break,
                 Ok(i) => {
                     market.load_orders_mut(&open_orders_accounts[i], None, program_id, None)?
                 }
             };
 
-            check_assert!(event.owner_slot < 128)?;
-            check_assert_eq!(&open_orders.slot_side(event.owner_slot), &Some(view.side()))?;
-            check_assert_eq!(
-                &open_orders.orders[event.owner_slot as usize],
-                &event.order_id
-            )?;
+            Self::apply_event(&mut open_orders, event)?;
 
-            // println!("{:#?}", event.as_view()?);
+            event_q
+                .pop_front()
+                .map_err(|()| DexErrorCode::ConsumeEventsQueueFailure)?;
+        }
+        Ok(())
+    }
 
-            match event.as_view()? {
-                EventView::Fill {
-                    side,
-                    maker,
-                    native_qty_paid,
-                    native_qty_received,
-                    native_fee_or_rebate,
-                    fee_tier: _,
-                    order_id: _,
-                    owner: _,
-                    owner_slot,
-                    client_order_id,
-                } => {
-                    match side {
-                        Side::Bid => {
-                            open_orders.native_pc_total -= native_qty_paid;
-                            open_orders.native_coin_total += native_qty_received;
-                            open_orders.native_coin_free += native_qty_received;
-
-                            if maker {
-                                open_orders.native_pc_free += native_fee_or_rebate;
-                            }
-                        }
-                        Side::Ask => {
-                            open_orders.native_coin_total -= native_qty_paid;
-                            open_orders.native_pc_total += native_qty_received;
-                            open_orders.native_pc_free += native_qty_received;
-                        }
-                    };
-                    if !maker {
-                        let referrer_rebate = fees::referrer_rebate(native_fee_or_rebate);
-                        open_orders.referrer_rebates_accrued += referrer_rebate;
-                    }
-                    if let Some(client_id) = client_order_id {
-                        debug_assert_eq!(
-                            client_id.get(),
-                            open_orders.client_order_ids[owner_slot as usize]
-                        );
-                    }
+    fn event_owner_index(
+        open_orders_accounts: &[([u64; 4], OpenOrdersMut)],
+        event: &Event,
+    ) -> DexResult<Option<usize>> {
+        if let EventView::MarketParams { .. } = event.as_view()? {
+            return Ok(None);
+        }
+        let owner = event.owner;
+        Ok(open_orders_accounts
+            .iter()
+            .position(|(key, _)| *key == owner))
+    }
+
+    fn process_match_orders(args: account_parser::MatchOrdersArgs) -> DexResult {
+        let account_parser::MatchOrdersArgs {
+            mut order_book_state,
+            mut req_q,
+            mut event_q,
+            limit,
+            mut open_orders_accounts,
+        } = args;
+        // the lots left to fill in the auction were counted against the book as it is
+        if order_book_state.market_state.auction_in_progress() {
+            Err(DexErrorCode::AuctionInProgress)?
+        }
+        let queued_len = event_q.len();
+        if open_orders_accounts.is_empty() || queued_len > MAX_AUTO_CONSUME_QUEUED_EVENTS {
+            return order_book_state.process_requests(&mut req_q, &mut event_q, limit);
+        }
+
+        // an account with events still queued has to wait for the crank,
+        // or its new events would be applied ahead of them
+        let mut has_queued_events = vec![false; open_orders_accounts.len()];
+        for event in event_q.iter() {
+            if let Some(i) = Self::event_owner_index(&open_orders_accounts, event)? {
+                has_queued_events[i] = true;
+            }
+        }
+
+        order_book_state.process_requests(&mut req_q, &mut event_q, limit)?;
+
+        // Applied events leave the queue and the rest keep their order. Each account
+        // still sees its own events in order, only ahead of other accounts' queued ones.
+        event_q.retain_pushes(queued_len, |event| {
+            match Self::event_owner_index(&open_orders_accounts, event)? {
+                Some(i) if !has_queued_events[i] => {
+                    Self::apply_event(&mut open_orders_accounts[i].1, event)?;
+                    Ok(false)
                 }
-                EventView::Out {
-                    side,
-                    native_qty_unlocked,
-                    native_qty_still_locked,
-                    order_id: _,
-                    owner: _,
-                    owner_slot,
-                    client_order_id,
-                } => {
-                    let fully_out = native_qty_still_locked == 0;
-
-                    match side {
-                        Side::Bid => {
-                            open_orders.native_pc_free += native_qty_unlocked;
-                            check_assert!(
-                                open_orders.native_pc_free <= open_orders.native_pc_total
-                            )?;
-                        }
-                        Side::Ask => {
-                            open_orders.native_coin_free += native_qty_unlocked;
-                            check_assert!(
-                                open_orders.native_coin_free <= open_orders.native_coin_total
-                            )?;
-                        }
-                    };
-                    if let Some(client_id) = client_order_id {
-                        debug_assert_eq!(
-                            client_id.get(),
-                            open_orders.client_order_ids[owner_slot as usize]
-                        );
-                    }
-                    if fully_out {
-                        open_orders.remove_order(owner_slot)?;
-                    }
+                _ => Ok(true),
+            }
+        })
+    }
+
+    #[cfg(feature = "program")]
+    fn process_replace_order(
+        replaced_order: ReplacedOrder,
+        args: account_parser::NewOrderArgs,
+    ) -> DexResult {
+        let open_orders = &args.open_orders;
+        let slot = match replaced_order {
+            ReplacedOrder::OrderId(order_id) => open_orders
+                .find_order_slot(order_id)
+                .ok_or(DexErrorCode::OrderNotFound)?,
+            ReplacedOrder::ClientId(client_id) => {
+                if client_id == 0 {
+                    Err(DexErrorCode::ClientOrderIdIsZero)?
                 }
-            };
+                open_orders
+                    .find_client_order_slot(client_id)
+                    .ok_or(DexErrorCode::ClientIdNotFound)?
+            }
+        };
+        let side = open_orders.slot_side(slot).ok_or(assertion_error!())?;
+        if side != args.instruction.side {
+            Err(DexErrorCode::ReplacedOrderSideMismatch)?
+        }
+        let order_id = *open_orders.order_id(slot);
+        Self::place_new_order(args, Some((order_id, slot)), None)
+    }
+
+    #[cfg(feature = "program")]
+    fn process_new_iceberg_order(
+        display_qty: NonZeroU64,
+        args: account_parser::NewOrderArgs,
+    ) -> DexResult {
+        match args.instruction.order_type {
+            OrderType::Limit | OrderType::PostOnly => (),
+            OrderType::ImmediateOrCancel | OrderType::FillOrKill => {
+                Err(DexErrorCode::InvalidIcebergOrder)?
+            }
+        };
+        Self::place_new_order(args, None, Some(display_qty))
+    }
+
+    #[cfg(feature = "program")]
+    fn process_new_order(args: account_parser::NewOrderArgs) -> DexResult {
+        Self::place_new_order(args, None, None)
+    }
+
+    #[cfg(feature = "program")]
+    fn place_new_order(
+        args: account_parser::NewOrderArgs,
+        replaces: Option<(u128, u16)>,
+        display_qty: Option<NonZeroU64>,
+    ) -> DexResult {
+        let account_parser::NewOrderArgs {
+            instruction,
+            trigger_price,
+            market,
+            mut open_orders,
+            open_orders_address,
+            mut req_q,
+            payer,
+            owner,
+            coin_vault,
+            pc_vault,
+            spl_token_program,
+            fee_tier,
+            stop_orders,
+        } = args;
+
+        market.check_order_type_allowed(instruction.order_type)?;
+        if let Some(trigger_price) = trigger_price {
+            if trigger_price.get() > MAX_TRIGGER_PRICE {
+                Err(DexErrorCode::InvalidTriggerPrice)?
+            }
+        }
+        if let Some(expiry) = instruction.expiry {
+            if !expiry.is_valid() {
+                Err(DexErrorCode::InvalidOrderExpiry)?
+            }
+        }
+
+        let native_coin_qty = instruction
+            .max_qty
+            .get()
+            .saturating_mul(market.coin_lot_size);
+        open_orders.check_position_limits(instruction.side, native_coin_qty)?;
+        if instruction.side == Side::Bid {
+            open_orders.add_native_coin_bid(native_coin_qty);
+        }
+
+        let deposit_amount;
+        let deposit_vault;
+
+        let native_pc_qty_locked;
+        match instruction.side {
+            Side::Bid => {
+                let lock_qty_native = market.native_qty_to_lock(instruction, fee_tier)?;
+                native_pc_qty_locked = Some(NonZeroU64::new(lock_qty_native).unwrap());
+                let free_qty_to_lock = lock_qty_native.min(open_orders.native_pc_free);
+                deposit_amount = lock_qty_native - free_qty_to_lock;
+                deposit_vault = pc_vault.token_account();
+                open_orders.lock_free_pc(free_qty_to_lock);
+                open_orders.credit_locked_pc(deposit_amount);
+                market.pc_deposits_total = market
+                    .pc_deposits_total
+                    .checked_add(deposit_amount)
+                    .unwrap();
+            }
+            Side::Ask => {
+                let lock_qty_native = market.native_qty_to_lock(instruction, fee_tier)?;
+                let free_qty_to_lock = lock_qty_native.min(open_orders.native_coin_free);
+                deposit_amount = lock_qty_native - free_qty_to_lock;
+                deposit_vault = coin_vault.token_account();
+                open_orders.lock_free_coin(free_qty_to_lock);
+                open_orders.credit_locked_coin(deposit_amount);
+                native_pc_qty_locked = None;
+                market.coin_deposits_total = market
+                    .coin_deposits_total
+                    .checked_add(deposit_amount)
+                    .unwrap();
+            }
+        };
+
+        deposit_to_vault(
+            deposit_amount,
+            payer,
+            deposit_vault,
+            owner,
+            spl_token_program,
+        )?;
+
+        // record the open order in the user account; stop orders go by their key until
+        // they're triggered
+        let order_id = req_q.gen_order_id(instruction.limit_price.get(), instruction.side);
+        let stop_key = trigger_price
+            .map(|trigger_price| stop_order_key(&order_id, instruction.side, trigger_price.get()));
+        let owner_slot = open_orders.add_order(stop_key.unwrap_or(order_id), instruction.side)?;
+        open_orders.set_client_order_id(owner_slot, instruction.client_id);
+
+        if let Some(key) = stop_key {
+            // park the order in the stop order slab until its trigger price trades
+            let stop_orders = stop_orders.ok_or(assertion_error!())?;
+            let mut stop_order = LeafNode::new(
+                owner_slot,
+                &key,
+                open_orders_address,
+                instruction.max_qty.get(),
+                fee_tier,
+                instruction.client_id,
+            );
+            stop_order.set_stop_order_params(
+                instruction.order_type,
+                instruction.self_trade_behavior,
+                instruction.limit_price.get(),
+                native_pc_qty_locked.map_or(0, NonZeroU64::get),
+            );
+            stop_order.set_expiry(instruction.expiry);
+            stop_orders
+                .insert_leaf(&stop_order)
+                .map_err(|_| DexErrorCode::StopOrdersFull)?;
+            return Ok(());
+        }
+
+        // add the request to the queue
+        let request = Request::new(RequestView::NewOrder {
+            side: instruction.side,
+            order_type: instruction.order_type,
+            order_id: &order_id,
+            fee_tier,
+            self_trade_behavior: instruction.self_trade_behavior,
+            owner: open_orders_address,
+            owner_slot,
+            max_coin_qty: instruction.max_qty,
+            native_pc_qty_locked,
+            client_order_id: NonZeroU64::new(instruction.client_id),
+            expiry: instruction.expiry,
+            replaces: replaces
+                .as_ref()
+                .map(|(order_id, owner_slot)| (order_id, *owner_slot)),
+            display_qty,
+        });
 
-            event_q
-                .pop_front()
-                .map_err(|()| DexErrorCode::ConsumeEventsQueueFailure)?;
-        }
+        req_q
+            .push_back(request)
+            .map_err(|_| DexErrorCode::RequestQueueFull)?;
         Ok(())
     }
 
-    fn process_match_orders(args: account_parser::MatchOrdersArgs) -> DexResult {
-        let account_parser::MatchOrdersArgs {
-            mut order_book_state,
+    #[cfg(feature = "program")]
+    fn process_new_market_buy(
+        max_native_pc_qty_including_fees: NonZeroU64,
+        args: account_parser::NewOrderArgs,
+    ) -> DexResult {
+        let account_parser::NewOrderArgs {
+            instruction,
+            market,
+            mut open_orders,
+            open_orders_address,
             mut req_q,
-            mut event_q,
-            limit,
+            payer,
+            owner,
+            pc_vault,
+            spl_token_program,
+            fee_tier,
+            ..
         } = args;
-        order_book_state.process_requests(&mut req_q, &mut event_q, limit)
+
+        market.check_order_type_allowed(instruction.order_type)?;
+        // under a position limit, a market buy takes at most the coin left below it
+        let max_qty = match open_orders.max_native_coin_position() {
+            0 => instruction.max_qty,
+            max_native_coin_position => {
+                let headroom = max_native_coin_position.saturating_sub(
+                    open_orders
+                        .native_coin_total
+                        .saturating_add(open_orders.native_coin_bid()),
+                );
+                NonZeroU64::new(
+                    instruction
+                        .max_qty
+                        .get()
+                        .min(headroom / market.coin_lot_size),
+                )
+                .ok_or(DexErrorCode::PositionLimitExceeded)?
+            }
+        };
+        let native_coin_qty = max_qty.get().saturating_mul(market.coin_lot_size);
+        open_orders.check_position_limits(Side::Bid, native_coin_qty)?;
+        open_orders.add_native_coin_bid(native_coin_qty);
+
+        // the whole budget is locked; the matcher unlocks whatever it doesn't spend
+        let lock_qty_native = max_native_pc_qty_including_fees.get();
+        let free_qty_to_lock = lock_qty_native.min(open_orders.native_pc_free);
+        let deposit_amount = lock_qty_native - free_qty_to_lock;
+        open_orders.lock_free_pc(free_qty_to_lock);
+        open_orders.credit_locked_pc(deposit_amount);
+        market.pc_deposits_total = market
+            .pc_deposits_total
+            .checked_add(deposit_amount)
+            .unwrap();
+
+        deposit_to_vault(
+            deposit_amount,
+            payer,
+            pc_vault.token_account(),
+            owner,
+            spl_token_program,
+        )?;
+
+        // a limit price of 0 marks the order as unbounded in price
+        let order_id = req_q.gen_order_id(0, Side::Bid);
+        let owner_slot = open_orders.add_order(order_id, Side::Bid)?;
+        open_orders.set_client_order_id(owner_slot, instruction.client_id);
+
+        let request = Request::new(RequestView::NewOrder {
+            side: Side::Bid,
+            order_type: OrderType::ImmediateOrCancel,
+            order_id: &order_id,
+            fee_tier,
+            self_trade_behavior: instruction.self_trade_behavior,
+            owner: open_orders_address,
+            owner_slot,
+            max_coin_qty: max_qty,
+            native_pc_qty_locked: Some(max_native_pc_qty_including_fees),
+            client_order_id: NonZeroU64::new(instruction.client_id),
+            expiry: None,
+            replaces: None,
+            display_qty: None,
+        });
+
+        req_q
+            .push_back(request)
+            .map_err(|_| DexErrorCode::RequestQueueFull)?;
+        Ok(())
     }
 
     #[cfg(feature = "program")]
-    fn process_new_order(args: account_parser::NewOrderArgs) -> DexResult {
-        let account_parser::NewOrderArgs {
+    fn process_new_pegged_order(args: account_parser::NewPeggedOrderArgs) -> DexResult {
+        let account_parser::NewPeggedOrderArgs {
             instruction,
             market,
-            open_orders,
+            mut open_orders,
             open_orders_address,
             mut req_q,
             payer,
@@ -2209,112 +5450,210 @@ impl State {
             pc_vault,
             spl_token_program,
             fee_tier,
+            pegged_orders,
         } = args;
 
-        let deposit_amount;
-        let deposit_vault;
+        // pegged orders only ever rest on the book
+        market.check_order_type_allowed(OrderType::PostOnly)?;
+        if instruction.peg_offset < -MAX_PEG_OFFSET || instruction.peg_offset > MAX_PEG_OFFSET {
+            Err(DexErrorCode::InvalidPeggedOrder)?
+        }
 
-        let native_pc_qty_locked;
-        match instruction.side {
+        let max_qty = instruction.max_qty.get();
+        let native_coin_qty = max_qty.saturating_mul(market.coin_lot_size);
+        open_orders.check_position_limits(instruction.side, native_coin_qty)?;
+        if instruction.side == Side::Bid {
+            open_orders.add_native_coin_bid(native_coin_qty);
+        }
+
+        // pegged orders only provide liquidity, so bids lock their cap without a fee
+        let (deposit_amount, deposit_vault) = match instruction.side {
             Side::Bid => {
-                let lock_qty_lots = instruction
-                    .max_qty
-                    .get()
-                    .checked_mul(instruction.limit_price.get())
-                    .ok_or(DexErrorCode::InsufficientFunds)?;
-                let native_lock_qty_before_fee = lock_qty_lots
-                    .checked_mul(market.pc_lot_size)
+                let cap_price = instruction
+                    .cap_price
+                    .ok_or(DexErrorCode::InvalidPeggedOrder)?;
+                let lock_qty_native = max_qty
+                    .checked_mul(cap_price.get())
+                    .and_then(|lock_qty_lots| lock_qty_lots.checked_mul(market.pc_lot_size))
                     .ok_or(DexErrorCode::InsufficientFunds)?;
-                let lock_qty_native = native_lock_qty_before_fee
-                    .checked_add(fee_tier.taker_fee(native_lock_qty_before_fee))
-                    .ok_or(DexErrorCode::InsufficientFunds)?;
-                native_pc_qty_locked = Some(NonZeroU64::new(lock_qty_native).unwrap());
                 let free_qty_to_lock = lock_qty_native.min(open_orders.native_pc_free);
-                deposit_amount = lock_qty_native - free_qty_to_lock;
-                deposit_vault = pc_vault.token_account();
+                let deposit_amount = lock_qty_native - free_qty_to_lock;
                 open_orders.lock_free_pc(free_qty_to_lock);
                 open_orders.credit_locked_pc(deposit_amount);
                 market.pc_deposits_total = market
                     .pc_deposits_total
                     .checked_add(deposit_amount)
                     .unwrap();
+                (deposit_amount, pc_vault.token_account())
             }
             Side::Ask => {
-                let lock_qty_native = instruction
-                    .max_qty
-                    .get()
-                    .checked_mul((*market).coin_lot_size)
+                let lock_qty_native = max_qty
+                    .checked_mul(market.coin_lot_size)
                     .ok_or(DexErrorCode::InsufficientFunds)?;
                 let free_qty_to_lock = lock_qty_native.min(open_orders.native_coin_free);
-                deposit_amount = lock_qty_native - free_qty_to_lock;
-                deposit_vault = coin_vault.token_account();
+                let deposit_amount = lock_qty_native - free_qty_to_lock;
                 open_orders.lock_free_coin(free_qty_to_lock);
                 open_orders.credit_locked_coin(deposit_amount);
-                native_pc_qty_locked = None;
                 market.coin_deposits_total = market
                     .coin_deposits_total
                     .checked_add(deposit_amount)
                     .unwrap();
+                (deposit_amount, coin_vault.token_account())
             }
         };
 
-        // pull balances from payer, signed by owner
-        let deposit_instruction = spl_token::instruction::transfer(
-            &spl_token::ID,
-            payer.inner().key,
-            deposit_vault.inner().key,
-            owner.inner().key,
-            &[],
+        deposit_to_vault(
             deposit_amount,
-        )
-        .unwrap();
-        assert_eq!(*spl_token_program.inner().key, spl_token::ID);
-
-        // pull the deposited funds
-        invoke_spl_token(
-            &deposit_instruction,
-            &[
-                payer.inner().clone(),
-                deposit_vault.inner().clone(),
-                owner.inner().clone(),
-                spl_token_program.inner().clone(),
-            ],
-            &[],
-        )
-        .map_err(|err| {
-            match err {
-                ProgramError::Custom(i) => {
-                    match TokenError::from_u32(i) {
-                        Some(TokenError::InsufficientFunds) => DexErrorCode::InsufficientFunds,
-                        _ => DexErrorCode::TransferFailed,
-                    }
+            payer,
+            deposit_vault,
+            owner,
+            spl_token_program,
+        )?;
+
+        // the order goes by its key until it first reaches the book
+        let price = instruction.cap_price.map_or(0, NonZeroU64::get);
+        let order_id = req_q.gen_order_id(price, instruction.side);
+        let key = pegged_order_key(&order_id, instruction.side, instruction.peg_offset);
+        let owner_slot = open_orders.add_order(key, instruction.side)?;
+        open_orders.set_client_order_id(owner_slot, instruction.client_id);
+
+        let mut pegged_order = LeafNode::new(
+            owner_slot,
+            &key,
+            open_orders_address,
+            max_qty,
+            fee_tier,
+            instruction.client_id,
+        );
+        pegged_order.set_pegged_order_params(instruction.cap_price);
+        pegged_order.set_peg_price(price);
+        pegged_orders
+            .insert_leaf(&pegged_order)
+            .map_err(|_| DexErrorCode::PeggedOrdersFull)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "program")]
+    fn process_new_orders_batch(args: account_parser::NewOrdersBatchArgs) -> DexResult {
+        let account_parser::NewOrdersBatchArgs {
+            instructions,
+            market,
+            mut open_orders,
+            open_orders_address,
+            mut req_q,
+            owner,
+            coin_payer,
+            pc_payer,
+            coin_vault,
+            pc_vault,
+            spl_token_program,
+            fee_tier,
+        } = args;
+
+        if open_orders.free_slot_count() < instructions.len() {
+            Err(DexErrorCode::TooManyOpenOrders)?
+        }
+        let mut native_coin_qty_to_lock: u64 = 0;
+        let mut native_pc_qty_to_lock: u64 = 0;
+        let mut native_coin_qty_to_buy: u64 = 0;
+        for instruction in instructions {
+            market.check_order_type_allowed(instruction.order_type)?;
+            if let Some(expiry) = instruction.expiry {
+                if !expiry.is_valid() {
+                    Err(DexErrorCode::InvalidOrderExpiry)?
                 }
-                _ => DexErrorCode::TransferFailed,
             }
-        })?;
+            if instruction.side == Side::Bid {
+                native_coin_qty_to_buy = native_coin_qty_to_buy.saturating_add(
+                    instruction
+                        .max_qty
+                        .get()
+                        .saturating_mul(market.coin_lot_size),
+                );
+            }
+            let lock_qty_native = market.native_qty_to_lock(instruction, fee_tier)?;
+            let total_qty_to_lock = match instruction.side {
+                Side::Bid => &mut native_pc_qty_to_lock,
+                Side::Ask => &mut native_coin_qty_to_lock,
+            };
+            *total_qty_to_lock = total_qty_to_lock
+                .checked_add(lock_qty_native)
+                .ok_or(DexErrorCode::InsufficientFunds)?;
+        }
 
-        // record the open order in the user account
-        let order_id = req_q.gen_order_id(instruction.limit_price.get(), instruction.side);
-        let owner_slot = open_orders.add_order(order_id, instruction.side)?;
-        open_orders.client_order_ids[owner_slot as usize] = instruction.client_id;
+        if native_coin_qty_to_buy > 0 {
+            open_orders.check_position_limits(Side::Bid, native_coin_qty_to_buy)?;
+            open_orders.add_native_coin_bid(native_coin_qty_to_buy);
+        }
+        if native_coin_qty_to_lock > 0 {
+            open_orders.check_position_limits(Side::Ask, native_coin_qty_to_lock)?;
+        }
 
-        // add the request to the queue
-        let request = Request::new(RequestView::NewOrder {
-            side: instruction.side,
-            order_type: instruction.order_type,
-            order_id: &order_id,
-            fee_tier,
-            self_trade_behavior: instruction.self_trade_behavior,
-            owner: open_orders_address,
-            owner_slot,
-            max_coin_qty: instruction.max_qty,
-            native_pc_qty_locked,
-            client_order_id: NonZeroU64::new(instruction.client_id),
-        });
+        let free_coin_qty_to_lock = native_coin_qty_to_lock.min(open_orders.native_coin_free);
+        let coin_deposit_amount = native_coin_qty_to_lock - free_coin_qty_to_lock;
+        open_orders.lock_free_coin(free_coin_qty_to_lock);
+        open_orders.credit_locked_coin(coin_deposit_amount);
+        market.coin_deposits_total = market
+            .coin_deposits_total
+            .checked_add(coin_deposit_amount)
+            .unwrap();
 
-        req_q
-            .push_back(request)
-            .map_err(|_| DexErrorCode::RequestQueueFull)?;
+        let free_pc_qty_to_lock = native_pc_qty_to_lock.min(open_orders.native_pc_free);
+        let pc_deposit_amount = native_pc_qty_to_lock - free_pc_qty_to_lock;
+        open_orders.lock_free_pc(free_pc_qty_to_lock);
+        open_orders.credit_locked_pc(pc_deposit_amount);
+        market.pc_deposits_total = market
+            .pc_deposits_total
+            .checked_add(pc_deposit_amount)
+            .unwrap();
+
+        if coin_deposit_amount > 0 {
+            deposit_to_vault(
+                coin_deposit_amount,
+                coin_payer,
+                coin_vault.token_account(),
+                owner,
+                spl_token_program,
+            )?;
+        }
+        if pc_deposit_amount > 0 {
+            deposit_to_vault(
+                pc_deposit_amount,
+                pc_payer,
+                pc_vault.token_account(),
+                owner,
+                spl_token_program,
+            )?;
+        }
+
+        for instruction in instructions {
+            let order_id = req_q.gen_order_id(instruction.limit_price.get(), instruction.side);
+            let owner_slot = open_orders.add_order(order_id, instruction.side)?;
+            open_orders.set_client_order_id(owner_slot, instruction.client_id);
+
+            let native_pc_qty_locked = match instruction.side {
+                Side::Bid => NonZeroU64::new(market.native_qty_to_lock(instruction, fee_tier)?),
+                Side::Ask => None,
+            };
+            let request = Request::new(RequestView::NewOrder {
+                side: instruction.side,
+                order_type: instruction.order_type,
+                order_id: &order_id,
+                fee_tier,
+                self_trade_behavior: instruction.self_trade_behavior,
+                owner: open_orders_address,
+                owner_slot,
+                max_coin_qty: instruction.max_qty,
+                native_pc_qty_locked,
+                client_order_id: NonZeroU64::new(instruction.client_id),
+                expiry: instruction.expiry,
+                replaces: None,
+                display_qty: None,
+            });
+            req_q
+                .push_back(request)
+                .map_err(|_| DexErrorCode::RequestQueueFull)?;
+        }
         Ok(())
     }
 
@@ -2327,6 +5666,185 @@ impl State {
         Ok(())
     }
 
+    fn process_set_delegate(args: account_parser::SetDelegateArgs) -> DexResult {
+        let account_parser::SetDelegateArgs {
+            mut open_orders,
+            orders_owner: _,
+            delegate,
+        } = args;
+        let delegate = delegate.ok_or(DexErrorCode::DelegateNotProvided)?;
+        open_orders.set_delegate(Some(delegate.key))
+    }
+
+    fn process_revoke_delegate(args: account_parser::SetDelegateArgs) -> DexResult {
+        let account_parser::SetDelegateArgs {
+            mut open_orders,
+            orders_owner: _,
+            delegate,
+        } = args;
+        check_assert!(delegate.is_none())?;
+        open_orders.set_delegate(None)
+    }
+
+    fn process_set_settle_wallets(args: account_parser::SetSettleWalletsArgs) -> DexResult {
+        let account_parser::SetSettleWalletsArgs {
+            mut open_orders,
+            orders_owner: _,
+            wallets,
+        } = args;
+        open_orders.set_settle_wallets(
+            wallets.map(|(coin_wallet, pc_wallet)| {
+                (coin_wallet.account().key, pc_wallet.account().key)
+            }),
+        )
+    }
+
+    fn process_close_open_orders(args: account_parser::CloseOpenOrdersArgs) -> DexResult {
+        let account_parser::CloseOpenOrdersArgs {
+            mut open_orders,
+            open_orders_acc,
+            orders_owner: _,
+            destination,
+        } = args;
+
+        if !open_orders.has_no_orders()
+            || open_orders.native_coin_total != 0
+            || open_orders.native_pc_total != 0
+            || open_orders.referrer_rebates_accrued != 0
+        {
+            Err(DexErrorCode::OpenOrdersNotEmpty)?
+        }
+        open_orders.clear();
+
+        let lamports = {
+            let mut lamports = open_orders_acc.try_borrow_mut_lamports()?;
+            std::mem::replace(&mut **lamports, 0)
+        };
+        **destination.try_borrow_mut_lamports()? += lamports;
+        Ok(())
+    }
+
+    fn process_enable_market(args: account_parser::EnableMarketArgs) -> DexResult {
+        let account_parser::EnableMarketArgs {
+            market,
+            authority: _,
+        } = args;
+        market.account_flags = market.account_flags & !(AccountFlag::Disabled as u64);
+        Ok(())
+    }
+
+    fn process_run_auction(args: account_parser::RunAuctionArgs) -> DexResult {
+        let account_parser::RunAuctionArgs {
+            limit,
+            mut order_book_state,
+            mut req_q,
+            mut event_q,
+            authority: _,
+        } = args;
+
+        if !order_book_state.market_state.is_auction() {
+            Err(DexErrorCode::MarketNotInAuction)?
+        }
+        // orders still in the queue would miss the auction; once it's being filled,
+        // they wait for it to finish
+        if !order_book_state.market_state.auction_in_progress() && !req_q.empty() {
+            Err(DexErrorCode::RequestQueueNotEmpty)?
+        }
+        if order_book_state.run_auction(&mut req_q, &mut event_q, limit)? {
+            order_book_state.market_state.set_mode(MarketMode::Normal);
+        }
+        Ok(())
+    }
+
+    fn process_initialize_market_stats(
+        args: account_parser::InitializeMarketStatsArgs,
+    ) -> DexResult {
+        let account_parser::InitializeMarketStatsArgs {
+            slots_per_candle,
+            market,
+            mut market_stats,
+            market_stats_acc,
+            authority: _,
+        } = args;
+
+        *market_stats = Zeroable::zeroed();
+        market_stats.account_flags = (AccountFlag::Initialized | AccountFlag::MarketStats).bits();
+        market_stats.market = market.own_address;
+        market_stats.slots_per_candle = slots_per_candle;
+        market.market_stats = market_stats_acc.key.to_aligned_bytes();
+        Ok(())
+    }
+
+    #[cfg(feature = "program")]
+    fn process_close_market(args: account_parser::CloseMarketArgs) -> DexResult {
+        let account_parser::CloseMarketArgs {
+            market,
+            market_acc,
+            authority,
+            book_accounts,
+            optional_accounts,
+            book_is_empty,
+            coin_vault,
+            pc_vault,
+            coin_wallet,
+            pc_wallet,
+            vault_signer,
+            spl_token_program,
+        } = args;
+
+        if !book_is_empty
+            || market.coin_deposits_total != 0
+            || market.pc_deposits_total != 0
+            || market.coin_fees_accrued != 0
+            || market.pc_fees_accrued != 0
+            || market.referrer_rebates_accrued != 0
+        {
+            Err(DexErrorCode::MarketNotEmpty)?
+        }
+
+        // with nothing deposited, whatever is left in the vaults was sent there directly
+        let market_pubkey = market.pubkey();
+        let vault_signer_seeds = gen_vault_signer_seeds(&market.vault_signer_nonce, &market_pubkey);
+        let vaults = [
+            (coin_vault.token_account(), coin_wallet.token_account()),
+            (pc_vault.token_account(), pc_wallet.token_account()),
+        ];
+        for &(vault, wallet) in vaults.iter() {
+            let stray_amount = vault.balance()?;
+            if stray_amount != 0 {
+                send_from_vault(
+                    stray_amount,
+                    wallet,
+                    vault,
+                    spl_token_program,
+                    vault_signer,
+                    &vault_signer_seeds,
+                )?;
+            }
+            close_vault(
+                vault,
+                authority,
+                spl_token_program,
+                vault_signer,
+                &vault_signer_seeds,
+            )?;
+        }
+        market.account_flags = 0;
+
+        // zero-lamport accounts are purged once the transaction completes
+        let mut reclaimed_lamports = 0;
+        let closed_accounts = std::iter::once(market_acc)
+            .chain(book_accounts)
+            .chain(optional_accounts);
+        for account in closed_accounts {
+            let mut lamports = account.try_borrow_mut_lamports()?;
+            reclaimed_lamports += **lamports;
+            **lamports = 0;
+        }
+        **authority.inner().try_borrow_mut_lamports()? += reclaimed_lamports;
+        Ok(())
+    }
+
     #[cfg(feature = "program")]
     fn process_sweep_fees(args: account_parser::SweepFeesArgs) -> DexResult {
         let account_parser::SweepFeesArgs {
@@ -2352,6 +5870,71 @@ impl State {
         )
     }
 
+    fn process_migrate_market(args: account_parser::MigrateMarketArgs) -> DexResult {
+        let account_parser::MigrateMarketArgs {
+            market,
+            authority,
+            old_accounts,
+            new_accounts,
+        } = args;
+        let [old_req_q_acc, old_bids_acc, old_asks_acc] = old_accounts;
+        let [new_req_q_acc, new_bids_acc, new_asks_acc] = new_accounts;
+
+        {
+            let (old_header, old_buf) =
+                strip_header::<RequestQueueHeader, u8>(old_req_q_acc, false)?;
+            let flags = BitFlags::from_bits(old_header.account_flags).unwrap();
+            check_assert_eq!(
+                &flags,
+                &(AccountFlag::Initialized | AccountFlag::RequestQueue)
+            )?;
+            let (mut header, buf) = strip_header::<RequestQueueHeader, u8>(new_req_q_acc, true)?;
+            *header = RequestQueueHeader {
+                account_flags: (AccountFlag::Initialized
+                    | AccountFlag::RequestQueue
+                    | AccountFlag::RequestQueueV2)
+                    .bits(),
+                head: 0,
+                count: 0,
+                next_seq_num: 0,
+            };
+            let mut req_q = Queue::new(header, RefMut::map(buf, remove_slop_mut));
+            if req_q.free_slots() == 0 {
+                Err(DexErrorCode::RequestQueueEmpty)?
+            }
+            req_q.copy_legacy_requests(&old_header, &old_buf)?;
+        }
+        let order_books = [
+            (AccountFlag::Bids, old_bids_acc, new_bids_acc),
+            (AccountFlag::Asks, old_asks_acc, new_asks_acc),
+        ];
+        for &(side_flag, old_acc, new_acc) in order_books.iter() {
+            let (old_header, old_buf) = strip_header::<OrderBookStateHeader, u8>(old_acc, false)?;
+            let flags = BitFlags::from_bits(old_header.account_flags).unwrap();
+            check_assert_eq!(&flags, &(AccountFlag::Initialized | side_flag))?;
+            let (mut header, buf) = strip_header::<OrderBookStateHeader, u8>(new_acc, true)?;
+            header.account_flags =
+                (AccountFlag::Initialized | side_flag | AccountFlag::OrderBookV2).bits();
+            if buf.len() < SLAB_HEADER_LEN {
+                Err(DexErrorCode::SlabTooSmall)?
+            }
+            RefMut::map(buf, Slab::new).copy_legacy_nodes(&old_buf)?;
+        }
+        market.req_q = new_req_q_acc.key.to_aligned_bytes();
+        market.bids = new_bids_acc.key.to_aligned_bytes();
+        market.asks = new_asks_acc.key.to_aligned_bytes();
+
+        // zero-lamport accounts are purged once the transaction completes
+        let mut reclaimed_lamports = 0;
+        for account in old_accounts {
+            let mut lamports = account.try_borrow_mut_lamports()?;
+            reclaimed_lamports += **lamports;
+            **lamports = 0;
+        }
+        **authority.inner().try_borrow_mut_lamports()? += reclaimed_lamports;
+        Ok(())
+    }
+
     fn process_initialize_market(args: account_parser::InitializeMarketArgs) -> DexResult {
         let &InitializeMarketInstruction {
             coin_lot_size,
@@ -2359,13 +5942,24 @@ impl State {
             fee_rate_bps,
             vault_signer_nonce,
             pc_dust_threshold,
+            fee_schedule,
+            authority,
+            order_slabs: _,
         } = args.instruction;
 
+        // without an explicit schedule, every taker pays the fee rate, if any
+        let fee_schedule = fee_schedule.unwrap_or_else(|| FeeSchedule::flat(fee_rate_bps));
+        if !fee_schedule.is_valid() {
+            Err(DexErrorCode::InvalidFeeSchedule)?
+        }
+
         let market = args.get_market();
         let req_q = args.get_req_q();
         let event_q = args.get_event_q();
         let bids = args.get_bids();
         let asks = args.get_asks();
+        let stop_orders = args.get_stop_orders();
+        let pegged_orders = args.get_pegged_orders();
         let coin_vault = args.coin_vault_and_mint.get_account().inner();
         let coin_mint = args.coin_vault_and_mint.get_mint().inner();
         let pc_vault = args.pc_vault_and_mint.get_account().inner();
@@ -2383,7 +5977,10 @@ impl State {
         let rq_hdr: &mut RequestQueueHeader =
             try_cast_mut(rq_hdr_array).or(check_unreachable!())?;
         *rq_hdr = RequestQueueHeader {
-            account_flags: (AccountFlag::Initialized | AccountFlag::RequestQueue).bits(),
+            account_flags: (AccountFlag::Initialized
+                | AccountFlag::RequestQueue
+                | AccountFlag::RequestQueueV2)
+                .bits(),
             head: 0,
             count: 0,
             next_seq_num: 0,
@@ -2406,7 +6003,17 @@ impl State {
             seq_num: 0,
         };
         // initialize orderbook storage
-        for (flag, account) in &[(AccountFlag::Bids, bids), (AccountFlag::Asks, asks)] {
+        let mut order_book_accounts = vec![
+            (AccountFlag::Bids | AccountFlag::OrderBookV2, bids),
+            (AccountFlag::Asks | AccountFlag::OrderBookV2, asks),
+        ];
+        if let Some(stop_orders) = stop_orders {
+            order_book_accounts.push((AccountFlag::StopOrders.into(), stop_orders));
+        }
+        if let Some(pegged_orders) = pegged_orders {
+            order_book_accounts.push((AccountFlag::PeggedOrders.into(), pegged_orders));
+        }
+        for (flag, account) in &order_book_accounts {
             let mut ob_data = account.try_borrow_mut_data().unwrap();
             let ob_view = init_account_padding(&mut ob_data)?;
             const OB_HEADER_WORDS: usize = size_of::<OrderBookStateHeader>() / size_of::<u64>();
@@ -2415,7 +6022,7 @@ impl State {
             let ob_hdr: &mut OrderBookStateHeader =
                 try_cast_mut(hdr_array).or(check_unreachable!())?;
             *ob_hdr = OrderBookStateHeader {
-                account_flags: (AccountFlag::Initialized | *flag).bits(),
+                account_flags: (*flag | AccountFlag::Initialized).bits(),
             };
             let slab = Slab::new(cast_slice_mut(slab_words));
             slab.assert_minimum_capacity(100)?;
@@ -2430,7 +6037,8 @@ impl State {
             coin_lot_size,
             pc_lot_size,
             own_address: market.key.to_aligned_bytes(),
-            account_flags: (AccountFlag::Initialized | AccountFlag::Market).bits(),
+            account_flags: (AccountFlag::Initialized | AccountFlag::Market | AccountFlag::MarketV2)
+                .bits(),
 
             coin_mint: coin_mint.key.to_aligned_bytes(),
             coin_vault: coin_vault.key.to_aligned_bytes(),
@@ -2451,6 +6059,32 @@ impl State {
             pc_dust_threshold,
             fee_rate_bps: fee_rate_bps as u64,
             referrer_rebates_accrued: 0,
+
+            stop_orders: stop_orders.map_or([0; 4], |a| a.key.to_aligned_bytes()),
+            last_fill_price: 0,
+
+            pegged_orders: pegged_orders.map_or([0; 4], |a| a.key.to_aligned_bytes()),
+
+            fee_schedule,
+
+            authority: authority.map_or([0; 4], |a| a.to_aligned_bytes()),
+
+            market_stats: [0; 4],
+
+            price_cumulative: 0,
+            price_cumulative_slot: 0,
+
+            price_band_bps: 0,
+            price_band_halt_slots: 0,
+            halted_until_slot: 0,
+
+            auction_price: 0,
+            auction_bid_qty_left: 0,
+            auction_ask_qty_left: 0,
+            auction_slot: 0,
+            auction_unix_timestamp: 0,
+
+            pegged_orders_cursor: [0; 2],
         };
         Ok(())
     }
@@ -1,22 +1,42 @@
+use std::cell::{RefCell, RefMut};
 use std::mem::size_of;
 use std::num::NonZeroU64;
 
 use bumpalo::{collections::Vec as BumpVec, vec as bump_vec, Bump};
+use bytemuck::Zeroable;
+use proptest::prelude::*;
 use rand::prelude::*;
 use safe_transmute::to_bytes::{transmute_to_bytes, transmute_to_bytes_mut};
 use solana_sdk::bpf_loader;
-use solana_sdk::clock::Epoch;
+use solana_sdk::clock::{Clock, Epoch};
 use solana_sdk::rent::Rent;
 use solana_sdk::system_program;
 use solana_sdk::sysvar;
 use solana_sdk::sysvar::Sysvar;
+use spl_token::option::COption;
 use spl_token::pack::Pack;
 use spl_token::state::{Account, AccountState, Mint};
 
-use instruction::{initialize_market, MarketInstruction, NewOrderInstructionV1};
-use matching::{OrderType, Side};
-use state::gen_vault_signer_key;
-use state::{MarketState, OpenOrders, State, ToAlignedBytes};
+use critbit::{LeafNode, Slab, SlabView, SLAB_HEADER_LEN};
+use error::{DexErrorCode, DexResult};
+use fees::{FeeSchedule, FeeTier};
+use instruction::{
+    disable_authority, initialize_market, CancelOrderInstruction, MarketInstruction, MarketMode,
+    NewMarketBuyInstruction, NewOrderInstructionV1, NewOrderInstructionV2, NewOrderInstructionV3,
+    NewPeggedOrderInstruction, OrderExpiry, SelfTradeBehavior, SetMarketParamsInstruction,
+    SetPositionLimitsInstruction,
+};
+use matching::{pegged_order_key, stop_order_key, OrderBookState, OrderType, Side};
+use state::{gen_vault_signer_key, RUN_INVOKED_PROGRAMS};
+use state::{
+    AccountFlag, Candle, MarketState, MarketStats, OpenOrders, OrderSlots, State, ToAlignedBytes,
+    ACCOUNT_HEAD_PADDING, ACCOUNT_TAIL_PADDING, LEGACY_MARKET_STATE_LEN, MARKET_STATS_CANDLES,
+    MAX_AUTO_CONSUME_QUEUED_EVENTS,
+};
+use state::{
+    Event, EventQueue, EventQueueHeader, EventView, Queue, Request, RequestQueue,
+    RequestQueueHeader, RequestView,
+};
 
 use super::*;
 
@@ -35,6 +55,9 @@ struct MarketAccounts<'bump> {
     coin_mint: AccountInfo<'bump>,
     pc_mint: AccountInfo<'bump>,
     rent_sysvar: AccountInfo<'bump>,
+    clock_sysvar: AccountInfo<'bump>,
+    stop_orders: Option<AccountInfo<'bump>>,
+    pegged_orders: Option<AccountInfo<'bump>>,
 }
 
 fn allocate_dex_owned_account(unpadded_size: usize, bump: &Bump) -> &mut [u8] {
@@ -69,6 +92,26 @@ fn new_rent_sysvar_account<'bump>(
     account_info
 }
 
+fn new_clock_sysvar_account<'bump>(
+    lamports: u64,
+    clock: Clock,
+    bump: &'bump Bump,
+) -> AccountInfo<'bump> {
+    let data = bump_vec![in bump; 0u8; size_of::<Clock>()].into_bump_slice_mut();
+    let mut account_info = AccountInfo::new(
+        &sysvar::clock::ID,
+        false,
+        false,
+        bump.alloc(lamports),
+        data,
+        &sysvar::ID,
+        false,
+        Epoch::default(),
+    );
+    clock.to_account_info(&mut account_info).unwrap();
+    account_info
+}
+
 fn new_sol_account<'bump, Gen: Rng>(
     rng: &mut Gen,
     lamports: u64,
@@ -158,18 +201,85 @@ fn new_spl_token_program<'bump>(bump: &'bump Bump) -> AccountInfo<'bump> {
     )
 }
 
+// The accounts CloseMarket takes for a market without stop orders, pegged orders or
+// stats, with whatever is left in the vaults going to the given wallets.
+fn close_market_accounts<'bump>(
+    accounts: &MarketAccounts<'bump>,
+    signer: &AccountInfo<'bump>,
+    coin_wallet: &AccountInfo<'bump>,
+    pc_wallet: &AccountInfo<'bump>,
+    bump: &'bump Bump,
+) -> &'bump [AccountInfo<'bump>] {
+    let program_id = accounts.market.owner;
+    let vault_signer_nonce = MarketState::load(&accounts.market, program_id)
+        .unwrap()
+        .vault_signer_nonce;
+    let vault_signer_pk =
+        gen_vault_signer_key(vault_signer_nonce, accounts.market.key, program_id).unwrap();
+    // signed for by the program's seeds
+    let vault_signer = AccountInfo::new(
+        bump.alloc(vault_signer_pk),
+        true,
+        false,
+        bump.alloc(0),
+        &mut [],
+        &system_program::ID,
+        false,
+        Epoch::default(),
+    );
+    bump_vec![in bump;
+        accounts.market.clone(),
+        signer.clone(),
+        accounts.req_q.clone(),
+        accounts.event_q.clone(),
+        accounts.bids.clone(),
+        accounts.asks.clone(),
+        accounts.coin_vault.clone(),
+        accounts.pc_vault.clone(),
+        coin_wallet.clone(),
+        pc_wallet.clone(),
+        vault_signer,
+        new_spl_token_program(bump),
+    ]
+    .into_bump_slice()
+}
+
 fn setup_market<'bump, R: Rng>(rng: &mut R, bump: &'bump Bump) -> MarketAccounts<'bump> {
+    setup_market_with_authority(rng, bump, None)
+}
+
+fn setup_market_with_authority<'bump, R: Rng>(
+    rng: &mut R,
+    bump: &'bump Bump,
+    authority: Option<&Pubkey>,
+) -> MarketAccounts<'bump> {
+    setup_market_with_order_slabs(rng, bump, authority, None, None)
+}
+
+// The sizes of the optional stop and pegged order slabs, if the market should have them.
+fn setup_market_with_order_slabs<'bump, R: Rng>(
+    rng: &mut R,
+    bump: &'bump Bump,
+    authority: Option<&Pubkey>,
+    stop_orders_size: Option<usize>,
+    pegged_orders_size: Option<usize>,
+) -> MarketAccounts<'bump> {
     let program_id = random_pubkey(rng, bump);
     let market = new_dex_owned_account(rng, size_of::<MarketState>(), program_id, bump);
     let bids = new_dex_owned_account(rng, 1 << 23, program_id, bump);
     let asks = new_dex_owned_account(rng, 1 << 23, program_id, bump);
-    let req_q = new_dex_owned_account(rng, 640, program_id, bump);
+    let req_q = new_dex_owned_account(rng, 4096, program_id, bump);
     let event_q = new_dex_owned_account(rng, 65536, program_id, bump);
+    let stop_orders =
+        stop_orders_size.map(|size| new_dex_owned_account(rng, size, program_id, bump));
+    let pegged_orders =
+        pegged_orders_size.map(|size| new_dex_owned_account(rng, size, program_id, bump));
 
     let coin_mint = new_token_mint(rng, bump);
     let pc_mint = new_token_mint(rng, bump);
 
     let rent_sysvar = new_rent_sysvar_account(100000, Rent::default(), bump);
+    let clock_sysvar = new_clock_sysvar_account(100000, Clock::default(), bump);
 
     let mut i = 0;
     let (vault_signer_nonce, vault_signer_pk) = loop {
@@ -199,15 +309,19 @@ fn setup_market<'bump, R: Rng>(rng: &mut R, bump: &'bump Bump) -> MarketAccounts
         &asks.key,
         &req_q.key,
         &event_q.key,
+        stop_orders.as_ref().map(|a| a.key),
+        pegged_orders.as_ref().map(|a| a.key),
         coin_lot_size,
         pc_lot_size,
         vault_signer_nonce,
         pc_dust_threshold,
+        Some(FeeSchedule::default()),
+        authority,
     )
     .unwrap();
 
     {
-        let accounts: &'bump [AccountInfo<'bump>] = bump_vec![in bump;
+        let mut accounts: BumpVec<AccountInfo<'bump>> = bump_vec![in bump;
             market.clone(),
             req_q.clone(),
             event_q.clone(),
@@ -217,9 +331,15 @@ fn setup_market<'bump, R: Rng>(rng: &mut R, bump: &'bump Bump) -> MarketAccounts
             pc_vault.clone(),
             coin_mint.clone(),
             pc_mint.clone(),
-        ]
-        .into_bump_slice_mut();
-        State::process(&program_id, accounts, &init_instruction.data).unwrap();
+        ];
+        accounts.extend(stop_orders.iter().cloned());
+        accounts.extend(pegged_orders.iter().cloned());
+        State::process(
+            &program_id,
+            accounts.into_bump_slice(),
+            &init_instruction.data,
+        )
+        .unwrap();
     }
 
     MarketAccounts {
@@ -233,6 +353,9 @@ fn setup_market<'bump, R: Rng>(rng: &mut R, bump: &'bump Bump) -> MarketAccounts
         coin_mint,
         pc_mint,
         rent_sysvar,
+        clock_sysvar,
+        stop_orders,
+        pegged_orders,
     }
 }
 
@@ -296,6 +419,7 @@ fn test_new_order() {
             accounts.asks.clone(),
             coin_account.clone(),
             pc_account.clone(),
+            accounts.clock_sysvar.clone(),
         ]
         .into_bump_slice(),
         &instruction_data,
@@ -341,6 +465,7 @@ fn test_new_order() {
             accounts.asks.clone(),
             coin_account.clone(),
             pc_account.clone(),
+            accounts.clock_sysvar.clone(),
         ]
         .into_bump_slice(),
         &instruction_data,
@@ -411,3 +536,4370 @@ fn test_new_order() {
         assert_eq!(open_orders_seller.native_pc_total, 399_120);
     }
 }
+
+#[test]
+fn test_new_orders_batch() {
+    let mut rng = StdRng::seed_from_u64(2);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+
+    let dex_program_id = accounts.market.owner;
+
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let orders_account =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+    let spl_token_program = new_spl_token_program(&bump);
+
+    let orders: Vec<NewOrderInstructionV2> =
+        [(Side::Bid, 100, 2), (Side::Bid, 90, 3), (Side::Ask, 120, 4)]
+            .iter()
+            .map(|&(side, limit_price, max_qty)| NewOrderInstructionV2 {
+                side,
+                limit_price: NonZeroU64::new(limit_price).unwrap(),
+                max_qty: NonZeroU64::new(max_qty).unwrap(),
+                order_type: OrderType::Limit,
+                client_id: 0,
+                self_trade_behavior: SelfTradeBehavior::DecrementTake,
+                expiry: None,
+            })
+            .collect();
+    let instruction_data = MarketInstruction::NewOrdersBatch(orders).pack();
+    let instruction_accounts: &[AccountInfo] = bump_vec![in &bump;
+        accounts.market.clone(),
+        orders_account.clone(),
+        accounts.req_q.clone(),
+        coin_account.clone(),
+        pc_account.clone(),
+        owner.clone(),
+        accounts.coin_vault.clone(),
+        accounts.pc_vault.clone(),
+        spl_token_program.clone(),
+        accounts.rent_sysvar.clone(),
+    ]
+    .into_bump_slice();
+    State::process(dex_program_id, instruction_accounts, &instruction_data).unwrap();
+
+    let native_pc_locked: u64 = [100 * 2, 90 * 3]
+        .iter()
+        .map(|&native_pc_qty| {
+            native_pc_qty + FeeSchedule::default().taker_fee(FeeTier::Base, native_pc_qty)
+        })
+        .sum();
+    let order_ids = {
+        let market = MarketState::load(&accounts.market, &dex_program_id).unwrap();
+        assert_eq!(market.pc_deposits_total, native_pc_locked);
+        assert_eq!(market.coin_deposits_total, 4_000);
+        assert_eq!(
+            market
+                .load_request_queue_mut(&accounts.req_q)
+                .unwrap()
+                .len(),
+            3
+        );
+        let open_orders = market
+            .load_orders_mut(&orders_account, None, &dex_program_id, None)
+            .unwrap();
+        assert_eq!(open_orders.native_pc_total, native_pc_locked);
+        assert_eq!(open_orders.native_coin_total, 4_000);
+        assert_eq!(open_orders.slots.free_slot_bits.count_ones(), 125);
+        open_orders.slots.orders
+    };
+
+    let instruction_data =
+        MarketInstruction::CancelOrdersBatch(vec![order_ids[0], order_ids[2], 12345]).pack();
+    let instruction_accounts: &[AccountInfo] = bump_vec![in &bump;
+        accounts.market.clone(),
+        orders_account.clone(),
+        accounts.req_q.clone(),
+        owner.clone(),
+    ]
+    .into_bump_slice();
+    State::process(dex_program_id, instruction_accounts, &instruction_data).unwrap();
+
+    let market = MarketState::load(&accounts.market, &dex_program_id).unwrap();
+    assert_eq!(
+        market
+            .load_request_queue_mut(&accounts.req_q)
+            .unwrap()
+            .len(),
+        5
+    );
+}
+
+#[test]
+fn test_cancel_all_orders() {
+    let mut rng = StdRng::seed_from_u64(3);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+
+    let dex_program_id = accounts.market.owner;
+
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let orders_account =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+    let spl_token_program = new_spl_token_program(&bump);
+
+    let orders: Vec<NewOrderInstructionV2> =
+        [(Side::Bid, 100, 2), (Side::Ask, 120, 4), (Side::Bid, 90, 3)]
+            .iter()
+            .map(|&(side, limit_price, max_qty)| NewOrderInstructionV2 {
+                side,
+                limit_price: NonZeroU64::new(limit_price).unwrap(),
+                max_qty: NonZeroU64::new(max_qty).unwrap(),
+                order_type: OrderType::Limit,
+                client_id: 0,
+                self_trade_behavior: SelfTradeBehavior::DecrementTake,
+                expiry: None,
+            })
+            .collect();
+    let instruction_data = MarketInstruction::NewOrdersBatch(orders).pack();
+    let instruction_accounts: &[AccountInfo] = bump_vec![in &bump;
+        accounts.market.clone(),
+        orders_account.clone(),
+        accounts.req_q.clone(),
+        coin_account.clone(),
+        pc_account.clone(),
+        owner.clone(),
+        accounts.coin_vault.clone(),
+        accounts.pc_vault.clone(),
+        spl_token_program.clone(),
+        accounts.rent_sysvar.clone(),
+    ]
+    .into_bump_slice();
+    State::process(dex_program_id, instruction_accounts, &instruction_data).unwrap();
+
+    let instruction_data = MarketInstruction::CancelAllOrders(Some(Side::Bid)).pack();
+    let instruction_accounts: &[AccountInfo] = bump_vec![in &bump;
+        accounts.market.clone(),
+        orders_account.clone(),
+        accounts.req_q.clone(),
+        owner.clone(),
+    ]
+    .into_bump_slice();
+    State::process(dex_program_id, instruction_accounts, &instruction_data).unwrap();
+
+    let market = MarketState::load(&accounts.market, &dex_program_id).unwrap();
+    let open_orders = market
+        .load_orders_mut(&orders_account, None, &dex_program_id, None)
+        .unwrap();
+    let req_q = market.load_request_queue_mut(&accounts.req_q).unwrap();
+    assert_eq!(req_q.len(), 5);
+    let cancels: Vec<&Request> = req_q.iter().skip(3).collect();
+    for (request, &slot) in cancels.into_iter().zip([0u16, 2].iter()) {
+        match request.as_view().unwrap() {
+            RequestView::CancelOrder {
+                expected_owner_slot,
+                order_id,
+                side,
+                ..
+            } => {
+                assert_eq!(expected_owner_slot, slot);
+                assert_eq!(*order_id, *open_orders.order_id(slot));
+                assert_eq!(side, Side::Bid);
+            }
+            _ => panic!("expected a cancel request"),
+        }
+    }
+}
+
+#[test]
+fn test_position_limits() {
+    let mut rng = StdRng::seed_from_u64(4);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+
+    let dex_program_id = accounts.market.owner;
+
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let orders_account =
+        new_dex_owned_account(&mut rng, OpenOrders::v2_len(1), dex_program_id, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+    let spl_token_program = new_spl_token_program(&bump);
+
+    let new_order = |side: Side, max_qty: u64| {
+        let instruction_data = MarketInstruction::NewOrderV2(NewOrderInstructionV2 {
+            side,
+            limit_price: NonZeroU64::new(100).unwrap(),
+            max_qty: NonZeroU64::new(max_qty).unwrap(),
+            order_type: OrderType::Limit,
+            client_id: 0,
+            self_trade_behavior: SelfTradeBehavior::DecrementTake,
+            expiry: None,
+        })
+        .pack();
+        let payer = match side {
+            Side::Bid => pc_account.clone(),
+            Side::Ask => coin_account.clone(),
+        };
+        let instruction_accounts: &[AccountInfo] = bump_vec![in &bump;
+            accounts.market.clone(),
+            orders_account.clone(),
+            accounts.req_q.clone(),
+            payer,
+            owner.clone(),
+            accounts.coin_vault.clone(),
+            accounts.pc_vault.clone(),
+            spl_token_program.clone(),
+            accounts.rent_sysvar.clone(),
+        ]
+        .into_bump_slice();
+        State::process(dex_program_id, instruction_accounts, &instruction_data)
+    };
+    let set_position_limits = |max_native_coin_position: u64, reduce_only: bool| {
+        let instruction_data = MarketInstruction::SetPositionLimits(SetPositionLimitsInstruction {
+            max_native_coin_position: NonZeroU64::new(max_native_coin_position),
+            reduce_only,
+        })
+        .pack();
+        let instruction_accounts: &[AccountInfo] = bump_vec![in &bump;
+            accounts.market.clone(),
+            orders_account.clone(),
+            owner.clone(),
+        ]
+        .into_bump_slice();
+        State::process(dex_program_id, instruction_accounts, &instruction_data)
+    };
+    let new_market_buy = || {
+        let instruction_data = MarketInstruction::NewMarketBuy(NewMarketBuyInstruction {
+            max_native_pc_qty_including_fees: NonZeroU64::new(1_000).unwrap(),
+            client_id: 0,
+            self_trade_behavior: SelfTradeBehavior::DecrementTake,
+        })
+        .pack();
+        let instruction_accounts: &[AccountInfo] = bump_vec![in &bump;
+            accounts.market.clone(),
+            orders_account.clone(),
+            accounts.req_q.clone(),
+            pc_account.clone(),
+            owner.clone(),
+            accounts.coin_vault.clone(),
+            accounts.pc_vault.clone(),
+            spl_token_program.clone(),
+            accounts.rent_sysvar.clone(),
+        ]
+        .into_bump_slice();
+        State::process(dex_program_id, instruction_accounts, &instruction_data)
+    };
+    let rejected: DexResult = Err(DexErrorCode::PositionLimitExceeded.into());
+
+    // 3 coin lots are locked in an ask
+    new_order(Side::Ask, 3).unwrap();
+
+    // bids count the coin already held against the limit
+    set_position_limits(5_000, false).unwrap();
+    assert_eq!(new_order(Side::Bid, 3), rejected);
+    new_order(Side::Bid, 1).unwrap();
+
+    // market buys are cut down to what's left under the limit, counting open bids
+    new_market_buy().unwrap();
+    assert_eq!(new_market_buy(), rejected);
+    set_position_limits(3_000, false).unwrap();
+    assert_eq!(new_market_buy(), rejected);
+
+    // a reduce-only account can only sell coin it has free
+    set_position_limits(0, true).unwrap();
+    assert_eq!(new_order(Side::Bid, 1), rejected);
+    assert_eq!(new_order(Side::Ask, 1), rejected);
+
+    set_position_limits(0, false).unwrap();
+    new_order(Side::Bid, 100).unwrap();
+    new_order(Side::Ask, 1).unwrap();
+}
+
+#[test]
+fn test_position_limits_count_open_bids() {
+    let mut rng = StdRng::seed_from_u64(22);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+
+    let dex_program_id = accounts.market.owner;
+
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let orders_account =
+        new_dex_owned_account(&mut rng, OpenOrders::v2_len(1), dex_program_id, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+    let spl_token_program = new_spl_token_program(&bump);
+
+    let new_bid = |max_qty: u64| {
+        let instruction_data = MarketInstruction::NewOrderV2(NewOrderInstructionV2 {
+            side: Side::Bid,
+            limit_price: NonZeroU64::new(100).unwrap(),
+            max_qty: NonZeroU64::new(max_qty).unwrap(),
+            order_type: OrderType::Limit,
+            client_id: 0,
+            self_trade_behavior: SelfTradeBehavior::DecrementTake,
+            expiry: None,
+        })
+        .pack();
+        let instruction_accounts: &[AccountInfo] = bump_vec![in &bump;
+            accounts.market.clone(),
+            orders_account.clone(),
+            accounts.req_q.clone(),
+            pc_account.clone(),
+            owner.clone(),
+            accounts.coin_vault.clone(),
+            accounts.pc_vault.clone(),
+            spl_token_program.clone(),
+            accounts.rent_sysvar.clone(),
+        ]
+        .into_bump_slice();
+        State::process(dex_program_id, instruction_accounts, &instruction_data)
+    };
+
+    // limits can only be set on an account that has been opened by an order
+    new_bid(3).unwrap();
+    {
+        let instruction_data = MarketInstruction::SetPositionLimits(SetPositionLimitsInstruction {
+            max_native_coin_position: NonZeroU64::new(5_000),
+            reduce_only: false,
+        })
+        .pack();
+        let instruction_accounts: &[AccountInfo] = bump_vec![in &bump;
+            accounts.market.clone(),
+            orders_account.clone(),
+            owner.clone(),
+        ]
+        .into_bump_slice();
+        State::process(dex_program_id, instruction_accounts, &instruction_data).unwrap();
+    }
+
+    // the next bid fits under the limit on its own, but not on top of the open one
+    assert_eq!(new_bid(3), Err(DexErrorCode::PositionLimitExceeded.into()));
+    new_bid(2).unwrap();
+}
+
+#[test]
+fn test_set_market_params() {
+    let mut rng = StdRng::seed_from_u64(5);
+    let bump = Bump::new();
+
+    let authority = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let accounts = setup_market_with_authority(&mut rng, &bump, Some(authority.key));
+
+    let dex_program_id = accounts.market.owner;
+
+    let stranger = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let set_market_params = |by_authority: bool, instruction: SetMarketParamsInstruction| {
+        let instruction_data = MarketInstruction::SetMarketParams(instruction).pack();
+        let signer = if by_authority {
+            authority.clone()
+        } else {
+            stranger.clone()
+        };
+        let instruction_accounts: &[AccountInfo] = bump_vec![in &bump;
+            accounts.market.clone(),
+            accounts.event_q.clone(),
+            signer,
+            accounts.req_q.clone(),
+            accounts.bids.clone(),
+            accounts.asks.clone(),
+        ]
+        .into_bump_slice();
+        State::process(dex_program_id, instruction_accounts, &instruction_data)
+    };
+    let new_lot_sizes = SetMarketParamsInstruction {
+        fee_schedule: None,
+        pc_dust_threshold: None,
+        coin_lot_size: NonZeroU64::new(100),
+        pc_lot_size: NonZeroU64::new(10),
+        auto_consume: None,
+        price_band: None,
+        mode: None,
+    };
+
+    let wrong_authority: DexResult = Err(DexErrorCode::WrongMarketAuthority.into());
+    assert_eq!(set_market_params(false, new_lot_sizes), wrong_authority);
+
+    let invalid_fee_schedule: DexResult = Err(DexErrorCode::InvalidFeeSchedule.into());
+    assert_eq!(
+        set_market_params(
+            true,
+            SetMarketParamsInstruction {
+                fee_schedule: Some(FeeSchedule::flat(2_000)),
+                pc_dust_threshold: None,
+                coin_lot_size: None,
+                pc_lot_size: None,
+                auto_consume: None,
+                price_band: None,
+                mode: None,
+            }
+        ),
+        invalid_fee_schedule
+    );
+
+    // an order waiting in the request queue pins the lot sizes
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let orders_account =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let spl_token_program = new_spl_token_program(&bump);
+    let instruction_data = MarketInstruction::NewOrderV2(NewOrderInstructionV2 {
+        side: Side::Ask,
+        limit_price: NonZeroU64::new(100).unwrap(),
+        max_qty: NonZeroU64::new(1).unwrap(),
+        order_type: OrderType::Limit,
+        client_id: 0,
+        self_trade_behavior: SelfTradeBehavior::DecrementTake,
+        expiry: None,
+    })
+    .pack();
+    let instruction_accounts: &[AccountInfo] = bump_vec![in &bump;
+        accounts.market.clone(),
+        orders_account.clone(),
+        accounts.req_q.clone(),
+        coin_account.clone(),
+        owner.clone(),
+        accounts.coin_vault.clone(),
+        accounts.pc_vault.clone(),
+        spl_token_program.clone(),
+        accounts.rent_sysvar.clone(),
+    ]
+    .into_bump_slice();
+    State::process(dex_program_id, instruction_accounts, &instruction_data).unwrap();
+
+    let market_not_empty: DexResult = Err(DexErrorCode::MarketNotEmpty.into());
+    assert_eq!(set_market_params(true, new_lot_sizes), market_not_empty);
+
+    let fee_schedule = FeeSchedule::flat(30);
+    set_market_params(
+        true,
+        SetMarketParamsInstruction {
+            fee_schedule: Some(fee_schedule),
+            pc_dust_threshold: Some(50),
+            coin_lot_size: None,
+            pc_lot_size: None,
+            auto_consume: None,
+            price_band: None,
+            mode: None,
+        },
+    )
+    .unwrap();
+
+    let market = MarketState::load(&accounts.market, &dex_program_id).unwrap();
+    assert_eq!(market.fee_schedule, fee_schedule);
+    assert_eq!(market.pc_dust_threshold, 50);
+    let event_q = market.load_event_queue_mut(&accounts.event_q).unwrap();
+    let events: Vec<_> = event_q.iter().map(|e| e.as_view().unwrap()).collect();
+    match events.as_slice() {
+        &[EventView::MarketParams {
+            fee_schedule: recorded_fee_schedule,
+            pc_dust_threshold,
+            coin_lot_size,
+            pc_lot_size,
+        }] => {
+            assert_eq!(recorded_fee_schedule, fee_schedule);
+            assert_eq!(
+                (pc_dust_threshold, coin_lot_size, pc_lot_size),
+                (50, 1_000, 1)
+            );
+        }
+        _ => panic!("expected a single MarketParams event, got {:?}", events),
+    }
+}
+
+#[test]
+fn test_enable_and_close_market() {
+    let mut rng = StdRng::seed_from_u64(6);
+    let bump = Bump::new();
+
+    let authority = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let accounts = setup_market_with_authority(&mut rng, &bump, Some(authority.key));
+
+    let dex_program_id = accounts.market.owner;
+
+    let disable_authority = AccountInfo::new(
+        &disable_authority::ID,
+        true,
+        false,
+        bump.alloc(0),
+        &mut [],
+        &system_program::ID,
+        false,
+        Epoch::default(),
+    );
+    // the disable authority disables any market, but only the market authority re-enables it
+    let set_disabled = |disabled: bool| {
+        let (instruction_data, signer) = if disabled {
+            (MarketInstruction::DisableMarket.pack(), &disable_authority)
+        } else {
+            (MarketInstruction::EnableMarket.pack(), &authority)
+        };
+        let instruction_accounts: &[AccountInfo] = bump_vec![in &bump;
+            accounts.market.clone(),
+            signer.clone(),
+        ]
+        .into_bump_slice();
+        State::process(dex_program_id, instruction_accounts, &instruction_data)
+    };
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let orders_account =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+    let close_market = || {
+        let instruction_accounts =
+            close_market_accounts(&accounts, &authority, &coin_account, &pc_account, &bump);
+        State::process(
+            dex_program_id,
+            instruction_accounts,
+            &MarketInstruction::CloseMarket.pack(),
+        )
+    };
+    let spl_token_program = new_spl_token_program(&bump);
+    let new_order = || {
+        let instruction_data = MarketInstruction::NewOrderV2(NewOrderInstructionV2 {
+            side: Side::Ask,
+            limit_price: NonZeroU64::new(100).unwrap(),
+            max_qty: NonZeroU64::new(1).unwrap(),
+            order_type: OrderType::Limit,
+            client_id: 0,
+            self_trade_behavior: SelfTradeBehavior::DecrementTake,
+            expiry: None,
+        })
+        .pack();
+        let instruction_accounts: &[AccountInfo] = bump_vec![in &bump;
+            accounts.market.clone(),
+            orders_account.clone(),
+            accounts.req_q.clone(),
+            coin_account.clone(),
+            owner.clone(),
+            accounts.coin_vault.clone(),
+            accounts.pc_vault.clone(),
+            spl_token_program.clone(),
+            accounts.rent_sysvar.clone(),
+        ]
+        .into_bump_slice();
+        State::process(dex_program_id, instruction_accounts, &instruction_data)
+    };
+
+    set_disabled(true).unwrap();
+    let disabled: DexResult = Err(DexErrorCode::MarketIsDisabled.into());
+    assert_eq!(new_order(), disabled);
+    let wrong_authority: DexResult = Err(DexErrorCode::WrongMarketAuthority.into());
+    let instruction_accounts: &[AccountInfo] = bump_vec![in &bump;
+        accounts.market.clone(),
+        disable_authority.clone(),
+    ]
+    .into_bump_slice();
+    assert_eq!(
+        State::process(
+            dex_program_id,
+            instruction_accounts,
+            &MarketInstruction::EnableMarket.pack()
+        ),
+        wrong_authority
+    );
+    set_disabled(false).unwrap();
+    new_order().unwrap();
+
+    let market_not_empty: DexResult = Err(DexErrorCode::MarketNotEmpty.into());
+    assert_eq!(close_market(), market_not_empty);
+
+    // a market that was never traded on can be closed once its fees are swept
+    let accounts = setup_market_with_authority(&mut rng, &bump, Some(authority.key));
+    let dex_program_id = accounts.market.owner;
+    let coin_wallet = new_token_account(&mut rng, accounts.coin_mint.key, authority.key, &bump);
+    let pc_wallet = new_token_account(&mut rng, accounts.pc_mint.key, authority.key, &bump);
+    let market_accounts = [
+        &accounts.market,
+        &accounts.req_q,
+        &accounts.event_q,
+        &accounts.bids,
+        &accounts.asks,
+        &accounts.coin_vault,
+        &accounts.pc_vault,
+    ];
+    let instruction_accounts =
+        close_market_accounts(&accounts, &authority, &coin_wallet, &pc_wallet, &bump);
+    let close_market = || {
+        State::process(
+            dex_program_id,
+            instruction_accounts,
+            &MarketInstruction::CloseMarket.pack(),
+        )
+    };
+
+    // fees and rebates that haven't been swept yet still sit in the vaults
+    let set_fees = |coin_fees: u64, pc_fees: u64, referrer_rebates: u64| {
+        let mut market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+        market.coin_fees_accrued = coin_fees;
+        market.pc_fees_accrued = pc_fees;
+        market.referrer_rebates_accrued = referrer_rebates;
+    };
+    for &(coin_fees, pc_fees, referrer_rebates) in [(1, 0, 0), (0, 1, 0), (0, 0, 1)].iter() {
+        set_fees(coin_fees, pc_fees, referrer_rebates);
+        assert_eq!(close_market(), market_not_empty);
+    }
+    set_fees(0, 0, 0);
+
+    assert_eq!(
+        State::process(
+            dex_program_id,
+            close_market_accounts(
+                &accounts,
+                &disable_authority,
+                &coin_wallet,
+                &pc_wallet,
+                &bump
+            ),
+            &MarketInstruction::CloseMarket.pack()
+        ),
+        wrong_authority
+    );
+
+    // tokens sent to a vault directly go to the wallet before the vault is closed
+    let stray_amount = 5;
+    let mut pc_vault = Account::unpack(&accounts.pc_vault.data.borrow()).unwrap();
+    pc_vault.amount = stray_amount;
+    Account::pack(pc_vault, &mut accounts.pc_vault.data.borrow_mut()).unwrap();
+    for vault in [&accounts.coin_vault, &accounts.pc_vault].iter() {
+        **vault.lamports.borrow_mut() = 1_000;
+    }
+
+    let authority_lamports = authority.lamports();
+    let reclaimed_lamports: u64 = market_accounts.iter().map(|a| a.lamports()).sum();
+    RUN_INVOKED_PROGRAMS.with(|run| run.set(true));
+    let result = close_market();
+    RUN_INVOKED_PROGRAMS.with(|run| run.set(false));
+    result.unwrap();
+    assert_eq!(
+        authority.lamports(),
+        authority_lamports + reclaimed_lamports
+    );
+    assert!(market_accounts.iter().all(|a| a.lamports() == 0));
+    assert_eq!(
+        Account::unpack(&pc_wallet.data.borrow()).unwrap().amount,
+        stray_amount
+    );
+    assert!(MarketState::load(&accounts.market, dex_program_id).is_err());
+
+    // markets without an authority are enabled and closed by the disable authority
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+    let coin_wallet = new_token_account(&mut rng, accounts.coin_mint.key, authority.key, &bump);
+    let pc_wallet = new_token_account(&mut rng, accounts.pc_mint.key, authority.key, &bump);
+    let process_as = |instruction: MarketInstruction, signer: &_| {
+        let instruction_accounts =
+            close_market_accounts(&accounts, signer, &coin_wallet, &pc_wallet, &bump);
+        let instruction_accounts = match instruction {
+            MarketInstruction::CloseMarket => instruction_accounts,
+            _ => &instruction_accounts[..2],
+        };
+        State::process(dex_program_id, instruction_accounts, &instruction.pack())
+    };
+    process_as(MarketInstruction::DisableMarket, &disable_authority).unwrap();
+    assert_eq!(
+        process_as(MarketInstruction::EnableMarket, &authority),
+        wrong_authority
+    );
+    process_as(MarketInstruction::EnableMarket, &disable_authority).unwrap();
+    assert_eq!(
+        process_as(MarketInstruction::CloseMarket, &authority),
+        wrong_authority
+    );
+    process_as(MarketInstruction::CloseMarket, &disable_authority).unwrap();
+    assert!(MarketState::load(&accounts.market, dex_program_id).is_err());
+}
+
+#[test]
+fn test_close_open_orders() {
+    let mut rng = StdRng::seed_from_u64(7);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+
+    let dex_program_id = accounts.market.owner;
+
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let destination = new_sol_account(&mut rng, 0, &bump);
+    let orders_accounts = [
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump),
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump),
+    ];
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+    let spl_token_program = new_spl_token_program(&bump);
+    let vault_signer_nonce = MarketState::load(&accounts.market, dex_program_id)
+        .unwrap()
+        .vault_signer_nonce;
+    let vault_signer_pk =
+        gen_vault_signer_key(vault_signer_nonce, accounts.market.key, dex_program_id).unwrap();
+    let vault_signer = AccountInfo::new(
+        bump.alloc(vault_signer_pk),
+        false,
+        false,
+        bump.alloc(0),
+        &mut [],
+        &system_program::ID,
+        false,
+        Epoch::default(),
+    );
+
+    let process = |instruction: MarketInstruction, instruction_accounts: &[&AccountInfo]| {
+        let instruction_accounts: Vec<AccountInfo> =
+            instruction_accounts.iter().map(|&a| a.clone()).collect();
+        State::process(dex_program_id, &instruction_accounts, &instruction.pack())
+    };
+    let close_open_orders = |i: usize| {
+        process(
+            MarketInstruction::CloseOpenOrders,
+            &[&orders_accounts[i], &owner, &destination, &accounts.market],
+        )
+    };
+    let not_empty: DexResult = Err(DexErrorCode::OpenOrdersNotEmpty.into());
+
+    for (i, orders_account) in orders_accounts.iter().enumerate() {
+        process(
+            MarketInstruction::NewOrderV2(NewOrderInstructionV2 {
+                side: Side::Ask,
+                limit_price: NonZeroU64::new(100).unwrap(),
+                max_qty: NonZeroU64::new(1).unwrap(),
+                order_type: OrderType::Limit,
+                client_id: 7,
+                self_trade_behavior: SelfTradeBehavior::DecrementTake,
+                expiry: None,
+            }),
+            &[
+                &accounts.market,
+                orders_account,
+                &accounts.req_q,
+                &coin_account,
+                &owner,
+                &accounts.coin_vault,
+                &accounts.pc_vault,
+                &spl_token_program,
+                &accounts.rent_sysvar,
+            ],
+        )
+        .unwrap();
+        assert_eq!(close_open_orders(i), not_empty);
+
+        // once the order is cancelled, the coin it locked still has to be settled
+        process(
+            MarketInstruction::CancelOrderByClientId(7),
+            &[&accounts.market, orders_account, &accounts.req_q, &owner],
+        )
+        .unwrap();
+        process(
+            MarketInstruction::MatchOrders(5),
+            &[
+                &accounts.market,
+                &accounts.req_q,
+                &accounts.event_q,
+                &accounts.bids,
+                &accounts.asks,
+                &coin_account,
+                &pc_account,
+                &accounts.clock_sysvar,
+            ],
+        )
+        .unwrap();
+        process(
+            MarketInstruction::ConsumeEvents(5),
+            &[
+                orders_account,
+                &accounts.market,
+                &accounts.event_q,
+                &coin_account,
+                &pc_account,
+            ],
+        )
+        .unwrap();
+        assert_eq!(close_open_orders(i), not_empty);
+
+        process(
+            MarketInstruction::SettleFunds,
+            &[
+                &accounts.market,
+                orders_account,
+                &owner,
+                &accounts.coin_vault,
+                &accounts.pc_vault,
+                &coin_account,
+                &pc_account,
+                &vault_signer,
+                &spl_token_program,
+            ],
+        )
+        .unwrap();
+    }
+
+    let orders_lamports = orders_accounts[0].lamports();
+    close_open_orders(0).unwrap();
+    assert_eq!(orders_accounts[0].lamports(), 0);
+    assert_eq!(destination.lamports(), orders_lamports);
+
+    // the other account outlives the market, and its rent can still be reclaimed
+    let disable_authority = AccountInfo::new(
+        &disable_authority::ID,
+        true,
+        true,
+        bump.alloc(0),
+        &mut [],
+        &system_program::ID,
+        false,
+        Epoch::default(),
+    );
+    State::process(
+        dex_program_id,
+        close_market_accounts(
+            &accounts,
+            &disable_authority,
+            &coin_account,
+            &pc_account,
+            &bump,
+        ),
+        &MarketInstruction::CloseMarket.pack(),
+    )
+    .unwrap();
+    assert_eq!(accounts.market.lamports(), 0);
+
+    let orders_lamports = orders_accounts[1].lamports();
+    close_open_orders(1).unwrap();
+    assert_eq!(orders_accounts[1].lamports(), 0);
+    assert_eq!(destination.lamports(), 2 * orders_lamports);
+}
+
+#[test]
+fn test_delegate() {
+    let mut rng = StdRng::seed_from_u64(8);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+
+    let dex_program_id = accounts.market.owner;
+
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let delegate = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let orders_account =
+        new_dex_owned_account(&mut rng, OpenOrders::v2_len(1), dex_program_id, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+    let spl_token_program = new_spl_token_program(&bump);
+
+    let process = |instruction: MarketInstruction, instruction_accounts: &[&AccountInfo]| {
+        let instruction_accounts: Vec<AccountInfo> =
+            instruction_accounts.iter().map(|&a| a.clone()).collect();
+        State::process(dex_program_id, &instruction_accounts, &instruction.pack())
+    };
+    let new_order = |by_owner: bool| {
+        process(
+            MarketInstruction::NewOrderV2(NewOrderInstructionV2 {
+                side: Side::Ask,
+                limit_price: NonZeroU64::new(100).unwrap(),
+                max_qty: NonZeroU64::new(1).unwrap(),
+                order_type: OrderType::Limit,
+                client_id: 0,
+                self_trade_behavior: SelfTradeBehavior::DecrementTake,
+                expiry: None,
+            }),
+            &[
+                &accounts.market,
+                &orders_account,
+                &accounts.req_q,
+                &coin_account,
+                if by_owner { &owner } else { &delegate },
+                &accounts.coin_vault,
+                &accounts.pc_vault,
+                &spl_token_program,
+                &accounts.rent_sysvar,
+            ],
+        )
+    };
+    let wrong_orders_account: DexResult = Err(DexErrorCode::WrongOrdersAccount.into());
+
+    new_order(true).unwrap();
+    assert_eq!(new_order(false), wrong_orders_account);
+
+    process(
+        MarketInstruction::SetDelegate,
+        &[&accounts.market, &orders_account, &owner, &delegate],
+    )
+    .unwrap();
+    new_order(false).unwrap();
+    process(
+        MarketInstruction::CancelAllOrders(None),
+        &[
+            &accounts.market,
+            &orders_account,
+            &accounts.req_q,
+            &delegate,
+        ],
+    )
+    .unwrap();
+
+    // only the owner may settle or hand out trading rights
+    let vault_signer_nonce = MarketState::load(&accounts.market, dex_program_id)
+        .unwrap()
+        .vault_signer_nonce;
+    let vault_signer_pk =
+        gen_vault_signer_key(vault_signer_nonce, accounts.market.key, dex_program_id).unwrap();
+    let vault_signer = AccountInfo::new(
+        &vault_signer_pk,
+        false,
+        false,
+        bump.alloc(0),
+        &mut [],
+        &system_program::ID,
+        false,
+        Epoch::default(),
+    );
+    assert_eq!(
+        process(
+            MarketInstruction::SettleFunds,
+            &[
+                &accounts.market,
+                &orders_account,
+                &delegate,
+                &accounts.coin_vault,
+                &accounts.pc_vault,
+                &coin_account,
+                &pc_account,
+                &vault_signer,
+                &spl_token_program,
+            ],
+        ),
+        wrong_orders_account
+    );
+    assert_eq!(
+        process(
+            MarketInstruction::RevokeDelegate,
+            &[&accounts.market, &orders_account, &delegate],
+        ),
+        wrong_orders_account
+    );
+
+    process(
+        MarketInstruction::RevokeDelegate,
+        &[&accounts.market, &orders_account, &owner],
+    )
+    .unwrap();
+    assert_eq!(new_order(false), wrong_orders_account);
+}
+
+#[test]
+fn test_open_orders_with_extra_slots() {
+    let mut rng = StdRng::seed_from_u64(9);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+
+    let dex_program_id = accounts.market.owner;
+
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let orders_account =
+        new_dex_owned_account(&mut rng, OpenOrders::v2_len(2), dex_program_id, &bump);
+    let misaligned_orders_account = new_dex_owned_account(
+        &mut rng,
+        OpenOrders::v2_len(2) - size_of::<OrderSlots>() / 2,
+        dex_program_id,
+        &bump,
+    );
+    let legacy_orders_account =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+    let spl_token_program = new_spl_token_program(&bump);
+
+    let process = |instruction: MarketInstruction, instruction_accounts: &[&AccountInfo]| {
+        let instruction_accounts: Vec<AccountInfo> =
+            instruction_accounts.iter().map(|&a| a.clone()).collect();
+        State::process(dex_program_id, &instruction_accounts, &instruction.pack())
+    };
+    let new_order = |orders_account: &_| {
+        process(
+            MarketInstruction::NewOrderV2(NewOrderInstructionV2 {
+                side: Side::Ask,
+                limit_price: NonZeroU64::new(100).unwrap(),
+                max_qty: NonZeroU64::new(1).unwrap(),
+                order_type: OrderType::Limit,
+                client_id: 0,
+                self_trade_behavior: SelfTradeBehavior::DecrementTake,
+                expiry: None,
+            }),
+            &[
+                &accounts.market,
+                orders_account,
+                &accounts.req_q,
+                &coin_account,
+                &owner,
+                &accounts.coin_vault,
+                &accounts.pc_vault,
+                &spl_token_program,
+                &accounts.rent_sysvar,
+            ],
+        )
+    };
+    let match_orders = || {
+        process(
+            MarketInstruction::MatchOrders(5),
+            &[
+                &accounts.market,
+                &accounts.req_q,
+                &accounts.event_q,
+                &accounts.bids,
+                &accounts.asks,
+                &coin_account,
+                &pc_account,
+                &accounts.clock_sysvar,
+            ],
+        )
+    };
+
+    assert_eq!(
+        new_order(&misaligned_orders_account),
+        Err(DexErrorCode::WrongOrdersAccount.into())
+    );
+
+    // accounts sized like before the version flag existed keep the legacy layout
+    new_order(&legacy_orders_account).unwrap();
+    {
+        let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+        let open_orders = market
+            .load_orders_mut(&legacy_orders_account, None, dex_program_id, None)
+            .unwrap();
+        assert!(!open_orders.is_v2());
+        assert_eq!(open_orders.slot_count(), 128);
+        assert_eq!(open_orders.slot_side(0), Some(Side::Ask));
+        assert_eq!(open_orders.delegate(), None);
+    }
+    assert_eq!(
+        process(
+            MarketInstruction::SetDelegate,
+            &[
+                &accounts.market,
+                &legacy_orders_account,
+                &owner,
+                &coin_account,
+            ],
+        ),
+        Err(DexErrorCode::LegacyOrdersAccount.into())
+    );
+
+    for _ in 0..130 {
+        new_order(&orders_account).unwrap();
+        match_orders().unwrap();
+    }
+    let order_id = {
+        let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+        let open_orders = market
+            .load_orders_mut(&orders_account, None, dex_program_id, None)
+            .unwrap();
+        assert!(open_orders.is_v2());
+        assert_eq!(open_orders.slot_count(), 256);
+        assert_eq!(open_orders.slots.free_slot_bits, 0);
+        assert_eq!(open_orders.slot_side(129), Some(Side::Ask));
+        assert_eq!(open_orders.slot_side(130), None);
+        assert_eq!(open_orders.native_coin_total, 130_000);
+        *open_orders.order_id(129)
+    };
+
+    process(
+        MarketInstruction::CancelOrder(CancelOrderInstruction {
+            side: Side::Ask,
+            order_id,
+            owner: [0; 4],
+            owner_slot: 129,
+        }),
+        &[&accounts.market, &orders_account, &accounts.req_q, &owner],
+    )
+    .unwrap();
+    match_orders().unwrap();
+    process(
+        MarketInstruction::ConsumeEvents(200),
+        &[
+            &orders_account,
+            &accounts.market,
+            &accounts.event_q,
+            &coin_account,
+            &pc_account,
+        ],
+    )
+    .unwrap();
+
+    let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+    let open_orders = market
+        .load_orders_mut(&orders_account, None, dex_program_id, None)
+        .unwrap();
+    assert_eq!(open_orders.slot_side(129), None);
+    assert_eq!(open_orders.slot_side(128), Some(Side::Ask));
+    assert_eq!(open_orders.native_coin_free, 1_000);
+}
+
+#[test]
+fn test_settle_funds_native_needs_wrapped_sol_market() {
+    let mut rng = StdRng::seed_from_u64(10);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+
+    let dex_program_id = accounts.market.owner;
+
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let orders_account =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+    let native_account = new_sol_account(&mut rng, 0, &bump);
+    let mut native_mint = new_token_mint(&mut rng, &bump);
+    native_mint.key = bump.alloc(spl_token::native_mint::ID);
+    let spl_token_program = new_spl_token_program(&bump);
+    let system_program = AccountInfo::new(
+        &system_program::ID,
+        false,
+        false,
+        bump.alloc(0),
+        &mut [],
+        &bpf_loader::ID,
+        true,
+        Epoch::default(),
+    );
+
+    let instruction_accounts: Vec<AccountInfo> = vec![
+        accounts.market.clone(),
+        orders_account.clone(),
+        owner.clone(),
+        accounts.coin_vault.clone(),
+        accounts.pc_vault.clone(),
+        pc_account.clone(),
+        owner.clone(),
+        spl_token_program.clone(),
+        native_account.clone(),
+        native_mint.clone(),
+        system_program.clone(),
+        accounts.rent_sysvar.clone(),
+    ];
+    assert_eq!(
+        State::process(
+            dex_program_id,
+            &instruction_accounts,
+            &MarketInstruction::SettleFundsNative.pack(),
+        ),
+        Err(DexErrorCode::NotWrappedSolMarket.into())
+    );
+}
+
+#[test]
+fn test_settle_funds_native() {
+    let mut rng = StdRng::seed_from_u64(21);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+
+    let dex_program_id = accounts.market.owner;
+
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let orders_account =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+    let spl_token_program = new_spl_token_program(&bump);
+
+    // make wrapped SOL the coin currency
+    let rent_exempt_reserve = Rent::default().minimum_balance(Account::LEN);
+    let set_wrapped_sol_balance = |account: &AccountInfo, amount: u64| {
+        let mut token_account = Account::unpack(&account.data.borrow()).unwrap();
+        token_account.mint = spl_token::native_mint::ID;
+        token_account.is_native = COption::Some(rent_exempt_reserve);
+        token_account.amount = amount;
+        Account::pack(token_account, &mut account.data.borrow_mut()).unwrap();
+        **account.lamports.borrow_mut() = rent_exempt_reserve + amount;
+    };
+    let wrapped_sol_balance =
+        |account: &AccountInfo| Account::unpack(&account.data.borrow()).unwrap().amount;
+    set_wrapped_sol_balance(&accounts.coin_vault, 0);
+    set_wrapped_sol_balance(&coin_account, 0);
+    MarketState::load(&accounts.market, dex_program_id)
+        .unwrap()
+        .coin_mint = spl_token::native_mint::ID.to_aligned_bytes();
+
+    let process = |instruction: MarketInstruction, instruction_accounts: &[&AccountInfo]| {
+        let instruction_accounts: Vec<AccountInfo> =
+            instruction_accounts.iter().map(|&a| a.clone()).collect();
+        State::process(dex_program_id, &instruction_accounts, &instruction.pack())
+    };
+
+    // leave some coin free by placing an order and cancelling it
+    process(
+        MarketInstruction::NewOrderV2(NewOrderInstructionV2 {
+            side: Side::Ask,
+            limit_price: NonZeroU64::new(100).unwrap(),
+            max_qty: NonZeroU64::new(1).unwrap(),
+            order_type: OrderType::Limit,
+            client_id: 7,
+            self_trade_behavior: SelfTradeBehavior::DecrementTake,
+            expiry: None,
+        }),
+        &[
+            &accounts.market,
+            &orders_account,
+            &accounts.req_q,
+            &coin_account,
+            &owner,
+            &accounts.coin_vault,
+            &accounts.pc_vault,
+            &spl_token_program,
+            &accounts.rent_sysvar,
+        ],
+    )
+    .unwrap();
+    process(
+        MarketInstruction::CancelOrderByClientId(7),
+        &[&accounts.market, &orders_account, &accounts.req_q, &owner],
+    )
+    .unwrap();
+    process(
+        MarketInstruction::MatchOrders(5),
+        &[
+            &accounts.market,
+            &accounts.req_q,
+            &accounts.event_q,
+            &accounts.bids,
+            &accounts.asks,
+            &coin_account,
+            &pc_account,
+            &accounts.clock_sysvar,
+        ],
+    )
+    .unwrap();
+    process(
+        MarketInstruction::ConsumeEvents(5),
+        &[
+            &orders_account,
+            &accounts.market,
+            &accounts.event_q,
+            &coin_account,
+            &pc_account,
+        ],
+    )
+    .unwrap();
+    // the deposit wasn't run through the token program, so fund the vault by hand
+    set_wrapped_sol_balance(&accounts.coin_vault, 1_000);
+
+    let vault_signer_nonce = MarketState::load(&accounts.market, dex_program_id)
+        .unwrap()
+        .vault_signer_nonce;
+    let vault_signer_pk =
+        gen_vault_signer_key(vault_signer_nonce, accounts.market.key, dex_program_id).unwrap();
+    // signed for by the program's seeds
+    let vault_signer = AccountInfo::new(
+        bump.alloc(vault_signer_pk),
+        true,
+        false,
+        bump.alloc(0),
+        &mut [],
+        &system_program::ID,
+        false,
+        Epoch::default(),
+    );
+    // created and closed again within the instruction
+    let native_account = AccountInfo::new(
+        random_pubkey(&mut rng, &bump),
+        true,
+        true,
+        bump.alloc(0),
+        bump_vec![in &bump; 0u8; Account::LEN].into_bump_slice_mut(),
+        &spl_token::ID,
+        false,
+        Epoch::default(),
+    );
+    let mut native_mint = new_token_mint(&mut rng, &bump);
+    native_mint.key = bump.alloc(spl_token::native_mint::ID);
+    let system_program = AccountInfo::new(
+        &system_program::ID,
+        false,
+        false,
+        bump.alloc(0),
+        &mut [],
+        &bpf_loader::ID,
+        true,
+        Epoch::default(),
+    );
+
+    let owner_lamports = owner.lamports();
+    let vault_lamports = accounts.coin_vault.lamports();
+    RUN_INVOKED_PROGRAMS.with(|run| run.set(true));
+    let result = process(
+        MarketInstruction::SettleFundsNative,
+        &[
+            &accounts.market,
+            &orders_account,
+            &owner,
+            &accounts.coin_vault,
+            &accounts.pc_vault,
+            &pc_account,
+            &vault_signer,
+            &spl_token_program,
+            &native_account,
+            &native_mint,
+            &system_program,
+            &accounts.rent_sysvar,
+        ],
+    );
+    RUN_INVOKED_PROGRAMS.with(|run| run.set(false));
+    result.unwrap();
+
+    assert_eq!(owner.lamports(), owner_lamports + 1_000);
+    assert_eq!(accounts.coin_vault.lamports(), vault_lamports - 1_000);
+    assert_eq!(wrapped_sol_balance(&accounts.coin_vault), 0);
+    assert_eq!(native_account.lamports(), 0);
+
+    let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+    assert_eq!(market.coin_deposits_total, 0);
+    let open_orders = market
+        .load_orders_mut(&orders_account, None, dex_program_id, None)
+        .unwrap();
+    assert_eq!(open_orders.native_coin_free, 0);
+    assert_eq!(open_orders.native_coin_total, 0);
+}
+
+#[test]
+fn test_settle_funds_to_wallets() {
+    let mut rng = StdRng::seed_from_u64(11);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+
+    let dex_program_id = accounts.market.owner;
+
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let orders_account =
+        new_dex_owned_account(&mut rng, OpenOrders::v2_len(1), dex_program_id, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+    let other_coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let spl_token_program = new_spl_token_program(&bump);
+    let vault_signer_nonce = MarketState::load(&accounts.market, dex_program_id)
+        .unwrap()
+        .vault_signer_nonce;
+    let vault_signer_pk =
+        gen_vault_signer_key(vault_signer_nonce, accounts.market.key, dex_program_id).unwrap();
+    let vault_signer = AccountInfo::new(
+        bump.alloc(vault_signer_pk),
+        false,
+        false,
+        bump.alloc(0),
+        &mut [],
+        &system_program::ID,
+        false,
+        Epoch::default(),
+    );
+
+    let process = |instruction: MarketInstruction, instruction_accounts: &[&AccountInfo]| {
+        let instruction_accounts: Vec<AccountInfo> =
+            instruction_accounts.iter().map(|&a| a.clone()).collect();
+        State::process(dex_program_id, &instruction_accounts, &instruction.pack())
+    };
+    let settle_to = |coin_wallet: &_| {
+        process(
+            MarketInstruction::SettleFundsToWallets,
+            &[
+                &accounts.market,
+                &orders_account,
+                &accounts.coin_vault,
+                &accounts.pc_vault,
+                coin_wallet,
+                &pc_account,
+                &vault_signer,
+                &spl_token_program,
+            ],
+        )
+    };
+
+    // leave some coin free by placing an order and cancelling it
+    process(
+        MarketInstruction::NewOrderV2(NewOrderInstructionV2 {
+            side: Side::Ask,
+            limit_price: NonZeroU64::new(100).unwrap(),
+            max_qty: NonZeroU64::new(1).unwrap(),
+            order_type: OrderType::Limit,
+            client_id: 7,
+            self_trade_behavior: SelfTradeBehavior::DecrementTake,
+            expiry: None,
+        }),
+        &[
+            &accounts.market,
+            &orders_account,
+            &accounts.req_q,
+            &coin_account,
+            &owner,
+            &accounts.coin_vault,
+            &accounts.pc_vault,
+            &spl_token_program,
+            &accounts.rent_sysvar,
+        ],
+    )
+    .unwrap();
+    process(
+        MarketInstruction::CancelOrderByClientId(7),
+        &[&accounts.market, &orders_account, &accounts.req_q, &owner],
+    )
+    .unwrap();
+    process(
+        MarketInstruction::MatchOrders(5),
+        &[
+            &accounts.market,
+            &accounts.req_q,
+            &accounts.event_q,
+            &accounts.bids,
+            &accounts.asks,
+            &coin_account,
+            &pc_account,
+            &accounts.clock_sysvar,
+        ],
+    )
+    .unwrap();
+    process(
+        MarketInstruction::ConsumeEvents(5),
+        &[
+            &orders_account,
+            &accounts.market,
+            &accounts.event_q,
+            &coin_account,
+            &pc_account,
+        ],
+    )
+    .unwrap();
+
+    assert_eq!(
+        settle_to(&coin_account),
+        Err(DexErrorCode::SettleWalletsNotRegistered.into())
+    );
+    process(
+        MarketInstruction::SetSettleWallets,
+        &[
+            &accounts.market,
+            &orders_account,
+            &owner,
+            &coin_account,
+            &pc_account,
+        ],
+    )
+    .unwrap();
+    assert_eq!(
+        settle_to(&other_coin_account),
+        Err(DexErrorCode::WrongSettleWallet.into())
+    );
+    settle_to(&coin_account).unwrap();
+
+    let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+    assert_eq!(market.coin_deposits_total, 0);
+    let open_orders = market
+        .load_orders_mut(&orders_account, None, dex_program_id, None)
+        .unwrap();
+    assert_eq!(open_orders.native_coin_free, 0);
+    assert_eq!(open_orders.native_coin_total, 0);
+}
+
+#[test]
+fn test_match_orders_auto_consume() {
+    let mut rng = StdRng::seed_from_u64(12);
+    let bump = Bump::new();
+
+    let authority = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let accounts = setup_market_with_authority(&mut rng, &bump, Some(authority.key));
+
+    let dex_program_id = accounts.market.owner;
+
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let orders_account =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+    let spl_token_program = new_spl_token_program(&bump);
+
+    let process = |instruction: MarketInstruction, instruction_accounts: &[&AccountInfo]| {
+        let instruction_accounts: Vec<AccountInfo> =
+            instruction_accounts.iter().map(|&a| a.clone()).collect();
+        State::process(dex_program_id, &instruction_accounts, &instruction.pack())
+    };
+    let place_and_cancel = |client_id: u64, pass_open_orders: bool| {
+        process(
+            MarketInstruction::NewOrderV2(NewOrderInstructionV2 {
+                side: Side::Ask,
+                limit_price: NonZeroU64::new(100).unwrap(),
+                max_qty: NonZeroU64::new(1).unwrap(),
+                order_type: OrderType::Limit,
+                client_id,
+                self_trade_behavior: SelfTradeBehavior::DecrementTake,
+                expiry: None,
+            }),
+            &[
+                &accounts.market,
+                &orders_account,
+                &accounts.req_q,
+                &coin_account,
+                &owner,
+                &accounts.coin_vault,
+                &accounts.pc_vault,
+                &spl_token_program,
+                &accounts.rent_sysvar,
+            ],
+        )
+        .unwrap();
+        process(
+            MarketInstruction::CancelOrderByClientId(client_id),
+            &[&accounts.market, &orders_account, &accounts.req_q, &owner],
+        )
+        .unwrap();
+        let mut match_accounts = vec![
+            &accounts.market,
+            &accounts.req_q,
+            &accounts.event_q,
+            &accounts.bids,
+            &accounts.asks,
+            &coin_account,
+            &pc_account,
+            &accounts.clock_sysvar,
+        ];
+        if pass_open_orders {
+            match_accounts.push(&orders_account);
+        }
+        process(MarketInstruction::MatchOrders(5), &match_accounts).unwrap();
+    };
+    let queued_events = || {
+        let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+        market
+            .load_event_queue_mut(&accounts.event_q)
+            .unwrap()
+            .len()
+    };
+    let coin_free = || {
+        let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+        let open_orders = market
+            .load_orders_mut(&orders_account, None, dex_program_id, None)
+            .unwrap();
+        open_orders.native_coin_free
+    };
+
+    let enable_auto_consume = || {
+        process(
+            MarketInstruction::SetMarketParams(SetMarketParamsInstruction {
+                fee_schedule: None,
+                pc_dust_threshold: None,
+                coin_lot_size: None,
+                pc_lot_size: None,
+                auto_consume: Some(true),
+                price_band: None,
+                mode: None,
+            }),
+            &[
+                &accounts.market,
+                &accounts.event_q,
+                &authority,
+                &accounts.req_q,
+                &accounts.bids,
+                &accounts.asks,
+            ],
+        )
+        .unwrap()
+    };
+
+    enable_auto_consume();
+    let params_events = queued_events();
+
+    place_and_cancel(1, true);
+    assert_eq!(queued_events(), params_events);
+    assert_eq!(coin_free(), 1_000);
+
+    // once an account has an event waiting for the crank, later ones queue up behind it
+    place_and_cancel(2, false);
+    place_and_cancel(3, true);
+    assert_eq!(queued_events(), params_events + 2);
+    assert_eq!(coin_free(), 0);
+
+    process(
+        MarketInstruction::ConsumeEvents(10),
+        &[
+            &orders_account,
+            &accounts.market,
+            &accounts.event_q,
+            &coin_account,
+            &pc_account,
+        ],
+    )
+    .unwrap();
+    assert_eq!(queued_events(), 0);
+    assert_eq!(coin_free(), 2_000);
+
+    // past the limit, events are left for the crank without looking through the queue
+    while queued_events() <= MAX_AUTO_CONSUME_QUEUED_EVENTS {
+        enable_auto_consume();
+    }
+    let long_queue = queued_events();
+    place_and_cancel(4, true);
+    assert_eq!(queued_events(), long_queue + 1);
+    // the order took half of the free coin and its Out event hasn't given it back yet
+    assert_eq!(coin_free(), 1_000);
+}
+
+#[test]
+fn test_market_stats() {
+    let mut rng = StdRng::seed_from_u64(13);
+    let bump = Bump::new();
+
+    let authority = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let accounts = setup_market_with_authority(&mut rng, &bump, Some(authority.key));
+
+    let dex_program_id = accounts.market.owner;
+    let market_stats_account =
+        new_dex_owned_account(&mut rng, size_of::<MarketStats>(), dex_program_id, &bump);
+    let spl_token_program = new_spl_token_program(&bump);
+
+    let process = |instruction: MarketInstruction, instruction_accounts: &[&AccountInfo]| {
+        let instruction_accounts: Vec<AccountInfo> =
+            instruction_accounts.iter().map(|&a| a.clone()).collect();
+        State::process(dex_program_id, &instruction_accounts, &instruction.pack())
+    };
+    let mut place_order = |side: Side, limit_price: u64| {
+        let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+        let orders_account =
+            new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+        let mint = match side {
+            Side::Bid => accounts.pc_mint.key,
+            Side::Ask => accounts.coin_mint.key,
+        };
+        let payer = new_token_account(&mut rng, mint, owner.key, &bump);
+        process(
+            MarketInstruction::NewOrderV2(NewOrderInstructionV2 {
+                side,
+                limit_price: NonZeroU64::new(limit_price).unwrap(),
+                max_qty: NonZeroU64::new(2).unwrap(),
+                order_type: OrderType::Limit,
+                client_id: 0,
+                self_trade_behavior: SelfTradeBehavior::DecrementTake,
+                expiry: None,
+            }),
+            &[
+                &accounts.market,
+                &orders_account,
+                &accounts.req_q,
+                &payer,
+                &owner,
+                &accounts.coin_vault,
+                &accounts.pc_vault,
+                &spl_token_program,
+                &accounts.rent_sysvar,
+            ],
+        )
+        .unwrap();
+    };
+    let match_orders = |pass_market_stats: bool| {
+        let mut match_accounts = vec![
+            &accounts.market,
+            &accounts.req_q,
+            &accounts.event_q,
+            &accounts.bids,
+            &accounts.asks,
+            &accounts.coin_vault,
+            &accounts.pc_vault,
+            &accounts.clock_sysvar,
+        ];
+        if pass_market_stats {
+            match_accounts.push(&market_stats_account);
+        }
+        process(MarketInstruction::MatchOrders(5), &match_accounts)
+    };
+
+    let initialize = |slots_per_candle: u64| {
+        process(
+            MarketInstruction::InitializeMarketStats(slots_per_candle),
+            &[&accounts.market, &market_stats_account, &authority],
+        )
+    };
+    assert!(initialize(0).is_err());
+    initialize(10).unwrap();
+    assert!(initialize(10).is_err());
+
+    place_order(Side::Ask, 100);
+    match_orders(true).unwrap();
+    place_order(Side::Bid, 110);
+    assert!(match_orders(false).is_err());
+    match_orders(true).unwrap();
+
+    let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+    let market_stats = market.load_market_stats_mut(&market_stats_account).unwrap();
+    assert_eq!(market_stats.last_fill_price, 100);
+    assert_eq!(market_stats.native_coin_volume, 2_000);
+    assert_eq!(market_stats.native_pc_volume, 200);
+    assert_eq!(
+        *market_stats.newest_candle(),
+        Candle {
+            start_slot: 0,
+            open: 100,
+            high: 100,
+            low: 100,
+            close: 100,
+            native_coin_volume: 2_000,
+            native_pc_volume: 200,
+        }
+    );
+}
+
+#[test]
+fn test_market_stats_candles() {
+    let mut market_stats = MarketStats::zeroed();
+    market_stats.slots_per_candle = 10;
+
+    market_stats.record_fill(3, 100, 1, 100);
+    market_stats.record_fill(7, 120, 1, 120);
+    market_stats.record_fill(9, 90, 1, 90);
+    market_stats.record_fill(25, 95, 2, 190);
+    assert_eq!(
+        market_stats.candles().copied().collect::<Vec<_>>(),
+        vec![
+            Candle {
+                start_slot: 20,
+                open: 95,
+                high: 95,
+                low: 95,
+                close: 95,
+                native_coin_volume: 2,
+                native_pc_volume: 190,
+            },
+            Candle {
+                start_slot: 0,
+                open: 100,
+                high: 120,
+                low: 90,
+                close: 90,
+                native_coin_volume: 3,
+                native_pc_volume: 310,
+            },
+        ]
+    );
+    assert_eq!(market_stats.volume_since(10), (2, 190));
+    assert_eq!(market_stats.volume_since(0), (5, 500));
+
+    // once every candle has been used, the oldest is overwritten
+    for i in 0..MARKET_STATS_CANDLES as u64 {
+        market_stats.record_fill(30 + 10 * i, 100, 1, 100);
+    }
+    assert_eq!(market_stats.candles().count(), MARKET_STATS_CANDLES);
+    assert_eq!(market_stats.volume_since(0), (96, 9_600));
+    assert_eq!(market_stats.native_coin_volume, 101);
+}
+
+#[test]
+fn test_price_twap() {
+    let mut rng = StdRng::seed_from_u64(14);
+    let bump = Bump::new();
+
+    let authority = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let accounts = setup_market_with_authority(&mut rng, &bump, Some(authority.key));
+    let dex_program_id = accounts.market.owner;
+    let spl_token_program = new_spl_token_program(&bump);
+
+    let process = |instruction: MarketInstruction, instruction_accounts: &[&AccountInfo]| {
+        let instruction_accounts: Vec<AccountInfo> =
+            instruction_accounts.iter().map(|&a| a.clone()).collect();
+        State::process(dex_program_id, &instruction_accounts, &instruction.pack())
+    };
+    let mut place_order = |side: Side, limit_price: u64| {
+        let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+        let orders_account =
+            new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+        let mint = match side {
+            Side::Bid => accounts.pc_mint.key,
+            Side::Ask => accounts.coin_mint.key,
+        };
+        let payer = new_token_account(&mut rng, mint, owner.key, &bump);
+        process(
+            MarketInstruction::NewOrderV2(NewOrderInstructionV2 {
+                side,
+                limit_price: NonZeroU64::new(limit_price).unwrap(),
+                max_qty: NonZeroU64::new(1).unwrap(),
+                order_type: OrderType::Limit,
+                client_id: 0,
+                self_trade_behavior: SelfTradeBehavior::DecrementTake,
+                expiry: None,
+            }),
+            &[
+                &accounts.market,
+                &orders_account,
+                &accounts.req_q,
+                &payer,
+                &owner,
+                &accounts.coin_vault,
+                &accounts.pc_vault,
+                &spl_token_program,
+                &accounts.rent_sysvar,
+            ],
+        )
+        .unwrap();
+    };
+    let clock_at = |slot: u64| {
+        new_clock_sysvar_account(
+            100000,
+            Clock {
+                slot,
+                ..Clock::default()
+            },
+            &bump,
+        )
+    };
+    let match_orders = |clock_sysvar: Option<&_>| {
+        let mut instruction_accounts = vec![
+            &accounts.market,
+            &accounts.req_q,
+            &accounts.event_q,
+            &accounts.bids,
+            &accounts.asks,
+            &accounts.coin_vault,
+            &accounts.pc_vault,
+        ];
+        instruction_accounts.extend(clock_sysvar);
+        process(MarketInstruction::MatchOrders(5), &instruction_accounts)
+    };
+    let match_orders_at = |slot: u64| match_orders(Some(&clock_at(slot))).unwrap();
+    let observe_price = |slot: u64| {
+        let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+        market.observe_price(slot)
+    };
+
+    place_order(Side::Ask, 100);
+    place_order(Side::Bid, 100);
+    match_orders_at(10);
+    let first = observe_price(10);
+    assert_eq!(first.price_cumulative, 0);
+
+    // a crank without the clock can't move the price before the time at 100 is counted
+    place_order(Side::Ask, 200);
+    place_order(Side::Bid, 200);
+    let no_clock: DexResult = Err(DexErrorCode::WrongClockSysvarAccount.into());
+    assert_eq!(match_orders(None), no_clock);
+    assert_eq!(observe_price(20).price_cumulative, 100 * 10);
+    match_orders_at(30);
+    let second = observe_price(30);
+    assert_eq!(second.price_cumulative, 100 * 20);
+    assert_eq!(second.twap_since(&first), Some(100));
+
+    // the last price counts for every slot since, even without matching
+    let third = observe_price(40);
+    assert_eq!(third.twap_since(&first), Some((100 * 20 + 200 * 10) / 30));
+    assert_eq!(third.twap_since(&second), Some(200));
+    assert_eq!(first.twap_since(&third), None);
+    assert_eq!(first.twap_since(&first), None);
+
+    // new lot sizes clear the price, but only once the time at the old one is counted
+    let set_lot_sizes = |clock_sysvar: Option<&_>| {
+        let mut instruction_accounts = vec![
+            &accounts.market,
+            &accounts.event_q,
+            &authority,
+            &accounts.req_q,
+            &accounts.bids,
+            &accounts.asks,
+        ];
+        instruction_accounts.extend(clock_sysvar);
+        process(
+            MarketInstruction::SetMarketParams(SetMarketParamsInstruction {
+                fee_schedule: None,
+                pc_dust_threshold: None,
+                coin_lot_size: NonZeroU64::new(100),
+                pc_lot_size: NonZeroU64::new(10),
+                auto_consume: None,
+                price_band: None,
+                mode: None,
+            }),
+            &instruction_accounts,
+        )
+    };
+    assert_eq!(set_lot_sizes(None), no_clock);
+    set_lot_sizes(Some(&clock_at(50))).unwrap();
+    let fourth = observe_price(60);
+    assert_eq!(fourth.price_cumulative, 100 * 20 + 200 * 20);
+}
+
+#[test]
+fn test_market_modes() {
+    let mut rng = StdRng::seed_from_u64(15);
+    let bump = Bump::new();
+
+    let authority = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let accounts = setup_market_with_authority(&mut rng, &bump, Some(authority.key));
+
+    let dex_program_id = accounts.market.owner;
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let orders_account =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+    // sells to the owner in the auction, where the owner's own orders don't fill
+    let seller = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let seller_orders_account =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let seller_coin_account =
+        new_token_account(&mut rng, accounts.coin_mint.key, seller.key, &bump);
+    let spl_token_program = new_spl_token_program(&bump);
+
+    let process = |instruction: MarketInstruction, instruction_accounts: &[&AccountInfo]| {
+        let instruction_accounts: Vec<AccountInfo> =
+            instruction_accounts.iter().map(|&a| a.clone()).collect();
+        State::process(dex_program_id, &instruction_accounts, &instruction.pack())
+    };
+    let set_mode = |mode: MarketMode| {
+        process(
+            MarketInstruction::SetMarketParams(SetMarketParamsInstruction {
+                fee_schedule: None,
+                pc_dust_threshold: None,
+                coin_lot_size: None,
+                pc_lot_size: None,
+                auto_consume: None,
+                price_band: None,
+                mode: Some(mode),
+            }),
+            &[
+                &accounts.market,
+                &accounts.event_q,
+                &authority,
+                &accounts.req_q,
+                &accounts.bids,
+                &accounts.asks,
+            ],
+        )
+    };
+    let new_order_by = |by_seller: bool, side: Side, order_type: OrderType, client_id: u64| {
+        let (owner, orders_account, payer) = match (by_seller, side) {
+            (false, Side::Bid) => (&owner, &orders_account, &pc_account),
+            (false, Side::Ask) => (&owner, &orders_account, &coin_account),
+            (true, _) => (&seller, &seller_orders_account, &seller_coin_account),
+        };
+        process(
+            MarketInstruction::NewOrderV2(NewOrderInstructionV2 {
+                side,
+                limit_price: NonZeroU64::new(100).unwrap(),
+                max_qty: NonZeroU64::new(1).unwrap(),
+                order_type,
+                client_id,
+                self_trade_behavior: SelfTradeBehavior::CancelProvide,
+                expiry: None,
+            }),
+            &[
+                &accounts.market,
+                orders_account,
+                &accounts.req_q,
+                payer,
+                owner,
+                &accounts.coin_vault,
+                &accounts.pc_vault,
+                &spl_token_program,
+                &accounts.rent_sysvar,
+            ],
+        )
+    };
+    let new_order = |side: Side, order_type: OrderType, client_id: u64| {
+        new_order_by(false, side, order_type, client_id)
+    };
+    let try_match_orders = || {
+        process(
+            MarketInstruction::MatchOrders(5),
+            &[
+                &accounts.market,
+                &accounts.req_q,
+                &accounts.event_q,
+                &accounts.bids,
+                &accounts.asks,
+                &coin_account,
+                &pc_account,
+                &accounts.clock_sysvar,
+            ],
+        )
+    };
+    let match_orders = || try_match_orders().unwrap();
+    let run_auction = |limit: u16| {
+        process(
+            MarketInstruction::RunAuction(limit),
+            &[
+                &accounts.market,
+                &authority,
+                &accounts.req_q,
+                &accounts.event_q,
+                &accounts.bids,
+                &accounts.asks,
+                &accounts.clock_sysvar,
+            ],
+        )
+    };
+    let book_is_empty = |side: Side| {
+        let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+        match side {
+            Side::Bid => market.load_bids_mut(&accounts.bids).unwrap().is_empty(),
+            Side::Ask => market.load_asks_mut(&accounts.asks).unwrap().is_empty(),
+        }
+    };
+    let last_fill_price = || {
+        let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+        market.last_fill_price
+    };
+
+    // an order queued before the switch is cancelled instead of matched
+    new_order(Side::Ask, OrderType::Limit, 1).unwrap();
+    set_mode(MarketMode::CancelOnly).unwrap();
+    match_orders();
+    assert!(book_is_empty(Side::Ask));
+
+    let cancel_only: DexResult = Err(DexErrorCode::MarketIsCancelOnly.into());
+    assert_eq!(new_order(Side::Ask, OrderType::Limit, 2), cancel_only);
+
+    set_mode(MarketMode::PostOnly).unwrap();
+    let post_only: DexResult = Err(DexErrorCode::MarketIsPostOnly.into());
+    assert_eq!(
+        new_order(Side::Bid, OrderType::ImmediateOrCancel, 3),
+        post_only
+    );
+    new_order(Side::Ask, OrderType::Limit, 4).unwrap();
+    match_orders();
+    assert!(!book_is_empty(Side::Ask));
+
+    // a limit bid that would cross is cancelled rather than filled
+    new_order(Side::Bid, OrderType::Limit, 5).unwrap();
+    match_orders();
+    assert!(!book_is_empty(Side::Ask));
+    assert!(book_is_empty(Side::Bid));
+
+    set_mode(MarketMode::Normal).unwrap();
+    let not_empty: DexResult = Err(DexErrorCode::MarketNotEmpty.into());
+    assert_eq!(set_mode(MarketMode::Auction), not_empty);
+    new_order(Side::Bid, OrderType::ImmediateOrCancel, 6).unwrap();
+    match_orders();
+
+    set_mode(MarketMode::Auction).unwrap();
+    let in_auction: DexResult = Err(DexErrorCode::MarketInAuction.into());
+    assert_eq!(
+        new_order(Side::Bid, OrderType::ImmediateOrCancel, 7),
+        in_auction
+    );
+    assert_eq!(set_mode(MarketMode::Normal), in_auction);
+
+    // crossing orders rest on the book until the auction is run
+    new_order_by(true, Side::Ask, OrderType::Limit, 8).unwrap();
+    new_order(Side::Bid, OrderType::Limit, 9).unwrap();
+    let queue_not_empty: DexResult = Err(DexErrorCode::RequestQueueNotEmpty.into());
+    assert_eq!(run_auction(5), queue_not_empty);
+    match_orders();
+    assert!(!book_is_empty(Side::Bid));
+    assert_eq!(last_fill_price(), 0);
+
+    // a run cut short by its limit leaves the market in auction until it's finished,
+    // with the book held as it is in the meantime
+    run_auction(1).unwrap();
+    assert_eq!(last_fill_price(), 100);
+    assert!(book_is_empty(Side::Bid));
+    assert!(!book_is_empty(Side::Ask));
+    new_order(Side::Bid, OrderType::Limit, 10).unwrap();
+    let auction_in_progress: DexResult = Err(DexErrorCode::AuctionInProgress.into());
+    assert_eq!(try_match_orders(), auction_in_progress);
+    assert_eq!(set_mode(MarketMode::Normal), in_auction);
+
+    run_auction(1).unwrap();
+    assert!(book_is_empty(Side::Ask));
+    let not_in_auction: DexResult = Err(DexErrorCode::MarketNotInAuction.into());
+    assert_eq!(run_auction(5), not_in_auction);
+    match_orders();
+    assert!(!book_is_empty(Side::Bid));
+    new_order(Side::Bid, OrderType::ImmediateOrCancel, 11).unwrap();
+}
+
+#[test]
+fn test_orders_rejected_while_halted() {
+    let mut rng = StdRng::seed_from_u64(16);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+
+    let dex_program_id = accounts.market.owner;
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let orders_account =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+    let spl_token_program = new_spl_token_program(&bump);
+    {
+        // as if the price band was hit at slot 0
+        let mut market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+        market.price_band_bps = 100;
+        market.price_band_halt_slots = 10;
+        market.halted_until_slot = 10;
+    }
+
+    let process = |instruction: MarketInstruction, instruction_accounts: &[&AccountInfo]| {
+        let instruction_accounts: Vec<AccountInfo> =
+            instruction_accounts.iter().map(|&a| a.clone()).collect();
+        State::process(dex_program_id, &instruction_accounts, &instruction.pack())
+    };
+    let clock_at = |slot: u64| {
+        new_clock_sysvar_account(
+            100000,
+            Clock {
+                slot,
+                ..Clock::default()
+            },
+            &bump,
+        )
+    };
+    let new_order = |clock_sysvar: Option<&_>| {
+        let mut order_accounts = vec![
+            &accounts.market,
+            &orders_account,
+            &accounts.req_q,
+            &pc_account,
+            &owner,
+            &accounts.coin_vault,
+            &accounts.pc_vault,
+            &spl_token_program,
+            &accounts.rent_sysvar,
+        ];
+        order_accounts.extend(clock_sysvar);
+        process(
+            MarketInstruction::NewOrderV2(NewOrderInstructionV2 {
+                side: Side::Bid,
+                limit_price: NonZeroU64::new(100).unwrap(),
+                max_qty: NonZeroU64::new(1).unwrap(),
+                order_type: OrderType::Limit,
+                client_id: 0,
+                self_trade_behavior: SelfTradeBehavior::DecrementTake,
+                expiry: None,
+            }),
+            &order_accounts,
+        )
+    };
+
+    let halted: DexResult = Err(DexErrorCode::MarketIsHalted.into());
+    assert_eq!(new_order(Some(&clock_at(5))), halted);
+    assert_eq!(new_order(None), halted);
+    new_order(Some(&clock_at(10))).unwrap();
+
+    // without the clock, orders wait for a crank to see that the halt is over
+    assert_eq!(new_order(None), halted);
+    process(
+        MarketInstruction::MatchOrders(5),
+        &[
+            &accounts.market,
+            &accounts.req_q,
+            &accounts.event_q,
+            &accounts.bids,
+            &accounts.asks,
+            &accounts.coin_vault,
+            &accounts.pc_vault,
+            &clock_at(10),
+        ],
+    )
+    .unwrap();
+    new_order(None).unwrap();
+}
+
+#[test]
+fn test_stop_orders() {
+    let mut rng = StdRng::seed_from_u64(17);
+    let bump = Bump::new();
+
+    let accounts = setup_market_with_order_slabs(&mut rng, &bump, None, Some(1 << 16), None);
+    let stop_orders = accounts.stop_orders.as_ref().unwrap();
+    let dex_program_id = accounts.market.owner;
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let maker_orders_account =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let taker_orders_account =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+    let spl_token_program = new_spl_token_program(&bump);
+
+    let process = |instruction: MarketInstruction, instruction_accounts: &[&AccountInfo]| {
+        let instruction_accounts: Vec<AccountInfo> =
+            instruction_accounts.iter().map(|&a| a.clone()).collect();
+        State::process(dex_program_id, &instruction_accounts, &instruction.pack())
+    };
+    let new_order =
+        |orders_account: &_, side: Side, limit_price: u64, trigger_price: Option<u64>| {
+            let payer = match side {
+                Side::Bid => &pc_account,
+                Side::Ask => &coin_account,
+            };
+            let instruction = NewOrderInstructionV2 {
+                side,
+                limit_price: NonZeroU64::new(limit_price).unwrap(),
+                max_qty: NonZeroU64::new(1).unwrap(),
+                order_type: OrderType::Limit,
+                client_id: 0,
+                self_trade_behavior: SelfTradeBehavior::DecrementTake,
+                expiry: None,
+            };
+            let mut instruction_accounts = vec![
+                &accounts.market,
+                orders_account,
+                &accounts.req_q,
+                payer,
+                &owner,
+                &accounts.coin_vault,
+                &accounts.pc_vault,
+                &spl_token_program,
+                &accounts.rent_sysvar,
+            ];
+            match trigger_price {
+                None => process(
+                    MarketInstruction::NewOrderV2(instruction),
+                    &instruction_accounts,
+                ),
+                Some(trigger_price) => {
+                    instruction_accounts.push(stop_orders);
+                    let instruction =
+                        instruction.add_trigger_price(NonZeroU64::new(trigger_price).unwrap());
+                    process(
+                        MarketInstruction::NewOrderV3(instruction),
+                        &instruction_accounts,
+                    )
+                }
+            }
+        };
+    let match_orders = || {
+        process(
+            MarketInstruction::MatchOrders(10),
+            &[
+                &accounts.market,
+                &accounts.req_q,
+                &accounts.event_q,
+                &accounts.bids,
+                &accounts.asks,
+                &coin_account,
+                &pc_account,
+                &accounts.clock_sysvar,
+                stop_orders,
+            ],
+        )
+        .unwrap();
+    };
+    let stop_order_count = || {
+        let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+        let stop_orders = market.load_stop_orders_mut(stop_orders).unwrap();
+        let mut count = 0;
+        stop_orders.find_leaf_by(|_| {
+            count += 1;
+            false
+        });
+        count
+    };
+
+    // a buy stop at 105 that bids up to 110 once triggered, and a sell stop at 95
+    new_order(&taker_orders_account, Side::Bid, 110, Some(105)).unwrap();
+    new_order(&taker_orders_account, Side::Ask, 90, Some(95)).unwrap();
+    for &price in [100, 106, 110].iter() {
+        new_order(&maker_orders_account, Side::Ask, price, None).unwrap();
+    }
+    match_orders();
+    assert_eq!(stop_order_count(), 2);
+
+    // trading at 100 touches neither trigger
+    new_order(&taker_orders_account, Side::Bid, 100, None).unwrap();
+    match_orders();
+    assert_eq!(stop_order_count(), 2);
+
+    // trading at 106 sets off the buy stop, which then takes the offer at 110
+    new_order(&taker_orders_account, Side::Bid, 106, None).unwrap();
+    match_orders();
+    assert_eq!(stop_order_count(), 1);
+    {
+        let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+        assert!(market.load_asks_mut(&accounts.asks).unwrap().is_empty());
+        assert!(market.load_bids_mut(&accounts.bids).unwrap().is_empty());
+        assert_eq!(market.last_fill_price, 110);
+    }
+
+    let order_id = {
+        let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+        let open_orders = market
+            .load_orders_mut(&taker_orders_account, None, dex_program_id, None)
+            .unwrap();
+        *open_orders.order_id(1)
+    };
+    process(
+        MarketInstruction::CancelOrder(CancelOrderInstruction {
+            side: Side::Ask,
+            order_id,
+            owner: [0; 4],
+            owner_slot: 1,
+        }),
+        &[
+            &accounts.market,
+            &taker_orders_account,
+            &accounts.req_q,
+            &owner,
+        ],
+    )
+    .unwrap();
+    match_orders();
+    assert_eq!(stop_order_count(), 0);
+    let mut crank_accounts = vec![&maker_orders_account, &taker_orders_account];
+    crank_accounts.sort_by_key(|account_info| account_info.key.to_aligned_bytes());
+    crank_accounts.extend(&[
+        &accounts.market,
+        &accounts.event_q,
+        &coin_account,
+        &pc_account,
+    ]);
+    process(MarketInstruction::ConsumeEvents(20), &crank_accounts).unwrap();
+
+    // the cancelled sell stop's coin is unlocked, and the bought coin credited
+    let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+    let open_orders = market
+        .load_orders_mut(&taker_orders_account, None, dex_program_id, None)
+        .unwrap();
+    assert_eq!(open_orders.slot_side(1), None);
+    assert_eq!(open_orders.native_coin_free, 4_000);
+    assert_eq!(open_orders.native_coin_total, 4_000);
+}
+
+#[test]
+fn test_stop_orders_full() {
+    let mut rng = StdRng::seed_from_u64(18);
+    let bump = Bump::new();
+
+    // room for exactly 201 nodes, which hold 101 leaves
+    let stop_orders_size = size_of::<u64>() + 32 + 201 * size_of::<LeafNode>();
+    let accounts =
+        setup_market_with_order_slabs(&mut rng, &bump, None, Some(stop_orders_size), None);
+    let stop_orders = accounts.stop_orders.as_ref().unwrap();
+    let dex_program_id = accounts.market.owner;
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let orders_account =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let spl_token_program = new_spl_token_program(&bump);
+
+    let new_stop_order = || {
+        let instruction = MarketInstruction::NewOrderV3(NewOrderInstructionV3 {
+            side: Side::Ask,
+            limit_price: NonZeroU64::new(100).unwrap(),
+            max_qty: NonZeroU64::new(1).unwrap(),
+            order_type: OrderType::Limit,
+            client_id: 0,
+            self_trade_behavior: SelfTradeBehavior::DecrementTake,
+            trigger_price: NonZeroU64::new(90).unwrap(),
+            expiry: None,
+        });
+        let instruction_accounts = vec![
+            accounts.market.clone(),
+            orders_account.clone(),
+            accounts.req_q.clone(),
+            coin_account.clone(),
+            owner.clone(),
+            accounts.coin_vault.clone(),
+            accounts.pc_vault.clone(),
+            spl_token_program.clone(),
+            accounts.rent_sysvar.clone(),
+            stop_orders.clone(),
+        ];
+        State::process(dex_program_id, &instruction_accounts, &instruction.pack())
+    };
+
+    for _ in 0..101 {
+        new_stop_order().unwrap();
+    }
+    assert_eq!(new_stop_order(), Err(DexErrorCode::StopOrdersFull.into()));
+}
+
+#[test]
+fn test_order_expiry() {
+    let mut rng = StdRng::seed_from_u64(19);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let maker_orders_account =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let taker_orders_account =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+    let spl_token_program = new_spl_token_program(&bump);
+
+    let process = |instruction: MarketInstruction, instruction_accounts: &[&AccountInfo]| {
+        let instruction_accounts: Vec<AccountInfo> =
+            instruction_accounts.iter().map(|&a| a.clone()).collect();
+        State::process(dex_program_id, &instruction_accounts, &instruction.pack())
+    };
+    let new_order =
+        |orders_account: &_, side: Side, limit_price: u64, expiry: Option<OrderExpiry>| {
+            let payer = match side {
+                Side::Bid => &pc_account,
+                Side::Ask => &coin_account,
+            };
+            process(
+                MarketInstruction::NewOrderV2(NewOrderInstructionV2 {
+                    side,
+                    limit_price: NonZeroU64::new(limit_price).unwrap(),
+                    max_qty: NonZeroU64::new(1).unwrap(),
+                    order_type: OrderType::Limit,
+                    client_id: 0,
+                    self_trade_behavior: SelfTradeBehavior::DecrementTake,
+                    expiry,
+                }),
+                &[
+                    &accounts.market,
+                    orders_account,
+                    &accounts.req_q,
+                    payer,
+                    &owner,
+                    &accounts.coin_vault,
+                    &accounts.pc_vault,
+                    &spl_token_program,
+                    &accounts.rent_sysvar,
+                ],
+            )
+            .unwrap();
+        };
+    let match_orders_at = |slot: u64| {
+        let clock_sysvar = new_clock_sysvar_account(
+            100000,
+            Clock {
+                slot,
+                ..Clock::default()
+            },
+            &bump,
+        );
+        process(
+            MarketInstruction::MatchOrders(10),
+            &[
+                &accounts.market,
+                &accounts.req_q,
+                &accounts.event_q,
+                &accounts.bids,
+                &accounts.asks,
+                &coin_account,
+                &pc_account,
+                &clock_sysvar,
+            ],
+        )
+        .unwrap();
+    };
+
+    new_order(
+        &maker_orders_account,
+        Side::Ask,
+        100,
+        Some(OrderExpiry::Slot(10)),
+    );
+    new_order(&maker_orders_account, Side::Ask, 105, None);
+    match_orders_at(5);
+
+    // by slot 20 the better offer has expired, so the bid skips it and takes the other
+    new_order(&taker_orders_account, Side::Bid, 110, None);
+    // a crank that leaves out the clock can't tell, so it can't match at all
+    assert_eq!(
+        process(
+            MarketInstruction::MatchOrders(10),
+            &[
+                &accounts.market,
+                &accounts.req_q,
+                &accounts.event_q,
+                &accounts.bids,
+                &accounts.asks,
+                &coin_account,
+                &pc_account,
+            ],
+        ),
+        Err(DexErrorCode::WrongClockSysvarAccount.into())
+    );
+    match_orders_at(20);
+    {
+        let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+        assert!(market.load_asks_mut(&accounts.asks).unwrap().is_empty());
+        assert_eq!(market.last_fill_price, 105);
+
+        let event_q = market.load_event_queue_mut(&accounts.event_q).unwrap();
+        let expired_order_out = event_q.iter().any(|event| match event.as_view().unwrap() {
+            EventView::Out {
+                side: Side::Ask,
+                native_qty_unlocked,
+                native_qty_still_locked: 0,
+                owner,
+                owner_slot: 0,
+                ..
+            } => {
+                *owner == maker_orders_account.key.to_aligned_bytes()
+                    && native_qty_unlocked == market.coin_lot_size
+            }
+            _ => false,
+        });
+        assert!(expired_order_out);
+    }
+
+    let mut crank_accounts = vec![&maker_orders_account, &taker_orders_account];
+    crank_accounts.sort_by_key(|account_info| account_info.key.to_aligned_bytes());
+    crank_accounts.extend(&[
+        &accounts.market,
+        &accounts.event_q,
+        &coin_account,
+        &pc_account,
+    ]);
+    process(MarketInstruction::ConsumeEvents(20), &crank_accounts).unwrap();
+
+    // the expired order's coin is free again, and the other one was sold
+    let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+    let open_orders = market
+        .load_orders_mut(&maker_orders_account, None, dex_program_id, None)
+        .unwrap();
+    assert_eq!(open_orders.slot_side(0), None);
+    assert_eq!(open_orders.native_coin_free, 1_000);
+    assert_eq!(open_orders.native_coin_total, 1_000);
+}
+
+#[test]
+fn test_pegged_orders_without_stop_orders() {
+    let mut rng = StdRng::seed_from_u64(20);
+    let bump = Bump::new();
+
+    let authority = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let accounts =
+        setup_market_with_order_slabs(&mut rng, &bump, Some(authority.key), None, Some(1 << 16));
+    let pegged_orders = accounts.pegged_orders.as_ref().unwrap();
+    let dex_program_id = accounts.market.owner;
+    let market_stats_account =
+        new_dex_owned_account(&mut rng, size_of::<MarketStats>(), dex_program_id, &bump);
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+    let spl_token_program = new_spl_token_program(&bump);
+
+    let process = |instruction: MarketInstruction, instruction_accounts: &[&AccountInfo]| {
+        let instruction_accounts: Vec<AccountInfo> =
+            instruction_accounts.iter().map(|&a| a.clone()).collect();
+        State::process(dex_program_id, &instruction_accounts, &instruction.pack())
+    };
+    process(
+        MarketInstruction::InitializeMarketStats(10),
+        &[&accounts.market, &market_stats_account, &authority],
+    )
+    .unwrap();
+
+    let mut new_orders_account =
+        || new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let bidder_orders_account = new_orders_account();
+    let pegged_bidder_orders_account = new_orders_account();
+    let seller_orders_account = new_orders_account();
+    let order_accounts = |orders_account, payer| {
+        vec![
+            &accounts.market,
+            orders_account,
+            &accounts.req_q,
+            payer,
+            &owner,
+            &accounts.coin_vault,
+            &accounts.pc_vault,
+            &spl_token_program,
+            &accounts.rent_sysvar,
+        ]
+    };
+    let new_order = |orders_account, side: Side, limit_price: u64, max_qty: u64| {
+        let payer = match side {
+            Side::Bid => &pc_account,
+            Side::Ask => &coin_account,
+        };
+        process(
+            MarketInstruction::NewOrderV2(NewOrderInstructionV2 {
+                side,
+                limit_price: NonZeroU64::new(limit_price).unwrap(),
+                max_qty: NonZeroU64::new(max_qty).unwrap(),
+                order_type: OrderType::Limit,
+                client_id: 0,
+                self_trade_behavior: SelfTradeBehavior::DecrementTake,
+                expiry: None,
+            }),
+            &order_accounts(orders_account, payer),
+        )
+        .unwrap();
+    };
+    let match_orders = |optional_accounts: &[&_]| {
+        let mut match_accounts = vec![
+            &accounts.market,
+            &accounts.req_q,
+            &accounts.event_q,
+            &accounts.bids,
+            &accounts.asks,
+            &coin_account,
+            &pc_account,
+            &accounts.clock_sysvar,
+        ];
+        match_accounts.extend(optional_accounts);
+        process(MarketInstruction::MatchOrders(10), &match_accounts)
+    };
+
+    new_order(&bidder_orders_account, Side::Bid, 100, 1);
+    let mut pegged_order_accounts = order_accounts(&pegged_bidder_orders_account, &pc_account);
+    pegged_order_accounts.push(pegged_orders);
+    process(
+        MarketInstruction::NewPeggedOrder(NewPeggedOrderInstruction {
+            side: Side::Bid,
+            peg_offset: 0,
+            cap_price: NonZeroU64::new(200),
+            max_qty: NonZeroU64::new(1).unwrap(),
+            client_id: 0,
+        }),
+        &pegged_order_accounts,
+    )
+    .unwrap();
+
+    // with no stop orders slab, the pegged orders come right after the clock
+    assert!(match_orders(&[&market_stats_account, pegged_orders]).is_err());
+    match_orders(&[pegged_orders, &market_stats_account]).unwrap();
+
+    new_order(&seller_orders_account, Side::Ask, 90, 2);
+    match_orders(&[pegged_orders, &market_stats_account]).unwrap();
+
+    let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+    assert!(!market.has_stop_orders());
+    assert!(market.load_bids_mut(&accounts.bids).unwrap().is_empty());
+    let market_stats = market.load_market_stats_mut(&market_stats_account).unwrap();
+    assert_eq!(market_stats.last_fill_price, 100);
+    assert_eq!(market_stats.native_coin_volume, 2_000);
+}
+
+// Gives a dex owned account new data of the given length, starting with as much of its
+// old data as fits.
+fn resize_dex_owned_account<'bump>(
+    account: &AccountInfo<'bump>,
+    unpadded_len: usize,
+    bump: &'bump Bump,
+) {
+    let data = allocate_dex_owned_account(unpadded_len, bump);
+    let tail = data.len() - ACCOUNT_TAIL_PADDING.len();
+    {
+        let old_data = account.try_borrow_data().unwrap();
+        let copied_len = tail.min(old_data.len() - ACCOUNT_TAIL_PADDING.len());
+        data[..copied_len].copy_from_slice(&old_data[..copied_len]);
+    }
+    data[tail..].copy_from_slice(ACCOUNT_TAIL_PADDING);
+    account.data.replace(data);
+}
+
+fn clear_account_flag(account: &AccountInfo, flag: AccountFlag) {
+    let mut data = account.try_borrow_mut_data().unwrap();
+    let mut account_flags = [0; 8];
+    account_flags.copy_from_slice(&data[5..13]);
+    let account_flags = u64::from_le_bytes(account_flags) & !(flag as u64);
+    data[5..13].copy_from_slice(&account_flags.to_le_bytes());
+}
+
+#[test]
+fn test_legacy_market() {
+    let mut rng = StdRng::seed_from_u64(21);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let orders_account =
+        new_dex_owned_account(&mut rng, size_of::<OpenOrders>(), dex_program_id, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+    let spl_token_program = new_spl_token_program(&bump);
+    let vault_signer_nonce = MarketState::load(&accounts.market, dex_program_id)
+        .unwrap()
+        .vault_signer_nonce;
+    let vault_signer_pk =
+        gen_vault_signer_key(vault_signer_nonce, accounts.market.key, dex_program_id).unwrap();
+    let vault_signer = AccountInfo::new(
+        bump.alloc(vault_signer_pk),
+        false,
+        false,
+        bump.alloc(0),
+        &mut [],
+        &system_program::ID,
+        false,
+        Epoch::default(),
+    );
+
+    let process = |instruction: MarketInstruction, instruction_accounts: &[&AccountInfo]| {
+        let instruction_accounts: Vec<AccountInfo> =
+            instruction_accounts.iter().map(|&a| a.clone()).collect();
+        State::process(dex_program_id, &instruction_accounts, &instruction.pack())
+    };
+    let new_bid = |limit_price: u64| {
+        process(
+            MarketInstruction::NewOrderV2(NewOrderInstructionV2 {
+                side: Side::Bid,
+                limit_price: NonZeroU64::new(limit_price).unwrap(),
+                max_qty: NonZeroU64::new(1).unwrap(),
+                order_type: OrderType::Limit,
+                client_id: limit_price,
+                self_trade_behavior: SelfTradeBehavior::DecrementTake,
+                expiry: None,
+            }),
+            &[
+                &accounts.market,
+                &orders_account,
+                &accounts.req_q,
+                &pc_account,
+                &owner,
+                &accounts.coin_vault,
+                &accounts.pc_vault,
+                &spl_token_program,
+                &accounts.rent_sysvar,
+            ],
+        )
+        .unwrap();
+    };
+    let match_orders = |req_q: &_, bids: &_, asks: &_| {
+        process(
+            MarketInstruction::MatchOrders(10),
+            &[
+                &accounts.market,
+                req_q,
+                &accounts.event_q,
+                bids,
+                asks,
+                &coin_account,
+                &pc_account,
+                &accounts.clock_sysvar,
+            ],
+        )
+    };
+
+    for limit_price in 100..106 {
+        new_bid(limit_price);
+    }
+    match_orders(&accounts.req_q, &accounts.bids, &accounts.asks).unwrap();
+    // these two are still queued when the program is upgraded
+    process(
+        MarketInstruction::CancelOrderByClientId(105),
+        &[&accounts.market, &orders_account, &accounts.req_q, &owner],
+    )
+    .unwrap();
+    new_bid(99);
+
+    // rewrite the market, the order books and the request queue as the program stored
+    // them before the V2 layouts, in accounts with no room to spare
+    let (legacy_bids, legacy_asks, slab_offset, requests) = {
+        let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+        let bids_address = accounts.bids.try_borrow_data().unwrap().as_ptr() as usize;
+        let bids = market.load_bids_mut(&accounts.bids).unwrap();
+        let slab_offset = &*bids as *const Slab as *const u8 as usize - bids_address;
+        let asks = market.load_asks_mut(&accounts.asks).unwrap();
+        let req_q = market.load_request_queue_mut(&accounts.req_q).unwrap();
+        let requests: Vec<Request> = req_q.iter().copied().collect();
+        let legacy_bids = bids.to_legacy_nodes();
+        (legacy_bids, asks.to_legacy_nodes(), slab_offset, requests)
+    };
+    assert_eq!(requests.len(), 2);
+
+    resize_dex_owned_account(&accounts.market, LEGACY_MARKET_STATE_LEN, &bump);
+    clear_account_flag(&accounts.market, AccountFlag::MarketV2);
+
+    for (account, legacy_nodes) in [
+        (&accounts.bids, &legacy_bids),
+        (&accounts.asks, &legacy_asks),
+    ]
+    .iter()
+    {
+        let legacy_nodes: &[u8] = transmute_to_bytes(legacy_nodes.as_slice());
+        resize_dex_owned_account(account, slab_offset - 5 + legacy_nodes.len(), &bump);
+        account.try_borrow_mut_data().unwrap()[slab_offset..slab_offset + legacy_nodes.len()]
+            .copy_from_slice(legacy_nodes);
+        clear_account_flag(account, AccountFlag::OrderBookV2);
+    }
+
+    // a ring of two 80-byte requests, wrapped around
+    const LEGACY_REQUEST_SIZE: usize = 80;
+    resize_dex_owned_account(
+        &accounts.req_q,
+        size_of::<RequestQueueHeader>() + 2 * LEGACY_REQUEST_SIZE,
+        &bump,
+    );
+    clear_account_flag(&accounts.req_q, AccountFlag::RequestQueueV2);
+    {
+        let mut data = accounts.req_q.try_borrow_mut_data().unwrap();
+        data[13..21].copy_from_slice(&1u64.to_le_bytes());
+        data[21..29].copy_from_slice(&2u64.to_le_bytes());
+        let ring = &mut data[5 + size_of::<RequestQueueHeader>()..];
+        for (request, slot) in requests.iter().zip(&[1, 0]) {
+            ring[slot * LEGACY_REQUEST_SIZE..(slot + 1) * LEGACY_REQUEST_SIZE]
+                .copy_from_slice(&bytemuck::bytes_of(request)[..LEGACY_REQUEST_SIZE]);
+        }
+    }
+
+    // nothing is matched or cancelled until the market is migrated
+    assert_eq!(
+        match_orders(&accounts.req_q, &accounts.bids, &accounts.asks),
+        Err(DexErrorCode::MarketNotMigrated.into())
+    );
+    assert_eq!(
+        process(
+            MarketInstruction::CancelOrderByClientId(104),
+            &[&accounts.market, &orders_account, &accounts.req_q, &owner],
+        ),
+        Err(DexErrorCode::MarketNotMigrated.into())
+    );
+    // but funds can still be settled
+    process(
+        MarketInstruction::ConsumeEvents(20),
+        &[
+            &orders_account,
+            &accounts.market,
+            &accounts.event_q,
+            &coin_account,
+            &pc_account,
+        ],
+    )
+    .unwrap();
+    process(
+        MarketInstruction::SettleFunds,
+        &[
+            &accounts.market,
+            &orders_account,
+            &owner,
+            &accounts.coin_vault,
+            &accounts.pc_vault,
+            &coin_account,
+            &pc_account,
+            &vault_signer,
+            &spl_token_program,
+        ],
+    )
+    .unwrap();
+
+    let disable_authority = AccountInfo::new(
+        &disable_authority::ID,
+        true,
+        false,
+        bump.alloc(0),
+        &mut [],
+        &system_program::ID,
+        false,
+        Epoch::default(),
+    );
+    let stranger = new_sol_account(&mut rng, 0, &bump);
+    let mut migrate = |signer: &_, new_req_q_len: usize, new_slab_len: usize| {
+        let new_accounts = [
+            new_dex_owned_account(&mut rng, new_req_q_len, dex_program_id, &bump),
+            new_dex_owned_account(&mut rng, new_slab_len, dex_program_id, &bump),
+            new_dex_owned_account(&mut rng, new_slab_len, dex_program_id, &bump),
+        ];
+        let result = process(
+            MarketInstruction::MigrateMarket,
+            &[
+                &accounts.market,
+                signer,
+                &accounts.req_q,
+                &accounts.bids,
+                &accounts.asks,
+                &new_accounts[0],
+                &new_accounts[1],
+                &new_accounts[2],
+            ],
+        );
+        result.map(|()| new_accounts)
+    };
+    let legacy_market_data = accounts.market.try_borrow_data().unwrap().to_vec();
+
+    // the old request queue holds two requests and the bids have used 11 nodes
+    let request_queue_len =
+        |requests: usize| size_of::<RequestQueueHeader>() + requests * size_of::<Request>();
+    let slab_len = |nodes: usize| slab_offset - 5 + SLAB_HEADER_LEN + nodes * size_of::<LeafNode>();
+    assert!(migrate(&stranger, request_queue_len(2), slab_len(11)).is_err());
+    assert_eq!(
+        migrate(&disable_authority, request_queue_len(1), slab_len(11)).err(),
+        Some(DexErrorCode::RequestQueueFull.into())
+    );
+    assert_eq!(
+        migrate(&disable_authority, request_queue_len(2), slab_len(10)).err(),
+        Some(DexErrorCode::SlabTooSmall.into())
+    );
+    assert_eq!(
+        &accounts.market.try_borrow_data().unwrap()[..],
+        &legacy_market_data[..]
+    );
+
+    let old_lamports: u64 = [&accounts.req_q, &accounts.bids, &accounts.asks]
+        .iter()
+        .map(|account| account.lamports())
+        .sum();
+    let [new_req_q, new_bids, new_asks] =
+        migrate(&disable_authority, request_queue_len(2), slab_len(11)).unwrap();
+    assert_eq!(disable_authority.lamports(), old_lamports);
+    for account in [&accounts.req_q, &accounts.bids, &accounts.asks].iter() {
+        assert_eq!(account.lamports(), 0);
+    }
+    // already migrated
+    assert!(migrate(&disable_authority, request_queue_len(2), slab_len(11)).is_err());
+
+    // the queued cancel and bid are matched as if nothing happened
+    match_orders(&new_req_q, &new_bids, &new_asks).unwrap();
+    assert_eq!(
+        accounts.market.data_len(),
+        LEGACY_MARKET_STATE_LEN + ACCOUNT_HEAD_PADDING.len() + ACCOUNT_TAIL_PADDING.len()
+    );
+    let market = MarketState::load(&accounts.market, dex_program_id).unwrap();
+    assert!(!market.is_v2());
+    assert!(market.load_request_queue_mut(&new_req_q).unwrap().empty());
+    let bids = market.load_bids_mut(&new_bids).unwrap();
+    let bid_prices: Vec<u64> = bids.leaves(false).map(|bid| bid.price().get()).collect();
+    assert_eq!(bid_prices, (99..105).collect::<Vec<_>>());
+    let event_q = market.load_event_queue_mut(&accounts.event_q).unwrap();
+    let cancelled: Vec<u64> = event_q
+        .iter()
+        .filter_map(|event| match event.as_view().unwrap() {
+            EventView::Out {
+                native_qty_still_locked: 0,
+                order_id,
+                ..
+            } => Some((order_id >> 64) as u64),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(cancelled, vec![105]);
+}
+
+fn opposite(side: Side) -> Side {
+    match side {
+        Side::Bid => Side::Ask,
+        Side::Ask => Side::Bid,
+    }
+}
+
+fn with_order_book<T>(
+    f: impl FnOnce(&mut OrderBookState, &mut RequestQueue, &mut EventQueue) -> T,
+) -> T {
+    let mut market_state = MarketState::zeroed();
+    market_state.account_flags =
+        (AccountFlag::Initialized | AccountFlag::Market | AccountFlag::MarketV2).bits();
+    market_state.coin_lot_size = 1_000;
+    market_state.pc_lot_size = 1;
+
+    let mut bids_words = vec![0u64; 1 << 12];
+    let mut asks_words = vec![0u64; 1 << 12];
+    let mut stop_orders_words = vec![0u64; 1 << 12];
+    let mut pegged_orders_words = vec![0u64; 1 << 12];
+    let req_q_header = RefCell::new(RequestQueueHeader::zeroed());
+    let req_q_buf = RefCell::new(vec![Request::zeroed(); 32]);
+    let event_q_header = RefCell::new(EventQueueHeader::zeroed());
+    let event_q_buf = RefCell::new(vec![Event::zeroed(); 256]);
+    let mut req_q: RequestQueue = Queue::new(
+        req_q_header.borrow_mut(),
+        RefMut::map(req_q_buf.borrow_mut(), Vec::as_mut_slice),
+    );
+    let mut event_q: EventQueue = Queue::new(
+        event_q_header.borrow_mut(),
+        RefMut::map(event_q_buf.borrow_mut(), Vec::as_mut_slice),
+    );
+    let clock = Clock::default();
+    let mut order_book = OrderBookState {
+        bids: Slab::new(transmute_to_bytes_mut(&mut bids_words)),
+        asks: Slab::new(transmute_to_bytes_mut(&mut asks_words)),
+        stop_orders: Some(Slab::new(transmute_to_bytes_mut(&mut stop_orders_words))),
+        pegged_orders: Some(Slab::new(transmute_to_bytes_mut(&mut pegged_orders_words))),
+        market_stats: None,
+        market_state: &mut market_state,
+        clock: &clock,
+    };
+    f(&mut order_book, &mut req_q, &mut event_q)
+}
+
+fn push_new_order(
+    req_q: &mut RequestQueue,
+    side: Side,
+    order_type: OrderType,
+    seq_num: u64,
+    owner: &[u64; 4],
+    limit_price: u64,
+    max_qty: u64,
+    pc_lot_size: u64,
+    self_trade_behavior: SelfTradeBehavior,
+) -> u128 {
+    let order_id = match side {
+        Side::Bid => ((limit_price as u128) << 64) | !seq_num as u128,
+        Side::Ask => ((limit_price as u128) << 64) | seq_num as u128,
+    };
+    let native_pc_qty_locked = match side {
+        Side::Bid => NonZeroU64::new(max_qty * limit_price * pc_lot_size),
+        Side::Ask => None,
+    };
+    req_q
+        .push_back(Request::new(RequestView::NewOrder {
+            side,
+            order_type,
+            owner_slot: seq_num as u16,
+            fee_tier: FeeTier::Base,
+            order_id: &order_id,
+            max_coin_qty: NonZeroU64::new(max_qty).unwrap(),
+            native_pc_qty_locked,
+            owner,
+            client_order_id: None,
+            self_trade_behavior,
+            expiry: None,
+            replaces: None,
+            display_qty: None,
+        }))
+        .unwrap();
+    order_id
+}
+
+proptest! {
+    #[test]
+    fn fill_or_kill_is_all_or_nothing(
+        taker_side: Side,
+        resting in proptest::collection::vec((1u64..20, 1u64..20, any::<bool>()), 0..20),
+        limit_price in 1u64..20,
+        max_qty in 1u64..100,
+        limit in 1u16..5,
+    ) {
+        with_order_book(|order_book, req_q, event_q| {
+            let coin_lot_size = order_book.market_state.coin_lot_size;
+            let pc_lot_size = order_book.market_state.pc_lot_size;
+
+            let taker = [1u64; 4];
+            let maker = [2u64; 4];
+            for (seq_num, &(price, qty, own)) in resting.iter().enumerate() {
+                let owner = if own { &taker } else { &maker };
+                push_new_order(
+                    req_q,
+                    opposite(taker_side),
+                    OrderType::PostOnly,
+                    seq_num as u64,
+                    owner,
+                    price,
+                    qty,
+                    pc_lot_size,
+                    SelfTradeBehavior::CancelProvide,
+                );
+            }
+            order_book.process_requests(req_q, event_q, 1000).unwrap();
+
+            // walk the crossing orders in the order they'd be matched, one step each; the
+            // taker cancels its own resting orders instead of trading with them
+            let mut crossing: Vec<_> = resting
+                .iter()
+                .enumerate()
+                .filter(|&(_, &(price, _, _))| match taker_side {
+                    Side::Bid => price <= limit_price,
+                    Side::Ask => price >= limit_price,
+                })
+                .map(|(seq_num, &(price, qty, own))| {
+                    let priority = match taker_side {
+                        Side::Bid => price,
+                        Side::Ask => u64::MAX - price,
+                    };
+                    (priority, seq_num, qty, own)
+                })
+                .collect();
+            crossing.sort();
+            let mut qty_remaining = max_qty;
+            let mut steps = 0;
+            for &(_, _, qty, own) in crossing.iter() {
+                if qty_remaining == 0 {
+                    break;
+                }
+                steps += 1;
+                if !own {
+                    qty_remaining -= qty.min(qty_remaining);
+                }
+            }
+            let fillable = qty_remaining == 0;
+
+            let taker_order_id = push_new_order(
+                req_q,
+                taker_side,
+                OrderType::FillOrKill,
+                resting.len() as u64,
+                &taker,
+                limit_price,
+                max_qty,
+                pc_lot_size,
+                SelfTradeBehavior::CancelProvide,
+            );
+            // an order the book can fill waits for a limit it can be filled within
+            if fillable && steps > limit {
+                prop_assert_eq!(
+                    order_book.process_requests(req_q, event_q, limit),
+                    Err(DexErrorCode::FillOrKillOverLimit.into())
+                );
+                prop_assert!(!req_q.empty());
+                order_book.process_requests(req_q, event_q, steps).unwrap();
+            } else {
+                order_book.process_requests(req_q, event_q, limit).unwrap();
+            }
+            prop_assert!(req_q.empty());
+
+            let mut filled_qty = 0;
+            let mut maker_fills = 0;
+            for event in event_q.iter() {
+                match event.as_view().unwrap() {
+                    EventView::Fill { maker: true, .. } => maker_fills += 1,
+                    EventView::Fill { side, native_qty_paid, native_qty_received, order_id, .. } => {
+                        prop_assert_eq!(*order_id, taker_order_id);
+                        filled_qty += match side {
+                            Side::Bid => native_qty_received / coin_lot_size,
+                            Side::Ask => native_qty_paid / coin_lot_size,
+                        };
+                    }
+                    EventView::Out { .. }
+                    | EventView::Requeue { .. }
+                    | EventView::MarketParams { .. } => (),
+                }
+            }
+            prop_assert!(filled_qty == 0 || filled_qty == max_qty);
+            prop_assert_eq!(filled_qty == max_qty, fillable);
+            if filled_qty == 0 {
+                prop_assert_eq!(maker_fills, 0);
+            }
+            Ok(())
+        })?;
+    }
+}
+
+proptest! {
+    #[test]
+    fn self_trade_never_fills(
+        orders in proptest::collection::vec(
+            (any::<Side>(), any::<bool>(), 1u64..10, 1u64..10, any::<SelfTradeBehavior>()),
+            1..40,
+        ),
+    ) {
+        with_order_book(|order_book, req_q, event_q| {
+            let pc_lot_size = order_book.market_state.pc_lot_size;
+            let owners = [[1u64; 4], [2u64; 4]];
+
+            for (seq_num, &(side, second_owner, price, qty, self_trade_behavior)) in
+                orders.iter().enumerate()
+            {
+                let owner = &owners[second_owner as usize];
+                push_new_order(
+                    req_q,
+                    side,
+                    OrderType::Limit,
+                    seq_num as u64,
+                    owner,
+                    price,
+                    qty,
+                    pc_lot_size,
+                    self_trade_behavior,
+                );
+                order_book.process_requests(req_q, event_q, 1000).unwrap();
+                prop_assert!(req_q.empty());
+
+                // every fill in this step is against the incoming order, so no maker
+                // may share its owner
+                while let Ok(event) = event_q.pop_front() {
+                    if let EventView::Fill { maker: true, owner: maker, .. } =
+                        event.as_view().unwrap()
+                    {
+                        prop_assert_ne!(maker, owner);
+                    }
+                }
+            }
+            Ok(())
+        })?;
+    }
+}
+
+proptest! {
+    #[test]
+    fn vaults_cover_deposits_and_fees(
+        orders in proptest::collection::vec(
+            (any::<Side>(), any::<FeeTier>(), 0usize..3, 1u64..20, 1u64..20),
+            1..50,
+        ),
+    ) {
+        with_order_book(|order_book, req_q, event_q| {
+            order_book.market_state.fee_schedule = FeeSchedule::default();
+            let fee_schedule = order_book.market_state.fee_schedule;
+            let coin_lot_size = order_book.market_state.coin_lot_size;
+            let pc_lot_size = order_book.market_state.pc_lot_size;
+
+            let mut coin_vault = 0;
+            let mut pc_vault = 0;
+            let mut native_coin_totals = [0u64; 3];
+            let mut native_pc_totals = [0u64; 3];
+            for (seq_num, &(side, fee_tier, owner, limit_price, max_qty)) in
+                orders.iter().enumerate()
+            {
+                // lock funds the way the new order instruction does
+                let native_pc_qty_locked = match side {
+                    Side::Bid => {
+                        let native_pc_qty = max_qty * limit_price * pc_lot_size;
+                        let native_pc_qty_locked =
+                            native_pc_qty + fee_schedule.taker_fee(fee_tier, native_pc_qty);
+                        pc_vault += native_pc_qty_locked;
+                        native_pc_totals[owner] += native_pc_qty_locked;
+                        order_book.market_state.pc_deposits_total += native_pc_qty_locked;
+                        NonZeroU64::new(native_pc_qty_locked)
+                    }
+                    Side::Ask => {
+                        let native_coin_qty_locked = max_qty * coin_lot_size;
+                        coin_vault += native_coin_qty_locked;
+                        native_coin_totals[owner] += native_coin_qty_locked;
+                        order_book.market_state.coin_deposits_total += native_coin_qty_locked;
+                        None
+                    }
+                };
+                let order_id = req_q.gen_order_id(limit_price, side);
+                req_q
+                    .push_back(Request::new(RequestView::NewOrder {
+                        side,
+                        order_type: OrderType::Limit,
+                        owner_slot: seq_num as u16,
+                        fee_tier,
+                        order_id: &order_id,
+                        max_coin_qty: NonZeroU64::new(max_qty).unwrap(),
+                        native_pc_qty_locked,
+                        owner: &[owner as u64; 4],
+                        client_order_id: None,
+                        self_trade_behavior: SelfTradeBehavior::DecrementTake,
+                        expiry: None,
+                        replaces: None,
+                        display_qty: None,
+                    }))
+                    .unwrap();
+                order_book.process_requests(req_q, event_q, 100).unwrap();
+
+                while let Ok(event) = event_q.pop_front() {
+                    if let EventView::Fill {
+                        side,
+                        native_qty_paid,
+                        native_qty_received,
+                        owner,
+                        ..
+                    } = event.as_view().unwrap()
+                    {
+                        let owner = owner[0] as usize;
+                        let (paid_from, received_into) = match side {
+                            Side::Bid => (&mut native_pc_totals, &mut native_coin_totals),
+                            Side::Ask => (&mut native_coin_totals, &mut native_pc_totals),
+                        };
+                        paid_from[owner] = paid_from[owner].checked_sub(native_qty_paid).unwrap();
+                        received_into[owner] += native_qty_received;
+                    }
+                }
+
+                let market_state = &order_book.market_state;
+                assert_eq!(
+                    market_state.coin_deposits_total,
+                    native_coin_totals.iter().sum::<u64>()
+                );
+                assert_eq!(
+                    market_state.pc_deposits_total,
+                    native_pc_totals.iter().sum::<u64>()
+                );
+                assert!(
+                    coin_vault >= market_state.coin_deposits_total + market_state.coin_fees_accrued
+                );
+                assert!(
+                    pc_vault
+                        >= market_state.pc_deposits_total
+                            + market_state.pc_fees_accrued
+                            + market_state.referrer_rebates_accrued
+                );
+            }
+        });
+    }
+}
+
+#[test]
+fn fill_or_kill_waits_for_a_limit_it_fits() {
+    with_order_book(|order_book, req_q, event_q| {
+        let pc_lot_size = order_book.market_state.pc_lot_size;
+        let taker = [1u64; 4];
+        let maker = [2u64; 4];
+        for seq_num in 0..3 {
+            push_new_order(
+                req_q,
+                Side::Ask,
+                OrderType::PostOnly,
+                seq_num,
+                &maker,
+                10,
+                1,
+                pc_lot_size,
+                SelfTradeBehavior::CancelProvide,
+            );
+        }
+        order_book.process_requests(req_q, event_q, 3).unwrap();
+        while event_q.pop_front().is_ok() {}
+
+        // the book holds enough, but taking it is three steps
+        push_new_order(
+            req_q,
+            Side::Bid,
+            OrderType::FillOrKill,
+            3,
+            &taker,
+            10,
+            3,
+            pc_lot_size,
+            SelfTradeBehavior::CancelProvide,
+        );
+        assert_eq!(
+            order_book.process_requests(req_q, event_q, 2),
+            Err(DexErrorCode::FillOrKillOverLimit.into())
+        );
+        assert!(!req_q.empty());
+        assert!(event_q.empty());
+
+        order_book.process_requests(req_q, event_q, 3).unwrap();
+        assert!(req_q.empty());
+        assert!(order_book.asks.is_empty());
+    });
+}
+
+#[test]
+fn auction_fills_charge_asks_the_taker_fee() {
+    with_order_book(|order_book, req_q, event_q| {
+        let fee_schedule = FeeSchedule::flat(30);
+        order_book.market_state.fee_schedule = fee_schedule;
+        order_book.market_state.pc_deposits_total = 1_000_000;
+        order_book.market_state.account_flags |= AccountFlag::Auction as u64;
+        let pc_lot_size = order_book.market_state.pc_lot_size;
+
+        // the later bid is filled as a maker all the same
+        let orders = [
+            (Side::Bid, [1u64; 4], 1),
+            (Side::Ask, [2u64; 4], 2),
+            (Side::Bid, [3u64; 4], 1),
+        ];
+        for (seq_num, &(side, ref owner, qty)) in orders.iter().enumerate() {
+            push_new_order(
+                req_q,
+                side,
+                OrderType::Limit,
+                seq_num as u64,
+                owner,
+                10_000,
+                qty,
+                pc_lot_size,
+                SelfTradeBehavior::DecrementTake,
+            );
+        }
+        order_book.process_requests(req_q, event_q, 3).unwrap();
+        while event_q.pop_front().is_ok() {}
+        assert!(order_book.run_auction(req_q, event_q, 3).unwrap());
+
+        let mut ask_fees = 0;
+        for event in event_q.iter() {
+            if let EventView::Fill {
+                side,
+                maker,
+                native_qty_paid,
+                native_qty_received,
+                native_fee_or_rebate,
+                ..
+            } = event.as_view().unwrap()
+            {
+                match side {
+                    Side::Bid => {
+                        assert!(maker);
+                        assert_eq!(native_fee_or_rebate, 0);
+                        assert_eq!(native_qty_paid, 10_000 * pc_lot_size);
+                    }
+                    Side::Ask => {
+                        let native_pc_qty = 2 * 10_000 * pc_lot_size;
+                        assert!(!maker);
+                        assert_eq!(
+                            native_fee_or_rebate,
+                            fee_schedule.taker_fee(FeeTier::Base, native_pc_qty)
+                        );
+                        assert_eq!(native_qty_received, native_pc_qty - native_fee_or_rebate);
+                        ask_fees += native_fee_or_rebate;
+                    }
+                }
+            }
+        }
+        assert!(ask_fees > 0);
+        let market = &order_book.market_state;
+        assert_eq!(
+            market.pc_fees_accrued + market.referrer_rebates_accrued,
+            ask_fees
+        );
+    });
+}
+
+#[test]
+fn replace_order_is_atomic() {
+    with_order_book(|order_book, req_q, event_q| {
+        let owner = [1u64; 4];
+        let pc_lot_size = order_book.market_state.pc_lot_size;
+        let old_order_id = push_new_order(
+            req_q,
+            Side::Bid,
+            OrderType::Limit,
+            0,
+            &owner,
+            10,
+            5,
+            pc_lot_size,
+            SelfTradeBehavior::CancelProvide,
+        );
+        order_book.process_requests(req_q, event_q, 1).unwrap();
+
+        let push_replacement = |req_q: &mut RequestQueue, seq_num: u64, limit_price: u64| {
+            let order_id = ((limit_price as u128) << 64) | !seq_num as u128;
+            req_q
+                .push_back(Request::new(RequestView::NewOrder {
+                    side: Side::Bid,
+                    order_type: OrderType::Limit,
+                    owner_slot: seq_num as u16,
+                    fee_tier: FeeTier::Base,
+                    order_id: &order_id,
+                    max_coin_qty: NonZeroU64::new(3).unwrap(),
+                    native_pc_qty_locked: NonZeroU64::new(3 * limit_price * pc_lot_size),
+                    owner: &owner,
+                    client_order_id: None,
+                    self_trade_behavior: SelfTradeBehavior::CancelProvide,
+                    expiry: None,
+                    replaces: Some((&old_order_id, 0)),
+                    display_qty: None,
+                }))
+                .unwrap();
+            order_id
+        };
+        let is_resting = |order_book: &OrderBookState, order_id: u128| {
+            order_book
+                .bids
+                .find_leaf_by(|leaf| *leaf.order_id() == order_id)
+                .is_some()
+        };
+
+        // the cancel and the new order go through in a single step
+        let new_order_id = push_replacement(req_q, 1, 11);
+        order_book.process_requests(req_q, event_q, 1).unwrap();
+        assert!(req_q.empty());
+        assert!(!is_resting(order_book, old_order_id));
+        assert!(is_resting(order_book, new_order_id));
+
+        // the old order is gone, so a second replacement of it never rests
+        let rejected_order_id = push_replacement(req_q, 2, 12);
+        order_book.process_requests(req_q, event_q, 1).unwrap();
+        assert!(req_q.empty());
+        assert!(is_resting(order_book, new_order_id));
+        assert!(!is_resting(order_book, rejected_order_id));
+    });
+}
+
+#[test]
+fn iceberg_order_loses_priority_when_replenished() {
+    with_order_book(|order_book, req_q, event_q| {
+        let iceberg_owner = [1u64; 4];
+        let other_owner = [2u64; 4];
+        let taker = [3u64; 4];
+        let push_ask = |req_q: &mut RequestQueue,
+                        owner: &[u64; 4],
+                        max_qty: u64,
+                        display_qty: Option<NonZeroU64>| {
+            let order_id = req_q.gen_order_id(10, Side::Ask);
+            req_q
+                .push_back(Request::new(RequestView::NewOrder {
+                    side: Side::Ask,
+                    order_type: OrderType::Limit,
+                    owner_slot: 0,
+                    fee_tier: FeeTier::Base,
+                    order_id: &order_id,
+                    max_coin_qty: NonZeroU64::new(max_qty).unwrap(),
+                    native_pc_qty_locked: None,
+                    owner,
+                    client_order_id: None,
+                    self_trade_behavior: SelfTradeBehavior::DecrementTake,
+                    expiry: None,
+                    replaces: None,
+                    display_qty,
+                }))
+                .unwrap();
+            order_id
+        };
+        let iceberg_order_id = push_ask(req_q, &iceberg_owner, 5, NonZeroU64::new(2));
+        push_ask(req_q, &other_owner, 1, None);
+        order_book.process_requests(req_q, event_q, 10).unwrap();
+
+        let iceberg = *order_book
+            .asks
+            .find_leaf_by(|leaf| leaf.owner() == &iceberg_owner)
+            .unwrap();
+        assert_eq!(*iceberg.order_id(), iceberg_order_id);
+        assert_eq!(iceberg.quantity(), 2);
+        assert_eq!(iceberg.hidden_qty(), 3);
+
+        let taker_order_id = req_q.gen_order_id(10, Side::Bid);
+        req_q
+            .push_back(Request::new(RequestView::NewOrder {
+                side: Side::Bid,
+                order_type: OrderType::ImmediateOrCancel,
+                owner_slot: 0,
+                fee_tier: FeeTier::Base,
+                order_id: &taker_order_id,
+                max_coin_qty: NonZeroU64::new(3).unwrap(),
+                native_pc_qty_locked: NonZeroU64::new(1_000),
+                owner: &taker,
+                client_order_id: None,
+                self_trade_behavior: SelfTradeBehavior::DecrementTake,
+                expiry: None,
+                replaces: None,
+                display_qty: None,
+            }))
+            .unwrap();
+        order_book.process_requests(req_q, event_q, 10).unwrap();
+        assert!(req_q.empty());
+
+        // the replenished slice queues up behind the order that was placed after it
+        let iceberg = *order_book
+            .asks
+            .find_leaf_by(|leaf| leaf.owner() == &iceberg_owner)
+            .unwrap();
+        assert_ne!(*iceberg.order_id(), iceberg_order_id);
+        assert_eq!(iceberg.quantity(), 2);
+        assert_eq!(iceberg.hidden_qty(), 1);
+
+        let mut maker_fills = vec![];
+        let mut replenished = vec![];
+        for event in event_q.iter() {
+            match event.as_view().unwrap() {
+                EventView::Fill {
+                    maker: true,
+                    owner,
+                    native_qty_paid,
+                    ..
+                } => maker_fills.push((*owner, native_qty_paid / 1_000)),
+                EventView::Requeue {
+                    order_id,
+                    new_order_id,
+                    ..
+                } => replenished.push((*order_id, new_order_id)),
+                _ => (),
+            }
+        }
+        assert_eq!(maker_fills, vec![(iceberg_owner, 2), (other_owner, 1)]);
+        assert_eq!(replenished, vec![(iceberg_order_id, *iceberg.order_id())]);
+    });
+}
+
+#[test]
+fn iceberg_order_can_be_cancelled_right_after_replenishing() {
+    with_order_book(|order_book, req_q, event_q| {
+        let iceberg_owner = [1u64; 4];
+        let taker = [2u64; 4];
+        let iceberg_order_id = req_q.gen_order_id(10, Side::Ask);
+        req_q
+            .push_back(Request::new(RequestView::NewOrder {
+                side: Side::Ask,
+                order_type: OrderType::Limit,
+                owner_slot: 0,
+                fee_tier: FeeTier::Base,
+                order_id: &iceberg_order_id,
+                max_coin_qty: NonZeroU64::new(5).unwrap(),
+                native_pc_qty_locked: None,
+                owner: &iceberg_owner,
+                client_order_id: None,
+                self_trade_behavior: SelfTradeBehavior::DecrementTake,
+                expiry: None,
+                replaces: None,
+                display_qty: NonZeroU64::new(2),
+            }))
+            .unwrap();
+        push_new_order(
+            req_q,
+            Side::Bid,
+            OrderType::ImmediateOrCancel,
+            1,
+            &taker,
+            10,
+            2,
+            order_book.market_state.pc_lot_size,
+            SelfTradeBehavior::DecrementTake,
+        );
+        order_book.process_requests(req_q, event_q, 10).unwrap();
+        let replenished_order_id = *order_book
+            .asks
+            .find_leaf_by(|leaf| leaf.owner() == &iceberg_owner)
+            .unwrap()
+            .order_id();
+        assert_ne!(replenished_order_id, iceberg_order_id);
+        let queued_len = event_q.len();
+
+        // the owner only knows the old order id until the Requeue event is consumed
+        req_q
+            .push_back(Request::new(RequestView::CancelOrder {
+                side: Side::Ask,
+                order_id: &iceberg_order_id,
+                expected_owner_slot: 0,
+                expected_owner: &iceberg_owner,
+                client_order_id: None,
+                cancel_id: 0,
+            }))
+            .unwrap();
+        order_book.process_requests(req_q, event_q, 10).unwrap();
+        assert!(order_book.asks.is_empty());
+
+        assert_eq!(event_q.len(), queued_len + 1);
+        let event = event_q.iter().last().unwrap();
+        match event.as_view().unwrap() {
+            EventView::Out {
+                side: Side::Ask,
+                native_qty_unlocked,
+                order_id,
+                owner_slot: 0,
+                ..
+            } => {
+                assert_eq!(*order_id, replenished_order_id);
+                assert_eq!(
+                    native_qty_unlocked,
+                    3 * order_book.market_state.coin_lot_size
+                );
+            }
+            view => panic!("{:?}", view),
+        }
+    });
+}
+
+#[test]
+fn pegged_bid_follows_best_bid() {
+    with_order_book(|order_book, req_q, event_q| {
+        let pegged_owner = [1u64; 4];
+        let bidder = [2u64; 4];
+        let seller = [3u64; 4];
+
+        // a pegged bid two ticks above the best bid, capped at 15
+        let cap_price = NonZeroU64::new(15);
+        let pegged_order_id = req_q.gen_order_id(15, Side::Bid);
+        let mut pegged_order = LeafNode::new(
+            0,
+            &pegged_order_key(&pegged_order_id, Side::Bid, 2),
+            &pegged_owner,
+            3,
+            FeeTier::Base,
+            0,
+        );
+        pegged_order.set_pegged_order_params(cap_price);
+        pegged_order.set_peg_price(15);
+        let pegged_orders = order_book.pegged_orders.as_deref_mut().unwrap();
+        pegged_orders.insert_leaf(&pegged_order).unwrap();
+
+        fn best_bid(order_book: &OrderBookState) -> LeafNode {
+            *order_book
+                .bids
+                .get(order_book.find_bbo(Side::Bid).unwrap())
+                .unwrap()
+                .as_leaf()
+                .unwrap()
+        }
+
+        // there is nothing to peg to until the first bid arrives
+        order_book.process_requests(req_q, event_q, 10).unwrap();
+        assert!(order_book.bids.is_empty());
+
+        push_new_order(
+            req_q,
+            Side::Bid,
+            OrderType::Limit,
+            100,
+            &bidder,
+            10,
+            1,
+            1,
+            SelfTradeBehavior::DecrementTake,
+        );
+        order_book.process_requests(req_q, event_q, 10).unwrap();
+        let pegged_bid = best_bid(order_book);
+        assert!(pegged_bid.is_pegged());
+        assert_eq!(pegged_bid.owner(), &pegged_owner);
+        assert_eq!(pegged_bid.price().get(), 12);
+
+        // the pegged bid stays clear of the asks, and is not moved while the price it
+        // would move to is unchanged
+        push_new_order(
+            req_q,
+            Side::Ask,
+            OrderType::Limit,
+            101,
+            &seller,
+            13,
+            1,
+            1,
+            SelfTradeBehavior::DecrementTake,
+        );
+        push_new_order(
+            req_q,
+            Side::Bid,
+            OrderType::Limit,
+            102,
+            &bidder,
+            12,
+            1,
+            1,
+            SelfTradeBehavior::DecrementTake,
+        );
+        order_book.process_requests(req_q, event_q, 10).unwrap();
+        assert_eq!(best_bid(order_book).order_id(), pegged_bid.order_id());
+
+        // filling below the cap frees the pc locked above the fill price
+        push_new_order(
+            req_q,
+            Side::Ask,
+            OrderType::ImmediateOrCancel,
+            103,
+            &seller,
+            12,
+            3,
+            1,
+            SelfTradeBehavior::DecrementTake,
+        );
+        order_book.process_requests(req_q, event_q, 10).unwrap();
+        assert!(!best_bid(order_book).is_pegged());
+        assert!(!order_book.pegged_orders.as_deref().unwrap().is_empty());
+
+        let mut requeued = vec![];
+        let mut pegged_outs = vec![];
+        for event in event_q.iter() {
+            match event.as_view().unwrap() {
+                EventView::Requeue {
+                    order_id,
+                    new_order_id,
+                    ..
+                } => requeued.push((*order_id, new_order_id)),
+                EventView::Out {
+                    owner,
+                    native_qty_unlocked,
+                    native_qty_still_locked,
+                    ..
+                } if owner == &pegged_owner => {
+                    pegged_outs.push((native_qty_unlocked, native_qty_still_locked))
+                }
+                _ => (),
+            }
+        }
+        assert_eq!(
+            requeued,
+            vec![(
+                pegged_order_key(&pegged_order_id, Side::Bid, 2),
+                *pegged_bid.order_id()
+            )]
+        );
+        assert_eq!(pegged_outs, vec![(9, 0)]);
+
+        // the filled order's entry is dropped the next time pegged orders are updated
+        order_book.process_requests(req_q, event_q, 10).unwrap();
+        assert!(order_book.pegged_orders.as_deref().unwrap().is_empty());
+    });
+}
+
+#[test]
+fn pegged_order_can_be_cancelled_before_its_requeues_are_consumed() {
+    with_order_book(|order_book, req_q, event_q| {
+        let pegged_owner = [1u64; 4];
+        let bidder = [2u64; 4];
+
+        let pegged_order_id = req_q.gen_order_id(15, Side::Bid);
+        let key = pegged_order_key(&pegged_order_id, Side::Bid, 2);
+        let mut pegged_order = LeafNode::new(0, &key, &pegged_owner, 3, FeeTier::Base, 0);
+        pegged_order.set_pegged_order_params(NonZeroU64::new(15));
+        pegged_order.set_peg_price(15);
+        let pegged_orders = order_book.pegged_orders.as_deref_mut().unwrap();
+        pegged_orders.insert_leaf(&pegged_order).unwrap();
+
+        // the pegged bid reaches the book at 12 and is then re-pegged to 13
+        for (seq_num, limit_price) in [(100, 10), (101, 11)].iter() {
+            push_new_order(
+                req_q,
+                Side::Bid,
+                OrderType::Limit,
+                *seq_num,
+                &bidder,
+                *limit_price,
+                1,
+                1,
+                SelfTradeBehavior::DecrementTake,
+            );
+            order_book.process_requests(req_q, event_q, 10).unwrap();
+        }
+        let pegged_bid = *order_book
+            .bids
+            .find_leaf_by(|leaf| leaf.is_pegged())
+            .unwrap();
+        assert_eq!(pegged_bid.price().get(), 13);
+        let mut requeues = 0;
+        for event in event_q.iter() {
+            if let EventView::Requeue { .. } = event.as_view().unwrap() {
+                requeues += 1;
+            }
+        }
+        assert_eq!(requeues, 2);
+        let queued_len = event_q.len();
+
+        // the owner still knows the order by the key it was placed with
+        req_q
+            .push_back(Request::new(RequestView::CancelOrder {
+                side: Side::Bid,
+                order_id: &key,
+                expected_owner_slot: 0,
+                expected_owner: &pegged_owner,
+                client_order_id: None,
+                cancel_id: 0,
+            }))
+            .unwrap();
+        order_book.process_requests(req_q, event_q, 10).unwrap();
+        assert!(order_book
+            .bids
+            .find_leaf_by(|leaf| leaf.is_pegged())
+            .is_none());
+
+        assert_eq!(event_q.len(), queued_len + 1);
+        match event_q.iter().last().unwrap().as_view().unwrap() {
+            EventView::Out {
+                side: Side::Bid,
+                native_qty_unlocked,
+                order_id,
+                owner_slot: 0,
+                ..
+            } => {
+                assert_eq!(order_id, pegged_bid.order_id());
+                assert_eq!(native_qty_unlocked, 3 * 15);
+            }
+            view => panic!("{:?}", view),
+        }
+    });
+}
+
+#[test]
+fn requeued_order_is_cancelled_after_its_requeue_is_consumed() {
+    with_order_book(|order_book, req_q, event_q| {
+        let pegged_owner = [1u64; 4];
+        let pegged_order_id = req_q.gen_order_id(15, Side::Bid);
+        let key = pegged_order_key(&pegged_order_id, Side::Bid, 2);
+        let mut pegged_order = LeafNode::new(7, &key, &pegged_owner, 3, FeeTier::Base, 0);
+        pegged_order.set_pegged_order_params(NonZeroU64::new(15));
+        pegged_order.set_peg_price(15);
+        let pegged_orders = order_book.pegged_orders.as_deref_mut().unwrap();
+        pegged_orders.insert_leaf(&pegged_order).unwrap();
+
+        // the cancel is queued while the owner only knows the key
+        push_new_order(
+            req_q,
+            Side::Bid,
+            OrderType::Limit,
+            100,
+            &[2; 4],
+            10,
+            1,
+            1,
+            SelfTradeBehavior::DecrementTake,
+        );
+        req_q
+            .push_back(Request::new(RequestView::CancelOrder {
+                side: Side::Bid,
+                order_id: &key,
+                expected_owner_slot: 7,
+                expected_owner: &pegged_owner,
+                client_order_id: None,
+                cancel_id: 0,
+            }))
+            .unwrap();
+        order_book.process_requests(req_q, event_q, 2).unwrap();
+        assert_eq!(req_q.len(), 1);
+        let pegged_bid = *order_book
+            .bids
+            .find_leaf_by(|leaf| leaf.is_pegged())
+            .unwrap();
+
+        // and the Requeue event is gone by the time the cancel is processed
+        while event_q.pop_front().is_ok() {}
+        order_book.process_requests(req_q, event_q, 10).unwrap();
+        assert!(order_book
+            .bids
+            .find_leaf_by(|leaf| leaf.is_pegged())
+            .is_none());
+        match event_q.iter().last().unwrap().as_view().unwrap() {
+            EventView::Out {
+                order_id,
+                owner_slot: 7,
+                native_qty_unlocked,
+                ..
+            } => {
+                assert_eq!(order_id, pegged_bid.order_id());
+                assert_eq!(native_qty_unlocked, 3 * 15);
+            }
+            view => panic!("{:?}", view),
+        }
+    });
+}
+
+#[test]
+fn triggered_stop_order_is_cancelled_before_it_reaches_the_book() {
+    with_order_book(|order_book, req_q, event_q| {
+        let stop_owner = [3u64; 4];
+        let stop_order_id = req_q.gen_order_id(9, Side::Ask);
+        let key = stop_order_key(&stop_order_id, Side::Ask, 10);
+        let mut stop_order = LeafNode::new(5, &key, &stop_owner, 2, FeeTier::Base, 0);
+        stop_order.set_stop_order_params(OrderType::Limit, SelfTradeBehavior::DecrementTake, 9, 0);
+        let stop_orders = order_book.stop_orders.as_deref_mut().unwrap();
+        stop_orders.insert_leaf(&stop_order).unwrap();
+
+        // a trade at 10 triggers the stop, which is queued behind the cancel
+        for &(side, seq_num, owner) in &[(Side::Bid, 100, [1u64; 4]), (Side::Ask, 101, [2; 4])] {
+            push_new_order(
+                req_q,
+                side,
+                OrderType::Limit,
+                seq_num,
+                &owner,
+                10,
+                1,
+                1,
+                SelfTradeBehavior::DecrementTake,
+            );
+        }
+        req_q
+            .push_back(Request::new(RequestView::CancelOrder {
+                side: Side::Ask,
+                order_id: &key,
+                expected_owner_slot: 5,
+                expected_owner: &stop_owner,
+                client_order_id: None,
+                cancel_id: 0,
+            }))
+            .unwrap();
+        order_book.process_requests(req_q, event_q, 10).unwrap();
+
+        assert!(req_q.empty());
+        assert!(order_book.asks.is_empty());
+        assert!(order_book.stop_orders.as_deref().unwrap().is_empty());
+        match event_q.iter().last().unwrap().as_view().unwrap() {
+            EventView::Out {
+                side: Side::Ask,
+                owner_slot: 5,
+                native_qty_unlocked,
+                order_id,
+                ..
+            } => {
+                assert_eq!(*order_id as u64, !(key as u64));
+                assert_eq!(native_qty_unlocked, 2 * 1_000);
+            }
+            view => panic!("{:?}", view),
+        }
+    });
+}
+
+#[test]
+fn pegged_orders_are_moved_within_the_limit() {
+    with_order_book(|order_book, req_q, event_q| {
+        let bidder = [2u64; 4];
+        for peg_offset in 1..=3 {
+            let pegged_order_id = req_q.gen_order_id(15, Side::Bid);
+            let key = pegged_order_key(&pegged_order_id, Side::Bid, peg_offset);
+            let mut pegged_order = LeafNode::new(0, &key, &[1; 4], 3, FeeTier::Base, 0);
+            pegged_order.set_pegged_order_params(NonZeroU64::new(15));
+            pegged_order.set_peg_price(15);
+            let pegged_orders = order_book.pegged_orders.as_deref_mut().unwrap();
+            pegged_orders.insert_leaf(&pegged_order).unwrap();
+        }
+        let pegged_bids = |order_book: &OrderBookState| {
+            order_book
+                .bids
+                .leaves(false)
+                .filter(|leaf| leaf.is_pegged())
+                .count()
+        };
+
+        // the bid takes one unit of the limit and the first pegged order the other
+        push_new_order(
+            req_q,
+            Side::Bid,
+            OrderType::Limit,
+            100,
+            &bidder,
+            10,
+            1,
+            1,
+            SelfTradeBehavior::DecrementTake,
+        );
+        order_book.process_requests(req_q, event_q, 2).unwrap();
+        assert_eq!(pegged_bids(order_book), 1);
+        assert_ne!(order_book.market_state.pegged_orders_cursor, [0; 2]);
+
+        // the next call carries on where the last one stopped
+        order_book.process_requests(req_q, event_q, 10).unwrap();
+        assert_eq!(pegged_bids(order_book), 3);
+        assert_eq!(order_book.market_state.pegged_orders_cursor, [0; 2]);
+        let prices: Vec<_> = order_book
+            .bids
+            .leaves(false)
+            .filter(|leaf| leaf.is_pegged())
+            .map(|leaf| leaf.price().get())
+            .collect();
+        assert_eq!(prices, vec![11, 12, 13]);
+    });
+}
+
+#[test]
+fn post_only_order_is_not_pegged() {
+    with_order_book(|order_book, req_q, event_q| {
+        let pc_lot_size = order_book.market_state.pc_lot_size;
+        push_new_order(
+            req_q,
+            Side::Bid,
+            OrderType::PostOnly,
+            0,
+            &[1; 4],
+            100,
+            2,
+            pc_lot_size,
+            SelfTradeBehavior::DecrementTake,
+        );
+        order_book.process_requests(req_q, event_q, 10).unwrap();
+
+        let bid = *order_book.bids.find_leaf_by(|_| true).unwrap();
+        assert!(!bid.is_pegged());
+        assert_eq!(bid.lock_price().get(), 100);
+        assert_eq!(bid.total_quantity(), 2);
+    });
+}
+
+#[test]
+fn market_buy_spends_its_pc_budget() {
+    with_order_book(|order_book, req_q, event_q| {
+        let seller = [1u64; 4];
+        let buyer = [2u64; 4];
+        let pc_lot_size = order_book.market_state.pc_lot_size;
+        for &(seq_num, price, qty) in &[(0, 10, 2), (1, 11, 5)] {
+            push_new_order(
+                req_q,
+                Side::Ask,
+                OrderType::Limit,
+                seq_num,
+                &seller,
+                price,
+                qty,
+                pc_lot_size,
+                SelfTradeBehavior::DecrementTake,
+            );
+        }
+        order_book.process_requests(req_q, event_q, 10).unwrap();
+
+        let order_id = req_q.gen_order_id(0, Side::Bid);
+        req_q
+            .push_back(Request::new(RequestView::NewOrder {
+                side: Side::Bid,
+                order_type: OrderType::ImmediateOrCancel,
+                owner_slot: 0,
+                fee_tier: FeeTier::Base,
+                order_id: &order_id,
+                max_coin_qty: NonZeroU64::new(std::u64::MAX).unwrap(),
+                native_pc_qty_locked: NonZeroU64::new(50),
+                owner: &buyer,
+                client_order_id: None,
+                self_trade_behavior: SelfTradeBehavior::DecrementTake,
+                expiry: None,
+                replaces: None,
+                display_qty: None,
+            }))
+            .unwrap();
+        order_book.process_requests(req_q, event_q, 10).unwrap();
+        assert!(req_q.empty());
+
+        // 2 lots at 10 and 2 lots at 11 exhaust the budget, one maker per match, and the
+        // rest is unlocked
+        let mut taker_fills = vec![];
+        let mut outs = vec![];
+        for event in event_q.iter() {
+            match event.as_view().unwrap() {
+                EventView::Fill {
+                    maker: false,
+                    native_qty_paid,
+                    native_qty_received,
+                    native_fee_or_rebate,
+                    ..
+                } => taker_fills.push((native_qty_paid, native_qty_received, native_fee_or_rebate)),
+                EventView::Out {
+                    owner,
+                    native_qty_unlocked,
+                    native_qty_still_locked,
+                    ..
+                } if owner == &buyer => outs.push((native_qty_unlocked, native_qty_still_locked)),
+                _ => (),
+            }
+        }
+        let fee_schedule = order_book.market_state.fee_schedule;
+        let fee = |native_pc_qty| fee_schedule.taker_fee(FeeTier::Base, native_pc_qty);
+        assert_eq!(
+            taker_fills,
+            vec![
+                (20 + fee(20), 2_000, fee(20)),
+                (22 + fee(22), 2_000, fee(22))
+            ]
+        );
+        assert_eq!(outs, vec![(50 - 42 - fee(20) - fee(22), 0)]);
+        assert!(order_book.bids.find_min().is_none());
+        let rest = order_book
+            .asks
+            .find_leaf_by(|leaf| leaf.owner() == &seller)
+            .unwrap();
+        assert_eq!((rest.price().get(), rest.quantity()), (11, 3));
+    });
+}
+
+#[test]
+fn price_band_stops_matching_and_halts() {
+    with_order_book(|order_book, req_q, event_q| {
+        order_book.market_state.last_fill_price = 100;
+        order_book.market_state.price_band_bps = 1_000;
+        order_book.market_state.price_band_halt_slots = 5;
+        let pc_lot_size = order_book.market_state.pc_lot_size;
+
+        for (seq_num, &limit_price) in [100, 105, 115].iter().enumerate() {
+            push_new_order(
+                req_q,
+                Side::Ask,
+                OrderType::Limit,
+                seq_num as u64,
+                &[seq_num as u64 + 1; 4],
+                limit_price,
+                1,
+                pc_lot_size,
+                SelfTradeBehavior::DecrementTake,
+            );
+        }
+        order_book.process_requests(req_q, event_q, 10).unwrap();
+
+        // a 10% band around 100 lets the bid take the first two offers, but instead of
+        // resting at 200 the rest of it is cancelled
+        push_new_order(
+            req_q,
+            Side::Bid,
+            OrderType::Limit,
+            3,
+            &[9; 4],
+            200,
+            3,
+            pc_lot_size,
+            SelfTradeBehavior::DecrementTake,
+        );
+        order_book.process_requests(req_q, event_q, 10).unwrap();
+        assert!(req_q.empty());
+        assert!(order_book.bids.is_empty());
+        assert!(order_book
+            .asks
+            .find_leaf_by(|leaf| leaf.price().get() != 115)
+            .is_none());
+        assert_eq!(order_book.market_state.last_fill_price, 105);
+        assert_eq!(order_book.market_state.halted_until_slot, 5);
+
+        // while halted, new orders are cancelled without touching the book
+        push_new_order(
+            req_q,
+            Side::Ask,
+            OrderType::Limit,
+            4,
+            &[1; 4],
+            110,
+            1,
+            pc_lot_size,
+            SelfTradeBehavior::DecrementTake,
+        );
+        order_book.process_requests(req_q, event_q, 10).unwrap();
+        assert!(req_q.empty());
+        assert!(order_book
+            .asks
+            .find_leaf_by(|leaf| leaf.price().get() != 115)
+            .is_none());
+        let mut last_event = None;
+        while let Ok(event) = event_q.pop_front() {
+            last_event = Some(event);
+        }
+        let last_event = last_event.as_ref().map(|event| event.as_view().unwrap());
+        match last_event {
+            Some(EventView::Out {
+                side: Side::Ask,
+                native_qty_unlocked,
+                owner_slot: 4,
+                ..
+            }) => assert_eq!(native_qty_unlocked, order_book.market_state.coin_lot_size),
+            _ => panic!("{:?}", last_event),
+        }
+    });
+}
+
+fn best_price(order_book: &OrderBookState, side: Side) -> Option<u64> {
+    let best_order_h = order_book.find_bbo(side)?;
+    let best_order = order_book.orders(side).get(best_order_h)?.as_leaf()?;
+    Some(best_order.price().get())
+}
+
+#[test]
+fn auction_fills_at_one_clearing_price() {
+    with_order_book(|order_book, req_q, event_q| {
+        order_book.market_state.account_flags |= AccountFlag::Auction as u64;
+        order_book.market_state.fee_schedule = FeeSchedule::default();
+        let pc_lot_size = order_book.market_state.pc_lot_size;
+        let coin_lot_size = order_book.market_state.coin_lot_size;
+        // what the bids below deposit
+        order_book.market_state.pc_deposits_total = (3 * 110 + 100) * pc_lot_size;
+
+        let orders = [
+            (Side::Ask, 100, 2),
+            (Side::Ask, 105, 2),
+            (Side::Bid, 110, 3),
+            (Side::Bid, 100, 1),
+        ];
+        for (seq_num, &(side, limit_price, max_qty)) in orders.iter().enumerate() {
+            push_new_order(
+                req_q,
+                side,
+                OrderType::Limit,
+                seq_num as u64,
+                &[seq_num as u64 + 1; 4],
+                limit_price,
+                max_qty,
+                pc_lot_size,
+                SelfTradeBehavior::DecrementTake,
+            );
+        }
+        order_book.process_requests(req_q, event_q, 10).unwrap();
+        assert!(req_q.empty());
+        assert_eq!(best_price(order_book, Side::Bid), Some(110));
+        assert_eq!(best_price(order_book, Side::Ask), Some(100));
+        assert_eq!(order_book.market_state.last_fill_price, 0);
+
+        // 105 and 110 both match 3 lots with 1 left over, so the lower one wins
+        assert!(!order_book.run_auction(req_q, event_q, 2).unwrap());
+        assert_eq!(order_book.market_state.last_fill_price, 105);
+        assert_eq!(order_book.market_state.auction_price, 105);
+        assert_eq!(order_book.market_state.auction_bid_qty_left, 0);
+        assert_eq!(order_book.market_state.auction_ask_qty_left, 1);
+
+        // the next call picks up at the same price
+        assert!(order_book.run_auction(req_q, event_q, 2).unwrap());
+        assert!(!order_book.market_state.auction_in_progress());
+        assert_eq!(best_price(order_book, Side::Bid), Some(100));
+        assert_eq!(best_price(order_book, Side::Ask), Some(105));
+
+        let mut bid_fills = (0, 0);
+        let mut ask_fills = (0, 0);
+        let mut ask_fees = 0;
+        let mut bid_unlocked = 0;
+        while let Ok(event) = event_q.pop_front() {
+            match event.as_view().unwrap() {
+                EventView::Fill {
+                    side,
+                    maker,
+                    native_qty_paid,
+                    native_qty_received,
+                    native_fee_or_rebate,
+                    ..
+                } => {
+                    // asks pay the taker fee, and bids neither pay it nor get a rebate
+                    assert_eq!(maker, side == Side::Bid);
+                    let fills = match side {
+                        Side::Bid => {
+                            assert_eq!(native_fee_or_rebate, 0);
+                            &mut bid_fills
+                        }
+                        Side::Ask => {
+                            ask_fees += native_fee_or_rebate;
+                            &mut ask_fills
+                        }
+                    };
+                    fills.0 += native_qty_paid;
+                    fills.1 += native_qty_received;
+                }
+                EventView::Out {
+                    side: Side::Bid,
+                    native_qty_unlocked,
+                    ..
+                } => bid_unlocked += native_qty_unlocked,
+                _ => (),
+            }
+        }
+        let fee_schedule = order_book.market_state.fee_schedule;
+        let fee = |native_pc_qty| fee_schedule.taker_fee(FeeTier::Base, native_pc_qty);
+        assert_eq!(
+            ask_fees,
+            fee(2 * 105 * pc_lot_size) + fee(105 * pc_lot_size)
+        );
+        assert!(ask_fees > 0);
+        assert_eq!(
+            order_book.market_state.pc_fees_accrued
+                + order_book.market_state.referrer_rebates_accrued,
+            ask_fees
+        );
+        assert_eq!(bid_fills, (3 * 105 * pc_lot_size, 3 * coin_lot_size));
+        assert_eq!(
+            ask_fills,
+            (3 * coin_lot_size, 3 * 105 * pc_lot_size - ask_fees)
+        );
+        // the bid was locked at 110 but only paid 105
+        assert_eq!(bid_unlocked, 3 * 5 * pc_lot_size);
+    });
+}
+
+#[test]
+fn auction_applies_self_trade_behavior() {
+    with_order_book(|order_book, req_q, event_q| {
+        order_book.market_state.account_flags |= AccountFlag::Auction as u64;
+        let pc_lot_size = order_book.market_state.pc_lot_size;
+        let coin_lot_size = order_book.market_state.coin_lot_size;
+
+        // the bid comes later, so it cancels the ask of its own owner that it crosses
+        let own_ask_id = push_new_order(
+            req_q,
+            Side::Ask,
+            OrderType::Limit,
+            0,
+            &[1; 4],
+            100,
+            2,
+            pc_lot_size,
+            SelfTradeBehavior::DecrementTake,
+        );
+        push_new_order(
+            req_q,
+            Side::Ask,
+            OrderType::Limit,
+            1,
+            &[2; 4],
+            105,
+            1,
+            pc_lot_size,
+            SelfTradeBehavior::DecrementTake,
+        );
+        push_new_order(
+            req_q,
+            Side::Bid,
+            OrderType::Limit,
+            2,
+            &[1; 4],
+            110,
+            3,
+            pc_lot_size,
+            SelfTradeBehavior::CancelProvide,
+        );
+        order_book.process_requests(req_q, event_q, 10).unwrap();
+        while event_q.pop_front().is_ok() {}
+
+        // cancelling the pair takes the only unit of the limit, so nothing fills yet
+        assert!(!order_book.run_auction(req_q, event_q, 1).unwrap());
+        assert_eq!(order_book.market_state.auction_price, 105);
+        assert_eq!(order_book.market_state.auction_bid_qty_left, 1);
+        assert_eq!(best_price(order_book, Side::Ask), Some(105));
+        match event_q.pop_front().unwrap().as_view().unwrap() {
+            EventView::Out {
+                side: Side::Ask,
+                order_id,
+                native_qty_unlocked,
+                ..
+            } => {
+                assert_eq!(*order_id, own_ask_id);
+                assert_eq!(native_qty_unlocked, 2 * coin_lot_size);
+            }
+            view => panic!("{:?}", view),
+        }
+
+        // only the other owner's ask is left to fill
+        assert!(order_book.run_auction(req_q, event_q, 4).unwrap());
+        assert_eq!(order_book.market_state.last_fill_price, 105);
+        assert_eq!(best_price(order_book, Side::Ask), None);
+        assert_eq!(best_price(order_book, Side::Bid), Some(110));
+    });
+}